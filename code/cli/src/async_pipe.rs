@@ -246,6 +246,10 @@ pub type AcceptedRW = (
 #[async_trait]
 pub trait AsyncRWAccepter {
 	async fn accept_rw(&mut self) -> Result<AcceptedRW, CodeError>;
+
+	/// Like `accept_rw`, but also returns the address of the peer, if the
+	/// listener knows it.
+	async fn accept_rw_with_peer(&mut self) -> Result<(AcceptedRW, Option<String>), CodeError>;
 }
 
 #[async_trait]
@@ -255,16 +259,24 @@ impl AsyncRWAccepter for AsyncPipeListener {
 		let (read, write) = socket_stream_split(pipe);
 		Ok((Box::new(read), Box::new(write)))
 	}
+
+	async fn accept_rw_with_peer(&mut self) -> Result<(AcceptedRW, Option<String>), CodeError> {
+		self.accept_rw().await.map(|rw| (rw, None))
+	}
 }
 
 #[async_trait]
 impl AsyncRWAccepter for TcpListener {
 	async fn accept_rw(&mut self) -> Result<AcceptedRW, CodeError> {
-		let (stream, _) = self
+		self.accept_rw_with_peer().await.map(|(rw, _)| rw)
+	}
+
+	async fn accept_rw_with_peer(&mut self) -> Result<(AcceptedRW, Option<String>), CodeError> {
+		let (stream, addr) = self
 			.accept()
 			.await
 			.map_err(CodeError::AsyncPipeListenerFailed)?;
 		let (read, write) = tokio::io::split(stream);
-		Ok((Box::new(read), Box::new(write)))
+		Ok(((Box::new(read), Box::new(write)), Some(addr.to_string())))
 	}
 }
@@ -74,6 +74,7 @@ pub fn try_parse_legacy(
 				subcommand: ExtensionSubcommand::List(ListExtensionArgs {
 					category: get_first_arg_value("category"),
 					show_versions: args.contains_key("show-versions"),
+					format: None,
 				}),
 				desktop_code_options,
 			})),
@@ -138,6 +139,8 @@ mod tests {
 			if let ExtensionSubcommand::List(list_args) = extension_args.subcommand {
 				assert_eq!(list_args.category, Some("themes".to_string()));
 				assert!(list_args.show_versions);
+				// scripts parse the one-per-line output of the legacy flag
+				assert!(list_args.format.is_none());
 			} else {
 				panic!(
 					"Expected list subcommand, got {:?}",
@@ -8,7 +8,7 @@ use std::process::Command;
 
 use clap::Parser;
 use cli::{
-	commands::{agent_host, args, serve_web, tunnels, update, version, CommandContext},
+	commands::{agent_host, args, extensions, serve_web, tunnels, update, version, CommandContext},
 	constants::get_default_user_agent,
 	desktop, log,
 	state::LauncherPaths,
@@ -74,6 +74,16 @@ async fn main() -> Result<(), std::convert::Infallible> {
 				start_code(context, ca).await
 			}
 
+			Some(args::Commands::Extension(args::ExtensionArgs {
+				subcommand: args::ExtensionSubcommand::List(list_args),
+				desktop_code_options,
+			})) => extensions::list(context!(), desktop_code_options, list_args).await,
+
+			Some(args::Commands::Extension(args::ExtensionArgs {
+				subcommand: args::ExtensionSubcommand::Sync(sync_args),
+				desktop_code_options,
+			})) => extensions::sync(context!(), desktop_code_options, sync_args).await,
+
 			Some(args::Commands::Extension(extension_args)) => {
 				let context = context!();
 				let mut ca = context.args.get_base_code_args();
@@ -99,20 +109,47 @@ async fn main() -> Result<(), std::convert::Infallible> {
 				tunnels::command_shell(context!(), cs_args).await
 			}
 
-			Some(args::Commands::ServeWeb(sw_args)) => {
-				serve_web::serve_web(context!(), sw_args).await
-			}
+			Some(args::Commands::ServeWeb(mut sw_args)) => match sw_args.subcommand.take() {
+				Some(args::ServeWebSubcommand::ApproveUpdate(approve_args)) => {
+					serve_web::approve_update(context!(), approve_args).await
+				}
+				None => serve_web::serve_web(context!(), *sw_args).await,
+			},
 
-			Some(args::Commands::AgentHost(ah_args)) => {
-				agent_host::agent_host(context!(), ah_args).await
-			}
+			Some(args::Commands::AgentHost(ah_args)) => match ah_args.subcommand {
+				Some(args::AgentHostSubcommand::Status(format)) => {
+					agent_host::status(context!(), format).await
+				}
+				Some(args::AgentHostSubcommand::Stop) => agent_host::stop(context!()).await,
+				Some(args::AgentHostSubcommand::Restart) => agent_host::restart(context!()).await,
+				Some(args::AgentHostSubcommand::Update(format)) => {
+					agent_host::update(context!(), format).await
+				}
+				None => agent_host::agent_host(context!(), ah_args.serve_args).await,
+			},
 
 			Some(args::Commands::Tunnel(mut tunnel_args)) => match tunnel_args.subcommand.take() {
-				Some(args::TunnelSubcommand::Prune) => tunnels::prune(context!()).await,
-				Some(args::TunnelSubcommand::Unregister) => tunnels::unregister(context!()).await,
-				Some(args::TunnelSubcommand::Kill) => tunnels::kill(context!()).await,
-				Some(args::TunnelSubcommand::Restart) => tunnels::restart(context!()).await,
-				Some(args::TunnelSubcommand::Status) => tunnels::status(context!()).await,
+				Some(args::TunnelSubcommand::Logs(logs_args)) => {
+					tunnels::logs(context!(), logs_args).await
+				}
+				Some(args::TunnelSubcommand::Audit(audit_args)) => {
+					tunnels::audit(context!(), audit_args).await
+				}
+				Some(args::TunnelSubcommand::Prune(prune_args)) => {
+					tunnels::prune(context!(), prune_args).await
+				}
+				Some(args::TunnelSubcommand::Unregister(instance_args)) => {
+					tunnels::unregister(context!(), instance_args).await
+				}
+				Some(args::TunnelSubcommand::Kill(instance_args)) => {
+					tunnels::kill(context!(), instance_args).await
+				}
+				Some(args::TunnelSubcommand::Restart(instance_args)) => {
+					tunnels::restart(context!(), instance_args).await
+				}
+				Some(args::TunnelSubcommand::Status(status_args)) => {
+					tunnels::status(context!(), status_args).await
+				}
 				Some(args::TunnelSubcommand::Rename(rename_args)) => {
 					tunnels::rename(context!(), rename_args).await
 				}
@@ -122,6 +159,12 @@ async fn main() -> Result<(), std::convert::Infallible> {
 				Some(args::TunnelSubcommand::Service(service_args)) => {
 					tunnels::service(context_no_logger(), tunnel_args, service_args).await
 				}
+				Some(args::TunnelSubcommand::Ext(ext_args)) => {
+					extensions::tunnel_ext(context!(), ext_args).await
+				}
+				Some(args::TunnelSubcommand::Forward(forward_args)) => {
+					tunnels::forward_ports(context!(), forward_args).await
+				}
 				Some(args::TunnelSubcommand::ForwardInternal(forward_args)) => {
 					tunnels::forward(context_no_logger(), forward_args).await
 				}
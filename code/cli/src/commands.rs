@@ -7,6 +7,8 @@ mod context;
 
 pub mod agent_host;
 pub mod args;
+pub mod extensions;
+mod output;
 pub mod serve_web;
 pub mod tunnels;
 pub mod update;
@@ -10,27 +10,44 @@ use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
+use chrono::{DateTime, Utc};
+use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::Server;
+use tokio::sync::mpsc;
 
+use crate::async_pipe::socket_stream_split;
+use crate::constants::APPLICATION_NAME;
+use crate::json_rpc::{new_json_rpc, start_json_rpc};
 use crate::log;
+use crate::singleton::{acquire_singleton, SingletonConnection, SingletonServer};
 use crate::tunnels::agent_host::{handle_request, AgentHostConfig, AgentHostManager};
 use crate::tunnels::legal;
-use crate::tunnels::shutdown_signal::ShutdownRequest;
+use crate::tunnels::protocol::{self, agent_host_singleton};
+use crate::tunnels::shutdown_signal::{ShutdownRequest, ShutdownSignal};
+use crate::tunnels::singleton_client::do_single_rpc_call;
 use crate::update_service::Platform;
 use crate::util::errors::AnyError;
 use crate::util::errors::CodeError;
 use crate::util::http::ReqwestSimpleHttp;
 use crate::util::prereqs::PreReqChecker;
+use crate::util::sync::Barrier;
 
-use super::{args::AgentHostArgs, CommandContext};
+use super::{
+	args::{AgentHostServeArgs, OutputFormat, OutputFormatOptions},
+	output::{Column, OutputTable},
+	CommandContext,
+};
 
 /// Runs a local agent host server. Downloads the latest VS Code server on
 /// demand, starts it with `--enable-remote-auto-shutdown`, and proxies
 /// WebSocket connections from a local TCP port to the server's agent host
 /// socket. The server auto-shuts down when idle; the CLI checks for updates
 /// in the background and starts the latest version on the next connection.
-pub async fn agent_host(ctx: CommandContext, mut args: AgentHostArgs) -> Result<i32, AnyError> {
+pub async fn agent_host(
+	ctx: CommandContext,
+	mut args: AgentHostServeArgs,
+) -> Result<i32, AnyError> {
 	legal::require_consent(&ctx.paths, args.accept_server_license_terms)?;
 
 	let platform: Platform = PreReqChecker::new().verify().await?;
@@ -52,13 +69,27 @@ pub async fn agent_host(ctx: CommandContext, mut args: AgentHostArgs) -> Result<
 	let manager = AgentHostManager::new(
 		ctx.log.clone(),
 		platform,
-		ctx.paths.server_cache.clone(),
+		ctx.paths
+			.server_cache
+			.clone()
+			.with_retention(args.retention.cache_policy()),
 		Arc::new(ReqwestSimpleHttp::with_client(ctx.http.clone())),
 		AgentHostConfig {
 			server_data_dir: args.server_data_dir.clone(),
+			workspaces_data_dir: args
+				.workspaces_data_dir
+				.as_ref()
+				.map(PathBuf::from)
+				.unwrap_or_else(|| ctx.paths.root().join("agent-host-workspaces")),
+			max_workspaces: args.max_workspaces,
 			without_connection_token: args.without_connection_token,
 			connection_token: args.connection_token.clone(),
 			connection_token_file: args.connection_token_file.clone(),
+			access_log: args.access_log.open()?,
+			fallback_on_crash_loop: args.fallback_on_crash_loop,
+			known_good_file: ctx.paths.agent_host_known_good_file(),
+			update_policy: args.update_policy.policy(),
+			limits: args.limits.limits(),
 		},
 	);
 
@@ -82,7 +113,11 @@ pub async fn agent_host(ctx: CommandContext, mut args: AgentHostArgs) -> Result<
 	}
 
 	// Bind the HTTP/WebSocket proxy
-	let mut shutdown = ShutdownRequest::create_rx([ShutdownRequest::CtrlC]);
+	let (rpc_shutdown_tx, rpc_shutdown_rx) = mpsc::unbounded_channel();
+	let mut shutdown = ShutdownRequest::create_rx([
+		ShutdownRequest::CtrlC,
+		ShutdownRequest::Derived(Box::new(rpc_shutdown_rx)),
+	]);
 
 	let addr: SocketAddr = match &args.host {
 		Some(h) => SocketAddr::new(h.parse().map_err(CodeError::InvalidHostAddress)?, args.port),
@@ -91,25 +126,48 @@ pub async fn agent_host(ctx: CommandContext, mut args: AgentHostArgs) -> Result<
 	let builder = Server::try_bind(&addr).map_err(CodeError::CouldNotListenOnInterface)?;
 	let bound_addr = builder.local_addr();
 
-	let mut url = format!("ws://{bound_addr}");
+	let address = format!("ws://{bound_addr}");
+	let mut url = address.clone();
 	if let Some(ct) = &args.connection_token {
 		url.push_str(&format!("?tkn={ct}"));
 	}
 	ctx.log
 		.result(format!("Agent host proxy listening on {url}"));
 
+	// Serve control commands from other CLI processes on the machine
+	match acquire_singleton(&ctx.paths.agent_host_lockfile()).await {
+		Ok(SingletonConnection::Singleton(server)) => {
+			let control = ControlContext {
+				log: ctx.log.clone(),
+				manager: manager.clone(),
+				address,
+				started_at: Utc::now(),
+				shutdown_tx: rpc_shutdown_tx,
+			};
+			tokio::spawn(serve_control_rpc(server, control, shutdown.clone()));
+		}
+		Ok(SingletonConnection::Client(_)) => warning!(
+			ctx.log,
+			"Another agent host is running on this machine, `{} agent-host status` and related commands will control that one",
+			APPLICATION_NAME
+		),
+		Err(e) => warning!(ctx.log, "Could not listen for control commands: {}", e),
+	}
+
 	let manager_for_svc = manager.clone();
-	let make_svc = move || {
+	let make_svc = move |remote_addr: SocketAddr| {
 		let mgr = manager_for_svc.clone();
 		let service = service_fn(move |req| {
 			let mgr = mgr.clone();
-			async move { handle_request(mgr, req).await }
+			async move { handle_request(mgr, Some(remote_addr), req).await }
 		});
 		async move { Ok::<_, Infallible>(service) }
 	};
 
 	let server_future = builder
-		.serve(make_service_fn(|_| make_svc()))
+		.serve(make_service_fn(|conn: &AddrStream| {
+			make_svc(conn.remote_addr())
+		}))
 		.with_graceful_shutdown(async {
 			let _ = shutdown.wait().await;
 		});
@@ -121,6 +179,206 @@ pub async fn agent_host(ctx: CommandContext, mut args: AgentHostArgs) -> Result<
 	Ok(0)
 }
 
+/// Gets the status of the running agent host.
+pub async fn status(ctx: CommandContext, format: OutputFormatOptions) -> Result<i32, AnyError> {
+	let status: agent_host_singleton::Status = call_running_agent_host(
+		&ctx,
+		agent_host_singleton::METHOD_STATUS,
+		protocol::EmptyObject {},
+	)
+	.await?;
+
+	if let OutputFormat::Json = format.format {
+		ctx.log.result(serde_json::to_string(&status).unwrap());
+		return Ok(0);
+	}
+
+	ctx.log.result(format!("Listening on: {}", status.address));
+	ctx.log
+		.result(format!("Uptime: {}", format_uptime(status.started_at)));
+	ctx.log.result(format!(
+		"Pending update: {}",
+		status.pending_update.as_deref().unwrap_or("none")
+	));
+
+	if status.servers.is_empty() {
+		ctx.log
+			.result("No servers are running, one is started on the next connection");
+		return Ok(0);
+	}
+
+	let mut workspace = Column::new("Workspace");
+	let mut commit = Column::new("Commit");
+	let mut uptime = Column::new("Uptime");
+	for server in status.servers {
+		workspace.add_row(server.workspace);
+		commit.add_row(server.commit);
+		uptime.add_row(format_uptime(server.started_at));
+	}
+	format
+		.format
+		.print_table(OutputTable::new(vec![workspace, commit, uptime]))
+		.ok();
+
+	Ok(0)
+}
+
+/// Stops the running agent host.
+pub async fn stop(ctx: CommandContext) -> Result<i32, AnyError> {
+	call_running_agent_host::<_, ()>(
+		&ctx,
+		agent_host_singleton::METHOD_SHUTDOWN,
+		protocol::EmptyObject {},
+	)
+	.await?;
+	Ok(0)
+}
+
+/// Stops the servers of the running agent host. They're started again, with
+/// the latest downloaded version, on their next connection.
+pub async fn restart(ctx: CommandContext) -> Result<i32, AnyError> {
+	call_running_agent_host::<_, ()>(
+		&ctx,
+		agent_host_singleton::METHOD_RESTART,
+		protocol::EmptyObject {},
+	)
+	.await?;
+	Ok(0)
+}
+
+/// Downloads the latest server version for the running agent host.
+pub async fn update(ctx: CommandContext, format: OutputFormatOptions) -> Result<i32, AnyError> {
+	let result: agent_host_singleton::UpdateResult = call_running_agent_host(
+		&ctx,
+		agent_host_singleton::METHOD_UPDATE,
+		protocol::EmptyObject {},
+	)
+	.await?;
+
+	match format.format {
+		OutputFormat::Json => ctx.log.result(serde_json::to_string(&result).unwrap()),
+		OutputFormat::Text if result.restart_required => ctx.log.result(format!(
+			"Downloaded server {}. Run `{} agent-host restart` to use it in running servers.",
+			result.commit, APPLICATION_NAME
+		)),
+		OutputFormat::Text => ctx
+			.log
+			.result(format!("Server {} is up to date", result.commit)),
+	}
+
+	Ok(0)
+}
+
+async fn call_running_agent_host<P, R>(
+	ctx: &CommandContext,
+	method: &'static str,
+	params: P,
+) -> Result<R, CodeError>
+where
+	P: serde::Serialize + 'static,
+	R: serde::de::DeserializeOwned + Send + 'static,
+{
+	do_single_rpc_call(
+		&ctx.paths.agent_host_lockfile(),
+		ctx.log.clone(),
+		method,
+		params,
+	)
+	.await
+	.map_err(|e| match e {
+		CodeError::NoRunningTunnel | CodeError::AsyncPipeFailed(_) => CodeError::NoRunningAgentHost,
+		e => e,
+	})
+}
+
+#[derive(Clone)]
+struct ControlContext {
+	log: log::Logger,
+	manager: Arc<AgentHostManager>,
+	address: String,
+	started_at: DateTime<Utc>,
+	shutdown_tx: mpsc::UnboundedSender<ShutdownSignal>,
+}
+
+/// Serves control commands sent by `code agent-host status` and friends.
+async fn serve_control_rpc(
+	mut server: SingletonServer,
+	control: ControlContext,
+	shutdown: Barrier<ShutdownSignal>,
+) -> Result<(), CodeError> {
+	let mut own_shutdown = shutdown.clone();
+	let shutdown_fut = own_shutdown.wait();
+	tokio::pin!(shutdown_fut);
+
+	loop {
+		let cnx = tokio::select! {
+			c = server.accept() => c?,
+			_ = &mut shutdown_fut => return Ok(()),
+		};
+
+		let (read, write) = socket_stream_split(cnx);
+		let log = control.log.clone();
+		let mut rpc = new_json_rpc().methods(control.clone());
+
+		rpc.register_async(
+			agent_host_singleton::METHOD_STATUS,
+			|_: protocol::EmptyObject, c| async move {
+				Ok(agent_host_singleton::Status {
+					address: c.address.clone(),
+					started_at: c.started_at,
+					pending_update: c.manager.pending_update().await,
+					servers: c.manager.server_statuses().await,
+				})
+			},
+		);
+
+		rpc.register_sync(
+			agent_host_singleton::METHOD_SHUTDOWN,
+			|_: protocol::EmptyObject, c| {
+				info!(c.log, "Stopping agent host after a control request");
+				let _ = c.shutdown_tx.send(ShutdownSignal::RpcShutdownRequested);
+				Ok(())
+			},
+		);
+
+		rpc.register_async(
+			agent_host_singleton::METHOD_RESTART,
+			|_: protocol::EmptyObject, c| async move {
+				info!(
+					c.log,
+					"Restarting agent host servers after a control request"
+				);
+				c.manager.kill_running_server().await;
+				Ok(())
+			},
+		);
+
+		rpc.register_async(
+			agent_host_singleton::METHOD_UPDATE,
+			|_: protocol::EmptyObject, c| async move {
+				info!(c.log, "Checking for updates after a control request");
+				Ok(c.manager.update_now().await?)
+			},
+		);
+
+		let shutdown = shutdown.clone();
+		tokio::spawn(async move {
+			let _ = start_json_rpc(rpc.build(log), read, write, (), shutdown).await;
+		});
+	}
+}
+
+/// Formats the time since the instant as e.g. "2h 5m 3s".
+fn format_uptime(since: DateTime<Utc>) -> String {
+	let secs = (Utc::now() - since).num_seconds().max(0);
+	let (h, m, s) = (secs / 3600, (secs / 60) % 60, secs % 60);
+	match (h, m) {
+		(0, 0) => format!("{s}s"),
+		(0, _) => format!("{m}m {s}s"),
+		_ => format!("{h}h {m}m {s}s"),
+	}
+}
+
 fn mint_connection_token(path: &Path, prefer_token: Option<String>) -> std::io::Result<String> {
 	#[cfg(not(windows))]
 	use std::os::unix::fs::OpenOptionsExt;
@@ -191,4 +449,42 @@ mod tests {
 		assert_eq!(token, "override");
 		assert_eq!(fs::read_to_string(&path).unwrap(), "override");
 	}
+
+	#[test]
+	fn parses_control_subcommands() {
+		use crate::commands::args::{AgentHostSubcommand, Commands, IntegratedCli};
+		use clap::Parser;
+
+		let parse =
+			|argv: &[&str]| match IntegratedCli::try_parse_from(argv).unwrap().core.subcommand {
+				Some(Commands::AgentHost(a)) => a,
+				_ => panic!("expected agent-host"),
+			};
+
+		let args = parse(&["code", "agent-host", "--port", "1234"]);
+		assert!(args.subcommand.is_none());
+		assert_eq!(args.serve_args.port, 1234);
+
+		let args = parse(&["code", "agent-host", "status", "--format", "json"]);
+		assert!(matches!(
+			args.subcommand,
+			Some(AgentHostSubcommand::Status(OutputFormatOptions {
+				format: OutputFormat::Json
+			}))
+		));
+
+		let args = parse(&["code", "agent-host", "stop"]);
+		assert!(matches!(args.subcommand, Some(AgentHostSubcommand::Stop)));
+	}
+
+	#[test]
+	fn formats_uptime() {
+		let now = Utc::now();
+		assert_eq!(format_uptime(now - chrono::Duration::seconds(5)), "5s");
+		assert_eq!(format_uptime(now - chrono::Duration::seconds(125)), "2m 5s");
+		assert_eq!(
+			format_uptime(now - chrono::Duration::seconds(3725)),
+			"1h 2m 5s"
+		);
+	}
 }
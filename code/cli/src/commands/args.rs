@@ -3,9 +3,31 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
-use std::{fmt, path::PathBuf};
-
-use crate::{constants, log, options, tunnels::code_server::CodeServerArgs};
+use std::{fmt, path::PathBuf, time::Duration};
+
+use crate::{
+	constants,
+	download_cache::{RetentionPolicy, KEEP_LRU},
+	log, options,
+	state::sanitize_instance_name,
+	tunnels::{
+		agent_host::DEFAULT_MAX_WORKSPACES,
+		audit_log::AuditEventKind,
+		code_server::CodeServerArgs,
+		protocol::{
+			forward_singleton::PortRec, AccessProvider, PortAccess, PortPrivacy, PortProtocol,
+		},
+		server_env::{parse_env_pair, EnvPolicy},
+	},
+	update_policy::{UpdatePin, UpdatePolicy, UpdateWindow},
+	util::{
+		access_log::{AccessLog, AccessLogFormat},
+		cidr::IpCidr,
+		errors::{wrap, WrappedError},
+		resource_limits::ResourceLimits,
+	},
+};
+use chrono::{DateTime, Utc};
 use clap::{Args, Parser, Subcommand, ValueEnum};
 use const_format::concatcp;
 
@@ -162,7 +184,7 @@ pub struct StandaloneUpdateArgs {
 }
 
 #[derive(Subcommand, Debug, Clone)]
-
+#[allow(clippy::large_enum_variant)] // parsed once, not worth boxing
 pub enum Commands {
 	/// Create a tunnel that's accessible on vscode.dev from anywhere.
 	/// Run `code tunnel --help` for more usage info.
@@ -180,7 +202,7 @@ pub enum Commands {
 
 	/// Runs a local web version of VS Code.
 	#[clap(about = concatcp!("Runs a local web version of ", constants::PRODUCT_NAME_LONG))]
-	ServeWeb(ServeWebArgs),
+	ServeWeb(Box<ServeWebArgs>),
 
 	/// Runs the control server on process stdin/stdout
 	#[clap(hide = true)]
@@ -193,15 +215,23 @@ pub enum Commands {
 
 #[derive(Args, Debug, Clone)]
 pub struct ServeWebArgs {
+	#[clap(subcommand)]
+	pub subcommand: Option<ServeWebSubcommand>,
+
+	/// A TOML or JSON file with settings, keyed by the names of these options.
+	/// Options given on the command line take precedence. The file is reloaded
+	/// when it changes or on SIGHUP.
+	#[clap(long, value_name = "file")]
+	pub config: Option<PathBuf>,
 	/// Host to listen on, defaults to 'localhost'
 	#[clap(long)]
 	pub host: Option<String>,
 	// The path to a socket file for the server to listen to.
 	#[clap(long)]
 	pub socket_path: Option<String>,
-	/// Port to listen on. If 0 is passed a random free port is picked.
-	#[clap(long, default_value_t = 8000)]
-	pub port: u16,
+	/// Port to listen on, defaults to 8000. If 0 is passed a random free port is picked.
+	#[clap(long)]
+	pub port: Option<u16>,
 	/// A secret that must be included with all requests.
 	#[clap(long)]
 	pub connection_token: Option<String>,
@@ -232,10 +262,254 @@ pub struct ServeWebArgs {
 	/// Use a specific commit SHA for the client.
 	#[clap(long)]
 	pub commit_id: Option<String>,
+	/// Seconds after which a server with no connections is shut down, defaults to an hour.
+	#[clap(long)]
+	pub server_idle_timeout_secs: Option<u64>,
+	/// Seconds to wait for a server to accept requests after starting it, defaults to 30.
+	#[clap(long)]
+	pub server_startup_timeout_secs: Option<u64>,
+	/// Seconds between checks for a new release, defaults to an hour.
+	#[clap(long)]
+	pub update_check_interval_secs: Option<u64>,
+	/// Comma-separated addresses or CIDR ranges of reverse proxies whose
+	/// X-Forwarded-For/Proto/Host/Prefix headers are honored. When set,
+	/// connections on the --socket-path are also trusted.
+	#[clap(long, value_delimiter = ',', value_name = "cidr")]
+	pub trusted_proxies: Vec<IpCidr>,
+
+	#[clap(flatten)]
+	pub access_log: AccessLogArgs,
+
+	#[clap(flatten)]
+	pub oidc: OidcArgs,
+
+	#[clap(flatten)]
+	pub update_policy: UpdatePolicyArgs,
+
+	#[clap(flatten)]
+	pub retention: RetentionArgs,
+
+	#[clap(flatten)]
+	pub limits: ServerLimitArgs,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum ServeWebSubcommand {
+	/// Approves the update that a running serve-web with
+	/// --update-manual-approval is waiting on.
+	ApproveUpdate(ServeWebApproveArgs),
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ServeWebApproveArgs {
+	/// Only approve the update if it's to this commit.
+	#[clap(long)]
+	pub commit: Option<String>,
+}
+
+#[derive(Args, Debug, Clone, Default, PartialEq, Eq)]
+pub struct UpdatePolicyArgs {
+	/// Pins servers to a commit or a version, like 1.95.0, instead of
+	/// following the latest release.
+	#[clap(long, value_name = "commit | x.y.z")]
+	pub update_pin: Option<UpdatePin>,
+	/// Comma-separated daily periods of local time, like 22:00-06:00, in which
+	/// servers may be updated.
+	#[clap(long, value_delimiter = ',', value_name = "hh:mm-hh:mm")]
+	pub update_windows: Vec<UpdateWindow>,
+	/// Hours a release must have been out for before servers are updated to it.
+	#[clap(long, value_name = "hours")]
+	pub update_min_age_hours: Option<u64>,
+	/// Downloads updates, but only applies them once approved with
+	/// `serve-web approve-update`, or on SIGUSR1.
+	#[clap(long)]
+	pub update_manual_approval: bool,
+}
+
+impl UpdatePolicyArgs {
+	pub fn policy(&self) -> UpdatePolicy {
+		UpdatePolicy {
+			pin: self.update_pin.clone(),
+			windows: self.update_windows.clone(),
+			min_release_age: self
+				.update_min_age_hours
+				.map(|h| Duration::from_secs(h * 3600)),
+			manual_approval: self.update_manual_approval,
+		}
+	}
+}
+
+#[derive(Args, Debug, Clone, Default, PartialEq, Eq)]
+pub struct RetentionArgs {
+	/// Number of most recently used downloads to keep for each quality.
+	#[clap(long, value_name = "count")]
+	pub cache_keep_per_quality: Option<usize>,
+	/// Disk space, in megabytes, that downloads of each cache may use.
+	#[clap(long, value_name = "mb")]
+	pub cache_max_size_mb: Option<u64>,
+	/// Hours after downloading before a download may be removed.
+	#[clap(long, value_name = "hours")]
+	pub cache_min_age_hours: Option<u64>,
+	/// Comma-separated commits whose downloads are never removed.
+	#[clap(long, value_delimiter = ',', value_name = "commit")]
+	pub cache_pin: Vec<String>,
+}
+
+impl RetentionArgs {
+	/// Gets the policy to prune with, which removes everything not otherwise
+	/// kept if no limits are given.
+	pub fn policy(&self) -> RetentionPolicy {
+		RetentionPolicy {
+			keep_per_quality: self.cache_keep_per_quality,
+			keep_total: None,
+			max_total_bytes: self.cache_max_size_mb.map(|mb| mb * 1024 * 1024),
+			min_age: self
+				.cache_min_age_hours
+				.map(|h| Duration::from_secs(h * 3600)),
+			pinned_commits: self.cache_pin.clone(),
+		}
+	}
+
+	/// Gets the policy that caches apply as downloads are used.
+	pub fn cache_policy(&self) -> RetentionPolicy {
+		RetentionPolicy {
+			keep_total: Some(KEEP_LRU),
+			..self.policy()
+		}
+	}
+
+	/// Gets the arguments that reproduce these options on a command line.
+	pub fn to_cli_args(&self) -> Vec<String> {
+		let mut args = vec![];
+		if let Some(n) = self.cache_keep_per_quality {
+			args.push(format!("--cache-keep-per-quality={n}"));
+		}
+		if let Some(mb) = self.cache_max_size_mb {
+			args.push(format!("--cache-max-size-mb={mb}"));
+		}
+		if let Some(h) = self.cache_min_age_hours {
+			args.push(format!("--cache-min-age-hours={h}"));
+		}
+		if !self.cache_pin.is_empty() {
+			args.push(format!("--cache-pin={}", self.cache_pin.join(",")));
+		}
+		args
+	}
+}
+
+#[derive(Args, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerLimitArgs {
+	/// Memory, in megabytes, that each server and its extension hosts may use.
+	#[clap(long, value_name = "mb")]
+	pub server_memory_limit_mb: Option<u64>,
+	/// Relative share of CPU time given to each server, from 1 to 10000. The
+	/// default share is 100.
+	#[clap(long, value_name = "weight", value_parser = clap::value_parser!(u32).range(1..=10000))]
+	pub server_cpu_weight: Option<u32>,
+	/// CPU time each server may use, in percent of one CPU.
+	#[clap(long, value_name = "percent", value_parser = clap::value_parser!(u32).range(1..))]
+	pub server_cpu_quota_percent: Option<u32>,
+	/// Number of processes and threads each server may run at once.
+	#[clap(long, value_name = "count", value_parser = clap::value_parser!(u32).range(1..))]
+	pub server_max_pids: Option<u32>,
+	/// Scheduling priority of servers, from 0 (the default) to 19 (lowest).
+	#[clap(long, value_name = "level", value_parser = clap::value_parser!(i32).range(0..=19))]
+	pub server_nice: Option<i32>,
+	/// Best-effort I/O priority of servers, from 0 (highest) to 7 (lowest).
+	#[clap(long, value_name = "level", value_parser = clap::value_parser!(u8).range(0..=7))]
+	pub server_ionice: Option<u8>,
+}
+
+impl ServerLimitArgs {
+	pub fn limits(&self) -> ResourceLimits {
+		ResourceLimits {
+			memory_max: self.server_memory_limit_mb.map(|mb| mb * 1024 * 1024),
+			cpu_weight: self.server_cpu_weight,
+			cpu_quota_percent: self.server_cpu_quota_percent,
+			pids_max: self.server_max_pids,
+			nice: self.server_nice,
+			ionice: self.server_ionice,
+		}
+	}
+
+	/// Gets the arguments that reproduce these options on a command line.
+	pub fn to_cli_args(&self) -> Vec<String> {
+		let mut args = vec![];
+		if let Some(mb) = self.server_memory_limit_mb {
+			args.push(format!("--server-memory-limit-mb={mb}"));
+		}
+		if let Some(w) = self.server_cpu_weight {
+			args.push(format!("--server-cpu-weight={w}"));
+		}
+		if let Some(p) = self.server_cpu_quota_percent {
+			args.push(format!("--server-cpu-quota-percent={p}"));
+		}
+		if let Some(n) = self.server_max_pids {
+			args.push(format!("--server-max-pids={n}"));
+		}
+		if let Some(n) = self.server_nice {
+			args.push(format!("--server-nice={n}"));
+		}
+		if let Some(n) = self.server_ionice {
+			args.push(format!("--server-ionice={n}"));
+		}
+		args
+	}
+}
+
+#[derive(Args, Debug, Clone, Default, PartialEq, Eq)]
+pub struct OidcArgs {
+	/// Requires users to sign in with this OpenID Connect issuer instead of
+	/// using a connection token.
+	#[clap(long, value_name = "url", requires = "oidc_client_id")]
+	pub oidc_issuer: Option<String>,
+	/// Client ID registered with the OpenID Connect issuer.
+	#[clap(long, requires = "oidc_issuer")]
+	pub oidc_client_id: Option<String>,
+	/// Client secret registered with the OpenID Connect issuer.
+	#[clap(long, env = "VSCODE_CLI_OIDC_CLIENT_SECRET", hide_env_values = true)]
+	pub oidc_client_secret: Option<String>,
+	/// Comma-separated users allowed to sign in, matched against their email,
+	/// preferred username, or subject.
+	#[clap(long, value_delimiter = ',', value_name = "user")]
+	pub oidc_allowed_users: Vec<String>,
+	/// Comma-separated groups whose members are allowed to sign in, matched
+	/// against the 'groups' claim.
+	#[clap(long, value_delimiter = ',', value_name = "group")]
+	pub oidc_allowed_groups: Vec<String>,
+	/// Seconds a sign-in lasts, defaults to the lifetime of the ID token.
+	#[clap(long)]
+	pub oidc_session_lifetime_secs: Option<u64>,
 }
 
 #[derive(Args, Debug, Clone)]
 pub struct AgentHostArgs {
+	#[clap(subcommand)]
+	pub subcommand: Option<AgentHostSubcommand>,
+
+	#[clap(flatten)]
+	pub serve_args: AgentHostServeArgs,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum AgentHostSubcommand {
+	/// Gets the status of the agent host running on the current machine.
+	Status(OutputFormatOptions),
+
+	/// Stops the agent host running on the current machine.
+	Stop,
+
+	/// Restarts the servers of the running agent host, so that they start
+	/// with the latest downloaded version on their next connection.
+	Restart,
+
+	/// Checks for and downloads a new server version for the running agent
+	/// host, applying it even if its update policy would hold it back.
+	Update(OutputFormatOptions),
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct AgentHostServeArgs {
 	/// Host to listen on, defaults to 'localhost'
 	#[clap(long)]
 	pub host: Option<String>,
@@ -257,6 +531,65 @@ pub struct AgentHostArgs {
 	/// Specifies the directory that server data is kept in.
 	#[clap(long)]
 	pub server_data_dir: Option<String>,
+	/// Directory containing the data directories of workspace servers, which
+	/// requests select with the `X-VSCode-Agent-Host-Workspace` header or a
+	/// `/workspace/<name>/` path prefix.
+	#[clap(long)]
+	pub workspaces_data_dir: Option<String>,
+	/// Maximum number of workspace servers that run at once.
+	#[clap(long, default_value_t = DEFAULT_MAX_WORKSPACES)]
+	pub max_workspaces: usize,
+	/// If a server keeps crashing, run the previous known-good version that's
+	/// been downloaded instead.
+	#[clap(long)]
+	pub fallback_on_crash_loop: bool,
+
+	#[clap(flatten)]
+	pub update_policy: UpdatePolicyArgs,
+
+	#[clap(flatten)]
+	pub retention: RetentionArgs,
+
+	#[clap(flatten)]
+	pub limits: ServerLimitArgs,
+
+	#[clap(flatten)]
+	pub access_log: AccessLogArgs,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct AccessLogArgs {
+	/// Writes a line for each request to this file.
+	#[clap(long, value_name = "file")]
+	pub access_log: Option<PathBuf>,
+	/// Format of the access log, defaults to 'combined'.
+	#[clap(long, value_enum)]
+	pub access_log_format: Option<AccessLogFormat>,
+	/// Size in megabytes at which the access log is rotated, defaults to 10.
+	#[clap(long)]
+	pub access_log_max_size_mb: Option<u64>,
+	/// Number of rotated access logs to keep, defaults to 5.
+	#[clap(long)]
+	pub access_log_max_files: Option<usize>,
+}
+
+impl AccessLogArgs {
+	/// Opens the access log, if one was requested.
+	pub fn open(&self) -> Result<Option<AccessLog>, WrappedError> {
+		let path = match &self.access_log {
+			Some(p) => p,
+			None => return Ok(None),
+		};
+
+		AccessLog::new(
+			path,
+			self.access_log_format.unwrap_or(AccessLogFormat::Combined),
+			self.access_log_max_size_mb.unwrap_or(10) * 1024 * 1024,
+			self.access_log_max_files.unwrap_or(5),
+		)
+		.map(Some)
+		.map_err(|e| wrap(e, format!("could not open access log {}", path.display())))
+	}
 }
 
 #[derive(Args, Debug, Clone)]
@@ -307,6 +640,8 @@ pub enum ExtensionSubcommand {
 	Uninstall(UninstallExtensionArgs),
 	/// Update the installed extensions.
 	Update,
+	/// Install, update and uninstall extensions to match a lock file.
+	Sync(SyncExtensionArgs),
 }
 
 impl ExtensionSubcommand {
@@ -343,6 +678,9 @@ impl ExtensionSubcommand {
 			ExtensionSubcommand::Update => {
 				target.push("--update-extensions".to_string());
 			}
+			ExtensionSubcommand::Sync(_) => {
+				// handled by the CLI
+			}
 		}
 	}
 }
@@ -356,6 +694,11 @@ pub struct ListExtensionArgs {
 	/// Show versions of installed extensions, when using --list-extensions.
 	#[clap(long)]
 	pub show_versions: bool,
+
+	/// Prints a table or JSON with details of each extension, instead of one
+	/// extension per line.
+	#[clap(value_enum, long, value_name = "format")]
+	pub format: Option<OutputFormat>,
 }
 
 #[derive(Args, Debug, Clone)]
@@ -389,6 +732,37 @@ pub struct UninstallExtensionArgs {
 	pub id: Vec<String>,
 }
 
+#[derive(Args, Debug, Clone)]
+pub struct SyncExtensionArgs {
+	/// JSON file with the extensions to have installed, as
+	/// '{ "extensions": [{ "id", "version", "preRelease" }] }'.
+	#[clap(long, value_name = "file", default_value = "extensions.lock.json")]
+	pub file: PathBuf,
+
+	/// Only check whether the installed extensions match the file, exiting
+	/// with a non-zero code if they don't.
+	#[clap(long)]
+	pub check: bool,
+
+	/// Keep installed extensions that aren't in the file.
+	#[clap(long)]
+	pub keep_extra: bool,
+
+	/// Sync the extensions of a server installed by `code tunnel`, instead of
+	/// those of the desktop editor.
+	#[clap(long)]
+	pub tunnel: bool,
+
+	/// Commit of the server to sync. Defaults to the most recently installed
+	/// server.
+	#[clap(long, value_name = "commit", requires = "tunnel")]
+	pub commit: Option<String>,
+
+	/// Specifies the directory that the server's data is kept in.
+	#[clap(long, value_name = "dir", requires = "tunnel")]
+	pub server_data_dir: Option<String>,
+}
+
 #[derive(Args, Debug, Clone)]
 pub struct VersionArgs {
 	#[clap(subcommand)]
@@ -512,7 +886,7 @@ pub struct DesktopCodeOptions {
 }
 
 /// Argument specifying the output format.
-#[derive(Args, Debug, Clone)]
+#[derive(Args, Debug, Clone, Default)]
 pub struct OutputFormatOptions {
 	/// Set the data output formats.
 	#[clap(value_enum, long, value_name = "format", default_value_t = OutputFormat::Text)]
@@ -655,9 +1029,10 @@ impl fmt::Display for SyncState {
 	}
 }
 
-#[derive(ValueEnum, Clone, Copy, Debug)]
+#[derive(ValueEnum, Clone, Copy, Debug, Default)]
 pub enum OutputFormat {
 	Json,
+	#[default]
 	Text,
 }
 
@@ -702,6 +1077,12 @@ pub struct TunnelServeArgs {
 	#[clap(long)]
 	pub name: Option<String>,
 
+	/// Runs the tunnel as a named instance. Instances run side by side, each
+	/// with its own tunnel, servers, and logs, and share the login and
+	/// downloaded servers.
+	#[clap(long, value_name = "instance", value_parser = sanitize_instance_name)]
+	pub instance: Option<String>,
+
 	/// Optional parent process id. If provided, the server will be stopped when the process of the given pid no longer exists
 	#[clap(long, hide = true)]
 	pub parent_process_id: Option<String>,
@@ -717,6 +1098,13 @@ pub struct BaseServerArgs {
 	#[clap(long)]
 	pub install_extension: Vec<String>,
 
+	/// A directory, or a zip or tar.gz archive, with VSIX files and a
+	/// manifest.json that locks their versions and hashes. The extensions are
+	/// verified and installed into each new server without using the
+	/// marketplace.
+	#[clap(long, value_name = "path")]
+	pub extension_bundle: Option<PathBuf>,
+
 	/// Specifies the directory that server data is kept in.
 	#[clap(long)]
 	pub server_data_dir: Option<String>,
@@ -728,6 +1116,61 @@ pub struct BaseServerArgs {
 	/// Reconnection grace time in seconds. Defaults to 10800 (3 hours).
 	#[clap(long)]
 	pub reconnection_grace_time: Option<u32>,
+
+	/// Size, in megabytes, a server log may grow to before it's rotated. Defaults to 10.
+	#[clap(long, value_name = "mb")]
+	pub server_log_max_size_mb: Option<u64>,
+
+	/// Number of rotated server logs to keep. Defaults to 3.
+	#[clap(long, value_name = "count")]
+	pub server_log_max_files: Option<usize>,
+
+	/// Seconds to wait for a server to accept requests after starting it.
+	#[clap(long, value_name = "secs")]
+	pub server_startup_timeout_secs: Option<u64>,
+
+	/// Sets an environment variable for servers. May be given multiple times.
+	#[clap(long, value_name = "key=value", value_parser = parse_env_pair)]
+	pub server_env: Vec<(String, String)>,
+
+	/// A dotenv file with environment variables for servers, which is read
+	/// each time a server starts. Variables given with --server-env take
+	/// precedence.
+	#[clap(long, value_name = "file")]
+	pub server_env_file: Option<PathBuf>,
+
+	/// Comma-separated variables that clients may set for the servers they
+	/// start. A trailing '*' matches any suffix. Clients may not set any
+	/// variables unless this is given.
+	#[clap(long, value_delimiter = ',', value_name = "name")]
+	pub server_env_allow: Vec<String>,
+
+	/// Comma-separated variables that clients may not set for the servers
+	/// they start. A trailing '*' matches any suffix.
+	#[clap(long, value_delimiter = ',', value_name = "name")]
+	pub server_env_deny: Vec<String>,
+
+	/// (Linux only) Forwards ports through the tunnel as soon as your
+	/// processes start listening on them. Ports are forwarded privately unless
+	/// rules say otherwise.
+	#[clap(long)]
+	pub auto_forward: bool,
+
+	/// A JSON or TOML file with rules that choose which detected ports are
+	/// forwarded, and how. Implies --auto-forward.
+	#[clap(long, value_name = "file")]
+	pub auto_forward_rules: Option<PathBuf>,
+
+	/// If an agent host server keeps crashing, run the previous known-good
+	/// version that's been downloaded instead.
+	#[clap(long)]
+	pub fallback_on_crash_loop: bool,
+
+	#[clap(flatten)]
+	pub retention: RetentionArgs,
+
+	#[clap(flatten)]
+	pub limits: ServerLimitArgs,
 }
 
 impl BaseServerArgs {
@@ -746,7 +1189,94 @@ impl BaseServerArgs {
 		if let Some(t) = self.reconnection_grace_time {
 			csa.reconnection_grace_time = Some(t);
 		}
+
+		if let Some(s) = self.server_log_max_size_mb {
+			csa.log_max_size_mb = Some(s);
+		}
+
+		if let Some(n) = self.server_log_max_files {
+			csa.log_max_files = Some(n);
+		}
+
+		if let Some(t) = self.server_startup_timeout_secs {
+			csa.startup_timeout_secs = Some(t);
+		}
+
+		csa.limits = self.limits.limits();
+
+		csa.env.extend(self.server_env.iter().cloned());
+		if let Some(f) = &self.server_env_file {
+			csa.env_file = Some(f.clone());
+		}
+		if let Some(b) = &self.extension_bundle {
+			csa.extension_bundle = Some(b.clone());
+		}
+		csa.auto_forward = self.auto_forward || self.auto_forward_rules.is_some();
+		if let Some(r) = &self.auto_forward_rules {
+			csa.auto_forward_rules = Some(r.clone());
+		}
+		csa.fallback_on_crash_loop = self.fallback_on_crash_loop;
+
+		csa.env_policy = EnvPolicy {
+			allow: self.server_env_allow.clone(),
+			deny: self.server_env_deny.clone(),
+		};
 	}
+
+	/// Gets arguments that reproduce the options which aren't passed along
+	/// individually when installing the tunnel service.
+	pub fn to_service_args(&self) -> Vec<String> {
+		let mut args = self.retention.to_cli_args();
+		args.extend(self.limits.to_cli_args());
+		if let Some(s) = self.server_log_max_size_mb {
+			args.push(format!("--server-log-max-size-mb={s}"));
+		}
+		if let Some(n) = self.server_log_max_files {
+			args.push(format!("--server-log-max-files={n}"));
+		}
+		if let Some(t) = self.server_startup_timeout_secs {
+			args.push(format!("--server-startup-timeout-secs={t}"));
+		}
+		for (k, v) in &self.server_env {
+			args.push(format!("--server-env={k}={v}"));
+		}
+		if let Some(f) = &self.server_env_file {
+			args.push(format!("--server-env-file={}", absolute(f).display()));
+		}
+		if let Some(b) = &self.extension_bundle {
+			args.push(format!("--extension-bundle={}", absolute(b).display()));
+		}
+		if self.auto_forward {
+			args.push("--auto-forward".to_string());
+		}
+		if let Some(r) = &self.auto_forward_rules {
+			args.push(format!("--auto-forward-rules={}", absolute(r).display()));
+		}
+		if self.fallback_on_crash_loop {
+			args.push("--fallback-on-crash-loop".to_string());
+		}
+		if !self.server_env_allow.is_empty() {
+			args.push(format!(
+				"--server-env-allow={}",
+				self.server_env_allow.join(",")
+			));
+		}
+		if !self.server_env_deny.is_empty() {
+			args.push(format!(
+				"--server-env-deny={}",
+				self.server_env_deny.join(",")
+			));
+		}
+		args
+	}
+}
+
+/// Makes the path absolute, since the service may run from a different
+/// working directory.
+fn absolute(path: &std::path::Path) -> PathBuf {
+	std::env::current_dir()
+		.map(|d| d.join(path))
+		.unwrap_or_else(|_| path.to_path_buf())
 }
 
 #[derive(Args, Debug, Clone)]
@@ -758,25 +1288,144 @@ pub struct TunnelArgs {
 	pub serve_args: TunnelServeArgs,
 }
 
+#[derive(Args, Debug, Clone)]
+pub struct TunnelPruneArgs {
+	#[clap(flatten)]
+	pub retention: RetentionArgs,
+
+	/// Also prunes downloads of the CLI and of servers for `serve-web`.
+	#[clap(long)]
+	pub include_downloads: bool,
+
+	/// Reports what would be removed without removing anything.
+	#[clap(long)]
+	pub dry_run: bool,
+
+	#[clap(flatten)]
+	pub format: OutputFormatOptions,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelLogsArgs {
+	/// Commit of the server whose log to show. Defaults to the server whose
+	/// log was most recently written.
+	#[clap(long, value_name = "commit")]
+	pub server: Option<String>,
+
+	/// Tunnel instance whose server log to show, as given to
+	/// `tunnel --instance`.
+	#[clap(long, value_parser = sanitize_instance_name)]
+	pub instance: Option<String>,
+
+	/// Keeps showing lines as they're written.
+	#[clap(long, short)]
+	pub follow: bool,
+
+	/// Only shows lines written since a time, either an RFC 3339 timestamp or
+	/// a duration ago like 30m, 2h, or 1d.
+	#[clap(long, value_name = "time", value_parser = parse_since)]
+	pub since: Option<DateTime<Utc>>,
+
+	/// Only shows lines matching a regular expression.
+	#[clap(long, value_name = "regex")]
+	pub grep: Option<String>,
+
+	/// Number of lines to show.
+	#[clap(long, short = 'n', default_value_t = 100)]
+	pub lines: usize,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelAuditArgs {
+	#[clap(flatten)]
+	pub instance: TunnelInstanceArgs,
+
+	/// Only shows events since a time, either an RFC 3339 timestamp or a
+	/// duration ago like 30m, 2h, or 1d.
+	#[clap(long, value_name = "time", value_parser = parse_since)]
+	pub since: Option<DateTime<Utc>>,
+
+	/// Only shows events of a kind.
+	#[clap(value_enum, long)]
+	pub event: Option<AuditEventKind>,
+
+	/// Only shows calls of an RPC method, such as `spawn` or `fs_write`.
+	#[clap(long)]
+	pub method: Option<String>,
+
+	/// Only shows events on a connection, by its ID in the tunnel status.
+	#[clap(long, value_name = "id")]
+	pub connection: Option<u64>,
+
+	/// Number of events to show.
+	#[clap(long, short = 'n', default_value_t = 100)]
+	pub lines: usize,
+
+	#[clap(flatten)]
+	pub format: OutputFormatOptions,
+}
+
+fn parse_since(s: &str) -> Result<DateTime<Utc>, String> {
+	if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+		return Ok(t.with_timezone(&Utc));
+	}
+
+	parse_short_duration(s)
+		.ok()
+		.and_then(|ago| Utc::now().checked_sub_signed(ago))
+		.ok_or_else(|| format!("'{s}' is not a timestamp or a duration like 30m, 2h, or 1d"))
+}
+
+/// Parses a duration that must also be representable from now on.
+fn parse_expires_in(s: &str) -> Result<chrono::Duration, String> {
+	let d = parse_short_duration(s)?;
+	match Utc::now().checked_add_signed(d) {
+		Some(_) => Ok(d),
+		None => Err(format!("'{s}' is not a duration like 30m, 2h, or 1d")),
+	}
+}
+
+fn parse_short_duration(s: &str) -> Result<chrono::Duration, String> {
+	let err = || format!("'{s}' is not a duration like 30m, 2h, or 1d");
+	let split = s.find(|c: char| !c.is_ascii_digit()).ok_or_else(err)?;
+	let (n, unit) = s.split_at(split);
+	let n: i64 = n.parse().map_err(|_| err())?;
+	let d = match unit {
+		"s" => chrono::Duration::try_seconds(n),
+		"m" => chrono::Duration::try_minutes(n),
+		"h" => chrono::Duration::try_hours(n),
+		"d" => chrono::Duration::try_days(n),
+		_ => None,
+	};
+	d.ok_or_else(err)
+}
+
 #[derive(Subcommand, Debug, Clone)]
 pub enum TunnelSubcommand {
-	/// Delete all servers which are currently not running.
-	Prune,
+	/// Delete servers which are currently not running, optionally keeping
+	/// some according to the given limits.
+	Prune(TunnelPruneArgs),
+
+	/// Shows the log of a server started by the tunnel.
+	Logs(TunnelLogsArgs),
+
+	/// Shows the audit log of activity on the tunnel's control server.
+	Audit(TunnelAuditArgs),
 
 	/// Stops any running tunnel on the system.
-	Kill,
+	Kill(TunnelInstanceArgs),
 
 	/// Restarts any running tunnel on the system.
-	Restart,
+	Restart(TunnelInstanceArgs),
 
 	/// Gets whether there is a tunnel running on the current machine.
-	Status,
+	Status(TunnelStatusArgs),
 
 	/// Rename the name of this machine associated with port forwarding service.
 	Rename(TunnelRenameArgs),
 
 	/// Remove this machine's association with the port forwarding service.
-	Unregister,
+	Unregister(TunnelInstanceArgs),
 
 	#[clap(subcommand)]
 	User(TunnelUserSubCommands),
@@ -785,11 +1434,96 @@ pub enum TunnelSubcommand {
 	#[clap(subcommand)]
 	Service(TunnelServiceSubCommands),
 
+	/// Manages extensions on the running tunnel's server, without restarting it.
+	Ext(TunnelExtensionArgs),
+
+	/// Forwards local ports through a tunnel, without starting a server.
+	Forward(TunnelForwardPortsArgs),
+
 	/// (Preview) Forwards local port using the dev tunnel
 	#[clap(hide = true)]
 	ForwardInternal(TunnelForwardArgs),
 }
 
+#[derive(Args, Debug, Clone, Default)]
+pub struct TunnelInstanceArgs {
+	/// Tunnel instance, as given to `tunnel --instance`. Defaults to the
+	/// tunnel started without an instance, or the only running tunnel.
+	#[clap(long, value_parser = sanitize_instance_name)]
+	pub instance: Option<String>,
+}
+
+#[derive(Args, Debug, Clone, Default)]
+pub struct TunnelStatusArgs {
+	#[clap(flatten)]
+	pub instance: TunnelInstanceArgs,
+
+	/// Shows the status of every tunnel instance on the machine.
+	#[clap(long, conflicts_with = "instance")]
+	pub all: bool,
+
+	/// Set the data output format. Text output includes traffic over the
+	/// tunnel in a readable form.
+	#[clap(value_enum, long, value_name = "format", default_value_t = OutputFormat::Json)]
+	pub format: OutputFormat,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelExtensionArgs {
+	#[clap(subcommand)]
+	pub subcommand: TunnelExtensionSubcommand,
+
+	/// Commit of the running server to manage. Defaults to the most recently
+	/// installed server that's running.
+	#[clap(long, value_name = "commit", global = true)]
+	pub commit: Option<String>,
+
+	/// Specifies the directory that the server's data is kept in.
+	#[clap(long, value_name = "dir", global = true)]
+	pub server_data_dir: Option<String>,
+
+	/// Set the root path for the server's extensions.
+	#[clap(long, value_name = "dir", global = true)]
+	pub extensions_dir: Option<String>,
+
+	#[clap(flatten)]
+	pub instance: TunnelInstanceArgs,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum TunnelExtensionSubcommand {
+	/// List the server's installed extensions.
+	List(OutputFormatOptions),
+	/// Install extensions on the server.
+	Install(TunnelInstallExtensionArgs),
+	/// Uninstall extensions from the server.
+	Uninstall(TunnelUninstallExtensionArgs),
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelInstallExtensionArgs {
+	/// Extension IDs, optionally with '@${version}', or paths to VSIX files.
+	#[clap(name = "ext-id | id", required = true)]
+	pub id_or_path: Vec<String>,
+
+	/// Installs the pre-release version of the extensions.
+	#[clap(long)]
+	pub pre_release: bool,
+
+	#[clap(flatten)]
+	pub format: OutputFormatOptions,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelUninstallExtensionArgs {
+	/// Extension IDs to uninstall.
+	#[clap(name = "ext-id", required = true)]
+	pub id: Vec<String>,
+
+	#[clap(flatten)]
+	pub format: OutputFormatOptions,
+}
+
 #[derive(Subcommand, Debug, Clone)]
 pub enum TunnelServiceSubCommands {
 	/// Installs or re-installs the tunnel service on the machine.
@@ -821,6 +1555,9 @@ pub struct TunnelServiceInstallArgs {
 pub struct TunnelRenameArgs {
 	/// The name you'd like to rename your machine to.
 	pub name: String,
+
+	#[clap(flatten)]
+	pub instance: TunnelInstanceArgs,
 }
 
 #[derive(Args, Debug, Clone)]
@@ -833,6 +1570,135 @@ pub struct TunnelForwardArgs {
 	pub login: LoginArgs,
 }
 
+#[derive(Args, Debug, Clone)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+pub struct TunnelForwardPortsArgs {
+	#[clap(subcommand)]
+	pub subcommand: Option<TunnelForwardSubcommand>,
+
+	/// Ports to forward, as `port[:privacy][:protocol]`, for example `3000`,
+	/// `8080:public`, or `8443:private:https`. Ports are private by default.
+	#[clap(
+		value_name = "port",
+		required_unless_present = "internal_background",
+		value_parser = parse_port_spec
+	)]
+	pub ports: Vec<PortRec>,
+
+	/// Adds a label to the forwarded ports. May be given multiple times.
+	#[clap(long = "label", value_name = "label")]
+	pub labels: Vec<String>,
+
+	/// Lets members of a GitHub organization, or Microsoft tenant, connect to
+	/// private ports.
+	#[clap(long, value_name = "org")]
+	pub allow_org: Option<String>,
+
+	/// Lets a user connect to private ports. May be given multiple times.
+	#[clap(long = "allow-user", value_name = "user")]
+	pub allow_users: Vec<String>,
+
+	/// Provider that --allow-org and --allow-user refer to. Defaults to GitHub.
+	#[clap(long, value_enum, value_name = "provider")]
+	pub allow_provider: Option<AuthProvider>,
+
+	/// Stops anonymous access to public ports after a duration like 30m, 2h,
+	/// or 1d.
+	#[clap(long, value_name = "duration", value_parser = parse_expires_in)]
+	pub expires_in: Option<chrono::Duration>,
+
+	/// Hands the ports to a forwarding process in the background and exits,
+	/// instead of forwarding them until this command is stopped. If another
+	/// `tunnel forward` is already running, it forwards them instead.
+	#[clap(long)]
+	pub detach: bool,
+
+	#[clap(flatten)]
+	pub format: OutputFormatOptions,
+
+	/// Runs the background process that --detach hands ports to.
+	#[clap(long, hide = true)]
+	pub internal_background: bool,
+}
+
+impl TunnelForwardPortsArgs {
+	/// Gets the access restrictions given for ports with the privacy.
+	/// Organizations and users apply to private ports, and expiry to public
+	/// ones.
+	pub fn access(&self, privacy: PortPrivacy) -> PortAccess {
+		match privacy {
+			PortPrivacy::Private => PortAccess {
+				provider: match self.allow_provider {
+					Some(AuthProvider::Microsoft) => AccessProvider::Microsoft,
+					_ => AccessProvider::Github,
+				},
+				organization: self.allow_org.clone(),
+				users: self.allow_users.clone(),
+				expires_at: None,
+			},
+			PortPrivacy::Public => PortAccess {
+				// saturates, as the expiry was checked when parsed
+				expires_at: self.expires_in.map(|d| {
+					Utc::now()
+						.checked_add_signed(d)
+						.unwrap_or(DateTime::<Utc>::MAX_UTC)
+				}),
+				..Default::default()
+			},
+		}
+	}
+}
+
+fn parse_port_spec(s: &str) -> Result<PortRec, String> {
+	let mut parts = s.split(':');
+	let number = parts
+		.next()
+		.and_then(|p| p.parse::<u16>().ok())
+		.filter(|n| *n > 0)
+		.ok_or_else(|| format!("'{s}' does not start with a port number"))?;
+
+	let mut privacy = None;
+	let mut protocol = None;
+	for part in parts {
+		match part {
+			"public" if privacy.is_none() => privacy = Some(PortPrivacy::Public),
+			"private" if privacy.is_none() => privacy = Some(PortPrivacy::Private),
+			"auto" if protocol.is_none() => protocol = Some(PortProtocol::Auto),
+			"http" if protocol.is_none() => protocol = Some(PortProtocol::Http),
+			"https" if protocol.is_none() => protocol = Some(PortProtocol::Https),
+			_ => {
+				return Err(format!(
+					"'{part}' in '{s}' is not one of public, private, auto, http, or https"
+				))
+			}
+		}
+	}
+
+	Ok(PortRec {
+		number,
+		privacy: privacy.unwrap_or(PortPrivacy::Private),
+		protocol: protocol.unwrap_or_default(),
+		access: PortAccess::default(),
+		labels: vec![],
+	})
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum TunnelForwardSubcommand {
+	/// Lists the ports forwarded on this machine.
+	List(OutputFormatOptions),
+
+	/// Stops forwarding ports that were handed off with --detach.
+	Remove(TunnelForwardRemoveArgs),
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TunnelForwardRemoveArgs {
+	/// Ports to stop forwarding.
+	#[clap(value_name = "port", required = true)]
+	pub ports: Vec<u16>,
+}
+
 #[derive(Subcommand, Debug, Clone)]
 pub enum TunnelUserSubCommands {
 	/// Log in to port forwarding service
@@ -865,3 +1731,50 @@ pub enum AuthProvider {
 	Microsoft,
 	Github,
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse_port_spec() {
+		let p = parse_port_spec("3000").unwrap();
+		assert_eq!(p.number, 3000);
+		assert_eq!(p.privacy, PortPrivacy::Private);
+		assert_eq!(p.protocol, PortProtocol::Auto);
+
+		let p = parse_port_spec("8443:public:https").unwrap();
+		assert_eq!(p.privacy, PortPrivacy::Public);
+		assert_eq!(p.protocol, PortProtocol::Https);
+
+		let p = parse_port_spec("8080:http").unwrap();
+		assert_eq!(p.privacy, PortPrivacy::Private);
+		assert_eq!(p.protocol, PortProtocol::Http);
+
+		assert!(parse_port_spec("0").is_err());
+		assert!(parse_port_spec("web").is_err());
+		assert!(parse_port_spec("3000:public:private").is_err());
+		assert!(parse_port_spec("3000:udp").is_err());
+	}
+
+	#[test]
+	fn test_parse_durations() {
+		assert_eq!(
+			parse_short_duration("90m").unwrap(),
+			chrono::Duration::minutes(90)
+		);
+		assert!(parse_short_duration("1w").is_err());
+		assert!(parse_short_duration("999999999999999d").is_err());
+
+		let since = parse_since("2h").unwrap();
+		assert!(Utc::now() - since >= chrono::Duration::hours(2));
+		assert_eq!(
+			parse_since("2024-01-02T03:04:05Z").unwrap(),
+			"2024-01-02T03:04:05Z".parse::<DateTime<Utc>>().unwrap()
+		);
+		// representable durations can still go out of range of dates
+		assert!(parse_since("99999999999d").is_err());
+		assert!(parse_expires_in("99999999999d").is_err());
+		assert!(parse_expires_in("1d").is_ok());
+	}
+}
@@ -0,0 +1,866 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	collections::HashMap,
+	fs,
+	path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	constants::DEFAULT_DATA_PARENT_DIR,
+	desktop, log,
+	state::LauncherPaths,
+	tunnels::{
+		code_server::CodeServerArgs,
+		paths::{get_all_servers, InstalledServer},
+		protocol::singleton,
+	},
+	util::{
+		command::{check_output_status, new_script_command},
+		errors::{AnyError, CodeError},
+		prereqs::PreReqChecker,
+	},
+};
+
+use super::{
+	args::{
+		CliCore, DesktopCodeOptions, ListExtensionArgs, OutputFormat, SyncExtensionArgs,
+		TunnelExtensionArgs, TunnelExtensionSubcommand,
+	},
+	output::{Column, OutputTable},
+	tunnels::{get_instance_status, resolve_instance},
+	CommandContext,
+};
+
+/// An editor or server whose extensions are managed.
+pub enum ExtensionTarget {
+	Desktop {
+		binary: PathBuf,
+		options: DesktopCodeOptions,
+	},
+	Server {
+		executable: PathBuf,
+		args: Box<CodeServerArgs>,
+	},
+}
+
+impl ExtensionTarget {
+	/// Gets the desktop editor the CLI would launch.
+	pub async fn desktop(
+		ctx: &CommandContext,
+		options: &DesktopCodeOptions,
+	) -> Result<Self, AnyError> {
+		let platform = PreReqChecker::new().verify().await?;
+		let version_manager =
+			desktop::CodeVersionManager::new(ctx.log.clone(), &ctx.paths, platform);
+		let use_version = options.use_version.as_ref().or(ctx
+			.args
+			.editor_options
+			.code_options
+			.use_version
+			.as_ref());
+		let version = match use_version {
+			Some(v) => desktop::RequestedVersion::try_from(v.as_str())?,
+			None => version_manager.get_preferred_version(),
+		};
+
+		match version_manager.try_get_entrypoint(&version).await {
+			Some(binary) => Ok(Self::Desktop {
+				binary,
+				options: options.clone(),
+			}),
+			None => {
+				desktop::prompt_to_install(&version);
+				Err(CodeError::NoEditorInstalled(version.to_string()).into())
+			}
+		}
+	}
+
+	/// Gets a server installed by the CLI, the one with the given commit or
+	/// the most recently installed one.
+	pub fn server(
+		paths: &LauncherPaths,
+		commit: Option<&str>,
+		args: CodeServerArgs,
+	) -> Result<Self, CodeError> {
+		Self::installed_server(paths, commit, args, false)
+	}
+
+	/// Gets a running server installed by the CLI, the one with the given
+	/// commit or the most recently installed one. Extensions it installs are
+	/// picked up by the server without restarting it. `args` should be those
+	/// the server was started with, see `tunnel_server_args`.
+	pub fn running_server(
+		paths: &LauncherPaths,
+		commit: Option<&str>,
+		args: CodeServerArgs,
+	) -> Result<Self, CodeError> {
+		Self::installed_server(paths, commit, args, true)
+	}
+
+	fn installed_server(
+		paths: &LauncherPaths,
+		commit: Option<&str>,
+		args: CodeServerArgs,
+		running: bool,
+	) -> Result<Self, CodeError> {
+		let server = find_installed_server(paths, commit, running)?;
+		Ok(Self::Server {
+			executable: server.server_paths(paths).executable,
+			args: Box::new(args),
+		})
+	}
+
+	/// Gets the directory extensions are installed in.
+	pub fn extensions_dir(&self) -> Option<PathBuf> {
+		let (explicit, data_dir, default_data_dir) = match self {
+			Self::Desktop { options, .. } => (
+				&options.extensions_dir,
+				&None,
+				DEFAULT_DATA_PARENT_DIR.to_string(),
+			),
+			Self::Server { args, .. } => (
+				&args.extensions_dir,
+				&args.server_data_dir,
+				DEFAULT_DATA_PARENT_DIR.replacen(".vscode", ".vscode-server", 1),
+			),
+		};
+
+		if let Some(d) = explicit {
+			return Some(PathBuf::from(d));
+		}
+		if let Some(d) = data_dir {
+			return Some(Path::new(d).join("extensions"));
+		}
+		dirs::home_dir().map(|h| h.join(default_data_dir).join("extensions"))
+	}
+
+	/// Runs the editor or server with arguments that manage extensions,
+	/// returning its output.
+	async fn run(&self, extension_args: &[String]) -> Result<String, AnyError> {
+		let mut cmd = match self {
+			Self::Desktop { binary, options } => {
+				let mut args = vec![];
+				options.add_code_args(&mut args);
+				let mut cmd = new_script_command(binary);
+				cmd.args(args);
+				cmd
+			}
+			Self::Server { executable, args } => {
+				let mut cmd = new_script_command(executable);
+				cmd.args(args.command_arguments()).envs(args.server_env()?);
+				cmd
+			}
+		};
+		cmd.args(extension_args)
+			.stdin(std::process::Stdio::null())
+			.stdout(std::process::Stdio::piped())
+			.stderr(std::process::Stdio::piped());
+
+		let command = format!("{:?}", cmd.as_std());
+		let cmd_str = || command.clone();
+		let output = cmd.output().await.map_err(|e| CodeError::CommandFailed {
+			command: cmd_str(),
+			code: -1,
+			output: e.to_string(),
+		})?;
+		let output = check_output_status(output, cmd_str)?;
+		Ok(String::from_utf8_lossy(&output.stdout).to_string())
+	}
+
+	/// Gets the installed extensions, optionally only those in a category.
+	pub async fn list(&self, category: Option<&str>) -> Result<Vec<InstalledExtension>, AnyError> {
+		let mut args = vec![
+			"--list-extensions".to_string(),
+			"--show-versions".to_string(),
+		];
+		if let Some(category) = category {
+			args.push(format!("--category={category}"));
+		}
+		let output = self.run(&args).await?;
+		let metadata = self
+			.extensions_dir()
+			.map(|d| read_extensions_json(&d))
+			.unwrap_or_default();
+
+		Ok(output
+			.lines()
+			.filter_map(|l| l.trim().split_once('@'))
+			.map(|(id, version)| {
+				let meta = metadata.get(&id.to_lowercase());
+				InstalledExtension {
+					id: id.to_string(),
+					version: version.to_string(),
+					pre_release: meta.map(|m| m.pre_release),
+					location: meta.and_then(|m| m.location.clone()),
+					pending_removal: meta.map(|m| m.obsolete).unwrap_or(false),
+				}
+			})
+			.collect())
+	}
+
+	async fn install(
+		&self,
+		extensions: &[&LockedExtension],
+		pre_release: bool,
+	) -> Result<(), AnyError> {
+		if extensions.is_empty() {
+			return Ok(());
+		}
+
+		let mut args: Vec<String> = extensions
+			.iter()
+			.map(|e| format!("--install-extension={}@{}", e.id, e.version))
+			.collect();
+		args.push("--force".to_string());
+		if pre_release {
+			args.push("--pre-release".to_string());
+		}
+		self.run(&args).await.map(|_| ())
+	}
+
+	async fn uninstall(&self, extensions: &[InstalledExtension]) -> Result<(), AnyError> {
+		if extensions.is_empty() {
+			return Ok(());
+		}
+
+		let args: Vec<String> = extensions
+			.iter()
+			.map(|e| format!("--uninstall-extension={}", e.id))
+			.collect();
+		self.run(&args).await.map(|_| ())
+	}
+
+	/// Runs the editor or server once for each extension, so that one failing
+	/// doesn't prevent changes to the others.
+	async fn run_each(
+		&self,
+		flag: &str,
+		ids: Vec<String>,
+		extra_args: &[String],
+	) -> Vec<ExtensionResult> {
+		let mut results = Vec::with_capacity(ids.len());
+		for id in ids {
+			let mut args = vec![format!("--{flag}={id}")];
+			args.extend_from_slice(extra_args);
+			let error = self.run(&args).await.err().map(|e| e.to_string());
+			results.push(ExtensionResult { id, error });
+		}
+		results
+	}
+}
+
+/// Result of installing or uninstalling one extension.
+#[derive(Serialize, Debug)]
+pub struct ExtensionResult {
+	pub id: String,
+	pub error: Option<String>,
+}
+
+/// An installed extension. Whether it's enabled isn't included: the editor
+/// keeps that in its SQLite state database, per profile and workspace, and
+/// servers' clients keep it in their own state, neither of which we can read.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct InstalledExtension {
+	pub id: String,
+	pub version: String,
+	/// Whether the pre-release version was requested, if known.
+	pub pre_release: Option<bool>,
+	/// Directory the extension is installed in, if known.
+	pub location: Option<PathBuf>,
+	/// Whether the editor marked the extension for removal, which happens the
+	/// next time it starts.
+	pub pending_removal: bool,
+}
+
+struct ExtensionMetadata {
+	pre_release: bool,
+	location: Option<PathBuf>,
+	/// Whether the editor will remove the extension the next time it starts.
+	obsolete: bool,
+}
+
+/// Reads the extensions.json the editor keeps in its extensions directory,
+/// keyed by lowercased extension ID, along with its list of obsolete
+/// extensions.
+fn read_extensions_json(dir: &Path) -> HashMap<String, ExtensionMetadata> {
+	#[derive(Deserialize)]
+	struct Identifier {
+		id: String,
+	}
+	#[derive(Deserialize, Default)]
+	#[serde(rename_all = "camelCase")]
+	struct Metadata {
+		#[serde(default)]
+		pre_release: bool,
+		#[serde(default)]
+		is_pre_release_version: bool,
+	}
+	#[derive(Deserialize)]
+	#[serde(rename_all = "camelCase")]
+	struct Entry {
+		identifier: Identifier,
+		relative_location: Option<String>,
+		#[serde(default)]
+		metadata: Option<Metadata>,
+	}
+
+	let entries: Vec<Entry> = fs::read_to_string(dir.join("extensions.json"))
+		.ok()
+		.and_then(|s| serde_json::from_str(&s).ok())
+		.unwrap_or_default();
+	// keyed by the extension's folder name
+	let obsolete: HashMap<String, bool> = fs::read_to_string(dir.join(".obsolete"))
+		.ok()
+		.and_then(|s| serde_json::from_str(&s).ok())
+		.unwrap_or_default();
+
+	entries
+		.into_iter()
+		.map(|e| {
+			let meta = e.metadata.unwrap_or_default();
+			let obsolete = e
+				.relative_location
+				.as_ref()
+				.and_then(|l| obsolete.get(l))
+				.copied()
+				.unwrap_or(false);
+			(
+				e.identifier.id.to_lowercase(),
+				ExtensionMetadata {
+					pre_release: meta.pre_release || meta.is_pre_release_version,
+					location: e.relative_location.map(|l| dir.join(l)),
+					obsolete,
+				},
+			)
+		})
+		.collect()
+}
+
+/// Finds a server installed by the CLI, the one with the given commit or the
+/// most recently installed one.
+fn find_installed_server(
+	paths: &LauncherPaths,
+	commit: Option<&str>,
+	running: bool,
+) -> Result<InstalledServer, CodeError> {
+	let mut servers: Vec<_> = get_all_servers(paths)
+		.into_iter()
+		.filter(|s| commit.map(|c| s.commit.starts_with(c)).unwrap_or(true))
+		.filter(|s| !running || s.server_paths(paths).is_running_in_any_instance())
+		.map(|s| {
+			let modified = fs::metadata(s.server_paths(paths).server_dir)
+				.and_then(|m| m.modified())
+				.ok();
+			(s, modified)
+		})
+		.collect();
+
+	servers.sort_by_key(|(_, modified)| std::cmp::Reverse(*modified));
+	servers.into_iter().next().map(|(s, _)| s).ok_or_else(|| {
+		let commit = commit.unwrap_or("any commit").to_string();
+		if running {
+			CodeError::NoServerRunning(commit)
+		} else {
+			CodeError::NoServerInstalled(commit)
+		}
+	})
+}
+
+/// Extensions that should be installed, as listed in a lock file.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExtensionLockFile {
+	pub extensions: Vec<LockedExtension>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct LockedExtension {
+	pub id: String,
+	pub version: String,
+	#[serde(default)]
+	pub pre_release: bool,
+}
+
+impl ExtensionLockFile {
+	pub fn read(path: &Path) -> Result<Self, CodeError> {
+		let err = |e: String| CodeError::InvalidExtensionLockFile(path.display().to_string(), e);
+		let contents = fs::read_to_string(path).map_err(|e| err(e.to_string()))?;
+		serde_json::from_str(&contents).map_err(|e| err(e.to_string()))
+	}
+}
+
+/// Changes that make the installed extensions match a lock file.
+#[derive(Debug, Default)]
+pub struct SyncPlan<'a> {
+	pub install: Vec<&'a LockedExtension>,
+	/// Extensions with a different version or pre-release flag, along with
+	/// what's installed.
+	pub update: Vec<(&'a InstalledExtension, &'a LockedExtension)>,
+	pub uninstall: Vec<&'a InstalledExtension>,
+}
+
+impl SyncPlan<'_> {
+	pub fn is_empty(&self) -> bool {
+		self.install.is_empty() && self.update.is_empty() && self.uninstall.is_empty()
+	}
+
+	fn describe(&self) -> Vec<String> {
+		let mut lines = vec![];
+		for e in &self.install {
+			lines.push(format!(
+				"install {}@{}{}",
+				e.id,
+				e.version,
+				pre(e.pre_release)
+			));
+		}
+		for (installed, e) in &self.update {
+			lines.push(format!(
+				"update {} from {}{} to {}{}",
+				e.id,
+				installed.version,
+				pre(installed.pre_release.unwrap_or_default()),
+				e.version,
+				pre(e.pre_release)
+			));
+		}
+		for e in &self.uninstall {
+			lines.push(format!("uninstall {}@{}", e.id, e.version));
+		}
+		lines
+	}
+}
+
+fn pre(pre_release: bool) -> &'static str {
+	if pre_release {
+		" (pre-release)"
+	} else {
+		""
+	}
+}
+
+/// Compares installed extensions to those in the lock file. Extension IDs are
+/// case-insensitive. The pre-release flag is only compared if it's known.
+pub fn plan_sync<'a>(
+	desired: &'a [LockedExtension],
+	installed: &'a [InstalledExtension],
+	keep_extra: bool,
+) -> SyncPlan<'a> {
+	let installed_by_id: HashMap<_, _> =
+		installed.iter().map(|e| (e.id.to_lowercase(), e)).collect();
+
+	let mut plan = SyncPlan::default();
+	for e in desired {
+		match installed_by_id.get(&e.id.to_lowercase()) {
+			None => plan.install.push(e),
+			Some(i)
+				if i.version != e.version
+					|| i.pre_release.unwrap_or(e.pre_release) != e.pre_release =>
+			{
+				plan.update.push((i, e))
+			}
+			Some(_) => {}
+		}
+	}
+
+	if !keep_extra {
+		plan.uninstall = installed
+			.iter()
+			.filter(|i| !desired.iter().any(|e| e.id.eq_ignore_ascii_case(&i.id)))
+			.collect();
+	}
+
+	plan
+}
+
+/// Lists the desktop editor's installed extensions.
+pub async fn list(
+	ctx: CommandContext,
+	options: DesktopCodeOptions,
+	args: ListExtensionArgs,
+) -> Result<i32, AnyError> {
+	let target = ExtensionTarget::desktop(&ctx, &options).await?;
+	let extensions = target.list(args.category.as_deref()).await?;
+	match args.format {
+		Some(format) => print_extensions(&ctx.log, format, &extensions, args.show_versions),
+		None => print_extension_lines(&ctx.log, &extensions, args.show_versions),
+	}
+	Ok(0)
+}
+
+/// Prints one extension per line, as `--list-extensions` always has.
+fn print_extension_lines(
+	log: &log::Logger,
+	extensions: &[InstalledExtension],
+	show_versions: bool,
+) {
+	for e in extensions {
+		if show_versions {
+			log.result(format!("{}@{}", e.id, e.version));
+		} else {
+			log.result(&e.id);
+		}
+	}
+}
+
+fn print_extensions(
+	log: &log::Logger,
+	format: OutputFormat,
+	extensions: &[InstalledExtension],
+	show_versions: bool,
+) {
+	if let OutputFormat::Json = format {
+		log.result(serde_json::to_string(extensions).unwrap());
+		return;
+	}
+
+	let mut id = Column::new("Id");
+	let mut version = Column::new("Version");
+	let mut pre_release = Column::new("Pre-release");
+	let mut pending_removal = Column::new("Pending removal");
+	let mut location = Column::new("Location");
+	for e in extensions {
+		id.add_row(e.id.clone());
+		version.add_row(e.version.clone());
+		pre_release.add_row(e.pre_release.map(|p| p.to_string()).unwrap_or_default());
+		pending_removal.add_row(e.pending_removal.to_string());
+		location.add_row(
+			e.location
+				.as_ref()
+				.map(|l| l.display().to_string())
+				.unwrap_or_default(),
+		);
+	}
+
+	let mut cols = vec![id];
+	if show_versions {
+		cols.push(version);
+	}
+	cols.extend([pre_release, pending_removal, location]);
+	format.print_table(OutputTable::new(cols)).ok();
+}
+
+/// Gets the arguments of the servers a tunnel runs, so their extensions
+/// directory is found. Directories given on the command line take precedence
+/// over those reported by the running tunnel, if any.
+fn server_args(
+	cli: &CliCore,
+	extensions_dir: Option<&String>,
+	server_data_dir: Option<&String>,
+	tunnel: Option<&singleton::Status>,
+) -> CodeServerArgs {
+	let mut csa: CodeServerArgs = cli.into();
+	csa.extensions_dir = extensions_dir
+		.cloned()
+		.or_else(|| tunnel.and_then(|t| t.extensions_dir.clone()));
+	csa.server_data_dir = server_data_dir
+		.cloned()
+		.or_else(|| tunnel.and_then(|t| t.server_data_dir.clone()));
+	csa
+}
+
+fn tunnel_server_args(
+	cli: &CliCore,
+	args: &TunnelExtensionArgs,
+	tunnel: Option<&singleton::Status>,
+) -> CodeServerArgs {
+	server_args(
+		cli,
+		args.extensions_dir.as_ref(),
+		args.server_data_dir.as_ref(),
+		tunnel,
+	)
+}
+
+/// Lists, installs or uninstalls extensions on a running tunnel server.
+pub async fn tunnel_ext(ctx: CommandContext, args: TunnelExtensionArgs) -> Result<i32, AnyError> {
+	let paths = resolve_instance(&ctx, &args.instance).await?;
+	let tunnel = get_instance_status(&ctx, &paths).await?;
+	let csa = tunnel_server_args(&ctx.args, &args, tunnel.as_ref().map(|t| &t.status));
+	let target = ExtensionTarget::running_server(&paths, args.commit.as_deref(), csa)?;
+
+	let (results, format) = match args.subcommand {
+		TunnelExtensionSubcommand::List(format) => {
+			let extensions = target.list(None).await?;
+			print_extensions(&ctx.log, format.format, &extensions, true);
+			return Ok(0);
+		}
+		TunnelExtensionSubcommand::Install(install_args) => {
+			let mut extra_args = vec!["--force".to_string()];
+			if install_args.pre_release {
+				extra_args.push("--pre-release".to_string());
+			}
+			let results = target
+				.run_each("install-extension", install_args.id_or_path, &extra_args)
+				.await;
+			(results, install_args.format)
+		}
+		TunnelExtensionSubcommand::Uninstall(uninstall_args) => {
+			let results = target
+				.run_each("uninstall-extension", uninstall_args.id, &[])
+				.await;
+			(results, uninstall_args.format)
+		}
+	};
+
+	let failed = results.iter().filter(|r| r.error.is_some()).count();
+	if let OutputFormat::Json = format.format {
+		ctx.log.result(serde_json::to_string(&results).unwrap());
+	} else {
+		let mut id = Column::new("Id");
+		let mut result = Column::new("Result");
+		for r in results {
+			id.add_row(r.id);
+			result.add_row(r.error.unwrap_or_else(|| "ok".to_string()));
+		}
+		format
+			.format
+			.print_table(OutputTable::new(vec![id, result]))
+			.ok();
+	}
+
+	Ok(if failed > 0 { 1 } else { 0 })
+}
+
+/// Installs, updates and uninstalls extensions to match a lock file, or with
+/// `--check`, only reports how they differ.
+pub async fn sync(
+	ctx: CommandContext,
+	options: DesktopCodeOptions,
+	args: SyncExtensionArgs,
+) -> Result<i32, AnyError> {
+	let lock = ExtensionLockFile::read(&args.file)?;
+	let target = if args.tunnel {
+		let csa = server_args(
+			&ctx.args,
+			options.extensions_dir.as_ref(),
+			args.server_data_dir.as_ref(),
+			None,
+		);
+		ExtensionTarget::server(&ctx.paths, args.commit.as_deref(), csa)?
+	} else {
+		ExtensionTarget::desktop(&ctx, &options).await?
+	};
+
+	let installed = target.list(None).await?;
+	let plan = plan_sync(&lock.extensions, &installed, args.keep_extra);
+	if plan.is_empty() {
+		ctx.log.result(format!(
+			"Installed extensions match {}",
+			args.file.display()
+		));
+		return Ok(0);
+	}
+
+	let changes = plan.describe();
+	if args.check {
+		for line in &changes {
+			ctx.log.result(format!("  {line}"));
+		}
+		ctx.log.result(format!(
+			"{} extension(s) differ from {}",
+			changes.len(),
+			args.file.display()
+		));
+		return Ok(1);
+	}
+
+	for line in &changes {
+		info!(ctx.log, "Going to {}", line);
+	}
+
+	let to_install: Vec<_> = plan
+		.install
+		.iter()
+		.copied()
+		.chain(plan.update.iter().map(|(_, e)| *e))
+		.collect();
+	let (pre_release, release): (Vec<_>, Vec<_>) =
+		to_install.into_iter().partition(|e| e.pre_release);
+	target.install(&release, false).await?;
+	target.install(&pre_release, true).await?;
+	let uninstall: Vec<_> = plan.uninstall.iter().map(|e| (*e).clone()).collect();
+	target.uninstall(&uninstall).await?;
+
+	ctx.log.result(format!(
+		"Made {} change(s) to match {}",
+		changes.len(),
+		args.file.display()
+	));
+	Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn installed(id: &str, version: &str, pre_release: Option<bool>) -> InstalledExtension {
+		InstalledExtension {
+			id: id.to_string(),
+			version: version.to_string(),
+			pre_release,
+			location: None,
+			pending_removal: false,
+		}
+	}
+
+	fn locked(id: &str, version: &str, pre_release: bool) -> LockedExtension {
+		LockedExtension {
+			id: id.to_string(),
+			version: version.to_string(),
+			pre_release,
+		}
+	}
+
+	#[test]
+	fn test_plan_sync() {
+		let desired = vec![
+			locked("ms-python.python", "2024.1.0", false),
+			locked("rust-lang.rust-analyzer", "0.4.1800", true),
+			locked("golang.go", "0.40.0", false),
+			locked("esbenp.prettier-vscode", "10.1.0", false),
+		];
+		let installed = vec![
+			installed("MS-Python.python", "2024.1.0", Some(false)),
+			installed("rust-lang.rust-analyzer", "0.4.1800", Some(false)),
+			installed("golang.go", "0.39.0", None),
+			installed("vscodevim.vim", "1.27.0", None),
+		];
+
+		let plan = plan_sync(&desired, &installed, false);
+		assert_eq!(plan.install, vec![&desired[3]]);
+		assert_eq!(
+			plan.update
+				.iter()
+				.map(|(_, e)| e.id.as_str())
+				.collect::<Vec<_>>(),
+			vec!["rust-lang.rust-analyzer", "golang.go"]
+		);
+		assert_eq!(plan.uninstall, vec![&installed[3]]);
+
+		assert!(plan_sync(&desired, &installed, true).uninstall.is_empty());
+		assert!(plan_sync(&desired[..1], &installed[..1], false).is_empty());
+	}
+
+	#[test]
+	fn test_installed_extension_json() {
+		let mut e = installed("golang.go", "0.40.0", Some(true));
+		e.location = Some(PathBuf::from("/ext/golang.go-0.40.0"));
+		assert_eq!(
+			serde_json::to_value(&e).unwrap(),
+			serde_json::json!({
+				"id": "golang.go",
+				"version": "0.40.0",
+				"preRelease": true,
+				"location": "/ext/golang.go-0.40.0",
+				"pendingRemoval": false,
+			})
+		);
+	}
+
+	fn ext_args(args: &[&str]) -> TunnelExtensionArgs {
+		use crate::commands::args::{Commands, TunnelSubcommand};
+		use clap::Parser;
+
+		let cli = crate::commands::args::IntegratedCli::parse_from(
+			["code", "tunnel", "ext"].iter().chain(args),
+		);
+		match cli.core.subcommand {
+			Some(Commands::Tunnel(t)) => match t.subcommand {
+				Some(TunnelSubcommand::Ext(e)) => e,
+				_ => unreachable!(),
+			},
+			_ => unreachable!(),
+		}
+	}
+
+	#[test]
+	fn test_tunnel_server_args() {
+		let cli = CliCore::default();
+		let tunnel = singleton::Status {
+			server_data_dir: Some("/tunnel/data".to_string()),
+			extensions_dir: Some("/tunnel/extensions".to_string()),
+			..Default::default()
+		};
+
+		let args = tunnel_server_args(&cli, &ext_args(&["list"]), Some(&tunnel));
+		assert_eq!(args.server_data_dir.as_deref(), Some("/tunnel/data"));
+		assert_eq!(args.extensions_dir.as_deref(), Some("/tunnel/extensions"));
+
+		let args = tunnel_server_args(
+			&cli,
+			&ext_args(&["--extensions-dir", "/given", "list"]),
+			Some(&tunnel),
+		);
+		assert_eq!(args.server_data_dir.as_deref(), Some("/tunnel/data"));
+		assert_eq!(args.extensions_dir.as_deref(), Some("/given"));
+
+		let args = tunnel_server_args(&cli, &ext_args(&["list"]), None);
+		assert_eq!(args.server_data_dir, None);
+		assert_eq!(args.extensions_dir, None);
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn test_running_server() {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_path_buf())
+			.with_tunnel_instance(Some("work"));
+		let servers: Vec<_> = ["aaa", "bbb"]
+			.iter()
+			.map(|commit| {
+				let server = InstalledServer {
+					quality: crate::options::Quality::Stable,
+					commit: commit.to_string(),
+					headless: true,
+				}
+				.server_paths(&paths);
+				fs::create_dir_all(&server.server_dir).unwrap();
+				server
+			})
+			.collect();
+		assert!(matches!(
+			ExtensionTarget::running_server(&paths, None, CodeServerArgs::default()),
+			Err(CodeError::NoServerRunning(_))
+		));
+
+		// a process whose command line has the server's executable
+		let server = &servers[1];
+		let mut child = std::process::Command::new("sh")
+			.arg("-c")
+			.arg("sleep 30; :")
+			.arg(&server.executable)
+			.spawn()
+			.unwrap();
+		fs::write(&server.pidfile, child.id().to_string()).unwrap();
+		// until the child execs, its command line is the test's
+		for _ in 0..50 {
+			if server.is_running_in_any_instance() {
+				break;
+			}
+			std::thread::sleep(std::time::Duration::from_millis(20));
+		}
+
+		let args = CodeServerArgs {
+			server_data_dir: Some("/tunnel/data".to_string()),
+			..Default::default()
+		};
+		let target = ExtensionTarget::running_server(&paths, None, args);
+		child.kill().ok();
+		child.wait().ok();
+
+		match target.unwrap() {
+			ExtensionTarget::Server { executable, .. } => {
+				assert_eq!(executable, server.executable)
+			}
+			_ => panic!("expected a server"),
+		}
+	}
+}
@@ -73,11 +73,15 @@ impl TablePrinter for JsonTablePrinter {
 				} else {
 					bw.write_all(b"{")?;
 				}
-				for col in &table.cols {
+				for (j, col) in table.cols.iter().enumerate() {
+					if j > 0 {
+						bw.write_all(b",")?;
+					}
 					serde_json::to_writer(&mut bw, col.heading)?;
 					bw.write_all(b":")?;
 					serde_json::to_writer(&mut bw, &col.data[i])?;
 				}
+				bw.write_all(b"}")?;
 			}
 		}
 
@@ -133,3 +137,31 @@ where
 	}
 	w.write_all(b"\r\n")
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_json_table_printer() {
+		let mut id = Column::new("Id");
+		let mut version = Column::new("Version");
+		for (i, v) in [("a.b", "1.0.0"), ("c.d", "2.0.0")] {
+			id.add_row(i.to_string());
+			version.add_row(v.to_string());
+		}
+
+		let mut out = vec![];
+		JsonTablePrinter()
+			.print(OutputTable::new(vec![id, version]), &mut out)
+			.unwrap();
+		let parsed: serde_json::Value = serde_json::from_slice(&out).unwrap();
+		assert_eq!(
+			parsed,
+			serde_json::json!([
+				{"Id": "a.b", "Version": "1.0.0"},
+				{"Id": "c.d", "Version": "2.0.0"},
+			])
+		);
+	}
+}
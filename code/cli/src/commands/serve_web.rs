@@ -3,7 +3,10 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
-use std::collections::HashMap;
+mod config;
+mod oidc;
+
+use std::collections::{HashMap, HashSet};
 use std::convert::Infallible;
 use std::fs;
 use std::io::{Read, Write};
@@ -12,6 +15,9 @@ use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
+use futures::{FutureExt, StreamExt};
+use hyper::client::conn::SendRequest;
+use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response, Server};
 use tokio::io::{AsyncBufReadExt, BufReader};
@@ -26,20 +32,29 @@ use crate::log;
 use crate::options::Quality;
 use crate::state::{LauncherPaths, PersistedState};
 use crate::tunnels::shutdown_signal::ShutdownRequest;
+use crate::update_policy::UpdateDecision;
 use crate::update_service::{
 	unzip_downloaded_release, Platform, Release, TargetKind, UpdateService,
 };
+use crate::util::access_log::{AccessLog, RoutedCommit};
 use crate::util::command::new_script_command;
-use crate::util::errors::AnyError;
+use crate::util::errors::{wrap, AnyError};
 use crate::util::http::{self, ReqwestSimpleHttp};
 use crate::util::io::SilentCopyProgress;
+use crate::util::readiness::{wait_for_http, OutputTail, ProbeTarget};
 use crate::util::sync::{new_barrier, Barrier, BarrierOpener};
 use crate::{
+	constants::APPLICATION_NAME,
 	tunnels::legal,
 	util::{errors::CodeError, prereqs::PreReqChecker},
 };
 
-use super::{args::ServeWebArgs, CommandContext};
+use super::{
+	args::{ServeWebApproveArgs, ServeWebArgs},
+	CommandContext,
+};
+use config::ServeWebConfigFile;
+use oidc::OidcAuth;
 
 /// Length of a commit hash, for validation
 const COMMIT_HASH_LEN: usize = 40;
@@ -49,8 +64,24 @@ const SERVER_IDLE_TIMEOUT_SECS: u64 = 60 * 60;
 /// Number of seconds in which the server times out when there is a connection
 /// (should be large enough to basically never happen)
 const SERVER_ACTIVE_TIMEOUT_SECS: u64 = SERVER_IDLE_TIMEOUT_SECS * 24 * 30 * 12;
+/// Number of seconds to wait for a started server to accept requests.
+const SERVER_STARTUP_TIMEOUT_SECS: u64 = 30;
 /// How long to cache the "latest" version we get from the update service.
 const RELEASE_CHECK_INTERVAL: u64 = 60 * 60;
+/// Minimum number of seconds between update checks, to not hammer the update service.
+const MIN_RELEASE_CHECK_INTERVAL: u64 = 60;
+/// Port serve-web listens on by default.
+const DEFAULT_PORT: u16 = 8000;
+/// Maximum number of seconds a previous server version keeps running for its
+/// existing clients after a newer version has been rolled out.
+const SERVER_MAX_DRAIN_SECS: u64 = 60 * 60 * 24;
+/// Maximum number of concurrent HTTP requests proxied to each server version.
+const POOL_MAX_CONNECTIONS: usize = 64;
+/// Maximum number of idle keep-alive connections kept for each server version.
+const POOL_MAX_IDLE_CONNECTIONS: usize = 16;
+/// How long an idle pooled connection may be reused. This is kept below the
+/// default 5s keep-alive timeout of the Node.js server.
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(4);
 
 /// Number of bytes for the secret keys. See workbench.ts for their usage.
 const SECRET_KEY_BYTES: usize = 32;
@@ -66,10 +97,70 @@ const SECRET_KEY_COOKIE_NAME: &str = "vscode-cli-secret-half";
 /// page. The VS Code server prefixes all assets and connections it loads with
 /// its version string, so existing clients can continue to get served even
 /// while new clients get new VS Code Server versions.
-pub async fn serve_web(ctx: CommandContext, mut args: ServeWebArgs) -> Result<i32, AnyError> {
+/// Approves the update a running serve-web is waiting on. It watches the
+/// approval file, which works the same on all platforms.
+pub async fn approve_update(
+	ctx: CommandContext,
+	args: ServeWebApproveArgs,
+) -> Result<i32, AnyError> {
+	let path = ctx.paths.web_server_update_approval();
+	fs::write(&path, args.commit.as_deref().unwrap_or_default())
+		.map_err(|e| wrap(e, format!("error writing {}", path.display())))?;
+	ctx.log.result(format!(
+		"Approved the pending update{}, a running serve-web will pick it up within {}s",
+		args.commit.map(|c| format!(" to {c}")).unwrap_or_default(),
+		config::CONFIG_POLL_INTERVAL.as_secs()
+	));
+	Ok(0)
+}
+
+pub async fn serve_web(ctx: CommandContext, cli_args: ServeWebArgs) -> Result<i32, AnyError> {
+	let mut args = match &cli_args.config {
+		Some(p) => ServeWebConfigFile::read(p)?.apply_to(cli_args.clone()),
+		None => cli_args.clone(),
+	};
+	info!(
+		ctx.log,
+		"Effective configuration:\n  {}",
+		config::describe(&args)
+	);
+	let initial_args = args.clone();
+
 	legal::require_consent(&ctx.paths, args.accept_server_license_terms)?;
 
 	let platform: crate::update_service::Platform = PreReqChecker::new().verify().await?;
+	let key = get_server_key_half(&ctx.paths);
+
+	let oidc = if args.oidc.oidc_issuer.is_some() {
+		// Signing in controls access, so servers run without a connection token.
+		if args.connection_token.is_some() || args.connection_token_file.is_some() {
+			warning!(
+				ctx.log,
+				"Connection tokens are not used when OpenID Connect sign-in is enabled"
+			);
+		}
+		if args.oidc.oidc_allowed_users.is_empty() && args.oidc.oidc_allowed_groups.is_empty() {
+			warning!(
+				ctx.log,
+				"No allowed users or groups are configured, so anyone who can sign in with the issuer can use this server"
+			);
+		}
+		args.without_connection_token = true;
+		args.connection_token = None;
+		args.connection_token_file = None;
+
+		let auth = OidcAuth::discover(
+			ctx.log.clone(),
+			ctx.http.clone(),
+			args.oidc.clone(),
+			key.0.as_ref(),
+		)
+		.await?;
+		Some(Arc::new(auth))
+	} else {
+		None
+	};
+
 	if !args.without_connection_token {
 		if let Some(p) = args.connection_token_file.as_deref() {
 			let token = fs::read_to_string(PathBuf::from(p))
@@ -87,29 +178,49 @@ pub async fn serve_web(ctx: CommandContext, mut args: ServeWebArgs) -> Result<i3
 	}
 
 	let cm: Arc<ConnectionManager> = ConnectionManager::new(&ctx, platform, args.clone());
-	let update_check_interval = 3600;
-	if args.commit_id.is_none() {
-		cm.clone()
-			.start_update_checker(Duration::from_secs(update_check_interval));
-	} else {
+	if args.commit_id.is_some() {
 		// If a commit was provided, invoke get_latest_release() once to ensure we're using that exact version;
 		// get_latest_release() will short-circuit to args.commit_id.
 		if let Err(e) = cm.get_latest_release().await {
 			warning!(cm.log, "error getting latest version: {}", e);
 		}
 	}
+	// The update checker also runs for a provided commit, since it can be
+	// changed by reloading the config file.
+	cm.clone().start_update_checker();
+	if let Some(path) = cli_args.config.clone() {
+		cm.clone()
+			.start_config_reloader(cli_args, initial_args, path);
+	}
 
-	let key = get_server_key_half(&ctx.paths);
-	let make_svc = move || {
+	let access_log = args.access_log.open()?;
+	let announced_urls = Arc::new(Mutex::new(HashSet::new()));
+	let make_svc = move |remote_addr: Option<SocketAddr>| {
 		let ctx = HandleContext {
 			cm: cm.clone(),
 			log: cm.log.clone(),
 			server_secret_key: key.clone(),
+			access_log: access_log.clone(),
+			oidc: oidc.clone(),
+			remote_addr,
+			announced_urls: announced_urls.clone(),
 		};
 		let service = service_fn(move |req| handle(ctx.clone(), req));
 		async move { Ok::<_, Infallible>(service) }
 	};
 
+	if !args.trusted_proxies.is_empty() {
+		info!(
+			ctx.log,
+			"Requests from trusted proxies ({}) are served under their forwarded host and prefix",
+			args.trusted_proxies
+				.iter()
+				.map(|p| p.to_string())
+				.collect::<Vec<_>>()
+				.join(", ")
+		);
+	}
+
 	let mut shutdown = ShutdownRequest::create_rx([ShutdownRequest::CtrlC]);
 	let r = if let Some(s) = args.socket_path {
 		let s = PathBuf::from(&s);
@@ -117,7 +228,7 @@ pub async fn serve_web(ctx: CommandContext, mut args: ServeWebArgs) -> Result<i3
 		ctx.log
 			.result(format!("Web UI available on {}", s.display()));
 		let r = Server::builder(socket.into_pollable())
-			.serve(make_service_fn(|_| make_svc()))
+			.serve(make_service_fn(|_| make_svc(None)))
 			.with_graceful_shutdown(async {
 				let _ = shutdown.wait().await;
 			})
@@ -125,30 +236,39 @@ pub async fn serve_web(ctx: CommandContext, mut args: ServeWebArgs) -> Result<i3
 		let _ = std::fs::remove_file(&s); // cleanup
 		r
 	} else {
+		let port = args.port.unwrap_or(DEFAULT_PORT);
 		let addr: SocketAddr = match &args.host {
-			Some(h) => {
-				SocketAddr::new(h.parse().map_err(CodeError::InvalidHostAddress)?, args.port)
-			}
-			None => SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), args.port),
+			Some(h) => SocketAddr::new(h.parse().map_err(CodeError::InvalidHostAddress)?, port),
+			None => SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port),
 		};
 		let builder = Server::try_bind(&addr).map_err(CodeError::CouldNotListenOnInterface)?;
 
 		// Get the actual bound address (important when port 0 is used for random port assignment)
 		let bound_addr = builder.local_addr();
-		let mut listening = format!("Web UI available at http://{bound_addr}");
+		let mut url = format!("http://{bound_addr}");
 		if let Some(base) = args.server_base_path {
 			if !base.starts_with('/') {
-				listening.push('/');
+				url.push('/');
 			}
-			listening.push_str(&base);
-		}
-		if let Some(ct) = args.connection_token {
-			listening.push_str(&format!("?tkn={ct}"));
+			url.push_str(&base);
 		}
-		ctx.log.result(listening);
+		// Behind a proxy, the client-facing URL is announced once the proxy
+		// forwards a request to it.
+		let location = if args.trusted_proxies.is_empty() {
+			"at"
+		} else {
+			"locally at"
+		};
+		ctx.log.result(web_ui_available(
+			location,
+			&url,
+			args.connection_token.as_deref(),
+		));
 
 		builder
-			.serve(make_service_fn(|_| make_svc()))
+			.serve(make_service_fn(|conn: &AddrStream| {
+				make_svc(Some(conn.remote_addr()))
+			}))
 			.with_graceful_shutdown(async {
 				let _ = shutdown.wait().await;
 			})
@@ -165,28 +285,97 @@ struct HandleContext {
 	cm: Arc<ConnectionManager>,
 	log: log::Logger,
 	server_secret_key: SecretKeyPart,
+	access_log: Option<AccessLog>,
+	oidc: Option<Arc<OidcAuth>>,
+	remote_addr: Option<SocketAddr>,
+	/// Client-facing URLs that trusted proxies have forwarded requests for
+	announced_urls: Arc<Mutex<HashSet<String>>>,
+}
+
+impl HandleContext {
+	/// Gets whether the connection came from a trusted reverse proxy.
+	fn is_trusted_proxy(&self) -> bool {
+		let args = self.cm.args.borrow();
+		let proxies = &args.trusted_proxies;
+		match self.remote_addr {
+			Some(a) => proxies.iter().any(|p| p.contains(&a.ip())),
+			None => !proxies.is_empty(),
+		}
+	}
 }
 
 /// Handler function for an inbound request
-async fn handle(ctx: HandleContext, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+async fn handle(ctx: HandleContext, mut req: Request<Body>) -> Result<Response<Body>, Infallible> {
 	let client_key_half = get_client_key_half(&req);
+	let fwd = take_forwarded_info(&ctx, &mut req);
+	announce_forwarded_url(&ctx, &fwd);
+	let entry = ctx.access_log.as_ref().map(|l| {
+		let mut e = l.begin(ctx.remote_addr, &req);
+		if let Some(client) = fwd.client {
+			e.set_client(client.to_string());
+		}
+		e
+	});
+	let auth_res = authenticate(&ctx, &fwd, &req).await;
 	let path = req.uri().path();
 
-	let mut res = if path.starts_with(&ctx.cm.base_path)
+	let mut res = if let Some(res) = auth_res {
+		res
+	} else if path.starts_with(&ctx.cm.base_path)
 		&& path.get(ctx.cm.base_path.len()..).unwrap_or_default() == SECRET_KEY_MINT_PATH
 	{
 		handle_secret_mint(&ctx, req)
 	} else {
-		handle_proxied(&ctx, req).await
+		handle_proxied(&ctx, &fwd, req).await
 	};
 
-	append_secret_headers(&ctx.cm.base_path, &mut res, &client_key_half);
+	append_secret_headers(&ctx.cm.base_path, &fwd, &mut res, &client_key_half);
 
-	Ok(res)
+	Ok(match entry {
+		Some(e) => e.finish(res),
+		None => res,
+	})
 }
 
-async fn handle_proxied(ctx: &HandleContext, req: Request<Body>) -> Response<Body> {
+/// Checks that the user is signed in, if sign-in is required. Returns a
+/// response to send instead of handling the request if they're not.
+async fn authenticate(
+	ctx: &HandleContext,
+	fwd: &ForwardedInfo,
+	req: &Request<Body>,
+) -> Option<Response<Body>> {
+	let oidc = ctx.oidc.as_ref()?;
+	let host = fwd
+		.host
+		.clone()
+		.or_else(|| {
+			req.headers()
+				.get(hyper::header::HOST)
+				.and_then(|h| h.to_str().ok())
+				.map(|h| h.to_string())
+		})
+		.unwrap_or_else(|| "localhost".to_string());
+
+	let site = oidc::RequestSite {
+		origin: format!("{}://{}", fwd.proto.as_deref().unwrap_or("http"), host),
+		prefix: fwd.prefix.clone(),
+		base_path: ctx.cm.base_path.clone(),
+		secure: fwd.secure,
+	};
+	oidc.authenticate(req, &site).await
+}
+
+async fn handle_proxied(
+	ctx: &HandleContext,
+	fwd: &ForwardedInfo,
+	req: Request<Body>,
+) -> Response<Body> {
 	let release = if let Some((r, _)) = get_release_from_path(req.uri().path(), ctx.cm.platform) {
+		// Versions being drained only keep serving the clients they already
+		// have; new page loads are sent to the latest version instead.
+		if is_document_load(&req) && ctx.cm.is_draining(&r) {
+			return response::redirect(&latest_version_location(fwd, &ctx.cm.base_path, req.uri()));
+		}
 		r
 	} else {
 		match ctx.cm.get_release_from_cache().await {
@@ -198,17 +387,23 @@ async fn handle_proxied(ctx: &HandleContext, req: Request<Body>) -> Response<Bod
 		}
 	};
 
-	match ctx.cm.get_connection(release).await {
-		Ok(rw) => {
-			if req.headers().contains_key(hyper::header::UPGRADE) {
-				forward_ws_req_to_server(ctx.log.clone(), rw, req).await
-			} else {
-				forward_http_req_to_server(rw, req).await
-			}
+	// Websockets are long-lived and get a dedicated pipe, while other requests
+	// share pooled keep-alive connections.
+	let commit = RoutedCommit(release.commit.clone());
+	let mut res = if req.headers().contains_key(hyper::header::UPGRADE) {
+		match ctx.cm.get_connection(release).await {
+			Ok(rw) => forward_ws_req_to_server(ctx.log.clone(), rw, req).await,
+			Err(e) => response::get_connection_err(e),
 		}
-		Err(CodeError::ServerNotYetDownloaded) => response::wait_for_download(),
-		Err(e) => response::code_err(e),
-	}
+	} else {
+		match ctx.cm.get_pooled_connection(release).await {
+			Ok(pool) => forward_http_req_to_server(pool, req).await,
+			Err(e) => response::get_connection_err(e),
+		}
+	};
+
+	res.extensions_mut().insert(commit);
+	res
 }
 
 fn handle_secret_mint(ctx: &HandleContext, req: Request<Body>) -> Response<Body> {
@@ -227,28 +422,187 @@ fn handle_secret_mint(ctx: &HandleContext, req: Request<Body>) -> Response<Body>
 /// and maintains the http-only cookie the client will use for cookies.
 fn append_secret_headers(
 	base_path: &str,
+	fwd: &ForwardedInfo,
 	res: &mut Response<Body>,
 	client_key_half: &SecretKeyPart,
 ) {
+	let prefix = &fwd.prefix;
+	let attrs = format!(
+		"SameSite=Strict; Path={prefix}/{}",
+		if fwd.secure { "; Secure" } else { "" }
+	);
 	let headers = res.headers_mut();
 	headers.append(
 		hyper::header::SET_COOKIE,
-		format!("{PATH_COOKIE_NAME}={base_path}{SECRET_KEY_MINT_PATH}; SameSite=Strict; Path=/",)
+		format!("{PATH_COOKIE_NAME}={prefix}{base_path}{SECRET_KEY_MINT_PATH}; {attrs}",)
 			.parse()
 			.unwrap(),
 	);
 	headers.append(
 		hyper::header::SET_COOKIE,
 		format!(
-			"{}={}; SameSite=Strict; HttpOnly; Max-Age=2592000; Path=/",
+			"{}={}; HttpOnly; Max-Age=2592000; {}",
 			SECRET_KEY_COOKIE_NAME,
-			client_key_half.encode()
+			client_key_half.encode(),
+			attrs,
 		)
 		.parse()
 		.unwrap(),
 	);
 }
 
+/// Maximum number of client-facing URLs announced, in case a proxy forwards
+/// whatever host its clients ask for.
+const MAX_ANNOUNCED_URLS: usize = 16;
+
+/// Headers a reverse proxy sets to describe the client-facing request.
+const FORWARDED_HEADERS: [&str; 4] = [
+	"x-forwarded-for",
+	"x-forwarded-proto",
+	"x-forwarded-host",
+	"x-forwarded-prefix",
+];
+
+/// Client-facing request details, read from the headers of a trusted proxy.
+#[derive(Default)]
+struct ForwardedInfo {
+	/// Address of the client that connected to the proxy
+	client: Option<IpAddr>,
+	/// Scheme the client connected with
+	proto: Option<String>,
+	/// Whether the client connected over HTTPS
+	secure: bool,
+	/// Host the client connected to
+	host: Option<String>,
+	/// Path prefix the proxy serves us under, without a trailing `/`
+	prefix: String,
+}
+
+impl ForwardedInfo {
+	/// Gets the client-facing URL for the path on this server.
+	fn url(&self, path: &str) -> String {
+		match &self.host {
+			Some(host) => format!(
+				"{}://{}{}{}",
+				self.proto.as_deref().unwrap_or("http"),
+				host,
+				self.prefix,
+				path
+			),
+			None => format!("{}{}", self.prefix, path),
+		}
+	}
+}
+
+/// Formats the message telling the user where the web UI is available.
+fn web_ui_available(location: &str, url: &str, connection_token: Option<&str>) -> String {
+	match connection_token {
+		Some(ct) => format!("Web UI available {location} {url}?tkn={ct}"),
+		None => format!("Web UI available {location} {url}"),
+	}
+}
+
+/// Prints the client-facing URL the first time a trusted proxy forwards a
+/// request under it, since the URL printed on startup is only the local one.
+fn announce_forwarded_url(ctx: &HandleContext, fwd: &ForwardedInfo) {
+	if fwd.host.is_none() {
+		return;
+	}
+
+	let url = fwd.url(&ctx.cm.base_path);
+	{
+		let mut announced = ctx.announced_urls.lock().unwrap();
+		if announced.len() >= MAX_ANNOUNCED_URLS || !announced.insert(url.clone()) {
+			return;
+		}
+	}
+
+	let token = ctx.cm.args.borrow().connection_token.clone();
+	ctx.log
+		.result(web_ui_available("at", &url, token.as_deref()));
+}
+
+/// Reads the forwarded headers if the request came from a trusted proxy.
+/// Otherwise, they're removed so that they aren't honored by the server.
+fn take_forwarded_info(ctx: &HandleContext, req: &mut Request<Body>) -> ForwardedInfo {
+	if !ctx.is_trusted_proxy() {
+		for h in FORWARDED_HEADERS {
+			req.headers_mut().remove(h);
+		}
+		return ForwardedInfo::default();
+	}
+
+	let headers = req.headers();
+	let first_value = |name: &str| {
+		headers
+			.get(name)
+			.and_then(|v| v.to_str().ok())
+			.and_then(|v| v.split(',').next())
+			.map(|v| v.trim())
+			.filter(|v| !v.is_empty())
+	};
+
+	let proto = first_value("x-forwarded-proto")
+		.filter(|p| p.chars().all(|c| c.is_ascii_alphanumeric()))
+		.map(|p| p.to_ascii_lowercase());
+
+	ForwardedInfo {
+		client: get_forwarded_client(ctx, headers),
+		secure: proto.as_deref() == Some("https"),
+		proto,
+		host: first_value("x-forwarded-host")
+			.filter(|h| !h.contains(['/', '\\', '@']))
+			.map(|h| h.to_string()),
+		prefix: first_value("x-forwarded-prefix")
+			.map(normalize_forwarded_prefix)
+			.unwrap_or_default(),
+	}
+}
+
+/// Gets the client address from `X-Forwarded-For`. Each proxy appends the
+/// address it received the request from, so the client is the last address
+/// that isn't one of our trusted proxies.
+fn get_forwarded_client(ctx: &HandleContext, headers: &hyper::HeaderMap) -> Option<IpAddr> {
+	let addrs: Vec<IpAddr> = headers
+		.get_all("x-forwarded-for")
+		.iter()
+		.filter_map(|v| v.to_str().ok())
+		.flat_map(|v| v.split(','))
+		.filter_map(|a| a.trim().parse().ok())
+		.collect();
+
+	let args = ctx.cm.args.borrow();
+	let proxies = &args.trusted_proxies;
+	addrs
+		.iter()
+		.rev()
+		.find(|a| !proxies.iter().any(|p| p.contains(a)))
+		.or_else(|| addrs.first())
+		.copied()
+}
+
+/// Normalizes a forwarded prefix to start with, but not end with, a `/`.
+/// Prefixes that can't be safely used in a cookie are ignored.
+fn normalize_forwarded_prefix(p: &str) -> String {
+	let p = p.trim_matches('/');
+	if p.is_empty() || p.contains(|c: char| c == ';' || c == ',' || c.is_whitespace()) {
+		return String::new();
+	}
+
+	format!("/{p}")
+}
+
+/// Gets where to send a page load from a version being drained: the base path,
+/// keeping the query, which has the connection token and folder to open.
+fn latest_version_location(fwd: &ForwardedInfo, base_path: &str, uri: &hyper::Uri) -> String {
+	let mut location = fwd.url(base_path);
+	if let Some(q) = uri.query() {
+		location.push('?');
+		location.push_str(q);
+	}
+	location
+}
+
 /// Gets the release info from the VS Code path prefix, which is in the
 /// format `/<quality>-<commit>/...`
 fn get_release_from_path(path: &str, platform: Platform) -> Option<(Release, String)> {
@@ -281,31 +635,72 @@ fn get_release_from_path(path: &str, platform: Platform) -> Option<(Release, Str
 	))
 }
 
-/// Proxies the standard HTTP request to the async pipe, returning the piped response
+/// Proxies the standard HTTP request over a pooled connection, returning the
+/// piped response
 async fn forward_http_req_to_server(
-	(rw, handle): (AsyncPipe, ConnectionHandle),
+	(pool, handle): (Arc<ConnectionPool>, ConnectionHandle),
 	req: Request<Body>,
 ) -> Response<Body> {
-	let (mut request_sender, connection) =
-		match hyper::client::conn::Builder::new().handshake(rw).await {
-			Ok(r) => r,
-			Err(e) => return response::connection_err(e),
-		};
+	// Requests without a body can be safely retried if a reused connection
+	// turns out to have been closed by the server.
+	let retry_req = clone_bodiless_request(&req);
 
-	tokio::spawn(connection);
+	let mut conn = match pool.checkout().await {
+		Ok(c) => c,
+		Err(e) => return response::connection_err(e),
+	};
 
-	let res = request_sender
-		.send_request(req)
-		.await
-		.unwrap_or_else(response::connection_err);
+	let res = match conn.send_request(req).await {
+		Err(_) if conn.reused && retry_req.is_some() => {
+			drop(conn);
+			conn = match pool.checkout_new().await {
+				Ok(c) => c,
+				Err(e) => return response::connection_err(e),
+			};
+			conn.send_request(retry_req.unwrap()).await
+		}
+		r => r,
+	};
 
-	// technically, we should buffer the body into memory since it may not be
-	// read at this point, but because the keepalive time is very large
-	// there's not going to be responses that take hours to send and x
-	// cause us to kill the server before the response is sent
-	drop(handle);
+	let res = match res {
+		Ok(r) => r,
+		Err(e) => return response::connection_err(e),
+	};
 
-	res
+	// Keep the connection checked out and the server alive until the body is
+	// fully sent. The connection is only reused once the body was read to the
+	// end, otherwise the rest of it would be read as the next response.
+	let (parts, body) = res.into_parts();
+	let body = futures::stream::unfold(Some((body, conn, handle)), |state| async move {
+		let (mut body, conn, handle) = state?;
+		match body.next().await {
+			Some(Ok(chunk)) => Some((Ok(chunk), Some((body, conn, handle)))),
+			Some(Err(e)) => Some((Err(e), None)),
+			None => {
+				conn.release();
+				None
+			}
+		}
+	});
+
+	Response::from_parts(parts, Body::wrap_stream(body))
+}
+
+/// Makes a copy of the request if it has no body.
+fn clone_bodiless_request(req: &Request<Body>) -> Option<Request<Body>> {
+	if req.method() != hyper::Method::GET && req.method() != hyper::Method::HEAD {
+		return None;
+	}
+
+	let mut cloned = Request::builder()
+		.method(req.method())
+		.uri(req.uri())
+		.version(req.version());
+	for (k, v) in req.headers() {
+		cloned = cloned.header(k, v);
+	}
+
+	cloned.body(Body::empty()).ok()
 }
 
 /// Proxies the websocket request to the async pipe
@@ -366,6 +761,15 @@ async fn forward_ws_req_to_server(
 	proxied_res
 }
 
+/// Returns whether the request is a top-level page load, as opposed to a
+/// subresource, iframe, or websocket request from an already-loaded client.
+fn is_document_load(req: &Request<Body>) -> bool {
+	req.headers()
+		.get("sec-fetch-dest")
+		.map(|v| v.as_bytes() == b"document")
+		.unwrap_or(false)
+}
+
 /// Returns whether the string looks like a commit hash.
 fn is_commit_hash(s: &str) -> bool {
 	s.len() == COMMIT_HASH_LEN && s.chars().all(|c| c.is_ascii_hexdigit())
@@ -450,13 +854,20 @@ mod response {
 
 	use super::*;
 
-	pub fn connection_err(err: hyper::Error) -> Response<Body> {
+	pub fn connection_err(err: impl std::fmt::Debug) -> Response<Body> {
 		Response::builder()
 			.status(503)
 			.body(Body::from(format!("Error connecting to server: {err:?}")))
 			.unwrap()
 	}
 
+	pub fn get_connection_err(err: CodeError) -> Response<Body> {
+		match err {
+			CodeError::ServerNotYetDownloaded => wait_for_download(),
+			e => code_err(e),
+		}
+	}
+
 	pub fn code_err(err: CodeError) -> Response<Body> {
 		Response::builder()
 			.status(500)
@@ -472,6 +883,14 @@ mod response {
 			.unwrap()
 	}
 
+	pub fn redirect(location: &str) -> Response<Body> {
+		Response::builder()
+			.status(302)
+			.header(hyper::header::LOCATION, location)
+			.body(Body::empty())
+			.unwrap()
+	}
+
 	pub fn secret_key(hash: Vec<u8>) -> Response<Body> {
 		Response::builder()
 			.status(200)
@@ -504,12 +923,147 @@ impl Drop for ConnectionHandle {
 	}
 }
 
-type StartData = (PathBuf, Arc<tokio::sync::watch::Sender<usize>>);
+/// Pool of keep-alive HTTP/1.1 connections to a single server version.
+struct ConnectionPool {
+	socket_path: PathBuf,
+	/// Idle connections, with the time they were returned to the pool
+	idle: Mutex<Vec<(Instant, SendRequest<Body>)>>,
+	/// Limits the number of requests in flight to the server
+	permits: Arc<tokio::sync::Semaphore>,
+}
+
+impl ConnectionPool {
+	pub fn new(socket_path: PathBuf) -> Arc<Self> {
+		Arc::new(Self {
+			socket_path,
+			idle: Mutex::new(Vec::new()),
+			permits: Arc::new(tokio::sync::Semaphore::new(POOL_MAX_CONNECTIONS)),
+		})
+	}
+
+	/// Checks out a connection, reusing a healthy idle connection if one exists.
+	pub async fn checkout(self: &Arc<Self>) -> Result<PooledConnection, AnyError> {
+		let permit = self.permits.clone().acquire_owned().await.unwrap();
+		if let Some(sender) = self.take_idle() {
+			return Ok(PooledConnection {
+				sender: Some(sender),
+				reused: true,
+				complete: false,
+				pool: self.clone(),
+				_permit: permit,
+			});
+		}
+
+		self.connect(permit).await
+	}
+
+	/// Checks out a newly-opened connection.
+	pub async fn checkout_new(self: &Arc<Self>) -> Result<PooledConnection, AnyError> {
+		let permit = self.permits.clone().acquire_owned().await.unwrap();
+		self.connect(permit).await
+	}
+
+	async fn connect(
+		self: &Arc<Self>,
+		permit: tokio::sync::OwnedSemaphorePermit,
+	) -> Result<PooledConnection, AnyError> {
+		let rw = get_socket_rw_stream(&self.socket_path).await?;
+		let (sender, connection) = hyper::client::conn::Builder::new()
+			.handshake(rw)
+			.await
+			.map_err(|e| wrap(e, "error connecting to server"))?;
+
+		tokio::spawn(connection);
+
+		Ok(PooledConnection {
+			sender: Some(sender),
+			reused: false,
+			complete: false,
+			pool: self.clone(),
+			_permit: permit,
+		})
+	}
+
+	/// Takes the most recently used idle connection that is still healthy,
+	/// discarding any that were closed or idle for too long.
+	fn take_idle(&self) -> Option<SendRequest<Body>> {
+		let mut idle = self.idle.lock().unwrap();
+		while let Some((since, mut sender)) = idle.pop() {
+			if since.elapsed() < POOL_IDLE_TIMEOUT
+				&& matches!(
+					futures::future::poll_fn(|cx| sender.poll_ready(cx)).now_or_never(),
+					Some(Ok(_))
+				) {
+				return Some(sender);
+			}
+		}
+
+		None
+	}
+
+	fn put_idle(&self, sender: SendRequest<Body>) {
+		let mut idle = self.idle.lock().unwrap();
+		if idle.len() < POOL_MAX_IDLE_CONNECTIONS {
+			idle.push((Instant::now(), sender));
+		}
+	}
+}
+
+/// Connection checked out of a `ConnectionPool`. It's returned to the pool
+/// by `release` once its response was read in full, and closed otherwise.
+struct PooledConnection {
+	sender: Option<SendRequest<Body>>,
+	/// Whether the connection was previously used for another request
+	reused: bool,
+	/// Whether the response was read in full, so the connection can be reused
+	complete: bool,
+	pool: Arc<ConnectionPool>,
+	_permit: tokio::sync::OwnedSemaphorePermit,
+}
+
+impl PooledConnection {
+	pub async fn send_request(
+		&mut self,
+		req: Request<Body>,
+	) -> Result<Response<Body>, hyper::Error> {
+		let sender = self.sender.as_mut().unwrap();
+		let res = sender.send_request(req).await;
+		if res.is_err() {
+			self.sender = None;
+		}
+
+		res
+	}
+
+	/// Returns the connection to the pool after its response body reached EOF.
+	pub fn release(mut self) {
+		self.complete = true;
+	}
+}
+
+impl Drop for PooledConnection {
+	fn drop(&mut self) {
+		if let Some(sender) = self.sender.take() {
+			if self.complete {
+				self.pool.put_idle(sender);
+			}
+		}
+	}
+}
+
+type StartData = (
+	PathBuf,
+	Arc<tokio::sync::watch::Sender<usize>>,
+	Arc<ConnectionPool>,
+);
 
 /// State stored in the ConnectionManager for each server version.
 struct VersionState {
 	downloaded: bool,
 	socket_path: Barrier<Result<StartData, String>>,
+	/// Set to true once a newer version is rolled out. The server then shuts
+	/// down as soon as its last client disconnects.
+	draining: tokio::sync::watch::Sender<bool>,
 }
 
 type ConnectionStateMap = Arc<Mutex<HashMap<(Quality, String), VersionState>>>;
@@ -519,7 +1073,8 @@ type ConnectionStateMap = Arc<Mutex<HashMap<(Quality, String), VersionState>>>;
 struct ConnectionManager {
 	pub platform: Platform,
 	pub log: log::Logger,
-	args: ServeWebArgs,
+	/// Effective arguments, some of which can be reloaded from the config file
+	args: tokio::sync::watch::Sender<ServeWebArgs>,
 	/// Server base path, ending in `/`
 	base_path: String,
 	/// Cache where servers are stored
@@ -530,6 +1085,38 @@ struct ConnectionManager {
 	update_service: UpdateService,
 	/// Cache of the latest released version, storing the time we checked as well
 	latest_version: tokio::sync::Mutex<Option<(Instant, Release)>>,
+	/// Newer release that's waiting for approval to be used, if any
+	staged_update: Mutex<Option<StagedUpdate>>,
+	/// File written by `serve-web approve-update`
+	approval_path: PathBuf,
+}
+
+/// A release that's waiting for approval, and is downloaded in the meantime.
+struct StagedUpdate {
+	release: Release,
+	downloaded: Barrier<Result<(), String>>,
+}
+
+fn server_idle_timeout(args: &ServeWebArgs) -> Duration {
+	Duration::from_secs(
+		args.server_idle_timeout_secs
+			.unwrap_or(SERVER_IDLE_TIMEOUT_SECS),
+	)
+}
+
+fn server_startup_timeout(args: &ServeWebArgs) -> Duration {
+	Duration::from_secs(
+		args.server_startup_timeout_secs
+			.unwrap_or(SERVER_STARTUP_TIMEOUT_SECS),
+	)
+}
+
+fn update_check_interval(args: &ServeWebArgs) -> Duration {
+	Duration::from_secs(
+		args.update_check_interval_secs
+			.unwrap_or(RELEASE_CHECK_INTERVAL)
+			.max(MIN_RELEASE_CHECK_INTERVAL),
+	)
 }
 
 fn key_for_release(release: &Release) -> (Quality, String) {
@@ -550,7 +1137,8 @@ impl ConnectionManager {
 	pub fn new(ctx: &CommandContext, platform: Platform, args: ServeWebArgs) -> Arc<Self> {
 		let base_path = normalize_base_path(args.server_base_path.as_deref().unwrap_or_default());
 
-		let cache = DownloadCache::new(ctx.paths.web_server_storage());
+		let cache = DownloadCache::new(ctx.paths.web_server_storage())
+			.with_retention(args.retention.cache_policy());
 		let target_kind = TargetKind::Web;
 
 		let quality = VSCODE_CLI_QUALITY.map_or(Quality::Stable, |q| match Quality::try_from(q) {
@@ -561,8 +1149,7 @@ impl ConnectionManager {
 		let now = Instant::now();
 		let latest_version = tokio::sync::Mutex::new(cache.get().first().map(|latest_commit| {
 			(
-				now.checked_sub(Duration::from_secs(RELEASE_CHECK_INTERVAL))
-					.unwrap_or(now), // handle 0-ish instants, #233155
+				now.checked_sub(update_check_interval(&args)).unwrap_or(now), // handle 0-ish instants, #233155
 				Release {
 					name: String::from("0.0.0"), // Version information not stored on cache
 					commit: latest_commit.clone(),
@@ -575,7 +1162,7 @@ impl ConnectionManager {
 
 		Arc::new(Self {
 			platform,
-			args,
+			args: tokio::sync::watch::channel(args).0,
 			base_path,
 			log: ctx.log.clone(),
 			cache,
@@ -585,23 +1172,342 @@ impl ConnectionManager {
 			),
 			state: ConnectionStateMap::default(),
 			latest_version,
+			staged_update: Mutex::new(None),
+			approval_path: ctx.paths.web_server_update_approval(),
 		})
 	}
 
-	// spawns a task that checks for updates every n seconds duration
-	pub fn start_update_checker(self: Arc<Self>, duration: Duration) {
+	// spawns a task that periodically checks for updates, and again whenever
+	// the configuration is reloaded
+	pub fn start_update_checker(self: Arc<Self>) {
+		// approvals carry the commit they're for, if they name one
+		let (approvals_tx, mut approvals) = tokio::sync::mpsc::unbounded_channel();
+		{
+			let approvals_tx = approvals_tx.clone();
+			let path = self.approval_path.clone();
+			tokio::spawn(async move {
+				let mut changes = config::watch_for_changes(path.clone());
+				while changes.recv().await.is_some() {
+					// removing the file isn't an approval
+					let commit = match fs::read_to_string(&path) {
+						Ok(c) => c,
+						Err(_) => continue,
+					};
+					let commit = Some(commit.trim().to_string()).filter(|c| !c.is_empty());
+					if approvals_tx.send(commit).is_err() {
+						return;
+					}
+				}
+			});
+		}
+		#[cfg(unix)]
+		{
+			let log = self.log.clone();
+			tokio::spawn(async move {
+				use tokio::signal::unix::{signal, SignalKind};
+				let mut usr1 = match signal(SignalKind::user_defined1()) {
+					Ok(s) => s,
+					Err(e) => {
+						warning!(log, "error listening for update approvals: {}", e);
+						return;
+					}
+				};
+				while usr1.recv().await.is_some() {
+					if approvals_tx.send(None).is_err() {
+						return;
+					}
+				}
+			});
+		}
+		#[cfg(not(unix))]
+		drop(approvals_tx);
+
 		tokio::spawn(async move {
-			let mut interval = time::interval(duration);
+			let mut args_rx = self.args.subscribe();
 			loop {
-				interval.tick().await;
-
-				if let Err(e) = self.get_latest_release().await {
+				if let Err(e) = self.roll_over_to_latest_release().await {
 					warning!(self.log, "error getting latest version: {}", e);
 				}
+
+				let interval = update_check_interval(&args_rx.borrow_and_update());
+				tokio::select! {
+					_ = time::sleep(interval) => {},
+					Ok(_) = args_rx.changed() => {},
+					Some(commit) = approvals.recv() => {
+						if let Err(e) = self.approve_staged_update(commit).await {
+							warning!(self.log, "error applying approved update: {}", e);
+						}
+					},
+				}
+			}
+		});
+	}
+
+	/// Spawns a task that reloads settings when the config file changes.
+	pub fn start_config_reloader(
+		self: Arc<Self>,
+		cli_args: ServeWebArgs,
+		initial_args: ServeWebArgs,
+		path: PathBuf,
+	) {
+		tokio::spawn(async move {
+			let mut reloads = config::watch_for_reload(path.clone());
+			while reloads.recv().await.is_some() {
+				match ServeWebConfigFile::read(&path) {
+					Ok(f) => self.reload_args(&initial_args, f.apply_to(cli_args.clone())),
+					Err(e) => warning!(self.log, "{}, keeping the current configuration", e),
+				}
 			}
 		});
 	}
 
+	/// Applies reloadable settings. Settings that affect how clients connect
+	/// need a restart, and are only compared to what we started with.
+	fn reload_args(&self, initial: &ServeWebArgs, new: ServeWebArgs) {
+		let needs_restart = [
+			("host", initial.host != new.host),
+			("socket-path", initial.socket_path != new.socket_path),
+			("port", initial.port != new.port),
+			(
+				"connection-token",
+				initial.connection_token != new.connection_token,
+			),
+			(
+				"connection-token-file",
+				initial.connection_token_file != new.connection_token_file,
+			),
+			(
+				"without-connection-token",
+				initial.without_connection_token != new.without_connection_token,
+			),
+			(
+				"server-base-path",
+				initial.server_base_path != new.server_base_path,
+			),
+			(
+				"server-data-dir",
+				initial.server_data_dir != new.server_data_dir,
+			),
+			("oidc", initial.oidc != new.oidc),
+			(
+				"access-log",
+				initial.access_log.access_log != new.access_log.access_log
+					|| initial.access_log.access_log_format != new.access_log.access_log_format
+					|| initial.access_log.access_log_max_size_mb
+						!= new.access_log.access_log_max_size_mb
+					|| initial.access_log.access_log_max_files
+						!= new.access_log.access_log_max_files,
+			),
+			("cache-*", initial.retention != new.retention),
+		];
+		for (name, _) in needs_restart.iter().filter(|(_, changed)| *changed) {
+			warning!(
+				self.log,
+				"{} changed in the config file, restart serve-web to apply it",
+				name
+			);
+		}
+
+		self.args.send_modify(|args| {
+			args.default_folder = new.default_folder;
+			args.default_workspace = new.default_workspace;
+			args.disable_telemetry = new.disable_telemetry;
+			args.commit_id = new.commit_id;
+			args.server_idle_timeout_secs = new.server_idle_timeout_secs;
+			args.server_startup_timeout_secs = new.server_startup_timeout_secs;
+			args.update_check_interval_secs = new.update_check_interval_secs;
+			args.trusted_proxies = new.trusted_proxies;
+			args.update_policy = new.update_policy;
+			args.limits = new.limits;
+		});
+
+		info!(
+			self.log,
+			"Reloaded configuration, new servers will use:\n  {}",
+			config::describe(&self.args.borrow())
+		);
+	}
+
+	/// Checks for a new release. A new release is downloaded and started in
+	/// the background, and only becomes the "latest" version once it's ready
+	/// to serve clients. Previous versions are then drained.
+	async fn roll_over_to_latest_release(&self) -> Result<(), CodeError> {
+		let current = self.latest_version.lock().await.clone();
+		let current = match current {
+			Some((_, r)) => r,
+			None => return self.get_latest_release().await.map(|_| ()),
+		};
+
+		let (release, released_at) = self.fetch_latest_release().await?;
+		self.update_to_release(&current, release, released_at).await
+	}
+
+	/// Moves from the current release to the given latest release, as the
+	/// update policy allows.
+	async fn update_to_release(
+		&self,
+		current: &Release,
+		release: Release,
+		released_at: Option<chrono::DateTime<chrono::Utc>>,
+	) -> Result<(), CodeError> {
+		if release.commit == current.commit {
+			*self.staged_update.lock().unwrap() = None;
+			*self.latest_version.lock().await = Some((Instant::now(), release));
+			return Ok(());
+		}
+
+		let policy = self.args.borrow().update_policy.policy();
+		match policy.decide(released_at, chrono::Local::now()) {
+			UpdateDecision::Adopt => {}
+			UpdateDecision::Defer(reason) => {
+				info!(
+					self.log,
+					"Not updating to release {} yet, as {}", release, reason
+				);
+				return Ok(());
+			}
+			UpdateDecision::AwaitApproval => {
+				self.stage_update(release);
+				return Ok(());
+			}
+		}
+
+		self.roll_over_to(release).await
+	}
+
+	/// Downloads a release that's waiting for approval, so it's ready to use,
+	/// without starting it.
+	fn stage_update(&self, release: Release) {
+		let mut staged = self.staged_update.lock().unwrap();
+		if staged.as_ref().map(|s| s.release.commit == release.commit) == Some(true) {
+			return;
+		}
+
+		info!(
+			self.log,
+			"Release {} is waiting for approval, run `{} serve-web approve-update` to start using it",
+			release,
+			APPLICATION_NAME
+		);
+
+		let (downloaded, opener) = new_barrier();
+		if self.cache.exists(&release.commit).is_some() {
+			opener.open(Ok(()));
+		} else {
+			let log = self.log.clone();
+			let update_service = self.update_service.clone();
+			let cache = self.cache.clone();
+			let release = release.clone();
+			tokio::spawn(async move {
+				let r = Self::download_release(&log, &update_service, &cache, &release).await;
+				opener.open(r.map(|_| ()).map_err(|e| e.to_string()));
+			});
+		}
+
+		*staged = Some(StagedUpdate {
+			release,
+			downloaded,
+		});
+	}
+
+	/// Starts using the release that's waiting for approval, if any, and if
+	/// it's for the given commit.
+	async fn approve_staged_update(&self, commit: Option<String>) -> Result<(), CodeError> {
+		let staged = {
+			let mut staged = self.staged_update.lock().unwrap();
+			match (&*staged, &commit) {
+				(Some(s), Some(c)) if !s.release.commit.eq_ignore_ascii_case(c) => {
+					info!(
+						self.log,
+						"Approval is for {}, but release {} is waiting for approval", c, s.release
+					);
+					None
+				}
+				_ => staged.take(),
+			}
+		};
+
+		let mut staged = match staged {
+			Some(s) => s,
+			None => {
+				info!(self.log, "No update is waiting for approval");
+				return Ok(());
+			}
+		};
+
+		info!(
+			self.log,
+			"Update to release {} was approved", staged.release
+		);
+		match staged.downloaded.wait().await {
+			Ok(Ok(())) => {}
+			Ok(Err(e)) => return Err(CodeError::ServerDownloadError(e)),
+			Err(_) => {
+				return Err(CodeError::ServerDownloadError(
+					"download was interrupted".to_string(),
+				))
+			}
+		}
+		self.roll_over_to(staged.release).await
+	}
+
+	/// Prepares the release in the background, then makes it the "latest"
+	/// version and drains the others.
+	async fn roll_over_to(&self, release: Release) -> Result<(), CodeError> {
+		info!(
+			self.log,
+			"Found new release {}, preparing it in the background", release
+		);
+
+		let mut barrier = {
+			let mut state = self.state.lock().unwrap();
+			self.ensure_version_started(&mut state, release.clone())
+				.socket_path
+				.clone()
+		};
+		match barrier.wait().await {
+			Ok(Ok(_)) => {}
+			Ok(Err(e)) => return Err(CodeError::ServerDownloadError(e)),
+			Err(_) => {
+				return Err(CodeError::ServerDownloadError(
+					"server exited before it was ready".to_string(),
+				))
+			}
+		}
+
+		info!(self.log, "Release {} is ready, rolling over", release);
+		*self.latest_version.lock().await = Some((Instant::now(), release.clone()));
+		self.drain_versions_except(&release);
+
+		Ok(())
+	}
+
+	/// Starts draining all server versions other than the given release.
+	fn drain_versions_except(&self, release: &Release) {
+		let keep = key_for_release(release);
+		let state = self.state.lock().unwrap();
+		for (key, s) in state.iter() {
+			if key == &keep {
+				continue;
+			}
+
+			let started_draining = s.draining.send_if_modified(|d| !std::mem::replace(d, true));
+			if started_draining {
+				info!(self.log, "Draining server {}", key.1);
+			}
+		}
+	}
+
+	/// Gets whether the server for the release is being drained.
+	pub fn is_draining(&self, release: &Release) -> bool {
+		self.state
+			.lock()
+			.unwrap()
+			.get(&key_for_release(release))
+			.map(|s| *s.draining.borrow())
+			.unwrap_or(false)
+	}
+
 	// Returns the latest release from the cache, if one exists.
 	pub async fn get_release_from_cache(&self) -> Result<Release, CodeError> {
 		let latest = self.latest_version.lock().await;
@@ -613,24 +1519,51 @@ impl ConnectionManager {
 		self.get_latest_release().await
 	}
 
-	/// Gets a connection to a server version
+	/// Gets a dedicated connection to a server version
 	pub async fn get_connection(
 		&self,
 		release: Release,
 	) -> Result<(AsyncPipe, ConnectionHandle), CodeError> {
-		// todo@connor4312: there is likely some performance benefit to
-		// implementing a 'keepalive' for these connections.
-		let (path, counter) = self.get_version_data(release).await?;
+		let (path, counter, _) = self.get_version_data(release).await?;
 		let handle = ConnectionHandle::new(counter);
 		let rw = get_socket_rw_stream(&path).await?;
 		Ok((rw, handle))
 	}
 
+	/// Gets the pool of keep-alive connections to a server version
+	pub async fn get_pooled_connection(
+		&self,
+		release: Release,
+	) -> Result<(Arc<ConnectionPool>, ConnectionHandle), CodeError> {
+		let (_, counter, pool) = self.get_version_data(release).await?;
+		Ok((pool, ConnectionHandle::new(counter)))
+	}
+
 	/// Gets the latest release for the CLI quality, caching its result for some
 	/// time to allow for fast loads.
 	pub async fn get_latest_release(&self) -> Result<Release, CodeError> {
 		let mut latest = self.latest_version.lock().await;
 		let now = Instant::now();
+		let release = self.fetch_latest_release().await.map(|(r, _)| r);
+
+		// If the update service is unavailable and we have stale data, use that
+		if let (Err(e), Some((_, previous))) = (&release, latest.clone()) {
+			warning!(self.log, "error getting latest release, using stale: {}", e);
+			*latest = Some((now, previous.clone()));
+			return Ok(previous.clone());
+		}
+
+		let release = release?;
+		*latest = Some((now, release.clone()));
+
+		Ok(release)
+	}
+
+	/// Looks up the release to use, per the update policy, without caching it.
+	/// Also returns when it was released, if known.
+	async fn fetch_latest_release(
+		&self,
+	) -> Result<(Release, Option<chrono::DateTime<chrono::Utc>>), CodeError> {
 		let target_kind = TargetKind::Web;
 
 		let quality = VSCODE_CLI_QUALITY
@@ -639,7 +1572,7 @@ impl ConnectionManager {
 				Quality::try_from(q).map_err(|_| CodeError::UpdatesNotConfigured("unknown quality"))
 			})?;
 
-		if let Some(commit) = &self.args.commit_id {
+		if let Some(commit) = self.args.borrow().commit_id.clone() {
 			let release = Release {
 				name: commit.to_string(),
 				commit: commit.to_string(),
@@ -651,28 +1584,16 @@ impl ConnectionManager {
 				self.log,
 				"using provided commit instead of latest release: {}", release
 			);
-			*latest = Some((now, release.clone()));
-			return Ok(release);
+			return Ok((release, None));
 		}
 
-		let release = self
-			.update_service
-			.get_latest_commit(self.platform, target_kind, quality)
-			.await
-			.map_err(|e| CodeError::UpdateCheckFailed(e.to_string()));
-
-		// If the update service is unavailable and we have stale data, use that
-		if let (Err(e), Some((_, previous))) = (&release, latest.clone()) {
-			warning!(self.log, "error getting latest release, using stale: {}", e);
-			*latest = Some((now, previous.clone()));
-			return Ok(previous.clone());
-		}
+		let policy = self.args.borrow().update_policy.policy();
+		let (release, released_at) = policy
+			.resolve(&self.update_service, self.platform, target_kind, quality)
+			.await?;
 
-		let release = release?;
 		debug!(self.log, "refreshed latest release: {}", release);
-		*latest = Some((now, release.clone()));
-
-		Ok(release)
+		Ok((release, released_at))
 	}
 
 	/// Gets the StartData for the a version of the VS Code server, triggering
@@ -691,58 +1612,64 @@ impl ConnectionManager {
 		release: Release,
 	) -> Result<Barrier<Result<StartData, String>>, CodeError> {
 		let mut state = self.state.lock().unwrap();
-		let key = key_for_release(&release);
-		if let Some(s) = state.get_mut(&key) {
-			if !s.downloaded {
-				if s.socket_path.is_open() {
-					s.downloaded = true;
-				} else {
-					return Err(CodeError::ServerNotYetDownloaded);
-				}
+		let s = self.ensure_version_started(&mut state, release);
+		if !s.downloaded {
+			if s.socket_path.is_open() {
+				s.downloaded = true;
+			} else {
+				return Err(CodeError::ServerNotYetDownloaded);
 			}
+		}
 
-			return Ok(s.socket_path.clone());
+		Ok(s.socket_path.clone())
+	}
+
+	/// Gets the state for a server version, downloading and starting the
+	/// server in the background if it's not already running.
+	fn ensure_version_started<'a>(
+		&self,
+		state: &'a mut HashMap<(Quality, String), VersionState>,
+		release: Release,
+	) -> &'a mut VersionState {
+		let key = key_for_release(&release);
+		if state.contains_key(&key) {
+			return state.get_mut(&key).unwrap();
 		}
 
 		let (socket_path, opener) = new_barrier();
+		let (draining, draining_rx) = tokio::sync::watch::channel(false);
 		let state_map_dup = self.state.clone();
 		let args = StartArgs {
-			args: self.args.clone(),
+			args: self.args.subscribe(),
 			log: self.log.clone(),
 			opener,
+			draining: draining_rx,
 			release,
 		};
 
-		if let Some(p) = self.cache.exists(&args.release.commit) {
-			state.insert(
-				key.clone(),
-				VersionState {
-					socket_path: socket_path.clone(),
-					downloaded: true,
-				},
-			);
-
+		let downloaded = if let Some(p) = self.cache.exists(&args.release.commit) {
+			let key = key.clone();
 			tokio::spawn(async move {
 				Self::start_version(args, p).await;
 				state_map_dup.lock().unwrap().remove(&key);
 			});
-			Ok(socket_path)
+			true
 		} else {
-			state.insert(
-				key.clone(),
-				VersionState {
-					socket_path,
-					downloaded: false,
-				},
-			);
+			let key = key.clone();
 			let update_service = self.update_service.clone();
 			let cache = self.cache.clone();
 			tokio::spawn(async move {
 				Self::download_version(args, update_service.clone(), cache.clone()).await;
 				state_map_dup.lock().unwrap().remove(&key);
 			});
-			Err(CodeError::ServerNotYetDownloaded)
-		}
+			false
+		};
+
+		state.entry(key).or_insert(VersionState {
+			socket_path,
+			downloaded,
+			draining,
+		})
 	}
 
 	/// Downloads a server version into the cache and starts it.
@@ -751,31 +1678,42 @@ impl ConnectionManager {
 		update_service: UpdateService,
 		cache: DownloadCache,
 	) {
-		let release_for_fut = args.release.clone();
-		let log_for_fut = args.log.clone();
-		let dir_fut = cache.create(&args.release.commit, |target_dir| async move {
-			info!(log_for_fut, "Downloading server {}", release_for_fut.commit);
-			let tmpdir = tempfile::tempdir().unwrap();
-			let response = update_service.get_download_stream(&release_for_fut).await?;
-
-			let name = response.url_path_basename().unwrap();
-			let archive_path = tmpdir.path().join(name);
-			http::download_into_file(
-				&archive_path,
-				log_for_fut.get_download_logger("Downloading server:"),
-				response,
-			)
-			.await?;
-			unzip_downloaded_release(&archive_path, &target_dir, SilentCopyProgress())?;
-			Ok(())
-		});
-
-		match dir_fut.await {
+		match Self::download_release(&args.log, &update_service, &cache, &args.release).await {
 			Err(e) => args.opener.open(Err(e.to_string())),
 			Ok(dir) => Self::start_version(args, dir).await,
 		}
 	}
 
+	/// Downloads a server version into the cache.
+	async fn download_release(
+		log: &log::Logger,
+		update_service: &UpdateService,
+		cache: &DownloadCache,
+		release: &Release,
+	) -> Result<PathBuf, AnyError> {
+		let release_for_fut = release.clone();
+		let log_for_fut = log.clone();
+		let update_service = update_service.clone();
+		cache
+			.create(&release.commit, |target_dir| async move {
+				info!(log_for_fut, "Downloading server {}", release_for_fut.commit);
+				let tmpdir = tempfile::tempdir().unwrap();
+				let response = update_service.get_download_stream(&release_for_fut).await?;
+
+				let name = response.url_path_basename().unwrap();
+				let archive_path = tmpdir.path().join(name);
+				http::download_into_file(
+					&archive_path,
+					log_for_fut.get_download_logger("Downloading server:"),
+					response,
+				)
+				.await?;
+				unzip_downloaded_release(&archive_path, &target_dir, SilentCopyProgress())?;
+				Ok(())
+			})
+			.await
+	}
+
 	/// Starts a downloaded server that can be found in the given `path`.
 	async fn start_version(args: StartArgs, path: PathBuf) {
 		info!(args.log, "Starting server {}", args.release.commit);
@@ -785,6 +1723,7 @@ impl ConnectionManager {
 			.join(args.release.quality.server_entrypoint());
 
 		let socket_path = get_socket_name();
+		let server_args = args.args.borrow().clone();
 
 		let mut cmd = new_script_command(&executable);
 		cmd.stdin(std::process::Stdio::null());
@@ -796,38 +1735,40 @@ impl ConnectionManager {
 		// License agreement already checked by the `server_web` function.
 		cmd.args(["--accept-server-license-terms"]);
 
-		if let Some(a) = &args.args.server_base_path {
+		if let Some(a) = &server_args.server_base_path {
 			cmd.arg("--server-base-path");
 			cmd.arg(a);
 		}
-		if let Some(a) = &args.args.server_data_dir {
+		if let Some(a) = &server_args.server_data_dir {
 			cmd.arg("--server-data-dir");
 			cmd.arg(a);
 		}
-		if args.args.without_connection_token {
+		if server_args.without_connection_token {
 			cmd.arg("--without-connection-token");
 		}
 		// Note: intentional that we don't pass --connection-token here, we always
 		// convert it into the file variant.
-		if let Some(ct) = &args.args.connection_token_file {
+		if let Some(ct) = &server_args.connection_token_file {
 			cmd.arg("--connection-token-file");
 			cmd.arg(ct);
 		}
-		if let Some(a) = &args.args.default_folder {
+		if let Some(a) = &server_args.default_folder {
 			cmd.arg("--default-folder");
 			cmd.arg(a);
 		}
-		if let Some(a) = &args.args.default_workspace {
+		if let Some(a) = &server_args.default_workspace {
 			cmd.arg("--default-workspace");
 			cmd.arg(a);
 		}
-		if args.args.disable_telemetry {
+		if server_args.disable_telemetry {
 			cmd.arg("--disable-telemetry");
 		}
 
 		// removed, otherwise the workbench will not be usable when running the CLI from sources.
 		cmd.env_remove("VSCODE_DEV");
 
+		let limits = server_args.limits.limits().apply(&args.log, &mut cmd);
+
 		let mut child = match cmd.spawn() {
 			Ok(c) => c,
 			Err(e) => {
@@ -843,45 +1784,94 @@ impl ConnectionManager {
 
 		// wrapped option to prove that we only use this once in the loop
 		let (counter_tx, mut counter_rx) = tokio::sync::watch::channel(0);
-		let mut opener = Some((args.opener, socket_path, Arc::new(counter_tx)));
+		let mut opener = Some((args.opener, socket_path.clone(), Arc::new(counter_tx)));
 		let commit_prefix = &args.release.commit[..7];
-		let kill_timer = tokio::time::sleep(Duration::from_secs(SERVER_IDLE_TIMEOUT_SECS));
+		let kill_timer = tokio::time::sleep(server_idle_timeout(&args.args.borrow()));
 		pin!(kill_timer);
 
+		// "Server bound to" in the output is a fast path, but the server is also
+		// probed in case its wording changes or its output is redirected.
+		let tail = OutputTail::default();
+		let startup_timeout = server_startup_timeout(&args.args.borrow());
+		let startup_deadline = tokio::time::sleep(startup_timeout);
+		pin!(startup_deadline);
+		let probe = wait_for_http(ProbeTarget::Socket(socket_path.clone()));
+		pin!(probe);
+
+		let mut draining_rx = args.draining;
+		let mut drain_deadline = None;
+
 		loop {
 			tokio::select! {
 				Ok(Some(l)) = stdout.next_line() => {
 					info!(args.log, "[{} stdout]: {}", commit_prefix, l);
+					tail.push(&l);
 
 					if l.contains("Server bound to") {
-						if let Some((opener, path, counter_tx)) = opener.take() {
-							opener.open(Ok((path, counter_tx)));
-						}
+						open_ready(&mut opener);
 					}
 				}
 				Ok(Some(l)) = stderr.next_line() => {
 					info!(args.log, "[{} stderr]: {}", commit_prefix, l);
+					tail.push(&l);
 				},
+				_ = &mut probe, if opener.is_some() => {
+					open_ready(&mut opener);
+				}
+				_ = &mut startup_deadline, if opener.is_some() => {
+					let err = CodeError::ServerStartupTimeout(startup_timeout.as_secs(), tail.to_string());
+					warning!(args.log, "[{} process]: {}", commit_prefix, err);
+					if let Some((opener, _, _)) = opener.take() {
+						opener.open(Err(err.to_string()));
+					}
+					let _ = child.kill().await;
+					break;
+				}
 				n = counter_rx.changed() => {
 					kill_timer.as_mut().reset(match n {
 						// err means that the record was dropped
 						Err(_) => tokio::time::Instant::now(),
 						Ok(_) => {
 							if *counter_rx.borrow() == 0 {
-								tokio::time::Instant::now() + Duration::from_secs(SERVER_IDLE_TIMEOUT_SECS)
+								match drain_deadline {
+									Some(_) => tokio::time::Instant::now(),
+									None => tokio::time::Instant::now() + server_idle_timeout(&args.args.borrow()),
+								}
 							} else {
-								tokio::time::Instant::now() + Duration::from_secs(SERVER_ACTIVE_TIMEOUT_SECS)
+								drain_deadline.unwrap_or_else(|| tokio::time::Instant::now() + Duration::from_secs(SERVER_ACTIVE_TIMEOUT_SECS))
 							}
 						}
 					});
 				}
+				Ok(_) = draining_rx.changed(), if drain_deadline.is_none() => {
+					let deadline = tokio::time::Instant::now() + Duration::from_secs(SERVER_MAX_DRAIN_SECS);
+					drain_deadline = Some(deadline);
+
+					let clients = *counter_rx.borrow();
+					info!(args.log, "[{} process]: draining, {} client(s) remaining", commit_prefix, clients);
+					kill_timer.as_mut().reset(if clients == 0 { tokio::time::Instant::now() } else { deadline });
+				}
 				_ = &mut kill_timer => {
-					info!(args.log, "[{} process]: idle timeout reached, ending", commit_prefix);
+					if drain_deadline.is_some() {
+						info!(args.log, "[{} process]: drained, ending", commit_prefix);
+					} else {
+						info!(args.log, "[{} process]: idle timeout reached, ending", commit_prefix);
+					}
 					let _ = child.kill().await;
 					break;
 				}
 				e = child.wait() => {
 					info!(args.log, "[{} process]: exited: {:?}", commit_prefix, e);
+					let oom = limits.oom_reason();
+					if let Some(reason) = &oom {
+						warning!(args.log, "[{} process]: ran out of memory: {}", commit_prefix, reason);
+					}
+					if let Some((opener, _, _)) = opener.take() {
+						opener.open(Err(match oom {
+							Some(reason) => format!("Server ran out of memory before it was ready: {reason}"),
+							None => format!("Server exited before it was ready. Its last output was:\n{tail}"),
+						}));
+					}
 					break;
 				}
 			}
@@ -889,11 +1879,28 @@ impl ConnectionManager {
 	}
 }
 
+/// Opener for a server that's starting, along with its socket and
+/// connection counter.
+type PendingStart = (
+	BarrierOpener<Result<StartData, String>>,
+	PathBuf,
+	Arc<tokio::sync::watch::Sender<usize>>,
+);
+
+/// Opens the start barrier of a server that's now ready, if it's not open yet.
+fn open_ready(opener: &mut Option<PendingStart>) {
+	if let Some((opener, path, counter_tx)) = opener.take() {
+		let pool = ConnectionPool::new(path.clone());
+		opener.open(Ok((path, counter_tx, pool)));
+	}
+}
+
 struct StartArgs {
 	log: log::Logger,
-	args: ServeWebArgs,
+	args: tokio::sync::watch::Receiver<ServeWebArgs>,
 	release: Release,
 	opener: BarrierOpener<Result<StartData, String>>,
+	draining: tokio::sync::watch::Receiver<bool>,
 }
 
 fn mint_connection_token(path: &Path, prefer_token: Option<String>) -> std::io::Result<String> {
@@ -922,3 +1929,421 @@ fn mint_connection_token(path: &Path, prefer_token: Option<String>) -> std::io::
 	f.write_all(prefer_token.as_bytes())?;
 	Ok(prefer_token)
 }
+
+#[cfg(test)]
+mod tests {
+	use clap::Parser;
+
+	use super::*;
+	use crate::commands::args::{Commands, IntegratedCli};
+
+	fn manager(dir: &Path) -> Arc<ConnectionManager> {
+		let args = match IntegratedCli::parse_from(["code", "serve-web"])
+			.core
+			.subcommand
+		{
+			Some(Commands::ServeWeb(a)) => *a,
+			_ => unreachable!(),
+		};
+		let ctx = CommandContext {
+			log: log::Logger::test(),
+			paths: LauncherPaths::new_without_replacements(dir.to_path_buf()),
+			args: Default::default(),
+			http: reqwest::Client::new(),
+		};
+		ConnectionManager::new(&ctx, Platform::LinuxX64, args)
+	}
+
+	fn release(commit: &str) -> Release {
+		Release {
+			name: "1.0.0".to_string(),
+			platform: Platform::LinuxX64,
+			target: TargetKind::Web,
+			quality: Quality::Stable,
+			commit: commit.to_string(),
+		}
+	}
+
+	#[tokio::test]
+	async fn test_stages_update_without_starting_it() {
+		let dir = tempfile::tempdir().unwrap();
+		let cm = manager(dir.path());
+		let r = release(&"a".repeat(40));
+		cm.cache
+			.create(&r.commit, |_| async { Ok(()) })
+			.await
+			.unwrap();
+
+		cm.stage_update(r.clone());
+		assert!(cm.state.lock().unwrap().is_empty());
+		let mut downloaded = cm
+			.staged_update
+			.lock()
+			.unwrap()
+			.as_ref()
+			.unwrap()
+			.downloaded
+			.clone();
+		assert_eq!(downloaded.wait().await.unwrap(), Ok(()));
+
+		// an approval for another commit leaves the update waiting
+		cm.approve_staged_update(Some("b".repeat(40)))
+			.await
+			.unwrap();
+		assert!(cm.staged_update.lock().unwrap().is_some());
+		assert!(cm.state.lock().unwrap().is_empty());
+	}
+
+	fn version_state() -> VersionState {
+		VersionState {
+			downloaded: true,
+			socket_path: new_barrier().0,
+			draining: tokio::sync::watch::channel(false).0,
+		}
+	}
+
+	#[tokio::test]
+	async fn test_drains_other_versions() {
+		let dir = tempfile::tempdir().unwrap();
+		let cm = manager(dir.path());
+		let (old, current) = (release(&"a".repeat(40)), release(&"b".repeat(40)));
+		{
+			let mut state = cm.state.lock().unwrap();
+			state.insert(key_for_release(&old), version_state());
+			state.insert(key_for_release(&current), version_state());
+		}
+
+		cm.drain_versions_except(&current);
+		assert!(cm.is_draining(&old));
+		assert!(!cm.is_draining(&current));
+		assert!(!cm.is_draining(&release(&"c".repeat(40))));
+
+		// draining again leaves the versions as they are
+		let draining = cm.state.lock().unwrap()[&key_for_release(&old)]
+			.draining
+			.subscribe();
+		cm.drain_versions_except(&current);
+		assert!(!draining.has_changed().unwrap());
+		assert!(!cm.is_draining(&current));
+	}
+
+	#[tokio::test]
+	async fn test_keeps_current_release() {
+		let dir = tempfile::tempdir().unwrap();
+		let cm = manager(dir.path());
+		let current = release(&"a".repeat(40));
+		cm.stage_update(release(&"b".repeat(40)));
+
+		cm.update_to_release(&current, current.clone(), None)
+			.await
+			.unwrap();
+		assert!(cm.staged_update.lock().unwrap().is_none());
+		assert_eq!(
+			cm.get_release_from_cache().await.unwrap().commit,
+			current.commit
+		);
+	}
+
+	#[tokio::test]
+	async fn test_stages_release_awaiting_approval() {
+		let dir = tempfile::tempdir().unwrap();
+		let cm = manager(dir.path());
+		let (current, latest) = (release(&"a".repeat(40)), release(&"b".repeat(40)));
+		cm.cache
+			.create(&latest.commit, |_| async { Ok(()) })
+			.await
+			.unwrap();
+		cm.args
+			.send_modify(|a| a.update_policy.update_manual_approval = true);
+
+		cm.update_to_release(&current, latest.clone(), None)
+			.await
+			.unwrap();
+		let staged = cm.staged_update.lock().unwrap();
+		assert_eq!(staged.as_ref().unwrap().release.commit, latest.commit);
+		assert!(cm.state.lock().unwrap().is_empty());
+		assert!(cm.latest_version.try_lock().unwrap().is_none());
+	}
+
+	#[cfg(unix)]
+	#[tokio::test]
+	async fn test_rolls_over_to_new_release() {
+		use std::os::unix::fs::PermissionsExt;
+
+		let dir = tempfile::tempdir().unwrap();
+		let cm = manager(dir.path());
+		let (current, latest) = (release(&"a".repeat(40)), release(&"b".repeat(40)));
+		cm.state
+			.lock()
+			.unwrap()
+			.insert(key_for_release(&current), version_state());
+		cm.cache
+			.create(&latest.commit, |dir| async move {
+				let bin = dir.join("bin");
+				std::fs::create_dir_all(&bin).unwrap();
+				let entrypoint = bin.join(Quality::Stable.server_entrypoint());
+				std::fs::write(
+					&entrypoint,
+					"#!/bin/sh\necho Server bound to socket\nexec sleep 30\n",
+				)
+				.unwrap();
+				std::fs::set_permissions(&entrypoint, std::fs::Permissions::from_mode(0o755))
+					.unwrap();
+				Ok(())
+			})
+			.await
+			.unwrap();
+
+		cm.update_to_release(&current, latest.clone(), None)
+			.await
+			.unwrap();
+		assert_eq!(
+			cm.get_release_from_cache().await.unwrap().commit,
+			latest.commit
+		);
+		assert!(cm.is_draining(&current));
+		assert!(!cm.is_draining(&latest));
+
+		// a drained server without clients stops right away
+		cm.drain_versions_except(&current);
+		for _ in 0..50 {
+			if !cm
+				.state
+				.lock()
+				.unwrap()
+				.contains_key(&key_for_release(&latest))
+			{
+				return;
+			}
+			tokio::time::sleep(Duration::from_millis(100)).await;
+		}
+		panic!("drained server did not stop");
+	}
+
+	/// Serves a response of `chunks` chunks on a new socket, returning the
+	/// socket and the number of connections accepted.
+	async fn chunked_server(chunks: usize) -> (PathBuf, Arc<std::sync::atomic::AtomicUsize>) {
+		let path = get_socket_name();
+		let mut listener = listen_socket_rw_stream(&path).await.unwrap();
+		let accepted = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+		let accepted_clone = accepted.clone();
+		tokio::spawn(async move {
+			while let Ok(rw) = listener.accept().await {
+				accepted_clone.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+				let service = service_fn(move |req: Request<Body>| async move {
+					let path = req.uri().path().to_string();
+					let body = futures::stream::iter(
+						(0..chunks).map(move |_| Ok::<_, Infallible>(format!("{path};"))),
+					);
+					Ok::<_, Infallible>(Response::new(Body::wrap_stream(body)))
+				});
+				tokio::spawn(hyper::server::conn::Http::new().serve_connection(rw, service));
+			}
+		});
+
+		(path, accepted)
+	}
+
+	fn proxied_context(cm: Arc<ConnectionManager>, remote: &str) -> HandleContext {
+		cm.args
+			.send_modify(|a| a.trusted_proxies = vec!["10.0.0.0/8".parse().unwrap()]);
+		HandleContext {
+			log: cm.log.clone(),
+			cm,
+			server_secret_key: SecretKeyPart::new(),
+			access_log: None,
+			oidc: None,
+			remote_addr: Some(SocketAddr::new(remote.parse().unwrap(), 1234)),
+			announced_urls: Default::default(),
+		}
+	}
+
+	fn forwarded_request(headers: &[(&'static str, &str)]) -> Request<Body> {
+		let mut req = Request::get("/").body(Body::empty()).unwrap();
+		for (name, value) in headers {
+			req.headers_mut().append(*name, value.parse().unwrap());
+		}
+		req
+	}
+
+	#[test]
+	fn test_takes_forwarded_info_from_trusted_proxies() {
+		let dir = tempfile::tempdir().unwrap();
+		let ctx = proxied_context(manager(dir.path()), "10.0.0.1");
+		let mut req = forwarded_request(&[
+			("x-forwarded-for", "203.0.113.5, 10.0.0.2"),
+			("x-forwarded-proto", "HTTPS, http"),
+			("x-forwarded-host", "example.com"),
+			("x-forwarded-prefix", "/user/ws/"),
+		]);
+
+		let fwd = take_forwarded_info(&ctx, &mut req);
+		assert_eq!(fwd.client, Some("203.0.113.5".parse().unwrap()));
+		assert_eq!(fwd.proto.as_deref(), Some("https"));
+		assert!(fwd.secure);
+		assert_eq!(fwd.host.as_deref(), Some("example.com"));
+		assert_eq!(fwd.prefix, "/user/ws");
+		assert_eq!(fwd.url("/"), "https://example.com/user/ws/");
+		assert!(req.headers().contains_key("x-forwarded-prefix"));
+
+		let mut req = forwarded_request(&[
+			("x-forwarded-proto", "java script"),
+			("x-forwarded-host", "evil.com/path"),
+		]);
+		let fwd = take_forwarded_info(&ctx, &mut req);
+		assert_eq!(fwd.proto, None);
+		assert!(!fwd.secure);
+		assert_eq!(fwd.host, None);
+		assert_eq!(fwd.url("/"), "/");
+	}
+
+	#[test]
+	fn test_strips_forwarded_info_from_other_clients() {
+		let dir = tempfile::tempdir().unwrap();
+		let ctx = proxied_context(manager(dir.path()), "192.168.1.1");
+		let mut req = forwarded_request(&[
+			("x-forwarded-for", "203.0.113.5"),
+			("x-forwarded-proto", "https"),
+			("x-forwarded-host", "example.com"),
+			("x-forwarded-prefix", "/user/ws"),
+		]);
+
+		let fwd = take_forwarded_info(&ctx, &mut req);
+		assert_eq!(fwd.client, None);
+		assert!(!fwd.secure);
+		assert_eq!(fwd.host, None);
+		assert_eq!(fwd.prefix, "");
+		for h in FORWARDED_HEADERS {
+			assert!(!req.headers().contains_key(h), "{h} was kept");
+		}
+	}
+
+	#[test]
+	fn test_get_forwarded_client() {
+		let dir = tempfile::tempdir().unwrap();
+		let ctx = proxied_context(manager(dir.path()), "10.0.0.1");
+		let client = |headers: &[(&'static str, &str)]| {
+			get_forwarded_client(&ctx, forwarded_request(headers).headers())
+		};
+
+		// addresses the client sent itself are skipped
+		assert_eq!(
+			client(&[("x-forwarded-for", "1.2.3.4, 5.6.7.8, 10.0.0.3")]),
+			Some("5.6.7.8".parse().unwrap())
+		);
+		assert_eq!(
+			client(&[
+				("x-forwarded-for", "1.2.3.4"),
+				("x-forwarded-for", "5.6.7.8, not-an-ip, 10.0.0.3"),
+			]),
+			Some("5.6.7.8".parse().unwrap())
+		);
+		// the first address is used when every hop is a trusted proxy
+		assert_eq!(
+			client(&[("x-forwarded-for", "10.0.0.4, 10.0.0.3")]),
+			Some("10.0.0.4".parse().unwrap())
+		);
+		assert_eq!(client(&[]), None);
+	}
+
+	#[test]
+	fn test_latest_version_location() {
+		let uri = "/stable-abc/?tkn=secret&folder=/home/me".parse().unwrap();
+		assert_eq!(
+			latest_version_location(&ForwardedInfo::default(), "/base/", &uri),
+			"/base/?tkn=secret&folder=/home/me"
+		);
+
+		let fwd = ForwardedInfo {
+			host: Some("example.com".to_string()),
+			proto: Some("https".to_string()),
+			prefix: "/user/ws".to_string(),
+			..Default::default()
+		};
+		assert_eq!(
+			latest_version_location(&fwd, "/", &"/stable-abc/".parse().unwrap()),
+			"https://example.com/user/ws/"
+		);
+	}
+
+	#[test]
+	fn test_normalize_forwarded_prefix() {
+		assert_eq!(normalize_forwarded_prefix("/user/ws/"), "/user/ws");
+		assert_eq!(normalize_forwarded_prefix("user/ws"), "/user/ws");
+		assert_eq!(normalize_forwarded_prefix("/"), "");
+		assert_eq!(normalize_forwarded_prefix(""), "");
+		assert_eq!(normalize_forwarded_prefix("/a;Path=/"), "");
+		assert_eq!(normalize_forwarded_prefix("/a b"), "");
+		assert_eq!(normalize_forwarded_prefix("/a,b"), "");
+	}
+
+	#[test]
+	fn test_announces_forwarded_urls_once() {
+		let dir = tempfile::tempdir().unwrap();
+		let ctx = proxied_context(manager(dir.path()), "10.0.0.1");
+		let fwd = |host: &str| ForwardedInfo {
+			host: Some(host.to_string()),
+			proto: Some("https".to_string()),
+			prefix: "/user/ws".to_string(),
+			..Default::default()
+		};
+
+		announce_forwarded_url(&ctx, &ForwardedInfo::default());
+		announce_forwarded_url(&ctx, &fwd("example.com"));
+		announce_forwarded_url(&ctx, &fwd("example.com"));
+		assert_eq!(
+			*ctx.announced_urls.lock().unwrap(),
+			HashSet::from(["https://example.com/user/ws/".to_string()])
+		);
+
+		for i in 0..MAX_ANNOUNCED_URLS * 2 {
+			announce_forwarded_url(&ctx, &fwd(&format!("host{i}.example.com")));
+		}
+		assert_eq!(ctx.announced_urls.lock().unwrap().len(), MAX_ANNOUNCED_URLS);
+
+		assert_eq!(
+			web_ui_available("at", "https://example.com/", Some("tk")),
+			"Web UI available at https://example.com/?tkn=tk"
+		);
+	}
+
+	async fn get(pool: &Arc<ConnectionPool>, path: &str) -> Response<Body> {
+		let (counter, _) = tokio::sync::watch::channel(0);
+		let handle = ConnectionHandle::new(Arc::new(counter));
+		let req = Request::get(path).body(Body::empty()).unwrap();
+		forward_http_req_to_server((pool.clone(), handle), req).await
+	}
+
+	#[tokio::test]
+	async fn test_pool_reuses_fully_read_connections() {
+		let (path, accepted) = chunked_server(3).await;
+		let pool = ConnectionPool::new(path);
+
+		for p in ["/a", "/b"] {
+			let body = hyper::body::to_bytes(get(&pool, p).await.into_body())
+				.await
+				.unwrap();
+			assert_eq!(body, format!("{p};{p};{p};"));
+		}
+
+		assert_eq!(accepted.load(std::sync::atomic::Ordering::SeqCst), 1);
+		assert_eq!(pool.idle.lock().unwrap().len(), 1);
+	}
+
+	#[tokio::test]
+	async fn test_pool_closes_partly_read_connections() {
+		let (path, accepted) = chunked_server(1000).await;
+		let pool = ConnectionPool::new(path);
+
+		let mut body = get(&pool, "/a").await.into_body();
+		assert_eq!(body.next().await.unwrap().unwrap(), "/a;");
+		drop(body);
+		assert!(pool.idle.lock().unwrap().is_empty());
+
+		let body = hyper::body::to_bytes(get(&pool, "/b").await.into_body())
+			.await
+			.unwrap();
+		assert_eq!(body, "/b;".repeat(1000));
+		assert_eq!(accepted.load(std::sync::atomic::Ordering::SeqCst), 2);
+	}
+}
@@ -0,0 +1,520 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	fs,
+	path::{Path, PathBuf},
+	time::{Duration, SystemTime},
+};
+
+use serde::Deserialize;
+use tokio::sync::mpsc;
+
+use crate::{
+	commands::args::ServeWebArgs,
+	update_policy::{UpdatePin, UpdateWindow},
+	util::{access_log::AccessLogFormat, cidr::IpCidr, errors::CodeError},
+};
+
+/// How often the config file is checked for changes.
+pub const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Contents of a `--config` file. Keys are the names of the `serve-web`
+/// command line options.
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ServeWebConfigFile {
+	host: Option<String>,
+	socket_path: Option<String>,
+	port: Option<u16>,
+	connection_token: Option<String>,
+	connection_token_file: Option<String>,
+	without_connection_token: Option<bool>,
+	accept_server_license_terms: Option<bool>,
+	server_base_path: Option<String>,
+	server_data_dir: Option<String>,
+	default_folder: Option<String>,
+	default_workspace: Option<String>,
+	disable_telemetry: Option<bool>,
+	commit_id: Option<String>,
+	server_idle_timeout_secs: Option<u64>,
+	server_startup_timeout_secs: Option<u64>,
+	update_check_interval_secs: Option<u64>,
+	trusted_proxies: Option<Vec<IpCidr>>,
+	access_log: Option<PathBuf>,
+	access_log_format: Option<AccessLogFormat>,
+	access_log_max_size_mb: Option<u64>,
+	access_log_max_files: Option<usize>,
+	oidc_issuer: Option<String>,
+	oidc_client_id: Option<String>,
+	oidc_client_secret: Option<String>,
+	oidc_allowed_users: Option<Vec<String>>,
+	oidc_allowed_groups: Option<Vec<String>>,
+	oidc_session_lifetime_secs: Option<u64>,
+	update_pin: Option<UpdatePin>,
+	update_windows: Option<Vec<UpdateWindow>>,
+	update_min_age_hours: Option<u64>,
+	update_manual_approval: Option<bool>,
+	cache_keep_per_quality: Option<usize>,
+	cache_max_size_mb: Option<u64>,
+	cache_min_age_hours: Option<u64>,
+	cache_pin: Option<Vec<String>>,
+	server_memory_limit_mb: Option<u64>,
+	server_cpu_weight: Option<u32>,
+	server_cpu_quota_percent: Option<u32>,
+	server_max_pids: Option<u32>,
+	server_nice: Option<i32>,
+	server_ionice: Option<u8>,
+}
+
+impl ServeWebConfigFile {
+	/// Reads the file, as JSON if it has a `.json` extension or TOML otherwise.
+	pub fn read(path: &Path) -> Result<Self, CodeError> {
+		let err = |e: String| CodeError::CouldNotLoadConfigFile(path.display().to_string(), e);
+		let contents = fs::read_to_string(path).map_err(|e| err(e.to_string()))?;
+		let file: Self = if path.extension().and_then(|e| e.to_str()) == Some("json") {
+			serde_json::from_str(&contents).map_err(|e| err(e.to_string()))?
+		} else {
+			toml::from_str(&contents).map_err(|e| err(e.to_string()))?
+		};
+		file.validate().map_err(err)?;
+		Ok(file)
+	}
+
+	/// Checks that resource limits are in the ranges the command line accepts,
+	/// since they'd otherwise only fail once a server is started.
+	fn validate(&self) -> Result<(), String> {
+		fn check<T: PartialOrd + std::fmt::Display>(
+			key: &str,
+			value: Option<T>,
+			min: T,
+			max: Option<T>,
+		) -> Result<(), String> {
+			match (value, max) {
+				(Some(v), Some(max)) if v < min || v > max => {
+					Err(format!("{key} must be from {min} to {max}, but is {v}"))
+				}
+				(Some(v), None) if v < min => {
+					Err(format!("{key} must be at least {min}, but is {v}"))
+				}
+				_ => Ok(()),
+			}
+		}
+
+		check("server-cpu-weight", self.server_cpu_weight, 1, Some(10000))?;
+		check(
+			"server-cpu-quota-percent",
+			self.server_cpu_quota_percent,
+			1,
+			None,
+		)?;
+		check("server-max-pids", self.server_max_pids, 1, None)?;
+		check("server-nice", self.server_nice, 0, Some(19))?;
+		check("server-ionice", self.server_ionice, 0, Some(7))
+	}
+
+	/// Fills in settings that weren't given on the command line.
+	pub fn apply_to(self, mut args: ServeWebArgs) -> ServeWebArgs {
+		fn merge<T>(arg: &mut Option<T>, file: Option<T>) {
+			if arg.is_none() {
+				*arg = file;
+			}
+		}
+
+		merge(&mut args.host, self.host);
+		merge(&mut args.socket_path, self.socket_path);
+		merge(&mut args.port, self.port);
+		merge(&mut args.connection_token, self.connection_token);
+		merge(&mut args.connection_token_file, self.connection_token_file);
+		merge(&mut args.server_base_path, self.server_base_path);
+		merge(&mut args.server_data_dir, self.server_data_dir);
+		merge(&mut args.default_folder, self.default_folder);
+		merge(&mut args.default_workspace, self.default_workspace);
+		merge(&mut args.commit_id, self.commit_id);
+		merge(
+			&mut args.server_idle_timeout_secs,
+			self.server_idle_timeout_secs,
+		);
+		merge(
+			&mut args.server_startup_timeout_secs,
+			self.server_startup_timeout_secs,
+		);
+		merge(
+			&mut args.update_check_interval_secs,
+			self.update_check_interval_secs,
+		);
+
+		args.without_connection_token |= self.without_connection_token.unwrap_or_default();
+		args.accept_server_license_terms |= self.accept_server_license_terms.unwrap_or_default();
+		args.disable_telemetry |= self.disable_telemetry.unwrap_or_default();
+
+		if args.trusted_proxies.is_empty() {
+			args.trusted_proxies = self.trusted_proxies.unwrap_or_default();
+		}
+
+		let log = &mut args.access_log;
+		merge(&mut log.access_log, self.access_log);
+		merge(&mut log.access_log_format, self.access_log_format);
+		merge(&mut log.access_log_max_size_mb, self.access_log_max_size_mb);
+		merge(&mut log.access_log_max_files, self.access_log_max_files);
+
+		let oidc = &mut args.oidc;
+		merge(&mut oidc.oidc_issuer, self.oidc_issuer);
+		merge(&mut oidc.oidc_client_id, self.oidc_client_id);
+		merge(&mut oidc.oidc_client_secret, self.oidc_client_secret);
+		merge(
+			&mut oidc.oidc_session_lifetime_secs,
+			self.oidc_session_lifetime_secs,
+		);
+		if oidc.oidc_allowed_users.is_empty() {
+			oidc.oidc_allowed_users = self.oidc_allowed_users.unwrap_or_default();
+		}
+		if oidc.oidc_allowed_groups.is_empty() {
+			oidc.oidc_allowed_groups = self.oidc_allowed_groups.unwrap_or_default();
+		}
+
+		let update = &mut args.update_policy;
+		merge(&mut update.update_pin, self.update_pin);
+		if update.update_windows.is_empty() {
+			update.update_windows = self.update_windows.unwrap_or_default();
+		}
+		merge(&mut update.update_min_age_hours, self.update_min_age_hours);
+		update.update_manual_approval |= self.update_manual_approval.unwrap_or_default();
+
+		let retention = &mut args.retention;
+		merge(
+			&mut retention.cache_keep_per_quality,
+			self.cache_keep_per_quality,
+		);
+		merge(&mut retention.cache_max_size_mb, self.cache_max_size_mb);
+		merge(&mut retention.cache_min_age_hours, self.cache_min_age_hours);
+		if retention.cache_pin.is_empty() {
+			retention.cache_pin = self.cache_pin.unwrap_or_default();
+		}
+
+		let limits = &mut args.limits;
+		merge(
+			&mut limits.server_memory_limit_mb,
+			self.server_memory_limit_mb,
+		);
+		merge(&mut limits.server_cpu_weight, self.server_cpu_weight);
+		merge(
+			&mut limits.server_cpu_quota_percent,
+			self.server_cpu_quota_percent,
+		);
+		merge(&mut limits.server_max_pids, self.server_max_pids);
+		merge(&mut limits.server_nice, self.server_nice);
+		merge(&mut limits.server_ionice, self.server_ionice);
+
+		args
+	}
+}
+
+/// Gets a human-readable description of the effective settings, with
+/// secrets omitted.
+pub fn describe(args: &ServeWebArgs) -> String {
+	fn opt<T: std::fmt::Debug>(v: &Option<T>) -> String {
+		match v {
+			Some(v) => format!("{v:?}"),
+			None => "(default)".to_string(),
+		}
+	}
+
+	let mut lines = vec![
+		format!("host: {}", opt(&args.host)),
+		format!("socket-path: {}", opt(&args.socket_path)),
+		format!("port: {}", opt(&args.port)),
+		format!(
+			"connection-token: {}",
+			if args.connection_token.is_some() {
+				"(set)"
+			} else {
+				"(none)"
+			}
+		),
+		format!(
+			"connection-token-file: {}",
+			opt(&args.connection_token_file)
+		),
+		format!(
+			"without-connection-token: {}",
+			args.without_connection_token
+		),
+		format!("server-base-path: {}", opt(&args.server_base_path)),
+		format!("server-data-dir: {}", opt(&args.server_data_dir)),
+		format!("default-folder: {}", opt(&args.default_folder)),
+		format!("default-workspace: {}", opt(&args.default_workspace)),
+		format!("disable-telemetry: {}", args.disable_telemetry),
+		format!("commit-id: {}", opt(&args.commit_id)),
+		format!(
+			"server-idle-timeout-secs: {}",
+			opt(&args.server_idle_timeout_secs)
+		),
+		format!(
+			"server-startup-timeout-secs: {}",
+			opt(&args.server_startup_timeout_secs)
+		),
+		format!(
+			"update-check-interval-secs: {}",
+			opt(&args.update_check_interval_secs)
+		),
+		format!(
+			"trusted-proxies: {}",
+			args.trusted_proxies
+				.iter()
+				.map(|p| p.to_string())
+				.collect::<Vec<_>>()
+				.join(", ")
+		),
+		format!("access-log: {}", opt(&args.access_log.access_log)),
+		format!("update-pin: {}", opt(&args.update_policy.update_pin)),
+		format!(
+			"update-windows: {}",
+			args.update_policy
+				.update_windows
+				.iter()
+				.map(|w| w.to_string())
+				.collect::<Vec<_>>()
+				.join(", ")
+		),
+		format!(
+			"update-min-age-hours: {}",
+			opt(&args.update_policy.update_min_age_hours)
+		),
+		format!(
+			"update-manual-approval: {}",
+			args.update_policy.update_manual_approval
+		),
+		format!(
+			"cache-keep-per-quality: {}",
+			opt(&args.retention.cache_keep_per_quality)
+		),
+		format!(
+			"cache-max-size-mb: {}",
+			opt(&args.retention.cache_max_size_mb)
+		),
+		format!(
+			"cache-min-age-hours: {}",
+			opt(&args.retention.cache_min_age_hours)
+		),
+		format!("cache-pin: {}", args.retention.cache_pin.join(", ")),
+		format!(
+			"server-memory-limit-mb: {}",
+			opt(&args.limits.server_memory_limit_mb)
+		),
+		format!("server-cpu-weight: {}", opt(&args.limits.server_cpu_weight)),
+		format!(
+			"server-cpu-quota-percent: {}",
+			opt(&args.limits.server_cpu_quota_percent)
+		),
+		format!("server-max-pids: {}", opt(&args.limits.server_max_pids)),
+		format!("server-nice: {}", opt(&args.limits.server_nice)),
+		format!("server-ionice: {}", opt(&args.limits.server_ionice)),
+	];
+
+	if let Some(issuer) = &args.oidc.oidc_issuer {
+		lines.push(format!("oidc-issuer: {issuer}"));
+		lines.push(format!(
+			"oidc-client-id: {}",
+			opt(&args.oidc.oidc_client_id)
+		));
+		lines.push(format!(
+			"oidc-allowed-users: {}",
+			args.oidc.oidc_allowed_users.join(", ")
+		));
+		lines.push(format!(
+			"oidc-allowed-groups: {}",
+			args.oidc.oidc_allowed_groups.join(", ")
+		));
+		lines.push(format!(
+			"oidc-session-lifetime-secs: {}",
+			opt(&args.oidc.oidc_session_lifetime_secs)
+		));
+	}
+
+	if args.access_log.access_log.is_some() {
+		lines.push(format!(
+			"access-log-format: {}",
+			opt(&args.access_log.access_log_format)
+		));
+		lines.push(format!(
+			"access-log-max-size-mb: {}",
+			opt(&args.access_log.access_log_max_size_mb)
+		));
+		lines.push(format!(
+			"access-log-max-files: {}",
+			opt(&args.access_log.access_log_max_files)
+		));
+	}
+
+	lines.join("\n  ")
+}
+
+/// Returns a receiver that gets a message when the config file is modified,
+/// or when the process receives SIGHUP.
+pub fn watch_for_reload(path: PathBuf) -> mpsc::UnboundedReceiver<()> {
+	let (tx, rx) = mpsc::unbounded_channel();
+
+	#[cfg(unix)]
+	{
+		let tx = tx.clone();
+		tokio::spawn(async move {
+			use tokio::signal::unix::{signal, SignalKind};
+			let mut hangup = match signal(SignalKind::hangup()) {
+				Ok(s) => s,
+				Err(_) => return,
+			};
+			while hangup.recv().await.is_some() {
+				if tx.send(()).is_err() {
+					return;
+				}
+			}
+		});
+	}
+
+	poll_for_changes(path, tx);
+	rx
+}
+
+/// Returns a receiver that gets a message when the file is modified.
+pub fn watch_for_changes(path: PathBuf) -> mpsc::UnboundedReceiver<()> {
+	let (tx, rx) = mpsc::unbounded_channel();
+	poll_for_changes(path, tx);
+	rx
+}
+
+fn poll_for_changes(path: PathBuf, tx: mpsc::UnboundedSender<()>) {
+	tokio::spawn(async move {
+		let modified = |p: &Path| -> Option<SystemTime> { fs::metadata(p).ok()?.modified().ok() };
+		let mut last = modified(&path);
+		loop {
+			tokio::time::sleep(CONFIG_POLL_INTERVAL).await;
+			let current = modified(&path);
+			if current != last {
+				last = current;
+				if tx.send(()).is_err() {
+					return;
+				}
+			}
+		}
+	});
+}
+
+#[cfg(test)]
+mod tests {
+	use clap::Parser;
+
+	use super::*;
+	use crate::commands::args::{Commands, IntegratedCli};
+
+	fn parse_args(argv: &[&str]) -> ServeWebArgs {
+		let cli = IntegratedCli::try_parse_from(argv).unwrap();
+		match cli.core.subcommand {
+			Some(Commands::ServeWeb(a)) => *a,
+			_ => panic!("expected serve-web"),
+		}
+	}
+
+	#[test]
+	fn test_reads_toml_and_json() {
+		let dir = tempfile::tempdir().unwrap();
+
+		let toml_path = dir.path().join("serve-web.toml");
+		fs::write(
+			&toml_path,
+			"port = 9000\ndefault-folder = \"/src\"\ntrusted-proxies = [\"10.0.0.0/8\"]\naccess-log-format = \"json\"\nupdate-windows = [\"22:00-06:00\"]\n",
+		)
+		.unwrap();
+		let cfg = ServeWebConfigFile::read(&toml_path).unwrap();
+		assert_eq!(cfg.port, Some(9000));
+		assert_eq!(cfg.default_folder.as_deref(), Some("/src"));
+		assert_eq!(cfg.trusted_proxies.unwrap().len(), 1);
+		assert_eq!(cfg.access_log_format, Some(AccessLogFormat::Json));
+		assert_eq!(cfg.update_windows.unwrap().len(), 1);
+
+		let json_path = dir.path().join("serve-web.json");
+		fs::write(&json_path, r#"{ "server-idle-timeout-secs": 60 }"#).unwrap();
+		let cfg = ServeWebConfigFile::read(&json_path).unwrap();
+		assert_eq!(cfg.server_idle_timeout_secs, Some(60));
+
+		fs::write(&json_path, r#"{ "not-an-option": true }"#).unwrap();
+		assert!(ServeWebConfigFile::read(&json_path).is_err());
+	}
+
+	#[test]
+	fn test_command_line_takes_precedence() {
+		let args = parse_args(&["code", "serve-web", "--port", "1234"]);
+		let cfg = ServeWebConfigFile {
+			port: Some(9000),
+			host: Some("0.0.0.0".to_string()),
+			disable_telemetry: Some(true),
+			..Default::default()
+		};
+
+		let args = cfg.apply_to(args);
+		assert_eq!(args.port, Some(1234));
+		assert_eq!(args.host.as_deref(), Some("0.0.0.0"));
+		assert!(args.disable_telemetry);
+	}
+
+	#[test]
+	fn test_server_limits() {
+		let args = parse_args(&[
+			"code",
+			"serve-web",
+			"--server-nice",
+			"5",
+			"--server-memory-limit-mb",
+			"512",
+		]);
+		let cfg = ServeWebConfigFile {
+			server_nice: Some(10),
+			server_cpu_weight: Some(50),
+			..Default::default()
+		};
+
+		let limits = cfg.apply_to(args).limits.limits();
+		assert_eq!(limits.nice, Some(5));
+		assert_eq!(limits.memory_max, Some(512 * 1024 * 1024));
+		assert_eq!(limits.cpu_weight, Some(50));
+
+		assert!(
+			IntegratedCli::try_parse_from(["code", "serve-web", "--server-ionice", "8"]).is_err()
+		);
+		// raising priority needs privileges, which would fail the spawn
+		assert!(
+			IntegratedCli::try_parse_from(["code", "serve-web", "--server-nice", "-5"]).is_err()
+		);
+	}
+
+	#[test]
+	fn test_rejects_out_of_range_limits() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("serve-web.toml");
+		for (contents, key) in [
+			("server-cpu-weight = 0", "server-cpu-weight"),
+			("server-cpu-weight = 10001", "server-cpu-weight"),
+			("server-cpu-quota-percent = 0", "server-cpu-quota-percent"),
+			("server-max-pids = 0", "server-max-pids"),
+			("server-nice = -10", "server-nice"),
+			("server-nice = 20", "server-nice"),
+			("server-ionice = 8", "server-ionice"),
+		] {
+			fs::write(&path, contents).unwrap();
+			match ServeWebConfigFile::read(&path) {
+				Err(CodeError::CouldNotLoadConfigFile(_, e)) => assert!(e.contains(key), "{e}"),
+				r => panic!("expected {contents} to be rejected, got {r:?}"),
+			}
+		}
+
+		fs::write(
+			&path,
+			"server-cpu-weight = 10000\nserver-nice = 19\nserver-ionice = 7",
+		)
+		.unwrap();
+		let cfg = ServeWebConfigFile::read(&path).unwrap();
+		assert_eq!(cfg.server_nice, Some(19));
+	}
+}
@@ -0,0 +1,1002 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use hmac::{Hmac, Mac};
+use hyper::{Body, Request, Response};
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{commands::args::OidcArgs, log, util::errors::CodeError};
+
+use super::{extract_cookie, is_document_load};
+
+/// Path, under the base path, the issuer redirects back to after sign-in.
+const CALLBACK_PATH: &str = "_vscode-cli/oidc/callback";
+/// Path, under the base path, that signs the user out. A GET shows a form
+/// that POSTs back to it with a token tied to the session.
+const LOGOUT_PATH: &str = "_vscode-cli/oidc/logout";
+/// Cookie holding the signed session of a signed-in user.
+const SESSION_COOKIE_NAME: &str = "vscode-cli-session";
+/// Cookie holding the signed state of a sign-in in progress.
+const LOGIN_COOKIE_NAME: &str = "vscode-cli-oidc-login";
+/// Number of seconds a user has to complete signing in with the issuer.
+const LOGIN_TIMEOUT_SECS: u64 = 10 * 60;
+/// Scopes requested from the issuer.
+const SCOPES: &str = "openid profile email";
+
+/// Where a request was made, as seen by the browser.
+pub struct RequestSite {
+	/// Scheme and host, like `https://example.com`
+	pub origin: String,
+	/// Path prefix a reverse proxy serves us under, without a trailing `/`
+	pub prefix: String,
+	/// Server base path, ending in `/`
+	pub base_path: String,
+	/// Whether cookies should be marked `Secure`
+	pub secure: bool,
+}
+
+impl RequestSite {
+	fn public_base_path(&self) -> String {
+		format!("{}{}", self.prefix, self.base_path)
+	}
+
+	fn url(&self, path: &str) -> String {
+		format!("{}{}{}", self.origin, self.public_base_path(), path)
+	}
+
+	fn cookie(&self, name: &str, value: &str, max_age: u64) -> String {
+		format!(
+			"{}={}; Path={}; HttpOnly; SameSite=Lax; Max-Age={}{}",
+			name,
+			value,
+			self.public_base_path(),
+			max_age,
+			if self.secure { "; Secure" } else { "" }
+		)
+	}
+}
+
+/// Provider metadata from the issuer's discovery document.
+#[derive(Deserialize)]
+struct ProviderMetadata {
+	issuer: String,
+	authorization_endpoint: String,
+	token_endpoint: String,
+	jwks_uri: String,
+	end_session_endpoint: Option<String>,
+}
+
+/// A signed-in user, stored in the session cookie.
+#[derive(Serialize, Deserialize)]
+struct Session {
+	sub: String,
+	name: String,
+	exp: u64,
+}
+
+/// A sign-in in progress, stored in the login cookie.
+#[derive(Serialize, Deserialize)]
+struct LoginState {
+	state: String,
+	nonce: String,
+	verifier: String,
+	return_to: String,
+	exp: u64,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+	id_token: String,
+}
+
+/// Claims of an ID token, read after its signature, issuer, audience and
+/// expiry are checked.
+#[derive(Deserialize)]
+struct IdTokenClaims {
+	exp: u64,
+	nonce: Option<String>,
+	sub: String,
+	email: Option<String>,
+	preferred_username: Option<String>,
+	name: Option<String>,
+	#[serde(default)]
+	groups: Vec<String>,
+}
+
+impl IdTokenClaims {
+	fn display_name(&self) -> &str {
+		self.email
+			.as_deref()
+			.or(self.preferred_username.as_deref())
+			.or(self.name.as_deref())
+			.unwrap_or(&self.sub)
+	}
+}
+
+/// OpenID Connect authorization-code sign-in. Sessions are kept in cookies
+/// signed with a key derived from the server's half of the secret key.
+pub struct OidcAuth {
+	log: log::Logger,
+	http: reqwest::Client,
+	args: OidcArgs,
+	metadata: ProviderMetadata,
+	key: [u8; 32],
+}
+
+impl OidcAuth {
+	/// Loads the issuer's discovery document.
+	pub async fn discover(
+		log: log::Logger,
+		http: reqwest::Client,
+		args: OidcArgs,
+		secret: &[u8],
+	) -> Result<Self, CodeError> {
+		let issuer = args.oidc_issuer.clone().unwrap_or_default();
+		let err = |e: String| CodeError::OidcDiscoveryFailed(issuer.clone(), e);
+
+		// the issuer may come from the config file, where clap doesn't check
+		// that a client ID is given with it
+		if args
+			.oidc_client_id
+			.as_deref()
+			.unwrap_or_default()
+			.is_empty()
+		{
+			return Err(err("an OpenID Connect client ID is required".to_string()));
+		}
+
+		let url = format!(
+			"{}/.well-known/openid-configuration",
+			issuer.trim_end_matches('/')
+		);
+		let metadata: ProviderMetadata = http
+			.get(&url)
+			.send()
+			.await
+			.and_then(|r| r.error_for_status())
+			.map_err(|e| err(e.to_string()))?
+			.json()
+			.await
+			.map_err(|e| err(e.to_string()))?;
+
+		if metadata.issuer.trim_end_matches('/') != issuer.trim_end_matches('/') {
+			return Err(err(format!(
+				"the discovery document is for issuer {}",
+				metadata.issuer
+			)));
+		}
+
+		let mut hasher = Sha256::new();
+		hasher.update(b"vscode-cli-oidc-session");
+		hasher.update(secret);
+
+		Ok(Self {
+			log,
+			http,
+			args,
+			metadata,
+			key: hasher.finalize().into(),
+		})
+	}
+
+	/// Authenticates the request. Returns a response to send instead of
+	/// proxying the request if it's for a sign-in endpoint, or if the user
+	/// isn't signed in or their session expired.
+	pub async fn authenticate(
+		&self,
+		req: &Request<Body>,
+		site: &RequestSite,
+	) -> Option<Response<Body>> {
+		match req.uri().path().strip_prefix(site.base_path.as_str()) {
+			Some(CALLBACK_PATH) => return Some(self.handle_callback(req, site).await),
+			Some(LOGOUT_PATH) => return Some(self.handle_logout(req, site)),
+			_ => {}
+		}
+
+		let session =
+			extract_cookie(req, SESSION_COOKIE_NAME).and_then(|c| self.verify::<Session>(&c));
+		match session {
+			Some(s) if s.exp > now() => None,
+			Some(s) => {
+				info!(self.log, "Session of {} expired, signing out", s.name);
+				Some(self.start_login(req, site))
+			}
+			None => Some(self.start_login(req, site)),
+		}
+	}
+
+	/// Redirects page loads to the issuer to sign in. Other requests can't
+	/// follow the redirect, so they're rejected until the page is reloaded.
+	fn start_login(&self, req: &Request<Body>, site: &RequestSite) -> Response<Body> {
+		let clear_session = site.cookie(SESSION_COOKIE_NAME, "", 0);
+		if req.method() != hyper::Method::GET || !(is_document_load(req) || accepts_html(req)) {
+			return Response::builder()
+				.status(401)
+				.header(hyper::header::SET_COOKIE, clear_session)
+				.body(Body::from("Sign-in required, please reload the page"))
+				.unwrap();
+		}
+
+		let login = LoginState {
+			state: random_token(),
+			nonce: random_token(),
+			verifier: random_token(),
+			return_to: format!(
+				"{}{}",
+				site.prefix,
+				req.uri()
+					.path_and_query()
+					.map(|p| p.as_str())
+					.unwrap_or("/")
+			),
+			exp: now() + LOGIN_TIMEOUT_SECS,
+		};
+
+		let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(login.verifier.as_bytes()));
+		let location = url::Url::parse_with_params(
+			&self.metadata.authorization_endpoint,
+			&[
+				("response_type", "code"),
+				("client_id", self.client_id()),
+				("redirect_uri", &site.url(CALLBACK_PATH)),
+				("scope", SCOPES),
+				("state", &login.state),
+				("nonce", &login.nonce),
+				("code_challenge", &challenge),
+				("code_challenge_method", "S256"),
+			],
+		);
+		let location = match location {
+			Ok(l) => l,
+			Err(e) => return error_response(500, format!("Invalid authorization endpoint: {e}")),
+		};
+
+		Response::builder()
+			.status(302)
+			.header(hyper::header::LOCATION, location.as_str())
+			.header(
+				hyper::header::SET_COOKIE,
+				site.cookie(LOGIN_COOKIE_NAME, &self.sign(&login), LOGIN_TIMEOUT_SECS),
+			)
+			.header(hyper::header::SET_COOKIE, clear_session)
+			.body(Body::empty())
+			.unwrap()
+	}
+
+	async fn handle_callback(&self, req: &Request<Body>, site: &RequestSite) -> Response<Body> {
+		let query: HashMap<String, String> =
+			url::form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+				.into_owned()
+				.collect();
+
+		if let Some(e) = query.get("error") {
+			warning!(self.log, "Sign-in failed at the issuer: {}", e);
+			return error_response(403, format!("Sign-in failed: {e}"));
+		}
+
+		let login = match extract_cookie(req, LOGIN_COOKIE_NAME)
+			.and_then(|c| self.verify::<LoginState>(&c))
+		{
+			Some(l) if l.exp > now() && query.get("state") == Some(&l.state) => l,
+			_ => return error_response(400, "Sign-in expired or is invalid, please try again"),
+		};
+
+		let code = match query.get("code") {
+			Some(c) => c,
+			None => return error_response(400, "Missing authorization code"),
+		};
+
+		let claims = match self.exchange_code(code, &login, site).await {
+			Ok(c) => c,
+			Err(e) => {
+				warning!(self.log, "Could not complete sign-in: {}", e);
+				return error_response(403, format!("Could not complete sign-in: {e}"));
+			}
+		};
+
+		if !self.is_allowed(&claims) {
+			warning!(
+				self.log,
+				"{} signed in, but is not an allowed user",
+				claims.display_name()
+			);
+			return error_response(
+				403,
+				format!(
+					"{} is not allowed to access this server",
+					claims.display_name()
+				),
+			);
+		}
+
+		let session = Session {
+			sub: claims.sub.clone(),
+			name: claims.display_name().to_string(),
+			exp: match self.args.oidc_session_lifetime_secs {
+				Some(s) => now() + s,
+				None => claims.exp,
+			},
+		};
+		info!(self.log, "{} signed in", session.name);
+
+		Response::builder()
+			.status(302)
+			.header(hyper::header::LOCATION, safe_return_path(&login.return_to))
+			.header(
+				hyper::header::SET_COOKIE,
+				site.cookie(
+					SESSION_COOKIE_NAME,
+					&self.sign(&session),
+					session.exp.saturating_sub(now()),
+				),
+			)
+			.header(
+				hyper::header::SET_COOKIE,
+				site.cookie(LOGIN_COOKIE_NAME, "", 0),
+			)
+			.body(Body::empty())
+			.unwrap()
+	}
+
+	/// Shows a sign-out form for GET requests, and signs out on POSTs with the
+	/// form's token, so that other sites can't sign the user out.
+	fn handle_logout(&self, req: &Request<Body>, site: &RequestSite) -> Response<Body> {
+		let session = extract_cookie(req, SESSION_COOKIE_NAME).unwrap_or_default();
+		let expected = self.logout_token(&session);
+		if req.method() == hyper::Method::GET {
+			let action = format!(
+				"{}{}?token={}",
+				site.public_base_path(),
+				LOGOUT_PATH,
+				expected
+			);
+			return Response::builder()
+				.header(hyper::header::CONTENT_TYPE, "text/html")
+				.body(Body::from(format!(
+					r#"<!DOCTYPE html><form method="post" action="{action}"><button type="submit">Sign out</button></form>"#
+				)))
+				.unwrap();
+		}
+
+		let token = url::form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+			.find(|(k, _)| k == "token")
+			.map(|(_, v)| v.into_owned());
+		let valid = req.method() == hyper::Method::POST
+			&& token
+				.and_then(|t| URL_SAFE_NO_PAD.decode(t).ok())
+				.map(|t| {
+					self.mac(session.as_bytes(), b"logout")
+						.verify_slice(&t)
+						.is_ok()
+				})
+				.unwrap_or(false);
+		if !valid {
+			return error_response(403, "Invalid sign-out request, please try again");
+		}
+
+		let res = Response::builder().header(
+			hyper::header::SET_COOKIE,
+			site.cookie(SESSION_COOKIE_NAME, "", 0),
+		);
+
+		let end_session = self.metadata.end_session_endpoint.as_deref().and_then(|e| {
+			url::Url::parse_with_params(
+				e,
+				&[
+					("client_id", self.client_id()),
+					("post_logout_redirect_uri", &site.url("")),
+				],
+			)
+			.ok()
+		});
+
+		match end_session {
+			Some(url) => res
+				.status(302)
+				.header(hyper::header::LOCATION, url.as_str())
+				.body(Body::empty()),
+			None => res.status(200).body(Body::from("Signed out")),
+		}
+		.unwrap()
+	}
+
+	/// Exchanges the authorization code for an ID token, and validates it
+	/// against the issuer's signing keys.
+	async fn exchange_code(
+		&self,
+		code: &str,
+		login: &LoginState,
+		site: &RequestSite,
+	) -> Result<IdTokenClaims, String> {
+		let redirect_uri = site.url(CALLBACK_PATH);
+		let mut form = vec![
+			("grant_type", "authorization_code"),
+			("code", code),
+			("redirect_uri", &redirect_uri),
+			("client_id", self.client_id()),
+			("code_verifier", &login.verifier),
+		];
+		if let Some(secret) = &self.args.oidc_client_secret {
+			form.push(("client_secret", secret));
+		}
+
+		let res: TokenResponse = self
+			.http
+			.post(&self.metadata.token_endpoint)
+			.form(&form)
+			.send()
+			.await
+			.and_then(|r| r.error_for_status())
+			.map_err(|e| format!("token request failed: {e}"))?
+			.json()
+			.await
+			.map_err(|e| format!("invalid token response: {e}"))?;
+
+		let claims = self.verify_id_token(&res.id_token).await?;
+		if claims.nonce.as_deref() != Some(&login.nonce) {
+			return Err("ID token nonce does not match".to_string());
+		}
+
+		Ok(claims)
+	}
+
+	/// Checks the ID token's signature with the issuer's keys, which are
+	/// fetched each time since sign-ins are rare and keys may be rotated.
+	async fn verify_id_token(&self, token: &str) -> Result<IdTokenClaims, String> {
+		let header =
+			jsonwebtoken::decode_header(token).map_err(|e| format!("invalid ID token: {e}"))?;
+		if matches!(
+			header.alg,
+			Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+		) {
+			return Err("ID token is not signed with the issuer's keys".to_string());
+		}
+
+		let keys: JwkSet = self
+			.http
+			.get(&self.metadata.jwks_uri)
+			.send()
+			.await
+			.and_then(|r| r.error_for_status())
+			.map_err(|e| format!("could not get the issuer's keys: {e}"))?
+			.json()
+			.await
+			.map_err(|e| format!("invalid keys from the issuer: {e}"))?;
+		let jwk = match &header.kid {
+			Some(kid) => keys.find(kid),
+			None if keys.keys.len() == 1 => keys.keys.first(),
+			None => None,
+		}
+		.ok_or("ID token is signed with an unknown key")?;
+		let key = DecodingKey::from_jwk(jwk).map_err(|e| format!("invalid issuer key: {e}"))?;
+
+		let mut validation = Validation::new(header.alg);
+		validation.leeway = 0;
+		validation.set_issuer(&[&self.metadata.issuer]);
+		validation.set_audience(&[self.client_id()]);
+		validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+		jsonwebtoken::decode::<IdTokenClaims>(token, &key, &validation)
+			.map(|d| d.claims)
+			.map_err(|e| format!("invalid ID token: {e}"))
+	}
+
+	fn is_allowed(&self, claims: &IdTokenClaims) -> bool {
+		let users = &self.args.oidc_allowed_users;
+		let groups = &self.args.oidc_allowed_groups;
+		if users.is_empty() && groups.is_empty() {
+			return true;
+		}
+
+		let ids = [
+			Some(&claims.sub),
+			claims.email.as_ref(),
+			claims.preferred_username.as_ref(),
+		];
+		users
+			.iter()
+			.any(|u| ids.iter().flatten().any(|id| id.eq_ignore_ascii_case(u)))
+			|| groups.iter().any(|g| claims.groups.contains(g))
+	}
+
+	fn client_id(&self) -> &str {
+		self.args.oidc_client_id.as_deref().unwrap_or_default()
+	}
+
+	/// Serializes and signs the value for use in a cookie.
+	fn sign(&self, value: &impl Serialize) -> String {
+		let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(value).unwrap());
+		let mac = self.mac(payload.as_bytes(), b"cookie").finalize();
+		format!("{}.{}", payload, URL_SAFE_NO_PAD.encode(mac.into_bytes()))
+	}
+
+	/// Verifies and deserializes a value created by `sign`.
+	fn verify<T: DeserializeOwned>(&self, value: &str) -> Option<T> {
+		let (payload, mac) = value.split_once('.')?;
+		let mac = URL_SAFE_NO_PAD.decode(mac).ok()?;
+		self.mac(payload.as_bytes(), b"cookie")
+			.verify_slice(&mac)
+			.ok()?;
+
+		serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()
+	}
+
+	/// Gets the token the sign-out form must be submitted with.
+	fn logout_token(&self, session: &str) -> String {
+		let mac = self.mac(session.as_bytes(), b"logout").finalize();
+		URL_SAFE_NO_PAD.encode(mac.into_bytes())
+	}
+
+	/// Creates a MAC of the message, for the given purpose.
+	fn mac(&self, message: &[u8], purpose: &[u8]) -> Hmac<Sha256> {
+		let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).unwrap();
+		mac.update(purpose);
+		mac.update(b".");
+		mac.update(message);
+		mac
+	}
+}
+
+fn random_token() -> String {
+	let bytes: [u8; 32] = rand::random();
+	URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn now() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|d| d.as_secs())
+		.unwrap_or_default()
+}
+
+/// Gets whether the request accepts HTML, for browsers that don't send
+/// fetch metadata headers.
+fn accepts_html(req: &Request<Body>) -> bool {
+	!req.headers().contains_key("sec-fetch-dest")
+		&& req
+			.headers()
+			.get(hyper::header::ACCEPT)
+			.and_then(|v| v.to_str().ok())
+			.map(|v| v.contains("text/html"))
+			.unwrap_or(false)
+}
+
+/// Only allows redirecting back to a path on this site after signing in.
+fn safe_return_path(p: &str) -> &str {
+	if p.starts_with('/') && !p.starts_with("//") && !p.contains('\\') {
+		p
+	} else {
+		"/"
+	}
+}
+
+fn error_response(status: u16, message: impl Into<String>) -> Response<Body> {
+	Response::builder()
+		.status(status)
+		.header(hyper::header::CONTENT_TYPE, "text/plain")
+		.body(Body::from(message.into()))
+		.unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+	use std::convert::Infallible;
+	use std::net::SocketAddr;
+	use std::sync::{Arc, Mutex};
+
+	use hyper::service::{make_service_fn, service_fn};
+	use hyper::Server;
+
+	use super::*;
+
+	/// Ed25519 key the mock issuer signs ID tokens with, as PKCS#8 DER.
+	const SIGNING_KEY: &str = "MC4CAQAwBQYDK2VwBCIEILPqjdoL6Ak/qtKkaRHN6NJszeX3KmgZufEKIsGi3qfO";
+	/// Public half of `SIGNING_KEY`, as in a JWK.
+	const SIGNING_KEY_X: &str = "PmFsszZLRMcHV-1vwEy1kTUeQAegQXuFsb6dzg9e44c";
+
+	/// Authorization the mock issuer will grant for a code.
+	struct PendingCode {
+		nonce: String,
+		challenge: String,
+		claims: serde_json::Value,
+		/// Whether to sign the ID token with a key the issuer doesn't publish.
+		forged: bool,
+	}
+
+	/// A minimal OpenID Connect issuer, serving discovery and token endpoints.
+	struct MockIssuer {
+		issuer: String,
+		codes: Arc<Mutex<HashMap<String, PendingCode>>>,
+	}
+
+	impl MockIssuer {
+		async fn start() -> Self {
+			let codes: Arc<Mutex<HashMap<String, PendingCode>>> = Default::default();
+			let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+			let builder = Server::bind(&addr);
+			let issuer = format!("http://{}", builder.local_addr());
+
+			let issuer_for_svc = issuer.clone();
+			let codes_for_svc = codes.clone();
+			let server = builder.serve(make_service_fn(move |_| {
+				let issuer = issuer_for_svc.clone();
+				let codes = codes_for_svc.clone();
+				async move {
+					Ok::<_, Infallible>(service_fn(move |req| {
+						let issuer = issuer.clone();
+						let codes = codes.clone();
+						async move { Ok::<_, Infallible>(Self::handle(&issuer, &codes, req).await) }
+					}))
+				}
+			}));
+			tokio::spawn(server);
+
+			Self { issuer, codes }
+		}
+
+		async fn handle(
+			issuer: &str,
+			codes: &Mutex<HashMap<String, PendingCode>>,
+			req: Request<Body>,
+		) -> Response<Body> {
+			match req.uri().path() {
+				"/.well-known/openid-configuration" => Response::new(Body::from(
+					serde_json::json!({
+						"issuer": issuer,
+						"authorization_endpoint": format!("{issuer}/authorize"),
+						"token_endpoint": format!("{issuer}/token"),
+						"jwks_uri": format!("{issuer}/jwks"),
+					})
+					.to_string(),
+				)),
+				"/jwks" => Response::new(Body::from(
+					serde_json::json!({
+						"keys": [{ "kty": "OKP", "crv": "Ed25519", "kid": "test", "x": SIGNING_KEY_X }],
+					})
+					.to_string(),
+				)),
+				"/token" => {
+					let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+					let form: HashMap<String, String> =
+						url::form_urlencoded::parse(&body).into_owned().collect();
+					let pending = codes.lock().unwrap().remove(&form["code"]);
+					let pending = match pending {
+						Some(p) => p,
+						None => return error_response(400, "invalid_grant"),
+					};
+					let challenge =
+						URL_SAFE_NO_PAD.encode(Sha256::digest(form["code_verifier"].as_bytes()));
+					if challenge != pending.challenge {
+						return error_response(400, "invalid_grant");
+					}
+
+					let mut claims = pending.claims;
+					claims["iss"] = issuer.into();
+					claims["nonce"] = pending.nonce.into();
+					let id_token = if pending.forged {
+						format!(
+							"{}.{}.{}",
+							URL_SAFE_NO_PAD.encode(r#"{"alg":"EdDSA","kid":"test"}"#),
+							URL_SAFE_NO_PAD.encode(claims.to_string()),
+							URL_SAFE_NO_PAD.encode([0u8; 64])
+						)
+					} else {
+						let mut header = jsonwebtoken::Header::new(Algorithm::EdDSA);
+						header.kid = Some("test".to_string());
+						let key = base64::engine::general_purpose::STANDARD
+							.decode(SIGNING_KEY)
+							.unwrap();
+						jsonwebtoken::encode(
+							&header,
+							&claims,
+							&jsonwebtoken::EncodingKey::from_ed_der(&key),
+						)
+						.unwrap()
+					};
+					Response::new(Body::from(
+						serde_json::json!({ "id_token": id_token, "token_type": "Bearer" })
+							.to_string(),
+					))
+				}
+				_ => error_response(404, "not found"),
+			}
+		}
+
+		async fn auth(&self, args: OidcArgs) -> OidcAuth {
+			let args = OidcArgs {
+				oidc_issuer: Some(self.issuer.clone()),
+				oidc_client_id: Some("client".to_string()),
+				..args
+			};
+			OidcAuth::discover(log::Logger::test(), reqwest::Client::new(), args, b"secret")
+				.await
+				.unwrap()
+		}
+	}
+
+	fn site() -> RequestSite {
+		RequestSite {
+			origin: "http://localhost:8000".to_string(),
+			prefix: String::new(),
+			base_path: "/".to_string(),
+			secure: false,
+		}
+	}
+
+	fn request(path: &str, cookies: &[String]) -> Request<Body> {
+		let mut req = Request::builder()
+			.uri(path)
+			.header("sec-fetch-dest", "document");
+		if !cookies.is_empty() {
+			req = req.header(hyper::header::COOKIE, cookies.join("; "));
+		}
+		req.body(Body::empty()).unwrap()
+	}
+
+	/// Gets `name=value` pairs from the Set-Cookie headers of the response.
+	fn set_cookies(res: &Response<Body>) -> Vec<String> {
+		res.headers()
+			.get_all(hyper::header::SET_COOKIE)
+			.iter()
+			.map(|c| c.to_str().unwrap().split(';').next().unwrap().to_string())
+			.collect()
+	}
+
+	fn cookie<'a>(cookies: &'a [String], name: &str) -> &'a String {
+		cookies
+			.iter()
+			.find(|c| c.starts_with(&format!("{name}=")))
+			.unwrap()
+	}
+
+	/// Signs in through the mock issuer with the given claims, returning the
+	/// response to the callback.
+	async fn sign_in(
+		issuer: &MockIssuer,
+		auth: &OidcAuth,
+		claims: serde_json::Value,
+	) -> Response<Body> {
+		sign_in_with(issuer, auth, claims, false).await
+	}
+
+	async fn sign_in_with(
+		issuer: &MockIssuer,
+		auth: &OidcAuth,
+		claims: serde_json::Value,
+		forged: bool,
+	) -> Response<Body> {
+		let res = auth
+			.authenticate(&request("/?folder=/src", &[]), &site())
+			.await
+			.unwrap();
+		assert_eq!(res.status(), 302);
+
+		let location =
+			url::Url::parse(res.headers()[hyper::header::LOCATION].to_str().unwrap()).unwrap();
+		assert_eq!(
+			location.as_str().split('?').next().unwrap(),
+			format!("{}/authorize", issuer.issuer)
+		);
+		let query: HashMap<String, String> = location.query_pairs().into_owned().collect();
+		assert_eq!(
+			query["redirect_uri"],
+			"http://localhost:8000/_vscode-cli/oidc/callback"
+		);
+
+		issuer.codes.lock().unwrap().insert(
+			"the-code".to_string(),
+			PendingCode {
+				nonce: query["nonce"].clone(),
+				challenge: query["code_challenge"].clone(),
+				claims,
+				forged,
+			},
+		);
+
+		let login = cookie(&set_cookies(&res), LOGIN_COOKIE_NAME).clone();
+		let callback = format!("/{}?code=the-code&state={}", CALLBACK_PATH, query["state"]);
+		auth.authenticate(&request(&callback, &[login]), &site())
+			.await
+			.unwrap()
+	}
+
+	fn claims(email: &str, groups: &[&str]) -> serde_json::Value {
+		serde_json::json!({
+			"sub": format!("sub-{email}"),
+			"aud": "client",
+			"exp": now() + 3600,
+			"email": email,
+			"groups": groups,
+		})
+	}
+
+	#[tokio::test]
+	async fn test_signs_in_and_accepts_session() {
+		let issuer = MockIssuer::start().await;
+		let auth = issuer.auth(OidcArgs::default()).await;
+
+		let res = sign_in(&issuer, &auth, claims("alice@example.com", &[])).await;
+		assert_eq!(res.status(), 302);
+		assert_eq!(res.headers()[hyper::header::LOCATION], "/?folder=/src");
+
+		let session = cookie(&set_cookies(&res), SESSION_COOKIE_NAME).clone();
+		assert!(auth
+			.authenticate(&request("/", &[session]), &site())
+			.await
+			.is_none());
+	}
+
+	#[tokio::test]
+	async fn test_rejects_unauthenticated_requests() {
+		let issuer = MockIssuer::start().await;
+		let auth = issuer.auth(OidcArgs::default()).await;
+
+		let req = Request::builder()
+			.uri("/stable-abc/static/file.js")
+			.body(Body::empty())
+			.unwrap();
+		let res = auth.authenticate(&req, &site()).await.unwrap();
+		assert_eq!(res.status(), 401);
+
+		let forged = format!("{SESSION_COOKIE_NAME}=e30.AAAA");
+		let res = auth
+			.authenticate(&request("/", &[forged]), &site())
+			.await
+			.unwrap();
+		assert_eq!(res.status(), 302);
+	}
+
+	#[tokio::test]
+	async fn test_allowed_users_and_groups() {
+		let issuer = MockIssuer::start().await;
+		let auth = issuer
+			.auth(OidcArgs {
+				oidc_allowed_users: vec!["Alice@example.com".to_string()],
+				oidc_allowed_groups: vec!["devs".to_string()],
+				..Default::default()
+			})
+			.await;
+
+		let res = sign_in(&issuer, &auth, claims("alice@example.com", &[])).await;
+		assert_eq!(res.status(), 302);
+
+		let res = sign_in(&issuer, &auth, claims("bob@example.com", &["devs"])).await;
+		assert_eq!(res.status(), 302);
+
+		let res = sign_in(&issuer, &auth, claims("eve@example.com", &["other"])).await;
+		assert_eq!(res.status(), 403);
+		assert!(set_cookies(&res).is_empty());
+	}
+
+	#[tokio::test]
+	async fn test_rejects_invalid_callbacks() {
+		let issuer = MockIssuer::start().await;
+		let auth = issuer.auth(OidcArgs::default()).await;
+
+		let callback = format!("/{CALLBACK_PATH}?code=the-code&state=forged");
+		let res = auth
+			.authenticate(&request(&callback, &[]), &site())
+			.await
+			.unwrap();
+		assert_eq!(res.status(), 400);
+
+		let mut wrong_audience = claims("alice@example.com", &[]);
+		wrong_audience["aud"] = "other-client".into();
+		let res = sign_in(&issuer, &auth, wrong_audience).await;
+		assert_eq!(res.status(), 403);
+
+		let mut expired = claims("alice@example.com", &[]);
+		expired["exp"] = (now() - 10).into();
+		let res = sign_in(&issuer, &auth, expired).await;
+		assert_eq!(res.status(), 403);
+
+		let res = sign_in_with(&issuer, &auth, claims("alice@example.com", &[]), true).await;
+		assert_eq!(res.status(), 403);
+		assert!(set_cookies(&res).is_empty());
+	}
+
+	#[tokio::test]
+	async fn test_sign_out_requires_token() {
+		let issuer = MockIssuer::start().await;
+		let auth = issuer.auth(OidcArgs::default()).await;
+		let res = sign_in(&issuer, &auth, claims("alice@example.com", &[])).await;
+		let session = cookie(&set_cookies(&res), SESSION_COOKIE_NAME).clone();
+		let logout = |method: &str, query: &str| {
+			Request::builder()
+				.method(method)
+				.uri(format!("/{LOGOUT_PATH}{query}"))
+				.header(hyper::header::COOKIE, &session)
+				.body(Body::empty())
+				.unwrap()
+		};
+
+		let res = auth
+			.authenticate(&logout("GET", ""), &site())
+			.await
+			.unwrap();
+		assert_eq!(res.status(), 200);
+		assert!(set_cookies(&res).is_empty());
+		let page = hyper::body::to_bytes(res.into_body()).await.unwrap();
+		let page = String::from_utf8(page.to_vec()).unwrap();
+		let token = page
+			.split("?token=")
+			.nth(1)
+			.unwrap()
+			.split('"')
+			.next()
+			.unwrap();
+
+		for (method, query) in [
+			("POST", String::new()),
+			("POST", "?token=AAAA".to_string()),
+			("GET", format!("?token={token}")),
+		] {
+			let res = auth
+				.authenticate(&logout(method, &query), &site())
+				.await
+				.unwrap();
+			assert!(!set_cookies(&res).contains(&format!("{SESSION_COOKIE_NAME}=")));
+		}
+
+		let res = auth
+			.authenticate(&logout("POST", &format!("?token={token}")), &site())
+			.await
+			.unwrap();
+		assert_eq!(res.status(), 200);
+		assert!(set_cookies(&res).contains(&format!("{SESSION_COOKIE_NAME}=")));
+	}
+
+	#[tokio::test]
+	async fn test_signs_out_when_session_expires() {
+		let issuer = MockIssuer::start().await;
+		let auth = issuer
+			.auth(OidcArgs {
+				oidc_session_lifetime_secs: Some(0),
+				..Default::default()
+			})
+			.await;
+
+		let res = sign_in(&issuer, &auth, claims("alice@example.com", &[])).await;
+		let session = cookie(&set_cookies(&res), SESSION_COOKIE_NAME).clone();
+
+		let res = auth
+			.authenticate(&request("/", &[session]), &site())
+			.await
+			.unwrap();
+		assert_eq!(res.status(), 302);
+		assert!(set_cookies(&res).contains(&format!("{SESSION_COOKIE_NAME}=")));
+	}
+
+	#[tokio::test]
+	async fn test_discovery_checks_issuer() {
+		let issuer = MockIssuer::start().await;
+		let args = OidcArgs {
+			oidc_issuer: Some(format!("{}/other", issuer.issuer)),
+			oidc_client_id: Some("client".to_string()),
+			..Default::default()
+		};
+		assert!(
+			OidcAuth::discover(log::Logger::test(), reqwest::Client::new(), args, b"secret")
+				.await
+				.is_err()
+		);
+
+		// an issuer from the config file may come without a client ID
+		let args = OidcArgs {
+			oidc_issuer: Some(issuer.issuer.clone()),
+			..Default::default()
+		};
+		assert!(
+			OidcAuth::discover(log::Logger::test(), reqwest::Client::new(), args, b"secret")
+				.await
+				.is_err()
+		);
+	}
+}
@@ -10,7 +10,9 @@ use serde::Serialize;
 use sha2::{Digest, Sha256};
 use std::{
 	net::{IpAddr, Ipv4Addr, SocketAddr},
+	process::Stdio,
 	str::FromStr,
+	sync::Arc,
 	time::Duration,
 };
 use sysinfo::Pid;
@@ -21,9 +23,12 @@ use tokio::{
 
 use super::{
 	args::{
-		AuthProvider, CliCore, CommandShellArgs, ExistingTunnelArgs, TunnelArgs, TunnelForwardArgs,
-		TunnelRenameArgs, TunnelServeArgs, TunnelServiceSubCommands, TunnelUserSubCommands,
+		AuthProvider, CliCore, CommandShellArgs, ExistingTunnelArgs, OutputFormat, TunnelArgs,
+		TunnelAuditArgs, TunnelForwardArgs, TunnelForwardPortsArgs, TunnelForwardSubcommand,
+		TunnelInstanceArgs, TunnelLogsArgs, TunnelPruneArgs, TunnelRenameArgs, TunnelServeArgs,
+		TunnelServiceSubCommands, TunnelStatusArgs, TunnelUserSubCommands,
 	},
+	output::{Column, OutputTable},
 	CommandContext,
 };
 
@@ -37,13 +42,23 @@ use crate::{
 	log,
 	state::LauncherPaths,
 	tunnels::{
+		audit_log::{read_records, AuditEvent, AuditFilter, AuditLog, AuditRecord},
 		code_server::CodeServerArgs,
 		create_service_manager,
 		dev_tunnels::{self, DevTunnels},
-		legal, local_forwarding,
-		paths::get_all_servers,
-		protocol, serve_stream,
-		shutdown_signal::ShutdownRequest,
+		legal,
+		local_forwarding::{self, PortsCallback},
+		paths::{prune_caches, PrunedCache},
+		protocol::{
+			self,
+			forward_singleton::{
+				self, ForwardedPort, ListPortsResponse, PortList, RemovePortsParams,
+				RemovePortsResponse, SetPortsParams, SetPortsResponse,
+			},
+		},
+		serve_stream,
+		server_logs::{find_server_log, follow_log, read_recent_lines, LogFilter},
+		shutdown_signal::{ShutdownRequest, ShutdownSignal},
 		singleton_client::do_single_rpc_call,
 		singleton_server::{
 			make_singleton_server, start_singleton_server, BroadcastLogSink, SingletonServerArgs,
@@ -56,10 +71,11 @@ use crate::{
 		errors::{wrap, AnyError, CodeError},
 		machine::canonical_exe,
 		prereqs::PreReqChecker,
+		sync::Barrier,
 	},
 };
 use crate::{
-	singleton::{acquire_singleton, SingletonConnection},
+	singleton::{acquire_singleton, connect_as_client, SingletonConnection, SingletonServer},
 	tunnels::{
 		dev_tunnels::ActiveTunnel,
 		singleton_client::{start_singleton_client, SingletonClientArgs},
@@ -126,9 +142,10 @@ impl ServiceContainer for TunnelServiceContainer {
 		launcher_paths: LauncherPaths,
 	) -> Result<(), AnyError> {
 		let mut csa = (&self.core_args).into();
-		self.tunnel_args.serve_args.server_args.apply_to(&mut csa);
+		let server_args = &self.tunnel_args.serve_args.server_args;
+		server_args.apply_to(&mut csa);
 		serve_with_csa(
-			launcher_paths,
+			launcher_paths.with_retention(server_args.retention.cache_policy()),
 			log,
 			TunnelServeArgs {
 				random_name: true, // avoid prompting
@@ -151,7 +168,9 @@ pub async fn command_shell(ctx: CommandContext, args: CommandShellArgs) -> Resul
 
 	let mut params = ServeStreamParams {
 		log: ctx.log,
-		launcher_paths: ctx.paths,
+		launcher_paths: ctx
+			.paths
+			.with_retention(args.server_args.retention.cache_policy()),
 		platform,
 		requires_auth: args
 			.require_token
@@ -159,6 +178,14 @@ pub async fn command_shell(ctx: CommandContext, args: CommandShellArgs) -> Resul
 			.unwrap_or(AuthRequired::VSDA),
 		exit_barrier: ShutdownRequest::create_rx(shutdown_reqs),
 		code_server_args: (&ctx.args).into(),
+		audit: None,
+	};
+	params.audit = match AuditLog::open(&params.launcher_paths) {
+		Ok(a) => Some(a),
+		Err(e) => {
+			warning!(params.log, "Could not open the audit log: {}", e);
+			None
+		}
 	};
 
 	args.server_args.apply_to(&mut params.code_server_args);
@@ -205,7 +232,7 @@ pub async fn command_shell(ctx: CommandContext, args: CommandShellArgs) -> Resul
 				Box::new(listener)
 			}
 			_ => {
-				serve_stream(tokio::io::stdin(), tokio::io::stderr(), params).await;
+				serve_stream(tokio::io::stdin(), tokio::io::stderr(), None, params).await;
 				return Ok(0);
 			}
 		};
@@ -215,9 +242,9 @@ pub async fn command_shell(ctx: CommandContext, args: CommandShellArgs) -> Resul
 	loop {
 		tokio::select! {
 			Some(_) = servers.next() => {},
-			socket = listener.accept_rw() => {
+			socket = listener.accept_rw_with_peer() => {
 				match socket {
-					Ok((read, write)) => servers.push(serve_stream(read, write, params.clone())),
+					Ok(((read, write), peer)) => servers.push(serve_stream(read, write, peer, params.clone())),
 					Err(e) => {
 						error!(params.log, &format!("Error accepting connection: {e}"));
 						return Ok(1);
@@ -251,6 +278,7 @@ async fn is_port_available(host: IpAddr, port: u16) -> bool {
 fn make_service_args<'a: 'c, 'b: 'c, 'c>(
 	root_path: &'a str,
 	tunnel_args: &'b TunnelArgs,
+	extra_args: &'b [String],
 ) -> Vec<&'c str> {
 	let mut args = ["--verbose", "--cli-data-dir", root_path, "tunnel"].to_vec();
 
@@ -260,6 +288,7 @@ fn make_service_args<'a: 'c, 'b: 'c, 'c>(
 	if let Some(d) = tunnel_args.serve_args.server_args.server_data_dir.as_ref() {
 		args.extend_from_slice(&["--server-data-dir", d]);
 	}
+	args.extend(extra_args.iter().map(|a| a.as_str()));
 
 	args.extend_from_slice(&["service", "internal-run"]);
 
@@ -291,7 +320,8 @@ pub async fn service(
 
 			let current_exe = canonical_exe().map_err(|e| wrap(e, "could not get current exe"))?;
 			let root_path = ctx.paths.root().as_os_str().to_string_lossy();
-			let args = make_service_args(&root_path, &tunnel_args);
+			let extra_args = tunnel_args.serve_args.server_args.to_service_args();
+			let args = make_service_args(&root_path, &tunnel_args, &extra_args);
 
 			manager.register(current_exe, &args).await?;
 			ctx.log.result(format!("Service successfully installed! You can use `{APPLICATION_NAME} tunnel service log` to monitor it, and `{APPLICATION_NAME} tunnel service uninstall` to remove it."));
@@ -345,8 +375,9 @@ pub async fn user(ctx: CommandContext, user_args: TunnelUserSubCommands) -> Resu
 
 /// Remove the tunnel used by this tunnel, if any.
 pub async fn rename(ctx: CommandContext, rename_args: TunnelRenameArgs) -> Result<i32, AnyError> {
-	let auth = Auth::new(&ctx.paths, ctx.log.clone());
-	let mut dt = dev_tunnels::DevTunnels::new_remote_tunnel(&ctx.log, auth, &ctx.paths);
+	let paths = resolve_instance(&ctx, &rename_args.instance).await?;
+	let auth = Auth::new(&paths, ctx.log.clone());
+	let mut dt = dev_tunnels::DevTunnels::new_remote_tunnel(&ctx.log, auth, &paths);
 	dt.rename_tunnel(&rename_args.name).await?;
 	ctx.log.result(format!(
 		"Successfully renamed this tunnel to {}",
@@ -357,16 +388,18 @@ pub async fn rename(ctx: CommandContext, rename_args: TunnelRenameArgs) -> Resul
 }
 
 /// Remove the tunnel used by this tunnel, if any.
-pub async fn unregister(ctx: CommandContext) -> Result<i32, AnyError> {
-	let auth = Auth::new(&ctx.paths, ctx.log.clone());
-	let mut dt = dev_tunnels::DevTunnels::new_remote_tunnel(&ctx.log, auth, &ctx.paths);
+pub async fn unregister(ctx: CommandContext, args: TunnelInstanceArgs) -> Result<i32, AnyError> {
+	let paths = resolve_instance(&ctx, &args).await?;
+	let auth = Auth::new(&paths, ctx.log.clone());
+	let mut dt = dev_tunnels::DevTunnels::new_remote_tunnel(&ctx.log, auth, &paths);
 	dt.remove_tunnel().await?;
 	Ok(0)
 }
 
-pub async fn restart(ctx: CommandContext) -> Result<i32, AnyError> {
+pub async fn restart(ctx: CommandContext, args: TunnelInstanceArgs) -> Result<i32, AnyError> {
+	let paths = resolve_instance(&ctx, &args).await?;
 	do_single_rpc_call::<_, ()>(
-		&ctx.paths.tunnel_lockfile(),
+		&paths.tunnel_lockfile(),
 		ctx.log,
 		protocol::singleton::METHOD_RESTART,
 		protocol::EmptyObject {},
@@ -376,9 +409,10 @@ pub async fn restart(ctx: CommandContext) -> Result<i32, AnyError> {
 	.map_err(|e| e.into())
 }
 
-pub async fn kill(ctx: CommandContext) -> Result<i32, AnyError> {
+pub async fn kill(ctx: CommandContext, args: TunnelInstanceArgs) -> Result<i32, AnyError> {
+	let paths = resolve_instance(&ctx, &args).await?;
 	do_single_rpc_call::<_, ()>(
-		&ctx.paths.tunnel_lockfile(),
+		&paths.tunnel_lockfile(),
 		ctx.log,
 		protocol::singleton::METHOD_SHUTDOWN,
 		protocol::EmptyObject {},
@@ -394,28 +428,69 @@ pub struct StatusOutput {
 	pub service_installed: bool,
 }
 
-pub async fn status(ctx: CommandContext) -> Result<i32, AnyError> {
-	let tunnel = do_single_rpc_call::<_, protocol::singleton::StatusWithTunnelName>(
-		&ctx.paths.tunnel_lockfile(),
-		ctx.log.clone(),
-		protocol::singleton::METHOD_STATUS,
-		protocol::EmptyObject {},
-	)
-	.await;
+#[derive(Serialize)]
+pub struct AllStatusOutput {
+	pub tunnels: Vec<InstanceStatus>,
+	pub service_installed: bool,
+}
 
+#[derive(Serialize)]
+pub struct InstanceStatus {
+	/// Name of the tunnel instance, or none for the default instance.
+	pub instance: Option<String>,
+	#[serde(flatten)]
+	pub tunnel: protocol::singleton::StatusWithTunnelName,
+}
+
+pub async fn status(ctx: CommandContext, args: TunnelStatusArgs) -> Result<i32, AnyError> {
 	let service_installed = create_service_manager(ctx.log.clone(), &ctx.paths)
 		.is_installed()
 		.await
 		.unwrap_or(false);
 
+	if args.all {
+		let tunnels: Vec<InstanceStatus> = running_instances(&ctx)
+			.await?
+			.into_iter()
+			.map(|(paths, tunnel)| InstanceStatus {
+				instance: paths.tunnel_instance().map(|n| n.to_string()),
+				tunnel,
+			})
+			.collect();
+		if let OutputFormat::Text = args.format {
+			if tunnels.is_empty() {
+				ctx.log.result("No tunnel is running");
+			}
+			for t in &tunnels {
+				print_status_text(&ctx, t.instance.as_deref(), &t.tunnel);
+			}
+			return Ok(0);
+		}
+
+		ctx.log.result(
+			serde_json::to_string(&AllStatusOutput {
+				tunnels,
+				service_installed,
+			})
+			.unwrap(),
+		);
+		return Ok(0);
+	}
+
+	let paths = resolve_instance(&ctx, &args.instance).await?;
+	let tunnel = get_instance_status(&ctx, &paths).await?;
+	if let OutputFormat::Text = args.format {
+		match &tunnel {
+			Some(t) => print_status_text(&ctx, paths.tunnel_instance(), t),
+			None => ctx.log.result("No tunnel is running"),
+		}
+		return Ok(0);
+	}
+
 	ctx.log.result(
 		serde_json::to_string(&StatusOutput {
 			service_installed,
-			tunnel: match tunnel {
-				Ok(s) => Some(s),
-				Err(CodeError::NoRunningTunnel | CodeError::AsyncPipeFailed(_)) => None,
-				Err(e) => return Err(e.into()),
-			},
+			tunnel,
 		})
 		.unwrap(),
 	);
@@ -423,24 +498,316 @@ pub async fn status(ctx: CommandContext) -> Result<i32, AnyError> {
 	Ok(0)
 }
 
-/// Removes unused servers.
-pub async fn prune(ctx: CommandContext) -> Result<i32, AnyError> {
-	get_all_servers(&ctx.paths)
-		.into_iter()
-		.map(|s| s.server_paths(&ctx.paths))
-		.filter(|s| s.get_running_pid().is_none())
-		.try_for_each(|s| {
-			ctx.log
-				.result(format!("Deleted {}", s.server_dir.display()));
-			s.delete()
+fn print_status_text(
+	ctx: &CommandContext,
+	instance: Option<&str>,
+	tunnel: &protocol::singleton::StatusWithTunnelName,
+) {
+	let status = &tunnel.status;
+	let traffic = &status.traffic;
+	let state = match status.tunnel {
+		protocol::singleton::TunnelState::Connected => "connected",
+		protocol::singleton::TunnelState::Disconnected => "disconnected",
+	};
+
+	let name = tunnel.name.as_deref().unwrap_or("(unnamed)");
+	let mut lines = vec![match instance {
+		Some(i) => format!("Tunnel {name} (instance {i}) is {state}"),
+		None => format!("Tunnel {name} is {state}"),
+	}];
+	if let Some(reason) = &status.last_fail_reason {
+		lines.push(format!("  Last failure:   {reason}"));
+	}
+	lines.push(format!("  Reconnects:     {}", traffic.reconnects));
+	lines.push(format!(
+		"  Connections:    {} active, {} total",
+		traffic.connections.len(),
+		traffic.total_connections
+	));
+	lines.push(format!(
+		"  Received:       {} bytes in {} messages",
+		traffic.totals.bytes_rx, traffic.totals.messages_rx
+	));
+	lines.push(format!(
+		"  Sent:           {} bytes in {} messages",
+		traffic.totals.bytes_tx, traffic.totals.messages_tx
+	));
+	ctx.log.result(lines.join("\n"));
+
+	if traffic.connections.is_empty() {
+		return;
+	}
+
+	let mut id = Column::new("Connection");
+	let mut connected_at = Column::new("Connected at");
+	let mut bytes_rx = Column::new("Bytes received");
+	let mut bytes_tx = Column::new("Bytes sent");
+	let mut messages_rx = Column::new("Messages received");
+	let mut messages_tx = Column::new("Messages sent");
+	for c in &traffic.connections {
+		id.add_row(c.id.to_string());
+		connected_at.add_row(c.connected_at.to_rfc3339());
+		bytes_rx.add_row(c.traffic.bytes_rx.to_string());
+		bytes_tx.add_row(c.traffic.bytes_tx.to_string());
+		messages_rx.add_row(c.traffic.messages_rx.to_string());
+		messages_tx.add_row(c.traffic.messages_tx.to_string());
+	}
+	OutputFormat::Text
+		.print_table(OutputTable::new(vec![
+			id,
+			connected_at,
+			bytes_rx,
+			bytes_tx,
+			messages_rx,
+			messages_tx,
+		]))
+		.ok();
+}
+
+/// Gets the status of a tunnel instance, if it's running.
+pub async fn get_instance_status(
+	ctx: &CommandContext,
+	paths: &LauncherPaths,
+) -> Result<Option<protocol::singleton::StatusWithTunnelName>, CodeError> {
+	let tunnel = do_single_rpc_call::<_, protocol::singleton::StatusWithTunnelName>(
+		&paths.tunnel_lockfile(),
+		ctx.log.clone(),
+		protocol::singleton::METHOD_STATUS,
+		protocol::EmptyObject {},
+	)
+	.await;
+
+	match tunnel {
+		Ok(s) => Ok(Some(s)),
+		Err(CodeError::NoRunningTunnel | CodeError::AsyncPipeFailed(_)) => Ok(None),
+		Err(e) => Err(e),
+	}
+}
+
+/// Gets the paths and status of every running tunnel instance.
+async fn running_instances(
+	ctx: &CommandContext,
+) -> Result<Vec<(LauncherPaths, protocol::singleton::StatusWithTunnelName)>, CodeError> {
+	let mut running = vec![];
+	for name in ctx.paths.tunnel_instances() {
+		let paths = ctx.paths.clone().with_tunnel_instance(name.as_deref());
+		if let Some(status) = get_instance_status(ctx, &paths).await? {
+			running.push((paths, status));
+		}
+	}
+	Ok(running)
+}
+
+/// Gets the paths of the tunnel instance a command applies to. Without a
+/// name, this is the default instance, unless it's not running and exactly
+/// one named instance is.
+pub async fn resolve_instance(
+	ctx: &CommandContext,
+	args: &TunnelInstanceArgs,
+) -> Result<LauncherPaths, CodeError> {
+	if args.instance.is_some() {
+		return Ok(ctx
+			.paths
+			.clone()
+			.with_tunnel_instance(args.instance.as_deref()));
+	}
+
+	let running = running_instances(ctx).await?;
+	if running.iter().any(|(p, _)| p.tunnel_instance().is_none()) {
+		return Ok(ctx.paths.clone());
+	}
+
+	match running.as_slice() {
+		[] => Ok(ctx.paths.clone()),
+		[(paths, _)] => Ok(paths.clone()),
+		_ => Err(CodeError::AmbiguousTunnelInstance(
+			running
+				.iter()
+				.filter_map(|(p, _)| p.tunnel_instance())
+				.collect::<Vec<_>>()
+				.join(", "),
+		)),
+	}
+}
+
+/// Removes unused servers and other downloads.
+pub async fn prune(ctx: CommandContext, args: TunnelPruneArgs) -> Result<i32, AnyError> {
+	let pruned = prune_caches(
+		&ctx.paths,
+		&args.retention.policy(),
+		args.include_downloads,
+		args.dry_run,
+	)?;
+	let reclaimable_bytes: u64 = pruned
+		.iter()
+		.flat_map(|c| c.removed.iter())
+		.map(|r| r.bytes)
+		.sum();
+
+	if let OutputFormat::Json = args.format.format {
+		#[derive(Serialize)]
+		#[serde(rename_all = "camelCase")]
+		struct PruneOutput {
+			dry_run: bool,
+			reclaimable_bytes: u64,
+			caches: Vec<PrunedCache>,
+		}
+
+		ctx.log.result(
+			serde_json::to_string(&PruneOutput {
+				dry_run: args.dry_run,
+				reclaimable_bytes,
+				caches: pruned,
+			})
+			.unwrap(),
+		);
+		return Ok(0);
+	}
+
+	let verb = if args.dry_run {
+		"Would delete"
+	} else {
+		"Deleted"
+	};
+	for removed in pruned.iter().flat_map(|c| c.removed.iter()) {
+		ctx.log.result(format!(
+			"{} {} ({})",
+			verb,
+			removed.path.display(),
+			format_bytes(removed.bytes)
+		));
+	}
+
+	ctx.log.result(if args.dry_run {
+		format!("{} can be reclaimed", format_bytes(reclaimable_bytes))
+	} else {
+		format!(
+			"Successfully removed unused downloads, reclaiming {}",
+			format_bytes(reclaimable_bytes)
+		)
+	});
+
+	Ok(0)
+}
+
+/// Shows the log of a server.
+pub async fn logs(ctx: CommandContext, args: TunnelLogsArgs) -> Result<i32, AnyError> {
+	let filter = LogFilter::new(args.since, args.grep.as_deref())?;
+	let paths = ctx
+		.paths
+		.clone()
+		.with_tunnel_instance(args.instance.as_deref());
+	let (commit, server_paths) = find_server_log(&paths, args.server.as_deref())?;
+	debug!(
+		ctx.log,
+		"Showing log of server {} from {}",
+		commit,
+		server_paths.logfile.display()
+	);
+
+	if args.follow {
+		follow_log(&server_paths.logfile, &filter, args.lines, |l| {
+			ctx.log.result(l)
 		})
-		.map_err(AnyError::from)?;
+		.await?;
+	} else {
+		for line in read_recent_lines(&server_paths.logfile, &filter, args.lines) {
+			ctx.log.result(line);
+		}
+	}
+
+	Ok(0)
+}
+
+/// Shows the audit log of the tunnel's control server.
+pub async fn audit(ctx: CommandContext, args: TunnelAuditArgs) -> Result<i32, AnyError> {
+	let paths = ctx
+		.paths
+		.clone()
+		.with_tunnel_instance(args.instance.instance.as_deref());
+	let filter = AuditFilter {
+		since: args.since,
+		event: args.event,
+		method: args.method,
+		connection: args.connection,
+	};
+	let mut records: Vec<AuditRecord> = read_records(&paths.audit_log_file())
+		.into_iter()
+		.filter(|r| filter.matches(r))
+		.collect();
+	records.drain(..records.len().saturating_sub(args.lines));
+
+	if let OutputFormat::Json = args.format.format {
+		ctx.log.result(serde_json::to_string(&records).unwrap());
+		return Ok(0);
+	}
 
-	ctx.log.result("Successfully removed all unused servers");
+	if records.is_empty() {
+		ctx.log.result("No audit events were recorded");
+		return Ok(0);
+	}
+
+	let mut time = Column::new("Time");
+	let mut connection = Column::new("Connection");
+	let mut client = Column::new("Client");
+	let mut event = Column::new("Event");
+	let mut details = Column::new("Details");
+	for r in &records {
+		time.add_row(r.time.to_rfc3339());
+		// prefix connection IDs with their session, since they restart at 0
+		connection.add_row(format!(
+			"{}/{}",
+			r.session.get(..8).unwrap_or(&r.session),
+			r.connection
+		));
+		client.add_row(r.client.clone().unwrap_or_default());
+		let (name, d) = match &r.event {
+			AuditEvent::Connect { auth } => ("connect", format!("auth: {:?}", auth)),
+			AuditEvent::Auth { outcome } => ("auth", format!("{:?}", outcome)),
+			AuditEvent::Rpc {
+				method,
+				target,
+				allowed,
+			} => (
+				"rpc",
+				format!(
+					"{} {}{}",
+					method,
+					target,
+					if *allowed { "" } else { " (denied)" }
+				),
+			),
+			AuditEvent::Disconnect {
+				bytes_rx,
+				bytes_tx,
+				duration_ms,
+			} => (
+				"disconnect",
+				format!(
+					"{} bytes received, {} bytes sent in {}s",
+					bytes_rx,
+					bytes_tx,
+					duration_ms / 1000
+				),
+			),
+		};
+		event.add_row(name.to_string());
+		details.add_row(d);
+	}
+	args.format
+		.format
+		.print_table(OutputTable::new(vec![
+			time, connection, client, event, details,
+		]))
+		.ok();
 
 	Ok(0)
 }
 
+fn format_bytes(bytes: u64) -> String {
+	format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0))
+}
+
 /// Starts the gateway server.
 pub async fn serve(ctx: CommandContext, gateway_args: TunnelServeArgs) -> Result<i32, AnyError> {
 	let CommandContext {
@@ -462,6 +829,9 @@ pub async fn serve(ctx: CommandContext, gateway_args: TunnelServeArgs) -> Result
 
 	let mut csa = (&args).into();
 	gateway_args.server_args.apply_to(&mut csa);
+	let paths = paths
+		.with_retention(gateway_args.server_args.retention.cache_policy())
+		.with_tunnel_instance(gateway_args.instance.as_deref());
 	let result = serve_with_csa(paths, log, gateway_args, csa, TUNNEL_CLI_LOCK_NAME).await;
 	drop(no_sleep);
 
@@ -501,9 +871,58 @@ pub async fn forward(
 
 	// #region singleton acquisition
 	let shutdown = ShutdownRequest::create_rx([ShutdownRequest::CtrlC]);
-	let server = loop {
+	let on_ports: PortsCallback = Arc::new(local_forwarding::print_forwarding_addr);
+	let server = match acquire_forwarding_singleton(&ctx, &shutdown, &own_ports_rx, &on_ports).await
+	{
+		Some(s) => s,
+		None => return Ok(0),
+	};
+
+	// #region singleton handler
+	let auth = Auth::new(&ctx.paths, ctx.log.clone());
+	if let (Some(p), Some(at)) = (
+		forward_args.login.provider.take(),
+		forward_args.login.access_token.take(),
+	) {
+		auth.login(
+			Some(p.into()),
+			Some(at),
+			forward_args.login.refresh_token.take(),
+		)
+		.await?;
+	}
+
+	let mut tunnels = DevTunnels::new_port_forwarding(&ctx.log, auth, &ctx.paths);
+	let tunnel = tunnels
+		.start_new_launcher_tunnel(None, true, &forward_args.ports)
+		.await?;
+
+	local_forwarding::server(local_forwarding::SingletonServerArgs {
+		log: ctx.log,
+		tunnel,
+		server,
+		port_requests: own_ports_rx,
+		on_ports,
+		exit_when_idle: false,
+		shutdown,
+	})
+	.await?;
+
+	Ok(0)
+}
+
+/// Connects to the port forwarding singleton as a client, for as long as it
+/// runs, until this process can become the singleton. Returns None if this
+/// process was asked to shut down first.
+async fn acquire_forwarding_singleton(
+	ctx: &CommandContext,
+	shutdown: &Barrier<ShutdownSignal>,
+	ports_rx: &watch::Receiver<PortList>,
+	on_ports: &PortsCallback,
+) -> Option<SingletonServer> {
+	loop {
 		if shutdown.is_open() {
-			return Ok(0);
+			return None;
 		}
 
 		match acquire_singleton(&ctx.paths.forwarding_lockfile()).await {
@@ -513,45 +932,280 @@ pub async fn forward(
 					log: ctx.log.clone(),
 					shutdown: shutdown.clone(),
 					stream,
-					port_requests: own_ports_rx.clone(),
+					port_requests: ports_rx.clone(),
+					on_ports: on_ports.clone(),
 				})
 				.await;
 				if let Err(e) = r {
 					warning!(ctx.log, "error contacting forwarding singleton: {}", e);
 				}
 			}
-			Ok(SingletonConnection::Singleton(server)) => break server,
+			Ok(SingletonConnection::Singleton(server)) => return Some(server),
 			Err(e) => {
 				warning!(ctx.log, "error access singleton, retrying: {}", e);
 				tokio::time::sleep(Duration::from_secs(2)).await
 			}
 		}
+	}
+}
+
+/// How long `tunnel forward --detach` waits for the background forwarding
+/// process to accept its ports.
+const DETACH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Forwards ports given on the command line, or manages ports forwarded
+/// that way.
+pub async fn forward_ports(
+	ctx: CommandContext,
+	args: TunnelForwardPortsArgs,
+) -> Result<i32, AnyError> {
+	match &args.subcommand {
+		Some(TunnelForwardSubcommand::List(format)) => {
+			return forward_list(ctx, format.format).await
+		}
+		Some(TunnelForwardSubcommand::Remove(remove_args)) => {
+			return forward_remove(ctx, &remove_args.ports).await
+		}
+		None if args.internal_background => return forward_background(ctx).await,
+		None => {}
+	}
+
+	let mut ports = args.ports.clone();
+	for port in ports.iter_mut() {
+		port.access = args.access(port.privacy);
+		port.labels = args.labels.clone();
+		port.access.validate(port.privacy)?;
+	}
+
+	let format = args.format.format;
+	if args.detach {
+		return forward_detach(ctx, ports, format).await;
+	}
+
+	let numbers: Vec<u16> = ports.iter().map(|p| p.number).collect();
+	let (ports_tx, ports_rx) = watch::channel(vec![]);
+	ports_tx.send(ports).ok();
+
+	let shutdown = ShutdownRequest::create_rx([ShutdownRequest::CtrlC]);
+	let on_ports: PortsCallback = Arc::new(move |r| print_forwarded_ports(format, &r.ports));
+	let server = match acquire_forwarding_singleton(&ctx, &shutdown, &ports_rx, &on_ports).await {
+		Some(s) => s,
+		None => return Ok(0),
 	};
 
-	// #region singleton handler
 	let auth = Auth::new(&ctx.paths, ctx.log.clone());
-	if let (Some(p), Some(at)) = (
-		forward_args.login.provider.take(),
-		forward_args.login.access_token.take(),
-	) {
-		auth.login(
-			Some(p.into()),
-			Some(at),
-			forward_args.login.refresh_token.take(),
-		)
+	let mut tunnel = DevTunnels::new_port_forwarding(&ctx.log, auth, &ctx.paths)
+		.start_new_launcher_tunnel(None, true, &numbers)
 		.await?;
+	tunnel.wait_for_endpoint().await?;
+
+	local_forwarding::server(local_forwarding::SingletonServerArgs {
+		log: ctx.log,
+		tunnel,
+		server,
+		port_requests: ports_rx,
+		on_ports,
+		exit_when_idle: false,
+		shutdown,
+	})
+	.await?;
+
+	Ok(0)
+}
+
+/// Hands ports to the background forwarding process, starting it if needed.
+async fn forward_detach(
+	ctx: CommandContext,
+	ports: PortList,
+	format: OutputFormat,
+) -> Result<i32, AnyError> {
+	// log in now, since the background process can't prompt for it
+	Auth::new(&ctx.paths, ctx.log.clone())
+		.get_credential()
+		.await?;
+
+	let lockfile = ctx.paths.forwarding_lockfile();
+	if connect_as_client(&lockfile).await.is_err() {
+		spawn_forwarding_process(&ctx.paths)?;
 	}
 
-	let mut tunnels = DevTunnels::new_port_forwarding(&ctx.log, auth, &ctx.paths);
-	let tunnel = tunnels
-		.start_new_launcher_tunnel(None, true, &forward_args.ports)
+	let detach = retry_while_starting(|| {
+		do_single_rpc_call::<_, SetPortsResponse>(
+			&lockfile,
+			ctx.log.clone(),
+			forward_singleton::METHOD_DETACH_PORTS,
+			SetPortsParams {
+				ports: ports.clone(),
+			},
+		)
+	});
+
+	let r = tokio::time::timeout(DETACH_TIMEOUT, detach)
+		.await
+		.map_err(|_| {
+			CodeError::ForwardingProcessNotStarted(
+				ctx.paths.forwarding_log_file().display().to_string(),
+			)
+		})??;
+
+	print_forwarded_ports(format, &r.ports);
+	Ok(0)
+}
+
+/// Calls a process that was just started, retrying while it may still be
+/// starting: until it acquires its lock, and until it listens on the socket
+/// the lock names.
+async fn retry_while_starting<T, F, Fut>(mut call: F) -> Result<T, CodeError>
+where
+	F: FnMut() -> Fut,
+	Fut: std::future::Future<Output = Result<T, CodeError>>,
+{
+	loop {
+		match call().await {
+			Err(CodeError::NoRunningTunnel | CodeError::AsyncPipeFailed(_)) => {
+				tokio::time::sleep(Duration::from_millis(500)).await
+			}
+			r => return r,
+		}
+	}
+}
+
+/// Runs the background forwarding process, which serves ports handed to it
+/// until none are left.
+async fn forward_background(ctx: CommandContext) -> Result<i32, AnyError> {
+	let server = match acquire_singleton(&ctx.paths.forwarding_lockfile()).await? {
+		SingletonConnection::Singleton(server) => server,
+		SingletonConnection::Client(_) => {
+			debug!(ctx.log, "Another process is already forwarding ports");
+			return Ok(0);
+		}
+	};
+
+	let auth = Auth::new(&ctx.paths, ctx.log.clone());
+	let mut tunnel = DevTunnels::new_port_forwarding(&ctx.log, auth, &ctx.paths)
+		.start_new_launcher_tunnel(None, true, &[])
 		.await?;
+	tunnel.wait_for_endpoint().await?;
+
+	let (_ports_tx, ports_rx) = watch::channel(vec![]);
+	local_forwarding::server(local_forwarding::SingletonServerArgs {
+		log: ctx.log,
+		tunnel,
+		server,
+		port_requests: ports_rx,
+		on_ports: Arc::new(|_| {}),
+		exit_when_idle: true,
+		shutdown: ShutdownRequest::create_rx([ShutdownRequest::CtrlC]),
+	})
+	.await?;
+
+	Ok(0)
+}
+
+/// Starts `tunnel forward --internal-background` so that it keeps running
+/// after this process exits.
+fn spawn_forwarding_process(paths: &LauncherPaths) -> Result<(), AnyError> {
+	let current_exe = canonical_exe().map_err(|e| wrap(e, "could not get current exe"))?;
+	let mut cmd = new_std_command(current_exe);
+	cmd.arg("--cli-data-dir")
+		.arg(paths.root())
+		.arg("--log-to-file")
+		.arg(paths.forwarding_log_file())
+		.args(["tunnel", "forward", "--internal-background"])
+		.stdin(Stdio::null())
+		.stdout(Stdio::null())
+		.stderr(Stdio::null());
+
+	#[cfg(unix)]
+	{
+		use std::os::unix::process::CommandExt;
+		cmd.process_group(0);
+	}
+	#[cfg(windows)]
+	{
+		use std::os::windows::process::CommandExt;
+		use winapi::um::winbase::{CREATE_NEW_PROCESS_GROUP, DETACHED_PROCESS};
+		cmd.creation_flags(CREATE_NEW_PROCESS_GROUP | DETACHED_PROCESS);
+	}
+
+	cmd.spawn()
+		.map_err(|e| wrap(e, "error starting forwarding process"))?;
+	Ok(())
+}
 
-	local_forwarding::server(ctx.log, tunnel, server, own_ports_rx, shutdown).await?;
+async fn forward_list(ctx: CommandContext, format: OutputFormat) -> Result<i32, AnyError> {
+	let r = do_single_rpc_call::<_, ListPortsResponse>(
+		&ctx.paths.forwarding_lockfile(),
+		ctx.log.clone(),
+		forward_singleton::METHOD_LIST_PORTS,
+		protocol::EmptyObject {},
+	)
+	.await;
 
+	let ports = match r {
+		Ok(r) => r.ports,
+		Err(CodeError::NoRunningTunnel) => vec![],
+		Err(e) => return Err(e.into()),
+	};
+
+	print_forwarded_ports(format, &ports);
 	Ok(0)
 }
 
+async fn forward_remove(ctx: CommandContext, ports: &[u16]) -> Result<i32, AnyError> {
+	let r = do_single_rpc_call::<_, RemovePortsResponse>(
+		&ctx.paths.forwarding_lockfile(),
+		ctx.log.clone(),
+		forward_singleton::METHOD_REMOVE_PORTS,
+		RemovePortsParams {
+			ports: ports.to_vec(),
+		},
+	)
+	.await;
+
+	let r = match r {
+		Ok(r) => r,
+		Err(CodeError::NoRunningTunnel) => {
+			warning!(ctx.log, "No ports are being forwarded");
+			return Ok(1);
+		}
+		Err(e) => return Err(e.into()),
+	};
+
+	for port in ports.iter().filter(|p| !r.removed.contains(p)) {
+		warning!(
+			ctx.log,
+			"Port {} was not handed off with --detach, stop the command that's forwarding it instead",
+			port
+		);
+	}
+
+	Ok(if r.removed.len() == ports.len() { 0 } else { 1 })
+}
+
+fn print_forwarded_ports(format: OutputFormat, ports: &[ForwardedPort]) {
+	let mut port = Column::new("Port");
+	let mut privacy = Column::new("Privacy");
+	let mut protocol = Column::new("Protocol");
+	let mut labels = Column::new("Labels");
+	let mut detached = Column::new("Detached");
+	let mut uri = Column::new("URI");
+	for p in ports {
+		port.add_row(p.number.to_string());
+		privacy.add_row(p.privacy.to_string());
+		protocol.add_row(p.protocol.to_string());
+		labels.add_row(p.labels.join(","));
+		detached.add_row(p.detached.to_string());
+		uri.add_row(p.uri.clone().unwrap_or_default());
+	}
+
+	format
+		.print_table(OutputTable::new(vec![
+			port, privacy, protocol, labels, detached, uri,
+		]))
+		.ok();
+}
+
 fn get_connection_token(tunnel: &ActiveTunnel) -> String {
 	let mut hash = Sha256::new();
 	hash.update(tunnel.id.as_bytes());
@@ -694,3 +1348,32 @@ async fn serve_with_csa(
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	use super::*;
+
+	#[tokio::test]
+	async fn test_retry_while_starting() {
+		let calls = AtomicUsize::new(0);
+		let r = retry_while_starting(|| async {
+			match calls.fetch_add(1, Ordering::SeqCst) {
+				0 => Err(CodeError::NoRunningTunnel),
+				1 => Err(CodeError::AsyncPipeFailed(
+					std::io::ErrorKind::NotFound.into(),
+				)),
+				n => Ok(n),
+			}
+		})
+		.await;
+		assert_eq!(r.unwrap(), 2);
+
+		let r: Result<(), _> = retry_while_starting(|| async {
+			Err(CodeError::ForwardingProcessNotStarted(String::new()))
+		})
+		.await;
+		assert!(matches!(r, Err(CodeError::ForwardingProcessNotStarted(_))));
+	}
+}
@@ -4,38 +4,98 @@
  *--------------------------------------------------------------------------------------------*/
 
 use std::{
-	fs::create_dir_all,
+	collections::HashMap,
+	fs::{self, create_dir_all},
 	path::{Path, PathBuf},
+	time::{Duration, SystemTime},
 };
 
 use futures::Future;
+use serde::Serialize;
 use tokio::fs::remove_dir_all;
 
 use crate::{
+	options::Quality,
 	state::PersistedState,
 	util::errors::{wrap, AnyError, WrappedError},
 };
 
-const KEEP_LRU: usize = 5;
+pub const KEEP_LRU: usize = 5;
 const STAGING_SUFFIX: &str = ".staging";
 const RENAME_ATTEMPTS: u32 = 20;
 const RENAME_DELAY: std::time::Duration = std::time::Duration::from_millis(200);
 const PERSISTED_STATE_FILE_NAME: &str = "lru.json";
 
+/// Limits on which entries a cache keeps. Entries that are pinned, in use,
+/// or younger than `min_age` are always kept. Other entries are kept, most
+/// recently used first, until any of the limits is reached.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+	/// Number of entries to keep for each quality.
+	pub keep_per_quality: Option<usize>,
+	/// Number of entries to keep in total.
+	pub keep_total: Option<usize>,
+	/// Disk space the cache's entries may use, in bytes.
+	pub max_total_bytes: Option<u64>,
+	/// Minimum time since an entry was downloaded before it can be removed.
+	pub min_age: Option<Duration>,
+	/// Commits whose entries are never removed.
+	pub pinned_commits: Vec<String>,
+}
+
+impl RetentionPolicy {
+	/// Policy used when none is configured, which keeps the few most recently
+	/// used entries.
+	pub fn lru() -> Self {
+		Self {
+			keep_total: Some(KEEP_LRU),
+			..Default::default()
+		}
+	}
+
+	fn has_limits(&self) -> bool {
+		self.keep_per_quality.is_some()
+			|| self.keep_total.is_some()
+			|| self.max_total_bytes.is_some()
+	}
+
+	fn is_pinned(&self, name: &str) -> bool {
+		self.pinned_commits
+			.iter()
+			.any(|c| !c.is_empty() && name.split('-').any(|p| p.starts_with(c.as_str())))
+	}
+}
+
+/// An entry that a retention policy would remove from a cache.
+#[derive(Serialize, Clone, Debug)]
+pub struct PruneCandidate {
+	pub name: String,
+	pub path: PathBuf,
+	pub bytes: u64,
+}
+
 #[derive(Clone)]
 pub struct DownloadCache {
 	path: PathBuf,
 	state: PersistedState<Vec<String>>,
+	retention: RetentionPolicy,
 }
 
 impl DownloadCache {
 	pub fn new(path: PathBuf) -> DownloadCache {
 		DownloadCache {
 			state: PersistedState::new(path.join(PERSISTED_STATE_FILE_NAME)),
+			retention: RetentionPolicy::lru(),
 			path,
 		}
 	}
 
+	/// Sets the policy applied whenever an entry is added.
+	pub fn with_retention(mut self, retention: RetentionPolicy) -> DownloadCache {
+		self.retention = retention;
+		self
+	}
+
 	/// Gets the value stored on the state
 	pub fn get(&self) -> Vec<String> {
 		self.state.load()
@@ -113,28 +173,232 @@ impl DownloadCache {
 			}
 		}
 
+		// apply the policy only as entries are added, since measuring the
+		// cache's size walks all of its entries
+		if self.retention.has_limits() {
+			let candidates = self.plan_prune(&self.retention, |p| p == target_dir);
+			self.remove(&candidates)?;
+		}
+
 		Ok(target_dir)
 	}
 
+	/// Gets the entries that the policy would remove from the cache, given
+	/// a function that returns whether an entry's path is in use. Without any
+	/// limits in the policy, all removable entries are returned.
+	pub fn plan_prune(
+		&self,
+		policy: &RetentionPolicy,
+		in_use: impl Fn(&Path) -> bool,
+	) -> Vec<PruneCandidate> {
+		let now = SystemTime::now();
+		let remove_all = !policy.has_limits();
+		let mut kept_per_quality: HashMap<Option<Quality>, usize> = HashMap::new();
+		let mut kept_total = 0;
+		let mut kept_bytes = 0;
+		let mut over_budget = false;
+		let mut candidates = vec![];
+
+		let entries = self.list_entries();
+		for (name, path, modified) in &entries {
+			let bytes = dir_size(path);
+			let young = match (policy.min_age, modified) {
+				(Some(min_age), Some(m)) => now.duration_since(*m).unwrap_or_default() < min_age,
+				_ => false,
+			};
+			// folders are named with the capitalized quality, like `Stable-<commit>`
+			let quality = name
+				.split_once('-')
+				.and_then(|(q, _)| Quality::try_from(q.to_lowercase().as_str()).ok());
+			let kept_of_quality = kept_per_quality.entry(quality).or_default();
+
+			over_budget = over_budget
+				|| policy
+					.max_total_bytes
+					.map(|max| kept_bytes + bytes > max)
+					.unwrap_or(false);
+			let over_limit = remove_all
+				|| over_budget
+				|| policy
+					.keep_per_quality
+					.map(|n| *kept_of_quality >= n)
+					.unwrap_or(false)
+				|| policy.keep_total.map(|n| kept_total >= n).unwrap_or(false);
+
+			if over_limit && !young && !policy.is_pinned(name) && !in_use(path) {
+				candidates.push(PruneCandidate {
+					name: name.clone(),
+					path: path.clone(),
+					bytes,
+				});
+			} else {
+				*kept_of_quality += 1;
+				kept_total += 1;
+				kept_bytes += bytes;
+			}
+		}
+
+		candidates
+	}
+
+	/// Removes entries returned from [DownloadCache::plan_prune].
+	pub fn remove(&self, candidates: &[PruneCandidate]) -> Result<(), WrappedError> {
+		for c in candidates {
+			self.delete(&c.name)?;
+		}
+		Ok(())
+	}
+
+	/// Lists entries on disk, most recently used first. Entries missing from
+	/// the persisted LRU list come after those in it, newest first.
+	fn list_entries(&self) -> Vec<(String, PathBuf, Option<SystemTime>)> {
+		let mut unlisted = vec![];
+		if let Ok(children) = fs::read_dir(&self.path) {
+			for child in children.flatten() {
+				let name = child.file_name().to_string_lossy().to_string();
+				if name.ends_with(STAGING_SUFFIX) || !child.path().is_dir() {
+					continue;
+				}
+				let modified = child.metadata().and_then(|m| m.modified()).ok();
+				unlisted.push((name, child.path(), modified));
+			}
+		}
+
+		let mut entries = vec![];
+		for name in self.get() {
+			if let Some(i) = unlisted.iter().position(|(n, _, _)| n == &name) {
+				entries.push(unlisted.remove(i));
+			}
+		}
+
+		unlisted.sort_by_key(|e| std::cmp::Reverse(e.2));
+		entries.extend(unlisted);
+		entries
+	}
+
 	fn touch(&self, name: String) -> Result<(), AnyError> {
 		self.state.update(|l| {
 			if let Some(index) = l.iter().position(|s| s == &name) {
 				l.remove(index);
 			}
 			l.insert(0, name);
+		})?;
 
-			if l.len() <= KEEP_LRU {
-				return;
-			}
+		Ok(())
+	}
+}
 
-			if let Some(f) = l.last() {
-				let f = self.path.join(f);
-				if !f.exists() || std::fs::remove_dir_all(f).is_ok() {
-					l.pop();
-				}
+/// Gets the size of the files in a directory, recursively.
+fn dir_size(path: &Path) -> u64 {
+	let mut size = 0;
+	if let Ok(children) = fs::read_dir(path) {
+		for child in children.flatten() {
+			match child.metadata() {
+				Ok(m) if m.is_dir() => size += dir_size(&child.path()),
+				Ok(m) => size += m.len(),
+				Err(_) => {}
 			}
-		})?;
+		}
+	}
+	size
+}
 
-		Ok(())
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::tunnels::paths::get_server_folder_name;
+
+	fn cache_with(dir: &Path, entries: &[(&str, usize)]) -> DownloadCache {
+		let cache = DownloadCache::new(dir.to_path_buf());
+		for (name, size) in entries.iter().rev() {
+			let p = dir.join(name);
+			create_dir_all(&p).unwrap();
+			fs::write(p.join("file"), vec![0u8; *size]).unwrap();
+			cache.touch(name.to_string()).unwrap();
+		}
+		cache
+	}
+
+	fn names(c: Vec<PruneCandidate>) -> Vec<String> {
+		c.into_iter().map(|c| c.name).collect()
+	}
+
+	#[test]
+	fn test_keep_per_quality_and_pins() {
+		let dir = tempfile::tempdir().unwrap();
+		let [c1, c2, c3, c4] = [
+			get_server_folder_name(Quality::Stable, "c1"),
+			get_server_folder_name(Quality::Insiders, "c2"),
+			get_server_folder_name(Quality::Stable, "c3"),
+			get_server_folder_name(Quality::Stable, "c4"),
+		];
+		let cache = cache_with(dir.path(), &[(&c1, 10), (&c2, 10), (&c3, 10), (&c4, 10)]);
+
+		let policy = RetentionPolicy {
+			keep_per_quality: Some(1),
+			..Default::default()
+		};
+		assert_eq!(
+			names(cache.plan_prune(&policy, |_| false)),
+			vec![c3.clone(), c4]
+		);
+
+		let policy = RetentionPolicy {
+			pinned_commits: vec!["c4".to_string()],
+			..policy
+		};
+		let in_use = dir.path().join(&c3);
+		assert!(cache.plan_prune(&policy, |p| p == in_use).is_empty());
+
+		assert_eq!(
+			cache
+				.plan_prune(&RetentionPolicy::default(), |_| false)
+				.len(),
+			4
+		);
+	}
+
+	#[test]
+	fn test_size_budget_and_min_age() {
+		let dir = tempfile::tempdir().unwrap();
+		let cache = cache_with(dir.path(), &[("a", 100), ("b", 100), ("c", 10)]);
+
+		let policy = RetentionPolicy {
+			max_total_bytes: Some(150),
+			..Default::default()
+		};
+		let removed = cache.plan_prune(&policy, |_| false);
+		assert_eq!(names(removed.clone()), vec!["b", "c"]);
+		assert_eq!(removed.iter().map(|c| c.bytes).sum::<u64>(), 110);
+
+		let policy = RetentionPolicy {
+			min_age: Some(Duration::from_secs(3600)),
+			..policy
+		};
+		assert!(cache.plan_prune(&policy, |_| false).is_empty());
+
+		cache.remove(&removed).unwrap();
+		assert_eq!(cache.get(), vec!["a"]);
+		assert!(!dir.path().join("b").exists());
+	}
+
+	#[tokio::test]
+	async fn test_create_keeps_lru() {
+		let dir = tempfile::tempdir().unwrap();
+		let cache = DownloadCache::new(dir.path().to_path_buf());
+		for i in 0..KEEP_LRU + 2 {
+			cache
+				.create(format!("stable-c{i}"), |_| async { Ok(()) })
+				.await
+				.unwrap();
+		}
+		assert_eq!(cache.get().len(), KEEP_LRU);
+
+		// using an entry doesn't prune the cache
+		create_dir_all(dir.path().join("stable-c7")).unwrap();
+		cache.touch("stable-c7".to_string()).unwrap();
+		assert_eq!(cache.get().len(), KEEP_LRU + 1);
+		assert!(!dir.path().join("stable-c0").exists());
+		assert!(dir.path().join("stable-c6").exists());
 	}
 }
@@ -15,6 +15,7 @@ pub mod options;
 pub mod self_update;
 pub mod state;
 pub mod tunnels;
+pub mod update_policy;
 pub mod update_service;
 pub mod util;
 
@@ -16,7 +16,7 @@ use serde::{de::DeserializeOwned, Serialize};
 
 use crate::{
 	constants::{DEFAULT_DATA_PARENT_DIR, VSCODE_CLI_QUALITY},
-	download_cache::DownloadCache,
+	download_cache::{DownloadCache, RetentionPolicy},
 	util::errors::{wrap, AnyError, NoHomeForLauncherError, WrappedError},
 };
 
@@ -27,6 +27,9 @@ pub struct LauncherPaths {
 	pub server_cache: DownloadCache,
 	pub cli_cache: DownloadCache,
 	root: PathBuf,
+	/// Named tunnel instance these paths are for. Instances share auth and
+	/// caches, but each has its own lockfile, tunnel, and server processes.
+	tunnel_instance: Option<String>,
 }
 
 struct PersistedStateContainer<T>
@@ -133,6 +136,23 @@ where
 	}
 }
 
+/// Checks that an instance name is safe to use in file names. Names that
+/// would need to be changed are rejected, rather than changed, so that two
+/// different names never share an instance's files.
+pub fn sanitize_instance_name(name: &str) -> Result<String, String> {
+	let valid = !name.is_empty()
+		&& name
+			.chars()
+			.all(|c| matches!(c, 'a'..='z' | '0'..='9' | '-' | '_'));
+	if valid {
+		Ok(name.to_string())
+	} else {
+		Err(format!(
+			"invalid instance name '{name}', only lowercase letters, digits, '-', and '_' are allowed"
+		))
+	}
+}
+
 impl LauncherPaths {
 	/// todo@conno4312: temporary migration from the old CLI data directory
 	pub fn migrate(root: Option<String>) -> Result<LauncherPaths, AnyError> {
@@ -195,6 +215,63 @@ impl LauncherPaths {
 			server_cache: DownloadCache::new(root.join("servers")),
 			cli_cache: DownloadCache::new(root.join("cli")),
 			root,
+			tunnel_instance: None,
+		}
+	}
+
+	/// Sets the retention policy of the server and CLI download caches.
+	pub fn with_retention(self, retention: RetentionPolicy) -> LauncherPaths {
+		LauncherPaths {
+			server_cache: self.server_cache.with_retention(retention.clone()),
+			cli_cache: self.cli_cache.with_retention(retention),
+			root: self.root,
+			tunnel_instance: self.tunnel_instance,
+		}
+	}
+
+	/// Gets paths for the named tunnel instance, or the default instance if
+	/// no name is given.
+	pub fn with_tunnel_instance(self, name: Option<&str>) -> LauncherPaths {
+		LauncherPaths {
+			tunnel_instance: name.map(|n| n.to_string()),
+			..self
+		}
+	}
+
+	/// Name of the tunnel instance, if not the default one.
+	pub fn tunnel_instance(&self) -> Option<&str> {
+		self.tunnel_instance.as_deref()
+	}
+
+	/// Names of tunnel instances that have a lockfile, starting with the
+	/// default instance, which is always included.
+	pub fn tunnel_instances(&self) -> Vec<Option<String>> {
+		let prefix = format!("tunnel-{}-", VSCODE_CLI_QUALITY.unwrap_or("oss"));
+		let mut names: Vec<String> = fs::read_dir(&self.root)
+			.map(|entries| {
+				entries
+					.flatten()
+					.filter_map(|e| {
+						let name = e.file_name().to_string_lossy().to_string();
+						name.strip_prefix(&prefix)?
+							.strip_suffix(".lock")
+							.map(|n| n.to_string())
+					})
+					.collect()
+			})
+			.unwrap_or_default();
+		names.sort();
+
+		std::iter::once(None)
+			.chain(names.into_iter().map(Some))
+			.collect()
+	}
+
+	/// Suffix added to files that belong to the tunnel instance.
+	pub fn tunnel_instance_suffix(&self) -> String {
+		match &self.tunnel_instance {
+			Some(name) => format!("-{name}"),
+			None => String::new(),
 		}
 	}
 
@@ -206,12 +283,34 @@ impl LauncherPaths {
 	/// Lockfile for the running tunnel
 	pub fn tunnel_lockfile(&self) -> PathBuf {
 		self.root.join(format!(
-			"tunnel-{}.lock",
+			"tunnel-{}{}.lock",
+			VSCODE_CLI_QUALITY.unwrap_or("oss"),
+			self.tunnel_instance_suffix()
+		))
+	}
+
+	/// File the tunnel used to access the server is persisted in
+	pub fn remote_tunnel_file(&self) -> PathBuf {
+		self.root
+			.join(format!("code_tunnel{}.json", self.tunnel_instance_suffix()))
+	}
+
+	/// Lockfile for the running agent host
+	pub fn agent_host_lockfile(&self) -> PathBuf {
+		self.root.join(format!(
+			"agent-host-{}.lock",
 			VSCODE_CLI_QUALITY.unwrap_or("oss")
 		))
 	}
 
-	/// Lockfile for port forwarding
+	/// Commits of agent host server releases that have run successfully
+	pub fn agent_host_known_good_file(&self) -> PathBuf {
+		self.root.join("agent-host-known-good.json")
+	}
+
+	/// Lockfile for port forwarding. It's shared by all tunnel instances on
+	/// purpose: `tunnel forward` hosts its own port forwarding tunnel rather
+	/// than using an instance's, so one process serves the whole machine.
 	pub fn forwarding_lockfile(&self) -> PathBuf {
 		self.root.join(format!(
 			"forwarding-{}.lock",
@@ -219,6 +318,21 @@ impl LauncherPaths {
 		))
 	}
 
+	/// Audit log of activity on the tunnel's control server
+	pub fn audit_log_file(&self) -> PathBuf {
+		self.root.join(format!(
+			"tunnel-audit{}.jsonl",
+			self.tunnel_instance_suffix()
+		))
+	}
+
+	/// Log file of the background forwarding process started by
+	/// `tunnel forward --detach`. Like its lockfile, it's shared by all
+	/// tunnel instances.
+	pub fn forwarding_log_file(&self) -> PathBuf {
+		self.root.join("forwarding.log")
+	}
+
 	/// Suggested path for tunnel service logs, when using file logs
 	pub fn service_log_file(&self) -> PathBuf {
 		self.root.join("tunnel-service.log")
@@ -237,8 +351,46 @@ impl LauncherPaths {
 		})
 	}
 
+	/// Directory that verified extension bundles are cached in
+	pub fn extension_bundles(&self) -> PathBuf {
+		self.root.join("extension-bundles")
+	}
+
 	/// Suggested path for web server storage
 	pub fn web_server_storage(&self) -> PathBuf {
 		self.root.join("serve-web")
 	}
+
+	/// File that's written to approve the update serve-web is waiting on.
+	pub fn web_server_update_approval(&self) -> PathBuf {
+		self.root.join("serve-web-update-approval")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_tunnel_instances() {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_path_buf());
+		assert!(sanitize_instance_name("Team A").is_err());
+		assert!(sanitize_instance_name("team_a").is_ok());
+
+		let team = paths.clone().with_tunnel_instance(Some("team_a"));
+		assert_eq!(team.tunnel_instance(), Some("team_a"));
+		assert_ne!(team.tunnel_lockfile(), paths.tunnel_lockfile());
+		assert_ne!(team.remote_tunnel_file(), paths.remote_tunnel_file());
+		assert_ne!(team.audit_log_file(), paths.audit_log_file());
+		assert_eq!(team.forwarding_lockfile(), paths.forwarding_lockfile());
+		assert_eq!(team.forwarding_log_file(), paths.forwarding_log_file());
+
+		fs::write(team.tunnel_lockfile(), "").unwrap();
+		fs::write(paths.tunnel_lockfile(), "").unwrap();
+		assert_eq!(
+			paths.tunnel_instances(),
+			vec![None, Some("team_a".to_string())]
+		);
+	}
 }
@@ -5,15 +5,20 @@
 
 pub mod code_server;
 pub mod dev_tunnels;
+pub mod extension_bundle;
 pub mod legal;
 pub mod local_forwarding;
 pub mod paths;
 pub mod protocol;
+pub mod server_env;
+pub mod server_logs;
 pub mod shutdown_signal;
 pub mod singleton_client;
 pub mod singleton_server;
 
 pub mod agent_host;
+pub mod audit_log;
+mod auto_forward;
 mod challenge;
 mod control_server;
 mod nosleep;
@@ -34,6 +39,7 @@ mod service_macos;
 #[cfg(target_os = "windows")]
 mod service_windows;
 mod socket_signal;
+mod traffic;
 mod wsl_detect;
 
 pub use control_server::{serve, serve_stream, AuthRequired, Next, ServeStreamParams};
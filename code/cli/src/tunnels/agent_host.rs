@@ -3,30 +3,41 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
+use std::collections::HashMap;
 use std::convert::Infallible;
+use std::fmt;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+use chrono::{DateTime, Local, Utc};
 use hyper::{Body, Request, Response};
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::sync::Mutex;
 
 use crate::async_pipe::{get_socket_name, get_socket_rw_stream, AsyncPipe};
-use crate::constants::VSCODE_CLI_QUALITY;
+use crate::constants::{APPLICATION_NAME, VSCODE_CLI_QUALITY};
 use crate::download_cache::DownloadCache;
 use crate::log;
 use crate::options::Quality;
+use crate::state::PersistedState;
+use crate::update_policy::{UpdateDecision, UpdatePolicy};
 use crate::update_service::{
 	unzip_downloaded_release, Platform, Release, TargetKind, UpdateService,
 };
+use crate::util::access_log::{AccessLog, RoutedCommit};
 use crate::util::command::new_script_command;
 use crate::util::errors::CodeError;
 use crate::util::http::{self, BoxedHttp};
 use crate::util::io::SilentCopyProgress;
+use crate::util::readiness::{wait_for_http, ProbeTarget};
+use crate::util::resource_limits::{AppliedLimits, ResourceLimits};
+use crate::util::ring_buffer::RingBuffer;
 use crate::util::sync::{new_barrier, Barrier, BarrierOpener};
 
 use super::paths::{get_server_folder_name, SERVER_FOLDER_NAME};
+use super::protocol::agent_host_singleton::{ServerStatus, UpdateResult};
 
 /// How often to check for server updates.
 pub const UPDATE_CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
@@ -34,24 +45,168 @@ pub const UPDATE_CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
 pub const UPDATE_POLL_INTERVAL: Duration = Duration::from_secs(10 * 60);
 /// How long to wait for the server to signal readiness.
 pub const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
-
-/// Configuration for the agent host server process.
+/// Default maximum number of workspace servers that run at once.
+pub const DEFAULT_MAX_WORKSPACES: usize = 8;
+/// Header that selects the workspace a request is routed to.
+pub const WORKSPACE_HEADER: &str = "x-vscode-agent-host-workspace";
+/// Path prefix that selects the workspace a request is routed to, as
+/// `/workspace/<name>/...`. It's removed before the request is proxied.
+const WORKSPACE_PATH_PREFIX: &str = "/workspace/";
+/// Workspace that requests are routed to if they don't select one.
+pub const DEFAULT_WORKSPACE: &str = "default";
+/// Maximum length of a workspace name.
+const MAX_WORKSPACE_NAME_LEN: usize = 64;
+/// Number of stderr lines kept from a crashed server.
+const CRASH_STDERR_LINES: usize = 20;
+/// Number of crashes kept for each workspace.
+const CRASH_HISTORY: usize = 10;
+/// Delay before restarting after a crash, doubled for each consecutive crash.
+const CRASH_BACKOFF_BASE: Duration = Duration::from_secs(1);
+/// Maximum delay before restarting after a crash.
+const CRASH_BACKOFF_MAX: Duration = Duration::from_secs(60);
+/// Number of consecutive crashes after which a server is crash-looping.
+const CRASH_LOOP_THRESHOLD: u32 = 5;
+/// How long a crash-looping server isn't restarted for.
+const CRASH_LOOP_COOLDOWN: Duration = Duration::from_secs(5 * 60);
+/// How long a server has to run for a later crash not to count as
+/// consecutive, and for its release to be considered known-good.
+const STABLE_UPTIME: Duration = Duration::from_secs(60);
+
+/// Configuration for the agent host server processes.
 #[derive(Clone, Debug)]
 pub struct AgentHostConfig {
+	/// Data directory of the default workspace's server.
 	pub server_data_dir: Option<String>,
+	/// Directory containing the data directories of other workspaces' servers.
+	pub workspaces_data_dir: PathBuf,
+	/// Maximum number of workspace servers that run at once.
+	pub max_workspaces: usize,
 	pub without_connection_token: bool,
 	pub connection_token: Option<String>,
 	pub connection_token_file: Option<String>,
+	/// Log that proxied requests are written to, if any.
+	pub access_log: Option<AccessLog>,
+	/// Whether to run the previous known-good cached release for a workspace
+	/// whose server is crash-looping.
+	pub fallback_on_crash_loop: bool,
+	/// File the releases that have run successfully are recorded in.
+	pub known_good_file: PathBuf,
+	/// Controls which releases servers are updated to, and when.
+	pub update_policy: UpdatePolicy,
+	/// Limits on the resources each server process may use.
+	pub limits: ResourceLimits,
 }
 
 /// State of the running VS Code server process.
 struct RunningServer {
 	child: tokio::process::Child,
 	commit: String,
+	started_at: DateTime<Utc>,
+}
+
+/// Unrequested exit of a server process.
+#[derive(Clone, Debug)]
+pub struct ServerCrash {
+	pub commit: String,
+	/// Exit code of the process, if it exited normally.
+	pub exit_code: Option<i32>,
+	/// Why the process was killed, if it was for exceeding its memory limit.
+	pub out_of_memory: Option<String>,
+	/// The last lines the process wrote to stderr.
+	pub stderr: Vec<String>,
+}
+
+impl ServerCrash {
+	fn new(
+		commit: &str,
+		status: Option<std::process::ExitStatus>,
+		limits: &AppliedLimits,
+		stderr: &RingBuffer<String>,
+	) -> Self {
+		Self {
+			commit: commit.to_string(),
+			exit_code: status.and_then(|s| s.code()),
+			out_of_memory: limits.oom_reason(),
+			stderr: stderr.iter().cloned().collect(),
+		}
+	}
+}
+
+impl fmt::Display for ServerCrash {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match (&self.out_of_memory, self.exit_code) {
+			(Some(r), _) => write!(f, "server {} ran out of memory: {}", self.commit, r)?,
+			(None, Some(c)) => write!(f, "server {} exited with code {}", self.commit, c)?,
+			(None, None) => write!(f, "server {} was terminated", self.commit)?,
+		}
+		for line in &self.stderr {
+			write!(f, "\n  {line}")?;
+		}
+		Ok(())
+	}
+}
+
+/// Restart supervision of a workspace's server.
+struct Supervisor {
+	crashes: RingBuffer<ServerCrash>,
+	consecutive: u32,
+	last_crash: Option<Instant>,
+	/// When the server was found to be crash-looping, if it is.
+	crash_loop_since: Option<Instant>,
+	/// Release run instead of the latest one after a crash loop.
+	fallback: Option<Release>,
+	/// Commits that have crash-looped, which aren't fallen back to.
+	failed_commits: Vec<String>,
+}
+
+impl Supervisor {
+	fn new() -> Self {
+		Self {
+			crashes: RingBuffer::new(CRASH_HISTORY),
+			consecutive: 0,
+			last_crash: None,
+			crash_loop_since: None,
+			fallback: None,
+			failed_commits: Vec::new(),
+		}
+	}
+
+	/// Gets how long to wait before the next start.
+	fn backoff(&self) -> Duration {
+		let last_crash = match (self.consecutive, self.last_crash) {
+			(0, _) | (_, None) => return Duration::ZERO,
+			(_, Some(l)) => l,
+		};
+
+		let delay = CRASH_BACKOFF_BASE
+			.saturating_mul(1 << (self.consecutive - 1).min(16))
+			.min(CRASH_BACKOFF_MAX);
+		delay.saturating_sub(last_crash.elapsed())
+	}
+}
+
+/// How a server is to be started.
+#[derive(Default)]
+struct PlannedStart {
+	fallback: Option<Release>,
+}
+
+/// The server of a single workspace. Each one runs its own process with its
+/// own data directory, and shuts down on its own when idle.
+struct AgentHostInstance {
+	workspace: String,
+	data_dir: Option<String>,
+	/// The currently running server, if any.
+	running: Mutex<Option<RunningServer>>,
+	/// Barrier that opens when a server is ready (socket path available).
+	/// Reset each time a new server is started, while its lock is held, so
+	/// concurrent requests wait for the same server to start.
+	ready: Mutex<Option<Barrier<Result<PathBuf, String>>>>,
 }
 
 /// Manages the VS Code server lifecycle: on-demand start, auto-restart
-/// after idle shutdown, and background update checking.
+/// after idle shutdown, and background update checking. A server is run for
+/// each workspace that requests are routed to.
 pub struct AgentHostManager {
 	log: log::Logger,
 	config: AgentHostConfig,
@@ -60,11 +215,20 @@ pub struct AgentHostManager {
 	update_service: UpdateService,
 	/// The latest known release, with the time it was checked.
 	latest_release: Mutex<Option<(Instant, Release)>>,
-	/// The currently running server, if any.
-	running: Mutex<Option<RunningServer>>,
-	/// Barrier that opens when a server is ready (socket path available).
-	/// Reset each time a new server is started.
-	ready: Mutex<Option<Barrier<Result<PathBuf, String>>>>,
+	/// Servers by workspace name. Removed once their process exits.
+	instances: std::sync::Mutex<HashMap<String, Arc<AgentHostInstance>>>,
+	/// Crash supervision by workspace name.
+	supervisors: std::sync::Mutex<HashMap<String, Supervisor>>,
+	/// Commits of releases that have run successfully, kept across restarts.
+	known_good: PersistedState<Vec<String>>,
+	/// Newer release that the update policy is holding back, if any.
+	pending_update: std::sync::Mutex<Option<PendingUpdate>>,
+}
+
+#[derive(Clone)]
+struct PendingUpdate {
+	release: Release,
+	awaiting_approval: bool,
 }
 
 impl AgentHostManager {
@@ -77,68 +241,126 @@ impl AgentHostManager {
 	) -> Arc<Self> {
 		Arc::new(Self {
 			update_service: UpdateService::new(log.clone(), http),
+			known_good: PersistedState::new(config.known_good_file.clone()),
 			log,
 			config,
 			platform,
 			cache,
 			latest_release: Mutex::new(None),
+			instances: std::sync::Mutex::new(HashMap::new()),
+			supervisors: std::sync::Mutex::new(HashMap::new()),
+
+			pending_update: std::sync::Mutex::new(None),
+		})
+	}
+
+	/// Gets the server instance for the workspace, creating it if needed.
+	fn get_instance(&self, workspace: &str) -> Result<Arc<AgentHostInstance>, CodeError> {
+		let mut instances = self.instances.lock().unwrap();
+		if let Some(i) = instances.get(workspace) {
+			return Ok(i.clone());
+		}
+
+		if instances.len() >= self.config.max_workspaces {
+			return Err(CodeError::TooManyAgentHostWorkspaces(
+				self.config.max_workspaces,
+			));
+		}
+
+		let data_dir = if workspace == DEFAULT_WORKSPACE {
+			self.config.server_data_dir.clone()
+		} else {
+			Some(
+				self.config
+					.workspaces_data_dir
+					.join(workspace)
+					.to_string_lossy()
+					.to_string(),
+			)
+		};
+
+		let instance = Arc::new(AgentHostInstance {
+			workspace: workspace.to_string(),
+			data_dir,
 			running: Mutex::new(None),
 			ready: Mutex::new(None),
-		})
+		});
+		instances.insert(workspace.to_string(), instance.clone());
+		Ok(instance)
 	}
 
-	/// Returns the socket path to a running server, starting one if needed.
-	pub async fn ensure_server(self: &Arc<Self>) -> Result<PathBuf, CodeError> {
-		// Fast path: if we already have a barrier, wait on it
-		{
-			let ready = self.ready.lock().await;
-			if let Some(barrier) = &*ready {
-				if barrier.is_open() {
-					// Check if the process is still running
-					let running = self.running.lock().await;
-					if running.is_some() {
-						return barrier
-							.clone()
-							.wait()
-							.await
-							.unwrap()
-							.map_err(CodeError::ServerDownloadError);
-					}
-				} else {
-					// Still starting up, wait for it
-					let mut barrier = barrier.clone();
-					drop(ready);
-					return barrier
-						.wait()
-						.await
-						.unwrap()
-						.map_err(CodeError::ServerDownloadError);
-				}
+	/// Forgets the instance once its server has exited, so the next request
+	/// for the workspace starts a new one.
+	fn remove_instance(&self, instance: &Arc<AgentHostInstance>) {
+		let mut instances = self.instances.lock().unwrap();
+		if let Some(i) = instances.get(&instance.workspace) {
+			if Arc::ptr_eq(i, instance) {
+				instances.remove(&instance.workspace);
 			}
 		}
+	}
 
-		// Need to start a new server
-		self.start_server().await
+	/// Makes sure the instance is the workspace's tracked one before a server
+	/// is started for it, since a failed start removes it. Returns false if
+	/// another instance has taken its place.
+	fn track_instance(&self, instance: &Arc<AgentHostInstance>) -> bool {
+		let mut instances = self.instances.lock().unwrap();
+		let tracked = instances
+			.entry(instance.workspace.clone())
+			.or_insert_with(|| instance.clone());
+		Arc::ptr_eq(tracked, instance)
 	}
 
-	/// Starts the server with the latest already-downloaded version.
-	/// Only blocks on a network fetch if no version has been downloaded yet.
-	async fn start_server(self: &Arc<Self>) -> Result<PathBuf, CodeError> {
-		let (release, server_dir) = self.get_cached_or_download().await?;
+	fn all_instances(&self) -> Vec<Arc<AgentHostInstance>> {
+		self.instances.lock().unwrap().values().cloned().collect()
+	}
 
-		let (mut barrier, opener) = new_barrier::<Result<PathBuf, String>>();
-		{
-			let mut ready = self.ready.lock().await;
-			*ready = Some(barrier.clone());
-		}
+	/// Returns the socket path to the workspace's running server, starting
+	/// one if needed.
+	pub async fn ensure_server(self: &Arc<Self>, workspace: &str) -> Result<PathBuf, CodeError> {
+		let instance = self.get_instance(workspace)?;
+		self.ensure_instance_server(&instance).await
+	}
 
-		let self_clone = self.clone();
-		let release_clone = release.clone();
-		tokio::spawn(async move {
-			self_clone
-				.run_server(release_clone, server_dir, opener)
-				.await;
-		});
+	async fn ensure_instance_server(
+		self: &Arc<Self>,
+		instance: &Arc<AgentHostInstance>,
+	) -> Result<PathBuf, CodeError> {
+		let (mut barrier, start) = {
+			let mut ready = instance.ready.lock().await;
+			let reusable = match &*ready {
+				// still starting up, or running
+				Some(b) if !b.is_open() => true,
+				Some(_) => instance.running.lock().await.is_some(),
+				None => false,
+			};
+
+			if reusable {
+				(ready.clone().unwrap(), None)
+			} else if !self.track_instance(instance) {
+				// a concurrent request already started a new one
+				let instance = self.get_instance(&instance.workspace)?;
+				drop(ready);
+				return Box::pin(self.ensure_instance_server(&instance)).await;
+			} else {
+				// Need to start a new server, unless it's crash-looping
+				let plan = match self.plan_start(&instance.workspace) {
+					Ok(p) => p,
+					Err(e) => {
+						*ready = None;
+						self.remove_instance(instance);
+						return Err(e);
+					}
+				};
+				let (barrier, opener) = new_barrier();
+				*ready = Some(barrier.clone());
+				(barrier, Some((plan, opener)))
+			}
+		};
+
+		if let Some((plan, opener)) = start {
+			self.start_server(instance, plan, opener).await;
+		}
 
 		barrier
 			.wait()
@@ -147,9 +369,45 @@ impl AgentHostManager {
 			.map_err(CodeError::ServerDownloadError)
 	}
 
+	/// Starts the server with the latest already-downloaded version, or the
+	/// fallback release if the latest one crash-looped. Only blocks on a
+	/// network fetch if no version has been downloaded yet.
+	/// Readiness, or the failure to start, is reported through `opener`.
+	async fn start_server(
+		self: &Arc<Self>,
+		instance: &Arc<AgentHostInstance>,
+		plan: PlannedStart,
+		opener: BarrierOpener<Result<PathBuf, String>>,
+	) {
+		let found = match plan.fallback {
+			Some(release) => self
+				.ensure_downloaded(&release)
+				.await
+				.map(|dir| (release, dir)),
+			None => self.get_cached_or_download().await,
+		};
+		let (release, server_dir) = match found {
+			Ok(f) => f,
+			Err(e) => {
+				opener.open(Err(e.to_string()));
+				self.remove_instance(instance);
+				return;
+			}
+		};
+
+		let self_clone = self.clone();
+		let instance = instance.clone();
+		tokio::spawn(async move {
+			self_clone
+				.run_server(&instance, release, server_dir, opener)
+				.await;
+		});
+	}
+
 	/// Runs the server process to completion, handling readiness signaling.
 	async fn run_server(
 		self: &Arc<Self>,
+		instance: &Arc<AgentHostInstance>,
 		release: Release,
 		server_dir: PathBuf,
 		opener: BarrierOpener<Result<PathBuf, String>>,
@@ -178,7 +436,7 @@ impl AgentHostManager {
 			"--enable-remote-auto-shutdown",
 		]);
 
-		if let Some(a) = &self.config.server_data_dir {
+		if let Some(a) = &instance.data_dir {
 			cmd.arg("--server-data-dir");
 			cmd.arg(a);
 		}
@@ -191,25 +449,38 @@ impl AgentHostManager {
 		}
 		cmd.env_remove("VSCODE_DEV");
 
+		let limits = self.config.limits.apply(&self.log, &mut cmd);
+
 		let mut child = match cmd.spawn() {
 			Ok(c) => c,
 			Err(e) => {
 				opener.open(Err(e.to_string()));
+				self.remove_instance(instance);
 				return;
 			}
 		};
 
-		let commit_prefix = &release.commit[..release.commit.len().min(7)];
+		let commit_prefix = format!(
+			"{} {}",
+			instance.workspace,
+			&release.commit[..release.commit.len().min(7)]
+		);
+		info!(self.log, "[{}]: Starting server", commit_prefix);
 		let (mut stdout, mut stderr) = (
 			BufReader::new(child.stdout.take().unwrap()).lines(),
 			BufReader::new(child.stderr.take().unwrap()).lines(),
 		);
 
+		let mut stderr_tail = RingBuffer::new(CRASH_STDERR_LINES);
+
 		// Wait for readiness with a timeout
 		let mut opener = Some(opener);
 		let socket_path = agent_host_socket.clone();
 		let startup_deadline = tokio::time::sleep(STARTUP_TIMEOUT);
 		tokio::pin!(startup_deadline);
+		// the output is a fast path, the probe works if its wording changes
+		let probe = wait_for_http(ProbeTarget::Socket(socket_path.clone()));
+		tokio::pin!(probe);
 
 		let mut ready = false;
 		loop {
@@ -225,6 +496,13 @@ impl AgentHostManager {
 				}
 				Ok(Some(l)) = stderr.next_line() => {
 					debug!(self.log, "[{} stderr]: {}", commit_prefix, l);
+					stderr_tail.push(l);
+				}
+				_ = &mut probe, if !ready => {
+					ready = true;
+					if let Some(o) = opener.take() {
+						o.open(Ok(socket_path.clone()));
+					}
 				}
 				_ = &mut startup_deadline, if !ready => {
 					warning!(self.log, "[{}]: Server did not become ready within {}s", commit_prefix, STARTUP_TIMEOUT.as_secs());
@@ -236,9 +514,15 @@ impl AgentHostManager {
 				}
 				e = child.wait() => {
 					info!(self.log, "[{} process]: exited: {:?}", commit_prefix, e);
+					// Pick up anything written just before the exit
+					while let Ok(Some(l)) = stderr.next_line().await {
+						stderr_tail.push(l);
+					}
+					let crash = ServerCrash::new(&release.commit, e.ok(), &limits, &stderr_tail);
 					if let Some(o) = opener.take() {
-						o.open(Err(format!("Server exited before ready: {e:?}")));
+						o.open(Err(format!("Server exited before ready: {crash}")));
 					}
+					self.record_exit(&instance.workspace, &release, None, Some(crash));
 					break;
 				}
 			}
@@ -248,25 +532,28 @@ impl AgentHostManager {
 			}
 		}
 
+		if !ready {
+			self.remove_instance(instance);
+			return;
+		}
+
 		// Store the running server state
 		{
-			let mut running = self.running.lock().await;
+			let mut running = instance.running.lock().await;
 			*running = Some(RunningServer {
 				child,
 				commit: release.commit.clone(),
+				started_at: Utc::now(),
 			});
 		}
 
-		if !ready {
-			return;
-		}
-
 		info!(self.log, "[{}]: Server ready", commit_prefix);
+		let started_at = Instant::now();
 
 		// Continue reading output until the process exits
 		let log = self.log.clone();
-		let commit_prefix = commit_prefix.to_string();
 		let self_clone = self.clone();
+		let instance = instance.clone();
 		tokio::spawn(async move {
 			loop {
 				tokio::select! {
@@ -275,22 +562,199 @@ impl AgentHostManager {
 					}
 					Ok(Some(l)) = stderr.next_line() => {
 						debug!(log, "[{} stderr]: {}", commit_prefix, l);
+						stderr_tail.push(l);
 					}
 					else => break,
 				}
 			}
 
-			// Server process has exited (auto-shutdown or crash)
+			// Server process has exited (auto-shutdown or crash). If the
+			// server is no longer tracked, it was killed deliberately.
 			info!(log, "[{}]: Server process ended", commit_prefix);
-			let mut running = self_clone.running.lock().await;
-			if let Some(r) = &*running {
-				if r.commit == commit_prefix || r.commit.starts_with(&commit_prefix) {
-					*running = None;
-				}
+			let running = instance.running.lock().await.take();
+			if let Some(mut server) = running {
+				let status = server.child.wait().await.ok();
+				let crash = match status {
+					Some(s) if s.success() => None,
+					s => Some(ServerCrash::new(&release.commit, s, &limits, &stderr_tail)),
+				};
+				self_clone.record_exit(
+					&instance.workspace,
+					&release,
+					Some(started_at.elapsed()),
+					crash,
+				);
 			}
+			self_clone.remove_instance(&instance);
 		});
 	}
 
+	/// Decides how the workspace's server should be started given its recent
+	/// crashes, failing if it's crash-looping or was restarted too recently.
+	fn plan_start(&self, workspace: &str) -> Result<PlannedStart, CodeError> {
+		let mut supervisors = self.supervisors.lock().unwrap();
+		let supervisor = match supervisors.get_mut(workspace) {
+			Some(s) => s,
+			None => return Ok(PlannedStart::default()),
+		};
+
+		if let Some(since) = supervisor.crash_loop_since {
+			let remaining = CRASH_LOOP_COOLDOWN.saturating_sub(since.elapsed());
+			if !remaining.is_zero() {
+				return Err(CodeError::AgentHostCrashLoop(
+					workspace.to_string(),
+					supervisor.consecutive,
+					remaining.as_secs().max(1),
+					supervisor
+						.crashes
+						.iter()
+						.last()
+						.map(|c| c.to_string())
+						.unwrap_or_default(),
+				));
+			}
+
+			// Give it another go after the cooldown.
+			supervisor.crash_loop_since = None;
+			supervisor.consecutive = 0;
+		}
+
+		let backoff = supervisor.backoff();
+		if !backoff.is_zero() {
+			return Err(CodeError::AgentHostRestartBackoff(
+				workspace.to_string(),
+				backoff.as_secs_f64().ceil() as u64,
+			));
+		}
+
+		Ok(PlannedStart {
+			fallback: supervisor.fallback.clone(),
+		})
+	}
+
+	/// Records the exit of a workspace's server. `uptime` is how long it ran
+	/// after becoming ready, and `crash` is set if it exited unsuccessfully.
+	fn record_exit(
+		&self,
+		workspace: &str,
+		release: &Release,
+		uptime: Option<Duration>,
+		crash: Option<ServerCrash>,
+	) {
+		let stable = uptime.map(|u| u >= STABLE_UPTIME).unwrap_or(false);
+		if stable || (uptime.is_some() && crash.is_none()) {
+			let recorded = self.known_good.update(|known_good| {
+				if !known_good.contains(&release.commit) {
+					known_good.push(release.commit.clone());
+				}
+			});
+			if let Err(e) = recorded {
+				warning!(
+					self.log,
+					"Could not record known-good server release: {}",
+					e
+				);
+			}
+		}
+
+		let mut supervisors = self.supervisors.lock().unwrap();
+		let supervisor = supervisors
+			.entry(workspace.to_string())
+			.or_insert_with(Supervisor::new);
+
+		let crash = match crash {
+			Some(c) => c,
+			None => {
+				supervisor.consecutive = 0;
+				return;
+			}
+		};
+
+		if stable {
+			supervisor.consecutive = 0;
+		}
+		supervisor.consecutive += 1;
+		supervisor.last_crash = Some(Instant::now());
+		warning!(
+			self.log,
+			"[{}]: Server crashed ({} in a row): {}",
+			workspace,
+			supervisor.consecutive,
+			crash
+		);
+		supervisor.crashes.push(crash);
+
+		if supervisor.consecutive < CRASH_LOOP_THRESHOLD {
+			return;
+		}
+
+		supervisor.failed_commits.push(release.commit.clone());
+		if self.config.fallback_on_crash_loop {
+			if let Some(fallback) = self.find_fallback_release(&supervisor.failed_commits) {
+				warning!(
+					self.log,
+					"[{}]: Server {} is crash-looping, falling back to {}",
+					workspace,
+					release.commit,
+					fallback.commit
+				);
+				supervisor.fallback = Some(fallback);
+				supervisor.consecutive = 0;
+				return;
+			}
+		}
+
+		error!(
+			self.log,
+			"[{}]: Server is crash-looping, not restarting it for {}s",
+			workspace,
+			CRASH_LOOP_COOLDOWN.as_secs()
+		);
+		supervisor.crash_loop_since = Some(Instant::now());
+	}
+
+	/// Finds a cached release to use in place of one that crash-looped,
+	/// preferring those that have run successfully. Releases that have
+	/// crash-looped themselves are skipped.
+	fn find_fallback_release(&self, failed_commits: &[String]) -> Option<Release> {
+		let quality = VSCODE_CLI_QUALITY.and_then(|q| Quality::try_from(q).ok())?;
+		let candidates = self
+			.cache
+			.get()
+			.iter()
+			.filter(|e| self.cache.path().join(e).exists())
+			.map(|e| self.cached_release(e, quality))
+			.filter(|r| !failed_commits.contains(&r.commit))
+			.collect::<Vec<_>>();
+
+		let known_good = self.known_good.load();
+		candidates
+			.iter()
+			.find(|r| known_good.contains(&r.commit))
+			.or_else(|| candidates.first())
+			.cloned()
+	}
+
+	/// Gets the release of a cache entry, which are named "<quality>-<commit>"
+	/// via get_server_folder_name.
+	fn cached_release(&self, entry: &str, default_quality: Quality) -> Release {
+		let (quality, commit) = match entry.split_once('-') {
+			Some((q, c)) => match Quality::try_from(q.to_lowercase().as_str()) {
+				Ok(parsed) => (parsed, c.to_string()),
+				Err(_) => (default_quality, entry.to_string()),
+			},
+			None => (default_quality, entry.to_string()),
+		};
+
+		Release {
+			name: String::new(),
+			commit,
+			platform: self.platform,
+			target: TargetKind::Server,
+			quality,
+		}
+	}
+
 	/// Returns a release and its local directory. Prefers the latest known
 	/// release if it has already been downloaded; otherwise falls back to any
 	/// cached version. Only fetches from the network and downloads if
@@ -324,24 +788,9 @@ impl AgentHostManager {
 			})?;
 
 		// Fall back to any cached version (still instant, just not the newest).
-		// Cache entries are named "<quality>-<commit>" via get_server_folder_name.
 		for entry in self.cache.get() {
 			if let Some(dir) = self.cache.exists(&entry) {
-				let (entry_quality, commit) = match entry.split_once('-') {
-					Some((q, c)) => match Quality::try_from(q.to_lowercase().as_str()) {
-						Ok(parsed) => (parsed, c.to_string()),
-						Err(_) => (quality, entry.clone()),
-					},
-					None => (quality, entry.clone()),
-				};
-				let release = Release {
-					name: String::new(),
-					commit,
-					platform: self.platform,
-					target: TargetKind::Server,
-					quality: entry_quality,
-				};
-				return Ok((release, dir));
+				return Ok((self.cached_release(&entry, quality), dir));
 			}
 		}
 
@@ -383,8 +832,14 @@ impl AgentHostManager {
 			.map_err(|e| CodeError::ServerDownloadError(e.to_string()))
 	}
 
-	/// Gets the latest release, caching the result.
+	/// Gets the release new servers should use, following the update policy.
 	pub async fn get_latest_release(&self) -> Result<Release, CodeError> {
+		self.resolve_release(true).await
+	}
+
+	/// Looks up the pinned or latest release. If `gated`, a release newer than
+	/// the one in use is only adopted once the update policy allows it.
+	async fn resolve_release(&self, gated: bool) -> Result<Release, CodeError> {
 		let mut latest = self.latest_release.lock().await;
 		let now = Instant::now();
 
@@ -394,11 +849,15 @@ impl AgentHostManager {
 				Quality::try_from(q).map_err(|_| CodeError::UpdatesNotConfigured("unknown quality"))
 			})?;
 
-		let result = self
-			.update_service
-			.get_latest_commit(self.platform, TargetKind::Server, quality)
-			.await
-			.map_err(|e| CodeError::UpdateCheckFailed(e.to_string()));
+		let policy = &self.config.update_policy;
+		let result = policy
+			.resolve(
+				&self.update_service,
+				self.platform,
+				TargetKind::Server,
+				quality,
+			)
+			.await;
 
 		// If the update service is unavailable, fall back to the cached version
 		if let (Err(e), Some((_, previous))) = (&result, latest.clone()) {
@@ -407,22 +866,68 @@ impl AgentHostManager {
 			return Ok(previous);
 		}
 
-		let release = result?;
+		let (release, released_at) = result?;
 		debug!(self.log, "Resolved server version: {}", release);
+
+		// Before anything's been resolved, the most recently used download is
+		// what's in use.
+		let current = match &*latest {
+			Some((_, r)) => Some(r.clone()),
+			None if policy.gates_updates() => self.most_recent_cached_release(quality),
+			None => None,
+		};
+
+		if let Some(current) = current.filter(|c| gated && c.commit != release.commit) {
+			let awaiting_approval = match policy.decide(released_at, Local::now()) {
+				UpdateDecision::Adopt => None,
+				UpdateDecision::Defer(reason) => {
+					info!(self.log, "Not updating to {} yet, as {}", release, reason);
+					Some(false)
+				}
+				UpdateDecision::AwaitApproval => {
+					info!(
+						self.log,
+						"Update to {} is waiting for approval with `{} agent-host update`",
+						release,
+						APPLICATION_NAME
+					);
+					Some(true)
+				}
+			};
+
+			if let Some(awaiting_approval) = awaiting_approval {
+				*self.pending_update.lock().unwrap() = Some(PendingUpdate {
+					release,
+					awaiting_approval,
+				});
+				*latest = Some((now, current.clone()));
+				return Ok(current);
+			}
+		}
+
+		*self.pending_update.lock().unwrap() = None;
 		*latest = Some((now, release.clone()));
 		Ok(release)
 	}
 
+	fn most_recent_cached_release(&self, quality: Quality) -> Option<Release> {
+		self.cache
+			.get()
+			.iter()
+			.find(|e| self.cache.path().join(e).exists())
+			.map(|e| self.cached_release(e, quality))
+	}
+
 	/// Background loop: checks for updates periodically and pre-downloads
 	/// new versions when the server is idle.
 	pub async fn run_update_loop(self: Arc<Self>) {
-		let mut interval = tokio::time::interval(UPDATE_CHECK_INTERVAL);
-		interval.tick().await; // skip the immediate first tick
+		let mut next_check = UPDATE_CHECK_INTERVAL;
 
 		loop {
-			interval.tick().await;
+			tokio::time::sleep(next_check).await;
+			next_check = UPDATE_CHECK_INTERVAL;
 
-			let new_release = match self.get_latest_release().await {
+			let mut new_release = match self.get_latest_release().await {
 				Ok(r) => r,
 				Err(e) => {
 					warning!(self.log, "Update check failed: {}", e);
@@ -430,6 +935,15 @@ impl AgentHostManager {
 				}
 			};
 
+			// Updates held back by the policy are checked again sooner, and
+			// ones awaiting approval are downloaded so they're ready to use.
+			let pending = self.pending_update.lock().unwrap().clone();
+			match pending {
+				Some(p) if p.awaiting_approval => new_release = p.release,
+				Some(_) => next_check = UPDATE_POLL_INTERVAL,
+				None => {}
+			}
+
 			// Check if we already have this version
 			let name = get_server_folder_name(new_release.quality, &new_release.commit);
 			if self.cache.exists(&name).is_some() {
@@ -438,13 +952,10 @@ impl AgentHostManager {
 
 			info!(self.log, "New server version available: {}", new_release);
 
-			// Wait until the server is not running before downloading
+			// Wait until no servers are running before downloading
 			loop {
-				{
-					let running = self.running.lock().await;
-					if running.is_none() {
-						break;
-					}
+				if !self.is_any_server_running().await {
+					break;
 				}
 				debug!(self.log, "Server still running, waiting before updating...");
 				tokio::time::sleep(UPDATE_POLL_INTERVAL).await;
@@ -452,17 +963,91 @@ impl AgentHostManager {
 
 			// Download the new version
 			match self.ensure_downloaded(&new_release).await {
-				Ok(_) => info!(self.log, "Updated server to {}", new_release),
+				Ok(_) => {
+					info!(self.log, "Updated server to {}", new_release);
+					// Give the new version a chance, even where the previous one crash-looped.
+					self.supervisors.lock().unwrap().clear();
+				}
 				Err(e) => warning!(self.log, "Failed to download update: {}", e),
 			}
 		}
 	}
 
-	/// Kills the currently running server, if any.
+	/// Checks for a new release and downloads it now, regardless of whether
+	/// servers are running or the update policy. Servers started afterwards
+	/// use it.
+	pub async fn update_now(&self) -> Result<UpdateResult, CodeError> {
+		let release = self.resolve_release(false).await?;
+		self.ensure_downloaded(&release).await?;
+		self.supervisors.lock().unwrap().clear();
+
+		let restart_required = self
+			.server_statuses()
+			.await
+			.iter()
+			.any(|s| s.commit != release.commit);
+		Ok(UpdateResult {
+			commit: release.commit,
+			restart_required,
+		})
+	}
+
+	/// Gets the servers that are currently running.
+	pub async fn server_statuses(&self) -> Vec<ServerStatus> {
+		let mut statuses = vec![];
+		for instance in self.all_instances() {
+			if let Some(r) = &*instance.running.lock().await {
+				statuses.push(ServerStatus {
+					workspace: instance.workspace.clone(),
+					commit: r.commit.clone(),
+					started_at: r.started_at,
+				});
+			}
+		}
+		statuses.sort_by(|a, b| a.workspace.cmp(&b.workspace));
+		statuses
+	}
+
+	/// Gets the commit of an update held back by the update policy, or of the
+	/// latest known release if it's yet to be downloaded, or if running servers
+	/// use a different one.
+	pub async fn pending_update(&self) -> Option<String> {
+		if let Some(p) = &*self.pending_update.lock().unwrap() {
+			return Some(p.release.commit.clone());
+		}
+
+		let latest = self.latest_release.lock().await.as_ref()?.1.clone();
+		let name = get_server_folder_name(latest.quality, &latest.commit);
+		let downloaded = self.cache.path().join(name).exists();
+		let outdated = self
+			.server_statuses()
+			.await
+			.iter()
+			.any(|s| s.commit != latest.commit);
+
+		if !downloaded || outdated {
+			Some(latest.commit)
+		} else {
+			None
+		}
+	}
+
+	async fn is_any_server_running(&self) -> bool {
+		for instance in self.all_instances() {
+			if instance.running.lock().await.is_some() {
+				return true;
+			}
+		}
+		false
+	}
+
+	/// Kills all running servers.
 	pub async fn kill_running_server(&self) {
-		let mut running = self.running.lock().await;
-		if let Some(mut server) = running.take() {
-			let _ = server.child.kill().await;
+		for instance in self.all_instances() {
+			let mut running = instance.running.lock().await;
+			if let Some(mut server) = running.take() {
+				let _ = server.child.kill().await;
+			}
 		}
 	}
 }
@@ -470,18 +1055,60 @@ impl AgentHostManager {
 // ---- HTTP/WebSocket proxy ---------------------------------------------------
 
 /// Proxies an incoming HTTP/WebSocket request to the agent host's Unix socket.
+/// `remote_addr` is the address of the client, if known, for access logging.
 pub async fn handle_request(
 	manager: Arc<AgentHostManager>,
+	remote_addr: Option<SocketAddr>,
 	req: Request<Body>,
 ) -> Result<Response<Body>, Infallible> {
-	let socket_path = match manager.ensure_server().await {
+	let entry = manager
+		.config
+		.access_log
+		.as_ref()
+		.map(|l| l.begin(remote_addr, &req));
+
+	let res = proxy_request(&manager, req).await;
+
+	Ok(match entry {
+		Some(e) => e.finish(res),
+		None => res,
+	})
+}
+
+async fn proxy_request(manager: &Arc<AgentHostManager>, mut req: Request<Body>) -> Response<Body> {
+	let workspace = match route_workspace(&mut req) {
+		Ok(w) => w,
+		Err(e) => return Response::builder().status(400).body(Body::from(e)).unwrap(),
+	};
+
+	let instance = match manager.get_instance(&workspace) {
+		Ok(i) => i,
+		Err(e) => {
+			return Response::builder()
+				.status(503)
+				.body(Body::from(e.to_string()))
+				.unwrap()
+		}
+	};
+
+	let socket_path = match manager.ensure_instance_server(&instance).await {
 		Ok(p) => p,
+		Err(
+			e @ (CodeError::AgentHostCrashLoop(_, _, retry_after, _)
+			| CodeError::AgentHostRestartBackoff(_, retry_after)),
+		) => {
+			return Response::builder()
+				.status(503)
+				.header(hyper::header::RETRY_AFTER, retry_after)
+				.body(Body::from(e.to_string()))
+				.unwrap();
+		}
 		Err(e) => {
 			error!(manager.log, "Error starting agent host: {:?}", e);
-			return Ok(Response::builder()
+			return Response::builder()
 				.status(503)
 				.body(Body::from(format!("Error starting agent host: {e:?}")))
-				.unwrap());
+				.unwrap();
 		}
 	};
 
@@ -494,18 +1121,30 @@ pub async fn handle_request(
 				manager.log,
 				"Error connecting to agent host socket: {:?}", e
 			);
-			return Ok(Response::builder()
+			return Response::builder()
 				.status(503)
 				.body(Body::from(format!("Error connecting to agent host: {e:?}")))
-				.unwrap());
+				.unwrap();
 		}
 	};
 
-	if is_upgrade {
-		Ok(forward_ws_to_server(rw, req).await)
+	let commit = instance
+		.running
+		.lock()
+		.await
+		.as_ref()
+		.map(|r| RoutedCommit(r.commit.clone()));
+
+	let mut res = if is_upgrade {
+		forward_ws_to_server(rw, req).await
 	} else {
-		Ok(forward_http_to_server(rw, req).await)
+		forward_http_to_server(rw, req).await
+	};
+
+	if let Some(commit) = commit {
+		res.extensions_mut().insert(commit);
 	}
+	res
 }
 
 /// Proxies a standard HTTP request through the socket.
@@ -572,3 +1211,253 @@ fn connection_err(err: hyper::Error) -> Response<Body> {
 		)))
 		.unwrap()
 }
+
+/// Gets the workspace a request is routed to, from the workspace header or
+/// a `/workspace/<name>/` path prefix, which is removed from the request.
+fn route_workspace(req: &mut Request<Body>) -> Result<String, String> {
+	if let Some(value) = req.headers().get(WORKSPACE_HEADER) {
+		let name = value.to_str().unwrap_or_default();
+		return validate_workspace_name(name).map(|n| n.to_string());
+	}
+
+	let path = req.uri().path();
+	let rest = match path.strip_prefix(WORKSPACE_PATH_PREFIX) {
+		Some(r) => r,
+		None => return Ok(DEFAULT_WORKSPACE.to_string()),
+	};
+
+	let (name, rest) = match rest.find('/') {
+		Some(i) => rest.split_at(i),
+		None => (rest, "/"),
+	};
+	let name = validate_workspace_name(name)?.to_string();
+
+	let new_uri = match req.uri().query() {
+		Some(q) => format!("{rest}?{q}"),
+		None => rest.to_string(),
+	};
+	*req.uri_mut() = new_uri
+		.parse()
+		.map_err(|_| "Invalid request path".to_string())?;
+
+	Ok(name)
+}
+
+fn validate_workspace_name(name: &str) -> Result<&str, String> {
+	let valid = !name.is_empty()
+		&& name.len() <= MAX_WORKSPACE_NAME_LEN
+		&& !name.starts_with('.')
+		&& name
+			.chars()
+			.all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+
+	if valid {
+		Ok(name)
+	} else {
+		Err(format!("Invalid workspace name '{name}'"))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::util::http::ReqwestSimpleHttp;
+
+	fn test_manager(dir: &std::path::Path) -> Arc<AgentHostManager> {
+		AgentHostManager::new(
+			log::Logger::test(),
+			Platform::LinuxX64,
+			DownloadCache::new(dir.to_path_buf()),
+			Arc::new(ReqwestSimpleHttp::new()),
+			AgentHostConfig {
+				server_data_dir: None,
+				workspaces_data_dir: dir.join("workspaces"),
+				max_workspaces: DEFAULT_MAX_WORKSPACES,
+				without_connection_token: true,
+				connection_token: None,
+				connection_token_file: None,
+				access_log: None,
+				fallback_on_crash_loop: false,
+				known_good_file: dir.join("known-good.json"),
+				update_policy: UpdatePolicy::default(),
+				limits: ResourceLimits::default(),
+			},
+		)
+	}
+
+	fn backoff(manager: &AgentHostManager, workspace: &str) -> Duration {
+		manager.supervisors.lock().unwrap()[workspace].backoff()
+	}
+
+	fn crash(commit: &str) -> ServerCrash {
+		ServerCrash {
+			commit: commit.to_string(),
+			exit_code: Some(1),
+			out_of_memory: None,
+			stderr: vec!["Error: boom".to_string()],
+		}
+	}
+
+	#[test]
+	fn test_backoff_grows_until_crash_loop() {
+		let dir = tempfile::tempdir().unwrap();
+		let manager = test_manager(dir.path());
+		let release = manager.cached_release("stable-abc", Quality::Stable);
+
+		assert!(manager.plan_start("ws").is_ok());
+
+		let mut last_delay = Duration::ZERO;
+		for _ in 1..CRASH_LOOP_THRESHOLD {
+			manager.record_exit("ws", &release, None, Some(crash("abc")));
+			let delay = backoff(&manager, "ws");
+			assert!(delay > last_delay);
+			last_delay = delay;
+			match manager.plan_start("ws") {
+				Err(CodeError::AgentHostRestartBackoff(w, retry_after)) => {
+					assert_eq!(w, "ws");
+					assert!(retry_after >= 1);
+				}
+				_ => panic!("expected restart backoff"),
+			}
+		}
+
+		manager.record_exit("ws", &release, None, Some(crash("abc")));
+		match manager.plan_start("ws") {
+			Err(CodeError::AgentHostCrashLoop(w, n, _, last)) => {
+				assert_eq!(w, "ws");
+				assert_eq!(n, CRASH_LOOP_THRESHOLD);
+				assert!(last.contains("exited with code 1"));
+				assert!(last.contains("Error: boom"));
+			}
+			_ => panic!("expected crash loop"),
+		}
+
+		// other workspaces are unaffected
+		assert!(manager.plan_start("other").is_ok());
+	}
+
+	#[test]
+	fn test_clean_exit_resets_backoff() {
+		let dir = tempfile::tempdir().unwrap();
+		let manager = test_manager(dir.path());
+		let release = manager.cached_release("stable-abc", Quality::Stable);
+
+		manager.record_exit("ws", &release, None, Some(crash("abc")));
+		manager.record_exit("ws", &release, None, Some(crash("abc")));
+		assert!(!backoff(&manager, "ws").is_zero());
+
+		manager.record_exit("ws", &release, Some(Duration::from_secs(1)), None);
+		assert!(backoff(&manager, "ws").is_zero());
+		assert!(manager.plan_start("ws").is_ok());
+		assert_eq!(manager.known_good.load(), vec!["abc".to_string()]);
+
+		// known-good releases are remembered across restarts
+		let restarted = test_manager(dir.path());
+		assert_eq!(restarted.known_good.load(), vec!["abc".to_string()]);
+	}
+
+	/// Caches a fake server for the release that runs `script`, and makes the
+	/// workspace use it.
+	#[cfg(unix)]
+	fn fake_server(manager: &AgentHostManager, workspace: &str, script: &str) {
+		use std::os::unix::fs::PermissionsExt;
+
+		let release = manager.cached_release("stable-abc", Quality::Stable);
+		let bin = manager
+			.cache
+			.path()
+			.join(get_server_folder_name(release.quality, &release.commit))
+			.join(SERVER_FOLDER_NAME)
+			.join("bin");
+		std::fs::create_dir_all(&bin).unwrap();
+		let entrypoint = bin.join(release.quality.server_entrypoint());
+		std::fs::write(&entrypoint, format!("#!/bin/sh\n{script}\n")).unwrap();
+		std::fs::set_permissions(&entrypoint, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+		let mut supervisor = Supervisor::new();
+		supervisor.fallback = Some(release);
+		manager
+			.supervisors
+			.lock()
+			.unwrap()
+			.insert(workspace.to_string(), supervisor);
+	}
+
+	#[cfg(unix)]
+	#[tokio::test]
+	async fn test_concurrent_requests_start_one_server() {
+		let dir = tempfile::tempdir().unwrap();
+		let manager = test_manager(dir.path());
+		let starts = dir.path().join("starts");
+		fake_server(
+			&manager,
+			"ws",
+			&format!(
+				"echo started >> '{}'\necho 'Agent host server listening on'\nexec sleep 30",
+				starts.display()
+			),
+		);
+
+		let results = futures::future::join_all((0..3).map(|_| manager.ensure_server("ws"))).await;
+		manager.kill_running_server().await;
+
+		let paths = results.into_iter().map(|r| r.unwrap()).collect::<Vec<_>>();
+		assert!(paths.iter().all(|p| p == &paths[0]));
+		assert_eq!(std::fs::read_to_string(&starts).unwrap(), "started\n");
+	}
+
+	#[cfg(unix)]
+	#[tokio::test]
+	async fn test_failed_start_is_forgotten() {
+		let dir = tempfile::tempdir().unwrap();
+		let manager = test_manager(dir.path());
+		fake_server(&manager, "ws", "echo 'Error: boom' >&2\nexit 1");
+
+		let results = futures::future::join_all((0..3).map(|_| manager.ensure_server("ws"))).await;
+		for r in results {
+			match r {
+				Err(CodeError::ServerDownloadError(e)) => assert!(e.contains("Error: boom")),
+				r => panic!("expected start failure, got {:?}", r),
+			}
+		}
+		assert!(manager.all_instances().is_empty());
+
+		// the failure was recorded once, not once for each request
+		let supervisors = manager.supervisors.lock().unwrap();
+		assert_eq!(supervisors.get("ws").unwrap().consecutive, 1);
+	}
+
+	fn route(uri: &str, header: Option<&str>) -> Result<(String, String), String> {
+		let mut builder = Request::builder().uri(uri);
+		if let Some(h) = header {
+			builder = builder.header(WORKSPACE_HEADER, h);
+		}
+		let mut req = builder.body(Body::empty()).unwrap();
+		let workspace = route_workspace(&mut req)?;
+		Ok((workspace, req.uri().to_string()))
+	}
+
+	#[test]
+	fn test_route_workspace() {
+		assert_eq!(
+			route("/foo?a=1", None).unwrap(),
+			(DEFAULT_WORKSPACE.to_string(), "/foo?a=1".to_string())
+		);
+		assert_eq!(
+			route("/workspace/proj-1/foo/bar?a=1", None).unwrap(),
+			("proj-1".to_string(), "/foo/bar?a=1".to_string())
+		);
+		assert_eq!(
+			route("/workspace/proj-1", None).unwrap(),
+			("proj-1".to_string(), "/".to_string())
+		);
+		assert_eq!(
+			route("/workspace/other/foo", Some("alice")).unwrap(),
+			("alice".to_string(), "/workspace/other/foo".to_string())
+		);
+
+		assert!(route("/workspace/../foo", None).is_err());
+		assert!(route("/workspace//foo", None).is_err());
+		assert!(route("/foo", Some("a/b")).is_err());
+	}
+}
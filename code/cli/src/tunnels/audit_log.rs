@@ -0,0 +1,419 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	fs,
+	io::{self, Write},
+	path::Path,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		mpsc, Arc,
+	},
+	thread,
+	time::Instant,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	state::LauncherPaths,
+	util::rotating_file::{rotated_path, RotatingFile},
+};
+
+/// Size at which the audit log is rotated.
+const AUDIT_LOG_MAX_SIZE: u64 = 10 * 1024 * 1024;
+/// Number of rotated audit logs to keep.
+pub const AUDIT_LOG_MAX_FILES: usize = 5;
+
+/// Record of security-relevant activity on the control server, written as
+/// JSON lines. Records are written by a background thread, so that logging
+/// never blocks the connections being audited.
+#[derive(Clone, Debug)]
+pub struct AuditLog {
+	tx: mpsc::Sender<WriterMessage>,
+	session: Arc<str>,
+	next_connection: Arc<AtomicU64>,
+}
+
+#[derive(Debug)]
+enum WriterMessage {
+	Record(AuditRecord),
+	Flush(mpsc::Sender<()>),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AuditRecord {
+	pub time: DateTime<Utc>,
+	/// Random ID of the process that served the connection. Connection IDs
+	/// start over each time the tunnel starts, so this tells them apart.
+	pub session: String,
+	/// Connection the event happened on. For the tunnel's connections, this
+	/// matches the connection IDs in the tunnel status.
+	pub connection: u64,
+	/// Identity of the client, such as its address, if it's known.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub client: Option<String>,
+	#[serde(flatten)]
+	pub event: AuditEvent,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+	Connect {
+		/// How the client is authenticated.
+		auth: ConnectAuth,
+	},
+	/// A step of the challenge flow that authenticates the client.
+	Auth { outcome: AuthOutcome },
+	Rpc {
+		method: String,
+		target: String,
+		/// Whether the call was allowed, which it's not before the client
+		/// is authenticated.
+		allowed: bool,
+	},
+	Disconnect {
+		bytes_rx: u64,
+		bytes_tx: u64,
+		duration_ms: u64,
+	},
+}
+
+impl AuditEvent {
+	pub fn kind(&self) -> AuditEventKind {
+		match self {
+			AuditEvent::Connect { .. } => AuditEventKind::Connect,
+			AuditEvent::Auth { .. } => AuditEventKind::Auth,
+			AuditEvent::Rpc { .. } => AuditEventKind::Rpc,
+			AuditEvent::Disconnect { .. } => AuditEventKind::Disconnect,
+		}
+	}
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditEventKind {
+	Connect,
+	Auth,
+	Rpc,
+	Disconnect,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectAuth {
+	/// The client was authenticated by the tunnel relay.
+	Relay,
+	/// The client connected locally, such as over a socket, and wasn't asked
+	/// to authenticate.
+	None,
+	/// The client must complete a challenge.
+	Challenge,
+	/// The client must present a token and complete a challenge.
+	ChallengeWithToken,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthOutcome {
+	ChallengeIssued,
+	BadToken,
+	/// The client tried to verify a challenge that wasn't issued.
+	NotIssued,
+	Verified,
+	Failed,
+}
+
+impl AuditLog {
+	pub fn open(paths: &LauncherPaths) -> io::Result<Self> {
+		let file = RotatingFile::open_private(
+			&paths.audit_log_file(),
+			AUDIT_LOG_MAX_SIZE,
+			AUDIT_LOG_MAX_FILES,
+		)?;
+		Ok(Self::new(file))
+	}
+
+	fn new(mut file: RotatingFile) -> Self {
+		let (tx, rx) = mpsc::channel::<WriterMessage>();
+		// the thread exits once every handle to the log is dropped
+		thread::spawn(move || {
+			for message in rx {
+				match message {
+					WriterMessage::Record(r) => {
+						let mut line = serde_json::to_string(&r).unwrap();
+						line.push('\n');
+						// ignore any errors, not much we can do if logging fails...
+						file.write_all(line.as_bytes()).ok();
+					}
+					WriterMessage::Flush(done) => {
+						file.flush().ok();
+						done.send(()).ok();
+					}
+				}
+			}
+		});
+
+		Self {
+			tx,
+			session: uuid::Uuid::new_v4().to_string().into(),
+			next_connection: Arc::new(AtomicU64::new(0)),
+		}
+	}
+
+	/// Records a new connection, and returns a handle to record events on it.
+	/// Connections without an ID, such as those not counted in the tunnel's
+	/// traffic, are given one.
+	pub fn connect(
+		&self,
+		connection: Option<u64>,
+		client: Option<String>,
+		auth: ConnectAuth,
+	) -> AuditConnection {
+		let c = AuditConnection {
+			log: self.clone(),
+			connection: connection
+				.unwrap_or_else(|| self.next_connection.fetch_add(1, Ordering::SeqCst)),
+			client,
+			started: Instant::now(),
+		};
+		c.record(AuditEvent::Connect { auth });
+		c
+	}
+
+	/// Waits until every record sent so far is written.
+	pub fn flush(&self) {
+		let (done_tx, done_rx) = mpsc::channel();
+		if self.tx.send(WriterMessage::Flush(done_tx)).is_ok() {
+			done_rx.recv().ok();
+		}
+	}
+
+	fn write(&self, record: AuditRecord) {
+		self.tx.send(WriterMessage::Record(record)).ok();
+	}
+}
+
+/// Records events on a single connection.
+#[derive(Clone, Debug)]
+pub struct AuditConnection {
+	log: AuditLog,
+	connection: u64,
+	client: Option<String>,
+	started: Instant,
+}
+
+impl AuditConnection {
+	pub fn auth(&self, outcome: AuthOutcome) {
+		self.record(AuditEvent::Auth { outcome });
+	}
+
+	pub fn rpc(&self, method: &str, target: String, allowed: bool) {
+		self.record(AuditEvent::Rpc {
+			method: method.to_string(),
+			target,
+			allowed,
+		});
+	}
+
+	/// Records that the connection closed after transferring the given bytes.
+	pub fn disconnect(&self, bytes_rx: u64, bytes_tx: u64) {
+		self.record(AuditEvent::Disconnect {
+			bytes_rx,
+			bytes_tx,
+			duration_ms: self.started.elapsed().as_millis() as u64,
+		});
+	}
+
+	fn record(&self, event: AuditEvent) {
+		self.log.write(AuditRecord {
+			time: Utc::now(),
+			session: self.log.session.to_string(),
+			connection: self.connection,
+			client: self.client.clone(),
+			event,
+		});
+	}
+}
+
+/// Selects records in the audit log.
+#[derive(Clone, Debug, Default)]
+pub struct AuditFilter {
+	pub since: Option<DateTime<Utc>>,
+	pub event: Option<AuditEventKind>,
+	/// Only selects RPC calls of this method.
+	pub method: Option<String>,
+	pub connection: Option<u64>,
+}
+
+impl AuditFilter {
+	pub fn matches(&self, record: &AuditRecord) -> bool {
+		self.since.map(|s| record.time >= s).unwrap_or(true)
+			&& self
+				.connection
+				.map(|c| record.connection == c)
+				.unwrap_or(true)
+			&& self.event.map(|e| record.event.kind() == e).unwrap_or(true)
+			&& match (&self.method, &record.event) {
+				(None, _) => true,
+				(Some(m), AuditEvent::Rpc { method, .. }) => m == method,
+				(Some(_), _) => false,
+			}
+	}
+}
+
+/// Reads the records in the audit log and its rotated files, oldest first.
+/// Lines that can't be parsed are skipped.
+pub fn read_records(path: &Path) -> Vec<AuditRecord> {
+	let mut files = vec![path.to_owned()];
+	files.extend((1..=AUDIT_LOG_MAX_FILES).map(|n| rotated_path(path, n)));
+
+	files
+		.iter()
+		.rev()
+		.filter_map(|f| fs::read_to_string(f).ok())
+		.flat_map(|contents| {
+			contents
+				.lines()
+				.filter_map(|l| serde_json::from_str(l).ok())
+				.collect::<Vec<_>>()
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_records_round_trip() {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let log = AuditLog::open(&paths).unwrap();
+
+		let c = log.connect(Some(3), None, ConnectAuth::Challenge);
+		c.auth(AuthOutcome::Verified);
+		c.rpc("fs_write", "/tmp/a".to_string(), true);
+		c.disconnect(10, 20);
+		let other = log.connect(None, Some("127.0.0.1:5000".to_string()), ConnectAuth::Relay);
+		log.flush();
+
+		let records = read_records(&paths.audit_log_file());
+		assert_eq!(records.len(), 5);
+		assert!(records[..4].iter().all(|r| r.connection == 3));
+		assert!(records.iter().all(|r| r.session == *log.session));
+		assert_eq!(
+			records[0].event,
+			AuditEvent::Connect {
+				auth: ConnectAuth::Challenge
+			}
+		);
+		assert_eq!(
+			records[2].event,
+			AuditEvent::Rpc {
+				method: "fs_write".to_string(),
+				target: "/tmp/a".to_string(),
+				allowed: true,
+			}
+		);
+		assert!(matches!(
+			records[3].event,
+			AuditEvent::Disconnect {
+				bytes_rx: 10,
+				bytes_tx: 20,
+				..
+			}
+		));
+		assert_eq!(records[4].connection, other.connection);
+		assert_eq!(records[4].client.as_deref(), Some("127.0.0.1:5000"));
+
+		let line = fs::read_to_string(paths.audit_log_file()).unwrap();
+		assert!(line.starts_with("{\"time\":"));
+		assert!(line.contains("\"event\":\"connect\",\"auth\":\"challenge\""));
+	}
+
+	#[test]
+	fn test_reads_rotated_files_in_order() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("audit.jsonl");
+		let log = AuditLog::new(RotatingFile::open_private(&path, 500, 5).unwrap());
+		let c = log.connect(Some(0), None, ConnectAuth::Relay);
+		for i in 0..6 {
+			c.rpc("sys_kill", i.to_string(), true);
+		}
+		log.flush();
+
+		assert!(rotated_path(&path, 2).exists());
+		let targets: Vec<String> = read_records(&path)
+			.into_iter()
+			.filter_map(|r| match r.event {
+				AuditEvent::Rpc { target, .. } => Some(target),
+				_ => None,
+			})
+			.collect();
+		assert_eq!(targets, vec!["0", "1", "2", "3", "4", "5"]);
+	}
+
+	#[test]
+	fn test_filter() {
+		let at = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc);
+		let record = |time: &str, connection: u64, event: AuditEvent| AuditRecord {
+			time: at(time),
+			session: "s".to_string(),
+			connection,
+			client: None,
+			event,
+		};
+		let rpc = |method: &str| AuditEvent::Rpc {
+			method: method.to_string(),
+			target: String::new(),
+			allowed: true,
+		};
+		let records = [
+			record(
+				"2026-01-01T00:00:00Z",
+				0,
+				AuditEvent::Connect {
+					auth: ConnectAuth::Relay,
+				},
+			),
+			record("2026-01-01T00:01:00Z", 0, rpc("spawn")),
+			record("2026-01-01T00:02:00Z", 1, rpc("fs_rm")),
+		];
+		let count = |f: AuditFilter| records.iter().filter(|r| f.matches(r)).count();
+
+		assert_eq!(count(AuditFilter::default()), 3);
+		assert_eq!(
+			count(AuditFilter {
+				since: Some(at("2026-01-01T00:01:00Z")),
+				..Default::default()
+			}),
+			2
+		);
+		assert_eq!(
+			count(AuditFilter {
+				event: Some(AuditEventKind::Rpc),
+				..Default::default()
+			}),
+			2
+		);
+		assert_eq!(
+			count(AuditFilter {
+				method: Some("spawn".to_string()),
+				..Default::default()
+			}),
+			1
+		);
+		assert_eq!(
+			count(AuditFilter {
+				connection: Some(0),
+				event: Some(AuditEventKind::Rpc),
+				..Default::default()
+			}),
+			1
+		);
+	}
+}
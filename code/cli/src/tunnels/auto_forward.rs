@@ -0,0 +1,779 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	collections::{HashMap, HashSet},
+	fs,
+	net::{IpAddr, Ipv6Addr},
+	path::Path,
+	time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use crate::{
+	log,
+	util::{errors::CodeError, sync::Barrier},
+};
+
+use super::{
+	dev_tunnels::StatusLock,
+	port_forwarder::{ForwardOptions, PortForwarding},
+	protocol::{
+		singleton::AutoForwardedPort, AccessProvider, PortAccess, PortPrivacy, PortProtocol,
+	},
+	shutdown_signal::ShutdownSignal,
+};
+
+/// How often listening sockets are checked.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// How often forwarding a port is retried after it failed.
+const FORWARD_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Rules that decide which listening ports are forwarded automatically. The
+/// first rule that matches a port applies.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct AutoForwardRules {
+	#[serde(default)]
+	pub rules: Vec<AutoForwardRule>,
+	/// Ports and processes that are never forwarded.
+	#[serde(default)]
+	pub ignore: IgnoreList,
+	/// Whether ports no rule matches are forwarded privately.
+	#[serde(default = "default_true")]
+	pub forward_unmatched: bool,
+}
+
+fn default_true() -> bool {
+	true
+}
+
+impl Default for AutoForwardRules {
+	fn default() -> Self {
+		Self {
+			rules: vec![],
+			ignore: IgnoreList::default(),
+			forward_unmatched: true,
+		}
+	}
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct IgnoreList {
+	#[serde(default)]
+	pub ports: Vec<PortRange>,
+	#[serde(default)]
+	pub processes: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct AutoForwardRule {
+	/// Ports the rule applies to. Applies to all ports if not set.
+	pub ports: Option<PortRange>,
+	/// Process name the rule applies to, matched exactly, or by prefix if it
+	/// ends in `*`. Applies to all processes if not set.
+	pub process: Option<String>,
+	/// Skips matching ports instead of forwarding them.
+	#[serde(default)]
+	pub ignore: bool,
+	#[serde(default = "default_privacy")]
+	pub privacy: PortPrivacy,
+	#[serde(default)]
+	pub protocol: PortProtocol,
+	#[serde(default)]
+	pub labels: Vec<String>,
+	/// Further restricts who may connect to matching ports.
+	#[serde(default, deserialize_with = "deserialize_access")]
+	pub access: PortAccess,
+}
+
+/// Reads a rule's [PortAccess] with kebab-case keys, like the rest of the
+/// file, rather than the camelCase it has in the protocol.
+fn deserialize_access<'de, D>(deserializer: D) -> Result<PortAccess, D::Error>
+where
+	D: serde::Deserializer<'de>,
+{
+	#[derive(Deserialize)]
+	#[serde(rename_all = "kebab-case")]
+	struct AccessRule {
+		#[serde(default)]
+		provider: AccessProvider,
+		organization: Option<String>,
+		#[serde(default)]
+		users: Vec<String>,
+		expires_at: Option<DateTime<Utc>>,
+	}
+
+	let a = AccessRule::deserialize(deserializer)?;
+	Ok(PortAccess {
+		provider: a.provider,
+		organization: a.organization,
+		users: a.users,
+		expires_at: a.expires_at,
+	})
+}
+
+fn default_privacy() -> PortPrivacy {
+	PortPrivacy::Private
+}
+
+/// A single port or an inclusive range, given as a number or `"start-end"`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "PortRangeDef")]
+pub struct PortRange {
+	pub start: u16,
+	pub end: u16,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PortRangeDef {
+	Port(u16),
+	Range(String),
+}
+
+impl TryFrom<PortRangeDef> for PortRange {
+	type Error = String;
+
+	fn try_from(def: PortRangeDef) -> Result<Self, Self::Error> {
+		let s = match def {
+			PortRangeDef::Port(p) => return Ok(Self { start: p, end: p }),
+			PortRangeDef::Range(s) => s,
+		};
+
+		let parse = |p: &str| {
+			p.trim()
+				.parse::<u16>()
+				.map_err(|_| format!("invalid port range '{s}'"))
+		};
+		let (start, end) = match s.split_once('-') {
+			Some((a, b)) => (parse(a)?, parse(b)?),
+			None => (parse(&s)?, parse(&s)?),
+		};
+		if start > end {
+			return Err(format!("invalid port range '{s}'"));
+		}
+		Ok(Self { start, end })
+	}
+}
+
+impl PortRange {
+	fn contains(&self, port: u16) -> bool {
+		self.start <= port && port <= self.end
+	}
+}
+
+fn matches_process(pattern: &str, process: Option<&str>) -> bool {
+	let process = match process {
+		Some(p) => p,
+		None => return false,
+	};
+	match pattern.strip_suffix('*') {
+		Some(prefix) => process.starts_with(prefix),
+		None => process == pattern,
+	}
+}
+
+impl AutoForwardRules {
+	/// Reads the file, as JSON if it has a `.json` extension or TOML otherwise.
+	pub fn read(path: &Path) -> Result<Self, CodeError> {
+		let err = |e: String| CodeError::CouldNotLoadConfigFile(path.display().to_string(), e);
+		let contents = fs::read_to_string(path).map_err(|e| err(e.to_string()))?;
+		if path.extension().and_then(|e| e.to_str()) == Some("json") {
+			serde_json::from_str(&contents).map_err(|e| err(e.to_string()))
+		} else {
+			toml::from_str(&contents).map_err(|e| err(e.to_string()))
+		}
+	}
+
+	/// Decides how to forward a port, returning None if it shouldn't be.
+	pub fn decide(&self, port: u16, process: Option<&str>) -> Option<ForwardOptions> {
+		if self.ignore.ports.iter().any(|r| r.contains(port))
+			|| self
+				.ignore
+				.processes
+				.iter()
+				.any(|p| matches_process(p, process))
+		{
+			return None;
+		}
+
+		let rule = self.rules.iter().find(|r| {
+			r.ports.map(|p| p.contains(port)).unwrap_or(true)
+				&& r.process
+					.as_deref()
+					.map(|p| matches_process(p, process))
+					.unwrap_or(true)
+		});
+
+		match rule {
+			Some(r) if r.ignore => None,
+			Some(r) => Some(ForwardOptions {
+				privacy: r.privacy,
+				protocol: r.protocol,
+				labels: r.labels.clone(),
+				access: r.access.clone(),
+			}),
+			None if self.forward_unmatched => Some(ForwardOptions::new(PortPrivacy::Private)),
+			None => None,
+		}
+	}
+}
+
+/// A socket listening for TCP connections.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListeningSocket {
+	pub port: u16,
+	pub inode: u64,
+}
+
+/// Parses the contents of `/proc/net/tcp` or `/proc/net/tcp6`, returning
+/// sockets owned by the user that listen on a loopback or any address, since
+/// only those can be reached through the tunnel.
+pub fn parse_proc_net_tcp(contents: &str, uid: u32) -> Vec<ListeningSocket> {
+	const TCP_LISTEN: &str = "0A";
+
+	contents
+		.lines()
+		.skip(1)
+		.filter_map(|line| {
+			let fields: Vec<&str> = line.split_whitespace().collect();
+			if fields.len() < 10 || fields[3] != TCP_LISTEN {
+				return None;
+			}
+			if fields[7].parse::<u32>().ok()? != uid {
+				return None;
+			}
+
+			let (addr, port) = fields[1].split_once(':')?;
+			if !is_local_address(addr) {
+				return None;
+			}
+
+			Some(ListeningSocket {
+				port: u16::from_str_radix(port, 16).ok()?,
+				inode: fields[9].parse().ok()?,
+			})
+		})
+		.collect()
+}
+
+/// Whether a hex-encoded address from /proc/net/tcp{,6} is the unspecified
+/// or a loopback address. The kernel writes each 32-bit word in host order.
+fn is_local_address(addr: &str) -> bool {
+	let bytes: Vec<u8> = (0..addr.len() / 8)
+		.filter_map(|i| addr.get(i * 8..i * 8 + 8))
+		.filter_map(|w| u32::from_str_radix(w, 16).ok())
+		.flat_map(|w| w.to_ne_bytes())
+		.collect();
+
+	let ip = if let Ok(v4) = <[u8; 4]>::try_from(bytes.as_slice()) {
+		IpAddr::from(v4)
+	} else if let Ok(v6) = <[u8; 16]>::try_from(bytes.as_slice()) {
+		let v6 = Ipv6Addr::from(v6);
+		v6.to_ipv4_mapped()
+			.map(IpAddr::V4)
+			.unwrap_or(IpAddr::V6(v6))
+	} else {
+		return false;
+	};
+
+	ip.is_unspecified() || ip.is_loopback()
+}
+
+/// A port that was forwarded automatically.
+struct Forwarded {
+	status: AutoForwardedPort,
+	/// Whether the port was forwarded, so it should be unforwarded once
+	/// nothing listens on it.
+	active: bool,
+	/// When forwarding the port last failed, if it did.
+	failed_at: Option<Instant>,
+}
+
+/// Forwards ports as they start being listened on, and unforwards them once
+/// nothing listens on them anymore. Only ports it forwarded itself are
+/// unforwarded.
+struct AutoForwarder {
+	log: log::Logger,
+	rules: AutoForwardRules,
+	forwarding: PortForwarding,
+	forwarded: HashMap<u16, Forwarded>,
+	/// Ports that were already forwarded, by a client, when they were found.
+	/// They're left alone.
+	external: HashSet<u16>,
+}
+
+impl AutoForwarder {
+	fn new(log: log::Logger, rules: AutoForwardRules, forwarding: PortForwarding) -> Self {
+		Self {
+			log,
+			rules,
+			forwarding,
+			forwarded: HashMap::new(),
+			external: HashSet::new(),
+		}
+	}
+
+	/// Updates what's forwarded given the ports that are listened on and their
+	/// processes. Returns whether anything changed.
+	async fn update(&mut self, listening: &HashMap<u16, Option<String>>) -> bool {
+		let mut changed = false;
+		self.external.retain(|p| listening.contains_key(p));
+
+		for port in self.forwarded.keys().copied().collect::<Vec<_>>() {
+			if listening.contains_key(&port) {
+				continue;
+			}
+			changed = true;
+			let f = self.forwarded.remove(&port).unwrap();
+			if f.active {
+				match self.forwarding.unforward(port).await {
+					Ok(()) => info!(
+						self.log,
+						"Stopped forwarding port {}, nothing listens on it", port
+					),
+					Err(e) => warning!(self.log, "Error unforwarding port {}: {}", port, e),
+				}
+			}
+		}
+
+		for (port, process) in listening {
+			let port = *port;
+			if self.external.contains(&port) {
+				continue;
+			}
+			if let Some(f) = self.forwarded.get(&port) {
+				// failed forwards are retried every so often
+				if !matches!(f.failed_at, Some(t) if t.elapsed() >= FORWARD_RETRY_INTERVAL) {
+					continue;
+				}
+			}
+
+			let options = match self.rules.decide(port, process.as_deref()) {
+				Some(o) => o,
+				None => {
+					debug!(
+						self.log,
+						"Not forwarding port {} ({:?}) due to rules", port, process
+					);
+					continue;
+				}
+			};
+
+			let (uri, error) = match self.forwarding.forward_new(port, options.clone()).await {
+				Ok(Some(uri)) => {
+					info!(self.log, "Automatically forwarded port {} at {}", port, uri);
+					(Some(uri), None)
+				}
+				Ok(None) => {
+					debug!(self.log, "Port {} is already forwarded by a client", port);
+					changed |= self.forwarded.remove(&port).is_some();
+					self.external.insert(port);
+					continue;
+				}
+				Err(e) => {
+					warning!(self.log, "Error forwarding port {}: {}", port, e);
+					(None, Some(e.to_string()))
+				}
+			};
+
+			changed = true;
+			self.forwarded.insert(
+				port,
+				Forwarded {
+					active: error.is_none(),
+					failed_at: error.as_ref().map(|_| Instant::now()),
+					status: AutoForwardedPort {
+						port,
+						process: process.clone(),
+						privacy: options.privacy,
+						protocol: options.protocol,
+						labels: options.labels,
+						uri,
+						error,
+					},
+				},
+			);
+		}
+
+		changed
+	}
+
+	/// Gets the status of each port, ordered by port.
+	fn statuses(&self) -> Vec<AutoForwardedPort> {
+		let mut ports: Vec<_> = self.forwarded.values().map(|f| f.status.clone()).collect();
+		ports.sort_by_key(|p| p.port);
+		ports
+	}
+
+	/// Unforwards all the ports that were forwarded.
+	async fn unforward_all(self) {
+		for (port, f) in self.forwarded {
+			if f.active {
+				self.forwarding.unforward(port).await.ok();
+			}
+		}
+	}
+}
+
+/// Watches for listening sockets and forwards them according to the rules
+/// until shutdown, reporting what's forwarded in the tunnel status.
+pub async fn run(
+	log: log::Logger,
+	rules: AutoForwardRules,
+	forwarding: PortForwarding,
+	status: StatusLock,
+	mut shutdown: Barrier<ShutdownSignal>,
+) {
+	if !cfg!(target_os = "linux") {
+		warning!(
+			log,
+			"Automatic port forwarding is only supported on Linux, ports must be forwarded manually"
+		);
+		return;
+	}
+
+	let mut scanner = linux::PortScanner::default();
+	let mut forwarder = AutoForwarder::new(log, rules, forwarding);
+	let mut interval = tokio::time::interval(POLL_INTERVAL);
+	loop {
+		tokio::select! {
+			_ = shutdown.wait() => break,
+			_ = interval.tick() => {},
+		}
+
+		let listening = scanner.listening_ports();
+		let changed = forwarder.update(&listening).await;
+		if changed || status.read().auto_forwarded.len() != forwarder.forwarded.len() {
+			let ports = forwarder.statuses();
+			status.update(|s| s.auto_forwarded = ports);
+		}
+	}
+
+	forwarder.unforward_all().await;
+	status.update(|s| s.auto_forwarded.clear());
+}
+
+/// Gets the rules to use, from a file if one is given.
+pub fn load_rules(path: Option<&Path>) -> Result<AutoForwardRules, CodeError> {
+	match path {
+		Some(p) => AutoForwardRules::read(p),
+		None => Ok(AutoForwardRules::default()),
+	}
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+	use std::{
+		collections::{HashMap, HashSet},
+		fs,
+	};
+
+	use super::parse_proc_net_tcp;
+
+	/// Finds the ports the user's processes listen on. The processes that own
+	/// sockets are only looked up for sockets that weren't seen before, since
+	/// that means reading the file descriptors of every process.
+	#[derive(Default)]
+	pub struct PortScanner {
+		/// The ID and name of the process owning each socket by inode, if found.
+		owners: HashMap<u64, Option<(u32, String)>>,
+	}
+
+	impl PortScanner {
+		/// Gets the ports the user's processes listen on, along with the name
+		/// of the listening process if it can be found.
+		pub fn listening_ports(&mut self) -> HashMap<u16, Option<String>> {
+			let uid = unsafe { libc::getuid() };
+			let sockets: Vec<_> = ["/proc/net/tcp", "/proc/net/tcp6"]
+				.iter()
+				.filter_map(|p| fs::read_to_string(p).ok())
+				.flat_map(|c| parse_proc_net_tcp(&c, uid))
+				.collect();
+
+			let inodes: HashSet<u64> = sockets.iter().map(|s| s.inode).collect();
+			self.owners.retain(|inode, _| inodes.contains(inode));
+			let new: HashSet<u64> = inodes
+				.into_iter()
+				.filter(|i| !self.owners.contains_key(i))
+				.collect();
+			if !new.is_empty() {
+				let mut found = socket_owners(&new);
+				for inode in new {
+					self.owners.insert(inode, found.remove(&inode));
+				}
+			}
+
+			let own_pid = std::process::id();
+			let mut ports = HashMap::new();
+			for socket in sockets {
+				let owner = self.owners.get(&socket.inode).and_then(|o| o.as_ref());
+				// ports the CLI listens on itself are never forwarded
+				if matches!(owner, Some((pid, _)) if *pid == own_pid) {
+					continue;
+				}
+				ports
+					.entry(socket.port)
+					.or_insert_with(|| owner.map(|(_, name)| name.clone()));
+			}
+			ports
+		}
+	}
+
+	/// Maps the socket inodes to the ID and name of the process that has them
+	/// open. Only the user's own processes can be inspected.
+	fn socket_owners(inodes: &HashSet<u64>) -> HashMap<u64, (u32, String)> {
+		let mut owners = HashMap::new();
+		let procs = match fs::read_dir("/proc") {
+			Ok(p) => p,
+			Err(_) => return owners,
+		};
+
+		for entry in procs.flatten() {
+			if owners.len() == inodes.len() {
+				break;
+			}
+
+			let pid = match entry
+				.file_name()
+				.to_str()
+				.and_then(|n| n.parse::<u32>().ok())
+			{
+				Some(pid) => pid,
+				None => continue,
+			};
+			let fds = match fs::read_dir(entry.path().join("fd")) {
+				Ok(fds) => fds,
+				Err(_) => continue,
+			};
+
+			let mut name = None;
+			for fd in fds.flatten() {
+				let target = match fs::read_link(fd.path()) {
+					Ok(t) => t,
+					Err(_) => continue,
+				};
+				let inode = target
+					.to_str()
+					.and_then(|t| t.strip_prefix("socket:["))
+					.and_then(|t| t.strip_suffix(']'))
+					.and_then(|t| t.parse::<u64>().ok())
+					.filter(|i| inodes.contains(i));
+				if let Some(inode) = inode {
+					let name = name.get_or_insert_with(|| {
+						fs::read_to_string(entry.path().join("comm"))
+							.map(|c| c.trim().to_string())
+							.unwrap_or_default()
+					});
+					owners.insert(inode, (pid, name.clone()));
+				}
+			}
+		}
+
+		owners
+	}
+}
+
+#[cfg(not(target_os = "linux"))]
+mod linux {
+	use std::collections::HashMap;
+
+	#[derive(Default)]
+	pub struct PortScanner;
+
+	impl PortScanner {
+		pub fn listening_ports(&mut self) -> HashMap<u16, Option<String>> {
+			HashMap::new()
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::{Arc, Mutex};
+
+	use super::*;
+	use crate::tunnels::port_forwarder::{PortForwardingProcessor, PortForwardingRec};
+	use crate::util::errors::ServerHasClosed;
+
+	#[test]
+	fn test_parse_proc_net_tcp() {
+		let contents = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 0100007F:0BB8 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 111 1 0000000000000000 100 0 0 10 0
+   1: 00000000:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 222 1 0000000000000000 100 0 0 10 0
+   2: 0100007F:0BB9 0100007F:D431 01 00000000:00000000 00:00000000 00000000  1000        0 333 1 0000000000000000 100 0 0 10 0
+   3: 0100007F:0016 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 444 1 0000000000000000 100 0 0 10 0
+   4: 0500000A:0050 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 555 1 0000000000000000 100 0 0 10 0";
+
+		assert_eq!(
+			parse_proc_net_tcp(contents, 1000),
+			vec![
+				ListeningSocket {
+					port: 3000,
+					inode: 111
+				},
+				ListeningSocket {
+					port: 8080,
+					inode: 222
+				},
+			]
+		);
+
+		let v6 = "  sl  local_address                         remote_address                        st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 00000000000000000000000001000000:1389 00000000000000000000000000000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 666 1 0000000000000000 100 0 0 10 0";
+		assert_eq!(
+			parse_proc_net_tcp(v6, 1000),
+			vec![ListeningSocket {
+				port: 5001,
+				inode: 666
+			}]
+		);
+	}
+
+	#[test]
+	fn test_decide() {
+		let rules: AutoForwardRules = toml::from_str(
+			r#"
+			forward-unmatched = false
+
+			[ignore]
+			ports = [22, "5432-5439"]
+			processes = ["sshd"]
+
+			[[rules]]
+			ports = "9229"
+			ignore = true
+
+			[[rules]]
+			process = "node*"
+			privacy = "public"
+			protocol = "http"
+			labels = ["dev-server"]
+			access = { expires-at = "2030-01-01T00:00:00Z" }
+
+			[[rules]]
+			ports = "8000-8999"
+			"#,
+		)
+		.unwrap();
+
+		assert_eq!(rules.decide(5433, Some("postgres")), None);
+		assert_eq!(rules.decide(3000, Some("sshd")), None);
+		assert_eq!(rules.decide(9229, Some("node")), None);
+		assert_eq!(
+			rules.decide(3000, Some("node-dev")),
+			Some(ForwardOptions {
+				privacy: PortPrivacy::Public,
+				protocol: PortProtocol::Http,
+				labels: vec!["dev-server".to_string()],
+				access: PortAccess {
+					expires_at: Some("2030-01-01T00:00:00Z".parse().unwrap()),
+					..Default::default()
+				},
+			})
+		);
+		assert_eq!(
+			rules.decide(8080, Some("python3")),
+			Some(ForwardOptions::new(PortPrivacy::Private))
+		);
+		assert_eq!(rules.decide(3000, None), None);
+		assert_eq!(
+			AutoForwardRules::default().decide(3000, None),
+			Some(ForwardOptions::new(PortPrivacy::Private))
+		);
+	}
+
+	/// Answers forwarding requests like the tunnel would, where port 1 is
+	/// forwarded by a client and forwarding port 2 fails once. Unforwarded
+	/// ports are recorded.
+	fn fake_tunnel() -> (PortForwarding, Arc<Mutex<Vec<u16>>>) {
+		let mut processor = PortForwardingProcessor::new();
+		let forwarding = processor.handle();
+		let unforwarded = Arc::new(Mutex::new(vec![]));
+		let unforwarded_clone = unforwarded.clone();
+		tokio::spawn(async move {
+			let mut failed = false;
+			while let Some(req) = processor.recv().await {
+				match req {
+					PortForwardingRec::ForwardNew(1, _, tx) => {
+						tx.send(Ok(None)).ok();
+					}
+					PortForwardingRec::ForwardNew(2, _, tx) if !failed => {
+						failed = true;
+						tx.send(Err(ServerHasClosed().into())).ok();
+					}
+					PortForwardingRec::ForwardNew(port, _, tx) => {
+						tx.send(Ok(Some(format!("https://{port}.example.com"))))
+							.ok();
+					}
+					PortForwardingRec::Forward(..) => unreachable!(),
+					PortForwardingRec::Unforward(port, tx) => {
+						unforwarded_clone.lock().unwrap().push(port);
+						tx.send(Ok(())).ok();
+					}
+				}
+			}
+		});
+
+		(forwarding, unforwarded)
+	}
+
+	fn listening(ports: &[u16]) -> HashMap<u16, Option<String>> {
+		ports
+			.iter()
+			.map(|p| (*p, Some("node".to_string())))
+			.collect()
+	}
+
+	#[tokio::test]
+	async fn test_auto_forwarder_leaves_client_ports_alone() {
+		let (forwarding, unforwarded) = fake_tunnel();
+		let mut forwarder =
+			AutoForwarder::new(log::Logger::test(), AutoForwardRules::default(), forwarding);
+
+		assert!(forwarder.update(&listening(&[1, 3])).await);
+		assert_eq!(
+			forwarder
+				.statuses()
+				.iter()
+				.map(|p| p.port)
+				.collect::<Vec<_>>(),
+			vec![3]
+		);
+		assert!(!forwarder.update(&listening(&[1, 3])).await);
+
+		assert!(forwarder.update(&listening(&[])).await);
+		assert_eq!(*unforwarded.lock().unwrap(), vec![3]);
+		assert!(forwarder.statuses().is_empty());
+	}
+
+	#[tokio::test]
+	async fn test_auto_forwarder_retries_failed_ports() {
+		let (forwarding, unforwarded) = fake_tunnel();
+		let mut forwarder =
+			AutoForwarder::new(log::Logger::test(), AutoForwardRules::default(), forwarding);
+
+		assert!(forwarder.update(&listening(&[2])).await);
+		assert!(forwarder.statuses()[0].error.is_some());
+
+		// not retried right away
+		assert!(!forwarder.update(&listening(&[2])).await);
+
+		forwarder.forwarded.get_mut(&2).unwrap().failed_at =
+			Some(Instant::now() - FORWARD_RETRY_INTERVAL);
+		assert!(forwarder.update(&listening(&[2])).await);
+		let status = &forwarder.statuses()[0];
+		assert_eq!(status.uri.as_deref(), Some("https://2.example.com"));
+		assert!(status.error.is_none());
+
+		forwarder.unforward_all().await;
+		assert_eq!(*unforwarded.lock().unwrap(), vec![2]);
+	}
+}
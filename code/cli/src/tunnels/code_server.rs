@@ -10,7 +10,10 @@ use crate::constants::{
 use crate::download_cache::DownloadCache;
 use crate::options::{Quality, TelemetryLevel};
 use crate::state::LauncherPaths;
+use crate::tunnels::extension_bundle::{ExtensionBundle, INSTALLED_MARKER};
 use crate::tunnels::paths::{get_server_folder_name, SERVER_FOLDER_NAME};
+use crate::tunnels::server_env::{read_env_file, EnvPolicy};
+use crate::tunnels::server_logs::{format_log_line, open_server_log, write_log_line};
 use crate::update_service::{
 	unzip_downloaded_release, Platform, Release, TargetKind, UpdateService,
 };
@@ -23,14 +26,15 @@ use crate::util::http::{self, BoxedHttp};
 use crate::util::io::SilentCopyProgress;
 use crate::util::machine::process_exists;
 use crate::util::prereqs::skip_requirements_check;
+use crate::util::readiness::{wait_for_http, OutputTail, ProbeTarget};
+use crate::util::resource_limits::{AppliedLimits, ResourceLimits};
+use crate::util::rotating_file::RotatingFile;
 use crate::{debug, info, log, spanf, trace, warning};
 use lazy_static::lazy_static;
 use opentelemetry::KeyValue;
 use regex::Regex;
 use serde::Deserialize;
 use std::fs;
-use std::fs::File;
-use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
@@ -38,7 +42,12 @@ use tokio::fs::remove_file;
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::process::{Child, Command};
 use tokio::sync::oneshot::Receiver;
-use tokio::time::{interval, timeout};
+use tokio::time::interval;
+
+/// Time to wait for a server listening on a port to start, by default.
+const PORT_STARTUP_TIMEOUT: Duration = Duration::from_secs(8);
+/// Time to wait for a server listening on a socket to start, by default.
+const SOCKET_STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
 
 lazy_static! {
 	static ref LISTENING_PORT_RE: Regex =
@@ -76,6 +85,28 @@ pub struct CodeServerArgs {
 	pub without_connection_token: bool,
 	// reconnection
 	pub reconnection_grace_time: Option<u32>,
+	// log rotation, handled by the CLI
+	pub log_max_size_mb: Option<u64>,
+	pub log_max_files: Option<usize>,
+	// seconds to wait for the server to start, handled by the CLI
+	pub startup_timeout_secs: Option<u64>,
+	// resource limits for the server process, handled by the CLI
+	pub limits: ResourceLimits,
+	// environment of the server process, handled by the CLI. `env` is
+	// applied after the variables in `env_file`.
+	pub env_file: Option<PathBuf>,
+	pub env: Vec<(String, String)>,
+	pub env_policy: EnvPolicy,
+	// directory or archive of extensions installed into each new server,
+	// handled by the CLI
+	pub extension_bundle: Option<PathBuf>,
+	// whether the tunnel forwards ports it detects, and the rules it uses to
+	// do so, handled by the CLI
+	pub auto_forward: bool,
+	pub auto_forward_rules: Option<PathBuf>,
+	// whether agent host servers that keep crashing fall back to a
+	// known-good release, handled by the CLI
+	pub fallback_on_crash_loop: bool,
 }
 
 impl CodeServerArgs {
@@ -87,6 +118,16 @@ impl CodeServerArgs {
 		}
 	}
 
+	/// Gets variables to set for the server process.
+	pub fn server_env(&self) -> Result<Vec<(String, String)>, CodeError> {
+		let mut env = match &self.env_file {
+			Some(f) => read_env_file(f)?,
+			None => vec![],
+		};
+		env.extend(self.env.iter().cloned());
+		Ok(env)
+	}
+
 	pub fn telemetry_disabled(&self) -> bool {
 		self.telemetry_level == Some(TelemetryLevel::Off)
 	}
@@ -490,29 +531,34 @@ impl<'a> ServerBuilder<'a> {
 	}
 
 	pub async fn listen_on_port(&self, port: u16) -> Result<PortCodeServer, AnyError> {
-		let mut cmd = self.get_base_command();
+		let mut cmd = self.get_base_command()?;
 		cmd.arg("--start-server")
 			.arg("--enable-remote-auto-shutdown")
 			.arg(format!("--port={port}"));
 
-		let child = self.spawn_server_process(cmd).await?;
+		let (child, limits) = self.spawn_server_process(cmd).await?;
 		let log_file = self.get_logfile()?;
 		let plog = self.logger.prefixed(&log::new_code_server_prefix());
 
-		let (mut origin, listen_rx) =
-			monitor_server::<PortMatcher, u16>(child, Some(log_file), plog, false);
+		let tail = OutputTail::default();
+		let (mut origin, listen_rx) = monitor_server::<PortMatcher, u16>(
+			child,
+			Some(log_file),
+			plog,
+			false,
+			tail.clone(),
+			limits,
+		);
 
-		let port = match timeout(Duration::from_secs(8), listen_rx).await {
-			Err(_) => {
-				origin.kill().await;
-				return Err(CodeError::ServerOriginTimeout.into());
-			}
-			Ok(Err(s)) => {
-				origin.kill().await;
-				return Err(CodeError::ServerUnexpectedExit(format!("{s}")).into());
-			}
-			Ok(Ok(p)) => p,
-		};
+		let port = wait_for_ready(
+			&mut origin,
+			listen_rx,
+			ProbeTarget::Port(port),
+			port,
+			self.startup_timeout(PORT_STARTUP_TIMEOUT),
+			&tail,
+		)
+		.await?;
 
 		info!(self.logger, "Server started");
 
@@ -523,17 +569,74 @@ impl<'a> ServerBuilder<'a> {
 		})
 	}
 
-	/// Runs the command that just installs extensions and exits.
+	/// Runs the command that just installs extensions and exits, after
+	/// installing the extension bundle if there is one.
 	pub async fn install_extensions(&self) -> Result<(), AnyError> {
+		self.install_extension_bundle().await?;
+		if self
+			.server_params
+			.code_server_args
+			.install_extensions
+			.is_empty()
+		{
+			return Ok(());
+		}
+
 		// cmd already has --install-extensions from base
-		let mut cmd = self.get_base_command();
-		let cmd_str = || {
-			self.server_params
-				.code_server_args
-				.command_arguments()
-				.join(" ")
+		self.run_extension_command(&self.server_params.code_server_args)
+			.await
+	}
+
+	/// Installs the extensions of the bundle, if there is one and this server
+	/// doesn't have it yet. Extensions are installed with `--force` so that the
+	/// versions in the bundle replace any others.
+	pub async fn install_extension_bundle(&self) -> Result<(), AnyError> {
+		let source = match &self.server_params.code_server_args.extension_bundle {
+			Some(s) => s,
+			None => return Ok(()),
 		};
 
+		// hashing and copying the bundle is synchronous and can take a while
+		let (launcher_paths, source) = (self.launcher_paths.clone(), source.clone());
+		let bundle =
+			tokio::task::spawn_blocking(move || ExtensionBundle::prepare(&launcher_paths, &source))
+				.await
+				.map_err(|e| wrap(e, "error preparing extension bundle"))??;
+		let marker = self.server_paths.server_dir.join(INSTALLED_MARKER);
+		if fs::read_to_string(&marker).ok().as_deref() == Some(bundle.id.as_str()) {
+			return Ok(());
+		}
+
+		info!(
+			self.logger,
+			"Installing {} extension(s) from bundle {}",
+			bundle.manifest.extensions.len(),
+			bundle.id
+		);
+
+		let mut args = self.server_params.code_server_args.clone();
+		args.install_extensions = bundle
+			.vsix_paths()
+			.iter()
+			.map(|p| p.display().to_string())
+			.collect();
+		args.force = true;
+		args.pre_release = false;
+		args.start_server = false;
+		args.uninstall_extensions.clear();
+		args.update_extensions = false;
+		args.list_extensions = false;
+		self.run_extension_command(&args).await?;
+
+		fs::write(&marker, &bundle.id)
+			.map_err(|e| wrap(e, format!("error writing {}", marker.display())))?;
+		Ok(())
+	}
+
+	async fn run_extension_command(&self, args: &CodeServerArgs) -> Result<(), AnyError> {
+		let mut cmd = self.get_command(args)?;
+		let cmd_str = || args.command_arguments().join(" ");
+
 		let r = cmd.output().await.map_err(|e| CodeError::CommandFailed {
 			command: cmd_str(),
 			code: -1,
@@ -564,29 +667,34 @@ impl<'a> ServerBuilder<'a> {
 	async fn _listen_on_socket(&self, socket: &Path) -> Result<SocketCodeServer, AnyError> {
 		remove_file(&socket).await.ok(); // ignore any error if it doesn't exist
 
-		let mut cmd = self.get_base_command();
+		let mut cmd = self.get_base_command()?;
 		cmd.arg("--start-server")
 			.arg("--enable-remote-auto-shutdown")
 			.arg(format!("--socket-path={}", socket.display()));
 
-		let child = self.spawn_server_process(cmd).await?;
+		let (child, limits) = self.spawn_server_process(cmd).await?;
 		let log_file = self.get_logfile()?;
 		let plog = self.logger.prefixed(&log::new_code_server_prefix());
 
-		let (mut origin, listen_rx) =
-			monitor_server::<SocketMatcher, PathBuf>(child, Some(log_file), plog, false);
+		let tail = OutputTail::default();
+		let (mut origin, listen_rx) = monitor_server::<SocketMatcher, PathBuf>(
+			child,
+			Some(log_file),
+			plog,
+			false,
+			tail.clone(),
+			limits,
+		);
 
-		let socket = match timeout(Duration::from_secs(30), listen_rx).await {
-			Err(_) => {
-				origin.kill().await;
-				return Err(CodeError::ServerOriginTimeout.into());
-			}
-			Ok(Err(s)) => {
-				origin.kill().await;
-				return Err(CodeError::ServerUnexpectedExit(format!("{s}")).into());
-			}
-			Ok(Ok(socket)) => socket,
-		};
+		let socket = wait_for_ready(
+			&mut origin,
+			listen_rx,
+			ProbeTarget::Socket(socket.to_path_buf()),
+			socket.to_path_buf(),
+			self.startup_timeout(SOCKET_STARTUP_TIMEOUT),
+			&tail,
+		)
+		.await?;
 
 		info!(self.logger, "Server started");
 
@@ -597,11 +705,20 @@ impl<'a> ServerBuilder<'a> {
 		})
 	}
 
-	async fn spawn_server_process(&self, mut cmd: Command) -> Result<Child, AnyError> {
+	async fn spawn_server_process(
+		&self,
+		mut cmd: Command,
+	) -> Result<(Child, AppliedLimits), AnyError> {
 		info!(self.logger, "Starting server...");
 
 		debug!(self.logger, "Starting server with command... {:?}", cmd);
 
+		let limits = self
+			.server_params
+			.code_server_args
+			.limits
+			.apply(self.logger, &mut cmd);
+
 		// On Windows spawning a code-server binary will run cmd.exe /c C:\path\to\code-server.cmd...
 		// This spawns a cmd.exe window for the user, which if they close will kill the code-server process
 		// and disconnect the tunnel. To prevent this, pass the CREATE_NO_WINDOW flag to the Command
@@ -627,35 +744,77 @@ impl<'a> ServerBuilder<'a> {
 		self.server_paths
 			.write_pid(child.id().expect("expected server to have pid"))?;
 
-		Ok(child)
+		Ok((child, limits))
 	}
 
-	fn get_logfile(&self) -> Result<File, WrappedError> {
-		File::create(&self.server_paths.logfile).map_err(|e| {
-			wrap(
-				e,
-				format!(
-					"error creating log file {}",
-					self.server_paths.logfile.display()
-				),
-			)
-		})
+	fn get_logfile(&self) -> Result<RotatingFile, WrappedError> {
+		let args = &self.server_params.code_server_args;
+		open_server_log(
+			&self.server_paths.logfile,
+			args.log_max_size_mb,
+			args.log_max_files,
+		)
+	}
+
+	fn startup_timeout(&self, default: Duration) -> Duration {
+		self.server_params
+			.code_server_args
+			.startup_timeout_secs
+			.map(Duration::from_secs)
+			.unwrap_or(default)
 	}
 
-	fn get_base_command(&self) -> Command {
+	fn get_base_command(&self) -> Result<Command, CodeError> {
+		self.get_command(&self.server_params.code_server_args)
+	}
+
+	fn get_command(&self, args: &CodeServerArgs) -> Result<Command, CodeError> {
 		let mut cmd = new_script_command(&self.server_paths.executable);
 		cmd.stdin(std::process::Stdio::null())
-			.args(self.server_params.code_server_args.command_arguments());
-		cmd
+			.args(args.command_arguments())
+			.envs(args.server_env()?);
+		Ok(cmd)
 	}
 }
 
+/// Waits until the server is ready. Its output is matched as a fast path, and
+/// in case its wording changes or it's redirected, the server is also probed
+/// over HTTP. `probed` is returned if the probe succeeds first.
+async fn wait_for_ready<R>(
+	origin: &mut CodeServerOrigin,
+	listen_rx: Receiver<Result<R, String>>,
+	probe: ProbeTarget,
+	probed: R,
+	startup_timeout: Duration,
+	tail: &OutputTail,
+) -> Result<R, AnyError> {
+	let result = tokio::select! {
+		r = listen_rx => match r {
+			Ok(Ok(r)) => Ok(r),
+			Ok(Err(oom_reason)) => Err(CodeError::ServerOutOfMemory(oom_reason)),
+			Err(_) => Err(CodeError::ServerUnexpectedExit(format!("its last output was:\n{tail}"))),
+		},
+		_ = wait_for_http(probe) => Ok(probed),
+		_ = tokio::time::sleep(startup_timeout) => {
+			Err(CodeError::ServerStartupTimeout(startup_timeout.as_secs(), tail.to_string()))
+		}
+	};
+
+	if result.is_err() {
+		origin.kill().await;
+	}
+
+	Ok(result?)
+}
+
 fn monitor_server<M, R>(
 	mut child: Child,
-	log_file: Option<File>,
+	mut log_file: Option<RotatingFile>,
 	plog: log::Logger,
 	write_directly: bool,
-) -> (CodeServerOrigin, Receiver<R>)
+	tail: OutputTail,
+	limits: AppliedLimits,
+) -> (CodeServerOrigin, Receiver<Result<R, String>>)
 where
 	M: ServerOutputMatcher<R>,
 	R: 'static + Send + std::fmt::Debug,
@@ -671,16 +830,20 @@ where
 		.expect("child did not have a handle to stdout");
 
 	let (listen_tx, listen_rx) = tokio::sync::oneshot::channel();
+	let mut listen_tx = Some(listen_tx);
 
 	// Handle stderr and stdout in a separate task. Initially scan lines looking
 	// for the listening port. Afterwards, just scan and write out to the file.
 	tokio::spawn(async move {
 		let mut stdout_reader = BufReader::new(stdout).lines();
 		let mut stderr_reader = BufReader::new(stderr).lines();
-		let write_line = |line: &str| -> std::io::Result<()> {
-			if let Some(mut f) = log_file.as_ref() {
-				f.write_all(line.as_bytes())?;
-				f.write_all(b"\n")?;
+		let mut write_line = |line: &str, is_listen_line: bool| -> std::io::Result<()> {
+			if let Some(f) = log_file.as_mut() {
+				write_log_line(f, line)?;
+				if is_listen_line {
+					// keep the listening location findable after rotation
+					f.set_header(format_log_line(line).as_bytes());
+				}
 			}
 			if write_directly {
 				println!("{line}");
@@ -703,11 +866,15 @@ where
 				}
 				Ok(None) => break,
 				Ok(Some(l)) => {
-					write_line(&l).ok();
+					tail.push(&l);
+					let listen_on = M::match_line(&l);
+					write_line(&l, listen_on.is_some()).ok();
 
-					if let Some(listen_on) = M::match_line(&l) {
+					if let Some(listen_on) = listen_on {
 						trace!(plog, "parsed location: {:?}", listen_on);
-						listen_tx.send(listen_on).ok();
+						if let Some(tx) = listen_tx.take() {
+							tx.send(Ok(listen_on)).ok();
+						}
 						break;
 					}
 				}
@@ -727,10 +894,22 @@ where
 				}
 				Ok(None) => break,
 				Ok(Some(l)) => {
-					write_line(&l).ok();
+					write_line(&l, false).ok();
 				}
 			}
 		}
+
+		// Report running out of memory as its own exit reason, to the caller
+		// waiting for the server to start if it hasn't yet.
+		if let Some(reason) = limits.oom_reason() {
+			error!(
+				plog,
+				"Server exited because it ran out of memory: {}", reason
+			);
+			if let Some(tx) = listen_tx.take() {
+				tx.send(Err(reason)).ok();
+			}
+		}
 	});
 
 	let origin = CodeServerOrigin::New(Box::new(child));
@@ -38,7 +38,7 @@ use tokio::pin;
 use tokio::process::{ChildStderr, ChildStdin};
 use tokio_util::codec::Decoder;
 
-use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::Arc;
 use std::time::Instant;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, DuplexStream};
@@ -46,7 +46,10 @@ use tokio::sync::{mpsc, Mutex};
 
 use super::agent_host::{
 	handle_request as handle_agent_host_request, AgentHostConfig, AgentHostManager,
+	DEFAULT_MAX_WORKSPACES,
 };
+use super::audit_log::{AuditConnection, AuditLog, AuthOutcome, ConnectAuth};
+use super::auto_forward;
 use super::challenge::{create_challenge, sign_challenge, verify_challenge};
 use super::code_server::{
 	download_cli_into_cache, AnyCodeServer, CodeServerArgs, ServerBuilder, ServerParamsRaw,
@@ -54,22 +57,25 @@ use super::code_server::{
 };
 use super::dev_tunnels::ActiveTunnel;
 use super::paths::prune_stopped_servers;
-use super::port_forwarder::{PortForwarding, PortForwardingProcessor};
+use super::port_forwarder::{ForwardOptions, PortForwarding, PortForwardingProcessor};
 use super::protocol::{
 	AcquireCliParams, CallServerHttpParams, CallServerHttpResult, ChallengeIssueParams,
 	ChallengeIssueResponse, ChallengeVerifyParams, ClientRequestMethod, EmptyObject, ForwardParams,
 	ForwardResult, FsReadDirEntry, FsReadDirResponse, FsRenameRequest, FsSinglePathRequest,
 	FsStatResponse, GetEnvResponse, GetHostnameResponse, HttpBodyParams, HttpHeadersParams,
-	NetConnectRequest, ServeParams, ServerLog, ServerMessageParams, SpawnParams, SpawnResult,
-	SysKillRequest, SysKillResponse, ToClientRequest, UnforwardParams, UpdateParams, UpdateResult,
-	VersionResponse, METHOD_CHALLENGE_VERIFY,
+	NetConnectRequest, ServeParams, ServerLog, ServerLogsParams, ServerLogsResponse,
+	ServerMessageParams, SpawnParams, SpawnResult, SysKillRequest, SysKillResponse,
+	ToClientRequest, UnforwardParams, UpdateParams, UpdateResult, VersionResponse,
+	METHOD_CHALLENGE_VERIFY,
 };
 use super::server_bridge::ServerBridge;
+use super::server_logs::{find_server_log, read_recent_lines, LogFilter};
 use super::server_multiplexer::ServerMultiplexer;
 use super::shutdown_signal::ShutdownSignal;
 use super::socket_signal::{
 	ClientMessageDecoder, ServerMessageDestination, ServerMessageSink, SocketSignal,
 };
+use super::traffic::{ConnectionCounter, ConnectionHandle};
 
 type HttpRequestsMap = Arc<std::sync::Mutex<HashMap<u32, DelegatedHttpRequest>>>;
 type CodeServerCell = Arc<Mutex<Option<SocketCodeServer>>>;
@@ -99,6 +105,8 @@ struct HandlerContext {
 	http: Arc<FallbackSimpleHttp>,
 	/// requests being served by the client
 	http_requests: HttpRequestsMap,
+	/// records security-relevant calls on the connection
+	audit: Option<AuditConnection>,
 }
 
 /// Handler auth state.
@@ -187,8 +195,30 @@ pub async fn serve(
 	let mut port = tunnel.add_port_direct(CONTROL_PORT).await?;
 	let mut agent_host_port = tunnel.add_port_direct(AGENT_HOST_PORT).await?;
 	let mut forwarding = PortForwardingProcessor::new();
+	tunnel.status().update(|s| {
+		s.server_data_dir = code_server_args.server_data_dir.clone();
+		s.extensions_dir = code_server_args.extensions_dir.clone();
+	});
+	if code_server_args.auto_forward {
+		let rules = auto_forward::load_rules(code_server_args.auto_forward_rules.as_deref())?;
+		tokio::spawn(auto_forward::run(
+			log.clone(),
+			rules,
+			forwarding.handle(),
+			tunnel.status(),
+			shutdown_rx.clone(),
+		));
+	}
 	let (tx, mut rx) = mpsc::channel::<ServerSignal>(4);
 	let (exit_barrier, signal_exit) = new_barrier();
+	let traffic = tunnel.status().traffic().clone();
+	let audit = match AuditLog::open(launcher_paths) {
+		Ok(a) => Some(a),
+		Err(e) => {
+			warning!(log, "Could not open the audit log: {}", e);
+			None
+		}
+	};
 
 	// Set up the agent host manager for on-demand server start on AGENT_HOST_PORT
 	let agent_host_manager = AgentHostManager::new(
@@ -198,9 +228,16 @@ pub async fn serve(
 		Arc::new(ReqwestSimpleHttp::new()),
 		AgentHostConfig {
 			server_data_dir: code_server_args.server_data_dir.clone(),
+			workspaces_data_dir: launcher_paths.root().join("agent-host-workspaces"),
+			max_workspaces: DEFAULT_MAX_WORKSPACES,
 			without_connection_token: true,
 			connection_token: None,
 			connection_token_file: None,
+			access_log: None,
+			fallback_on_crash_loop: code_server_args.fallback_on_crash_loop,
+			known_good_file: launcher_paths.agent_host_known_good_file(),
+			update_policy: Default::default(),
+			limits: code_server_args.limits.clone(),
 		},
 	);
 
@@ -225,7 +262,9 @@ pub async fn serve(
 		});
 	}
 
-	if !code_server_args.install_extensions.is_empty() {
+	if !code_server_args.install_extensions.is_empty()
+		|| code_server_args.extension_bundle.is_some()
+	{
 		info!(
 			log,
 			"Preloading extensions using stable server: {:?}", code_server_args.install_extensions
@@ -280,7 +319,7 @@ pub async fn serve(
 					let rw = socket.into_rw();
 					let svc = hyper::service::service_fn(move |req| {
 						let mgr = mgr.clone();
-						async move { handle_agent_host_request(mgr, req).await }
+						async move { handle_agent_host_request(mgr, None, req).await }
 					});
 					if let Err(e) = hyper::server::conn::Http::new()
 						.serve_connection(rw, svc)
@@ -309,6 +348,8 @@ pub async fn serve(
 				let own_exit = exit_barrier.clone();
 				let own_code_server_args = code_server_args.clone();
 				let own_forwarding = forwarding.handle();
+				let own_traffic = traffic.connect();
+				let own_audit = audit.clone();
 
 				tokio::spawn(async move {
 					use opentelemetry::trace::{FutureExt, TraceContextExt};
@@ -320,13 +361,14 @@ pub async fn serve(
 					debug!(own_log, "Serving new connection");
 
 					let (writehalf, readhalf) = socket.into_split();
-					let stats = process_socket(readhalf, writehalf, own_tx, Some(own_forwarding), ServeStreamParams {
+					let stats = process_socket(readhalf, writehalf, own_tx, Some(own_forwarding), Some(own_traffic), None, ServeStreamParams {
 						log: own_log,
 						launcher_paths: own_paths,
 						code_server_args: own_code_server_args,
 						platform,
 						exit_barrier: own_exit,
 						requires_auth: AuthRequired::None,
+						audit: own_audit,
 					}).with_context(cx.clone()).await;
 
 					cx.span().add_event(
@@ -359,11 +401,16 @@ pub struct ServeStreamParams {
 	pub platform: Platform,
 	pub requires_auth: AuthRequired,
 	pub exit_barrier: Barrier<ShutdownSignal>,
+	/// Log that records the connection's activity, if any.
+	pub audit: Option<AuditLog>,
 }
 
+/// Serves the control protocol over a stream. `client` identifies who's on
+/// the other end, such as their address, if it's known.
 pub async fn serve_stream(
 	readhalf: impl AsyncRead + Send + Unpin + 'static,
 	writehalf: impl AsyncWrite + Unpin,
+	client: Option<String>,
 	params: ServeStreamParams,
 ) -> SocketStats {
 	// Currently the only server signal is respawn, that doesn't have much meaning
@@ -371,12 +418,12 @@ pub async fn serve_stream(
 	let (server_rx, server_tx) = mpsc::channel(1);
 	drop(server_tx);
 
-	process_socket(readhalf, writehalf, server_rx, None, params).await
+	process_socket(readhalf, writehalf, server_rx, None, None, client, params).await
 }
 
 pub struct SocketStats {
-	rx: usize,
-	tx: usize,
+	rx: u64,
+	tx: u64,
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -390,6 +437,7 @@ fn make_socket_rpc(
 	requires_auth: AuthRequired,
 	platform: Platform,
 	http_requests: HttpRequestsMap,
+	audit: Option<AuditConnection>,
 ) -> RpcDispatcher<MsgPackSerializer, HandlerContext> {
 	let server_bridges = ServerMultiplexer::new();
 	let mut rpc = RpcBuilder::new(MsgPackSerializer {}).methods(HandlerContext {
@@ -412,12 +460,13 @@ fn make_socket_rpc(
 			http_delegated,
 		)),
 		http_requests,
+		audit,
 	});
 
 	rpc.register_sync("ping", |_: EmptyObject, _| Ok(EmptyObject {}));
 	rpc.register_sync("gethostname", |_: EmptyObject, _| handle_get_hostname());
 	rpc.register_sync("sys_kill", |p: SysKillRequest, c| {
-		ensure_auth(&c.auth_state)?;
+		ensure_auth_audited(c, "sys_kill", p.pid.to_string())?;
 		handle_sys_kill(p.pid)
 	});
 	rpc.register_sync("fs_stat", |p: FsSinglePathRequest, c| {
@@ -436,7 +485,7 @@ fn make_socket_rpc(
 		"fs_write",
 		1,
 		move |mut streams, p: FsSinglePathRequest, c| async move {
-			ensure_auth(&c.auth_state)?;
+			ensure_auth_audited(&c, "fs_write", p.path.clone())?;
 			handle_fs_write(streams.remove(0), p.path).await
 		},
 	);
@@ -452,12 +501,12 @@ fn make_socket_rpc(
 		"net_connect",
 		1,
 		move |mut streams, n: NetConnectRequest, c| async move {
-			ensure_auth(&c.auth_state)?;
+			ensure_auth_audited(&c, "net_connect", format!("{}:{}", n.host, n.port))?;
 			handle_net_connect(streams.remove(0), n).await
 		},
 	);
 	rpc.register_async("fs_rm", move |p: FsSinglePathRequest, c| async move {
-		ensure_auth(&c.auth_state)?;
+		ensure_auth_audited(&c, "fs_rm", p.path.clone())?;
 		handle_fs_remove(p.path).await
 	});
 	rpc.register_sync("fs_mkdirp", |p: FsSinglePathRequest, c| {
@@ -477,10 +526,30 @@ fn make_socket_rpc(
 		handle_get_env()
 	});
 	rpc.register_sync(METHOD_CHALLENGE_ISSUE, |p: ChallengeIssueParams, c| {
-		handle_challenge_issue(p, &c.auth_state)
+		let r = handle_challenge_issue(p, &c.auth_state);
+		if let Some(a) = &c.audit {
+			a.auth(match &r {
+				Ok(_) => AuthOutcome::ChallengeIssued,
+				Err(AnyError::CodeError(CodeError::AuthChallengeBadToken)) => AuthOutcome::BadToken,
+				Err(_) => AuthOutcome::Failed,
+			});
+		}
+		r
 	});
 	rpc.register_sync(METHOD_CHALLENGE_VERIFY, |p: ChallengeVerifyParams, c| {
-		handle_challenge_verify(p.response, &c.auth_state)
+		let was_issued = matches!(
+			*c.auth_state.lock().unwrap(),
+			AuthState::ChallengeIssued(_) | AuthState::Authenticated
+		);
+		let r = handle_challenge_verify(p.response, &c.auth_state);
+		if let Some(a) = &c.audit {
+			a.auth(match (&r, was_issued) {
+				(Ok(_), _) => AuthOutcome::Verified,
+				(Err(_), false) => AuthOutcome::NotIssued,
+				(Err(_), true) => AuthOutcome::Failed,
+			});
+		}
+		r
 	});
 	rpc.register_async("serve", move |params: ServeParams, c| async move {
 		ensure_auth(&c.auth_state)?;
@@ -496,12 +565,16 @@ fn make_socket_rpc(
 		Ok(EmptyObject {})
 	});
 	rpc.register_sync("prune", |_: EmptyObject, c| handle_prune(&c.launcher_paths));
+	rpc.register_sync("serverlogs", |p: ServerLogsParams, c| {
+		ensure_auth_audited(c, "serverlogs", p.commit.clone().unwrap_or_default())?;
+		handle_server_logs(&c.launcher_paths, p)
+	});
 	rpc.register_async("callserverhttp", |p: CallServerHttpParams, c| async move {
 		let code_server = c.code_server.lock().await.clone();
 		handle_call_server_http(code_server, p).await
 	});
 	rpc.register_async("forward", |p: ForwardParams, c| async move {
-		ensure_auth(&c.auth_state)?;
+		ensure_auth_audited(&c, "forward", p.port.to_string())?;
 		handle_forward(&c.log, &c.port_forwarding, p).await
 	});
 	rpc.register_async("unforward", |p: UnforwardParams, c| async move {
@@ -513,7 +586,7 @@ fn make_socket_rpc(
 		handle_acquire_cli(&c.launcher_paths, &c.http, &c.log, p).await
 	});
 	rpc.register_duplex("spawn", 3, |mut streams, p: SpawnParams, c| async move {
-		ensure_auth(&c.auth_state)?;
+		ensure_auth_audited(&c, "spawn", command_line(&p))?;
 		handle_spawn(
 			&c.log,
 			p,
@@ -527,7 +600,7 @@ fn make_socket_rpc(
 		"spawn_cli",
 		3,
 		|mut streams, p: SpawnParams, c| async move {
-			ensure_auth(&c.auth_state)?;
+			ensure_auth_audited(&c, "spawn_cli", command_line(&p))?;
 			handle_spawn_cli(
 				&c.log,
 				p,
@@ -568,6 +641,23 @@ fn make_socket_rpc(
 	rpc.build(log)
 }
 
+/// Checks that the connection is authenticated, like `ensure_auth`, and
+/// records the call in the audit log.
+fn ensure_auth_audited(c: &HandlerContext, method: &str, target: String) -> Result<(), AnyError> {
+	let r = ensure_auth(&c.auth_state);
+	if let Some(a) = &c.audit {
+		a.rpc(method, target, r.is_ok());
+	}
+	r
+}
+
+fn command_line(p: &SpawnParams) -> String {
+	std::iter::once(p.command.as_str())
+		.chain(p.args.iter().map(|a| a.as_str()))
+		.collect::<Vec<_>>()
+		.join(" ")
+}
+
 fn ensure_auth(is_authed: &Arc<std::sync::Mutex<AuthState>>) -> Result<(), AnyError> {
 	if let AuthState::Authenticated = &*is_authed.lock().unwrap() {
 		Ok(())
@@ -582,6 +672,8 @@ async fn process_socket(
 	mut writehalf: impl AsyncWrite + Unpin,
 	server_tx: mpsc::Sender<ServerSignal>,
 	port_forwarding: Option<PortForwarding>,
+	traffic: Option<ConnectionHandle>,
+	client: Option<String>,
 	params: ServeStreamParams,
 ) -> SocketStats {
 	let ServeStreamParams {
@@ -591,14 +683,29 @@ async fn process_socket(
 		code_server_args,
 		platform,
 		requires_auth,
+		audit,
 	} = params;
 
 	let (http_delegated, mut http_rx) = DelegatedSimpleHttp::new(log.clone());
 	let (socket_tx, mut socket_rx) = mpsc::channel(4);
-	let rx_counter = Arc::new(AtomicUsize::new(0));
+	let counter = traffic.as_ref().map(|t| t.counter()).unwrap_or_default();
 	let http_requests = Arc::new(std::sync::Mutex::new(HashMap::new()));
 
 	let already_authed = matches!(requires_auth, AuthRequired::None);
+	// use the traffic counter's ID, if any, to match the tunnel status
+	let audit = audit.map(|a| {
+		a.connect(
+			traffic.as_ref().map(|t| t.id()),
+			client,
+			match &requires_auth {
+				// only connections through the relay have their traffic counted
+				AuthRequired::None if traffic.is_some() => ConnectAuth::Relay,
+				AuthRequired::None => ConnectAuth::None,
+				AuthRequired::VSDA => ConnectAuth::Challenge,
+				AuthRequired::VSDAWithToken(_) => ConnectAuth::ChallengeWithToken,
+			},
+		)
+	});
 	let rpc = make_socket_rpc(
 		log.clone(),
 		socket_tx.clone(),
@@ -609,11 +716,12 @@ async fn process_socket(
 		requires_auth,
 		platform,
 		http_requests.clone(),
+		audit.clone(),
 	);
 
 	{
 		let log = log.clone();
-		let rx_counter = rx_counter.clone();
+		let counter = counter.clone();
 		let socket_tx = socket_tx.clone();
 		let exit_barrier = exit_barrier.clone();
 		tokio::spawn(async move {
@@ -622,7 +730,7 @@ async fn process_socket(
 			}
 
 			if let Err(e) =
-				handle_socket_read(&log, readhalf, exit_barrier, &socket_tx, rx_counter, &rpc).await
+				handle_socket_read(&log, readhalf, exit_barrier, &socket_tx, &counter, &rpc).await
 			{
 				debug!(log, "closing socket reader: {}", e);
 				socket_tx
@@ -646,8 +754,6 @@ async fn process_socket(
 		});
 	}
 
-	let mut tx_counter = 0;
-
 	loop {
 		tokio::select! {
 			_ = exit_barrier.wait() => {
@@ -668,7 +774,7 @@ async fn process_socket(
 
 				http_requests.lock().unwrap().insert(id, r);
 
-				tx_counter += serialized.len();
+				counter.add_tx_message(serialized.len());
 				if let Err(e) = writehalf.write_all(&serialized).await {
 					debug!(log, "Closing connection: {}", e);
 					break;
@@ -678,7 +784,7 @@ async fn process_socket(
 				None => break,
 				Some(message) => match message {
 					SocketSignal::Send(bytes) => {
-						tx_counter += bytes.len();
+						counter.add_tx_message(bytes.len());
 						if let Err(e) = writehalf.write_all(&bytes).await {
 							debug!(log, "Closing connection: {}", e);
 							break;
@@ -693,9 +799,14 @@ async fn process_socket(
 		}
 	}
 
+	let traffic = counter.read();
+	if let Some(a) = &audit {
+		a.disconnect(traffic.bytes_rx, traffic.bytes_tx);
+	}
+
 	SocketStats {
-		tx: tx_counter,
-		rx: rx_counter.load(Ordering::Acquire),
+		tx: traffic.bytes_tx,
+		rx: traffic.bytes_rx,
 	}
 }
 
@@ -712,7 +823,7 @@ async fn handle_socket_read(
 	readhalf: impl AsyncRead + Unpin,
 	mut closer: Barrier<ShutdownSignal>,
 	socket_tx: &mpsc::Sender<SocketSignal>,
-	rx_counter: Arc<AtomicUsize>,
+	counter: &ConnectionCounter,
 	rpc: &RpcDispatcher<MsgPackSerializer, HandlerContext>,
 ) -> Result<(), std::io::Error> {
 	let mut readhalf = BufReader::new(readhalf);
@@ -729,9 +840,10 @@ async fn handle_socket_read(
 			return Ok(());
 		}
 
-		rx_counter.fetch_add(read_len, Ordering::Relaxed);
+		counter.add_rx_bytes(read_len);
 
 		while let Some(frame) = decoder.decode(&mut decoder_buf)? {
+			counter.add_rx_message();
 			match rpc.dispatch_with_partial(&frame.vec, frame.obj) {
 				MaybeSync::Sync(Some(v)) => {
 					if socket_tx.send(SocketSignal::Send(v)).await.is_err() {
@@ -792,6 +904,8 @@ async fn handle_serve(
 	let mut csa = c.code_server_args.clone();
 	csa.connection_token = params.connection_token.or(csa.connection_token);
 	csa.install_extensions.extend(params.extensions.into_iter());
+	let session_env = csa.env_policy.check(params.env)?;
+	csa.env.extend(session_env.iter().cloned());
 
 	let params_raw = ServerParamsRaw {
 		commit_id: params.commit_id,
@@ -811,7 +925,15 @@ async fn handle_serve(
 
 	let mut server_ref = c.code_server.lock().await;
 	let server = match &*server_ref {
-		Some(o) => o.clone(),
+		Some(o) => {
+			if !session_env.is_empty() {
+				warning!(
+					c.log,
+					"Server is already running, so the requested environment won't be applied"
+				);
+			}
+			o.clone()
+		}
 		None => {
 			let install_log = c.log.tee(ServerOutputSink {
 				tx: c.socket_tx.clone(),
@@ -824,6 +946,7 @@ async fn handle_serve(
 						Some(_) => return Err(AnyError::from(MismatchedLaunchModeError())),
 						None => {
 							$sb.setup().await?;
+							$sb.install_extension_bundle().await?;
 							let r = $sb.listen_on_default_socket().await;
 							($sb, r)
 						}
@@ -941,6 +1064,34 @@ fn handle_prune(paths: &LauncherPaths) -> Result<Vec<String>, AnyError> {
 	})
 }
 
+const DEFAULT_SERVER_LOG_LINES: usize = 200;
+const MAX_SERVER_LOG_LINES: usize = 5000;
+
+fn handle_server_logs(
+	paths: &LauncherPaths,
+	params: ServerLogsParams,
+) -> Result<ServerLogsResponse, AnyError> {
+	let since = params
+		.since
+		.map(|s| {
+			chrono::DateTime::parse_from_rfc3339(&s)
+				.map(|t| t.with_timezone(&chrono::Utc))
+				.map_err(|e| CodeError::InvalidLogFilter(format!("since: {e}")))
+		})
+		.transpose()?;
+	let filter = LogFilter::new(since, params.grep.as_deref())?;
+	let (commit, server_paths) = find_server_log(paths, params.commit.as_deref())?;
+	let max_lines = params
+		.max_lines
+		.unwrap_or(DEFAULT_SERVER_LOG_LINES)
+		.min(MAX_SERVER_LOG_LINES);
+
+	Ok(ServerLogsResponse {
+		commit,
+		lines: read_recent_lines(&server_paths.logfile, &filter, max_lines),
+	})
+}
+
 async fn handle_update(
 	http: &Arc<FallbackSimpleHttp>,
 	log: &log::Logger,
@@ -1169,7 +1320,15 @@ async fn handle_forward(
 		false => PortPrivacy::Private,
 	};
 
-	let uri = port_forwarding.forward(params.port, privacy).await?;
+	let uri = port_forwarding
+		.forward(
+			params.port,
+			ForwardOptions {
+				access: params.access,
+				..ForwardOptions::new(privacy)
+			},
+		)
+		.await?;
 	Ok(ForwardResult { uri })
 }
 
@@ -1489,3 +1648,145 @@ async fn do_challenge_response_flow(
 
 	Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::rpc::FullRequest;
+	use crate::tunnels::audit_log::{read_records, AuditEvent, AuditRecord};
+
+	/// Serves the requests on a local connection, and returns what was written
+	/// to the audit log.
+	async fn serve_audited(
+		paths: &LauncherPaths,
+		requires_auth: AuthRequired,
+		requests: &[u8],
+	) -> Vec<AuditRecord> {
+		let audit = AuditLog::open(paths).unwrap();
+		let (exit_barrier, _exit) = new_barrier();
+		let params = ServeStreamParams {
+			log: log::Logger::test(),
+			launcher_paths: paths.clone(),
+			code_server_args: Default::default(),
+			platform: Platform::LinuxX64,
+			requires_auth,
+			exit_barrier,
+			audit: Some(audit.clone()),
+		};
+
+		let (mut client_w, server_r) = tokio::io::duplex(4096);
+		let (server_w, _client_r) = tokio::io::duplex(65536);
+		client_w.write_all(requests).await.unwrap();
+		drop(client_w);
+
+		serve_stream(
+			server_r,
+			server_w,
+			Some("10.0.0.1:1234".to_string()),
+			params,
+		)
+		.await;
+		audit.flush();
+
+		read_records(&paths.audit_log_file())
+	}
+
+	#[tokio::test]
+	async fn test_audits_calls_before_auth() {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let mut requests = vec![];
+		requests.extend(
+			rmp_serde::to_vec_named(&FullRequest {
+				id: Some(1),
+				method: METHOD_CHALLENGE_ISSUE,
+				params: HashMap::from([("token", "wrong")]),
+			})
+			.unwrap(),
+		);
+		requests.extend(
+			rmp_serde::to_vec_named(&FullRequest {
+				id: Some(2),
+				method: "challenge_verify",
+				params: HashMap::from([("response", "guess")]),
+			})
+			.unwrap(),
+		);
+		requests.extend(
+			rmp_serde::to_vec_named(&FullRequest {
+				id: Some(3),
+				method: "sys_kill",
+				params: HashMap::from([("pid", u32::MAX)]),
+			})
+			.unwrap(),
+		);
+		let records = serve_audited(
+			&paths,
+			AuthRequired::VSDAWithToken("secret".to_string()),
+			&requests,
+		)
+		.await;
+		let events: Vec<AuditEvent> = records.iter().map(|r| r.event.clone()).collect();
+		assert_eq!(
+			events[..4],
+			[
+				AuditEvent::Connect {
+					auth: ConnectAuth::ChallengeWithToken
+				},
+				AuditEvent::Auth {
+					outcome: AuthOutcome::BadToken
+				},
+				AuditEvent::Auth {
+					outcome: AuthOutcome::NotIssued
+				},
+				AuditEvent::Rpc {
+					method: "sys_kill".to_string(),
+					target: u32::MAX.to_string(),
+					allowed: false,
+				},
+			]
+		);
+		assert!(matches!(
+			events[4],
+			AuditEvent::Disconnect { bytes_rx, .. } if bytes_rx == requests.len() as u64
+		));
+		assert!(records
+			.iter()
+			.all(|r| r.client.as_deref() == Some("10.0.0.1:1234") && r.connection == 0));
+	}
+
+	#[tokio::test]
+	async fn test_server_logs_require_auth() {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let requests = rmp_serde::to_vec_named(&FullRequest {
+			id: Some(1),
+			method: "serverlogs",
+			params: HashMap::from([("commit", "abc")]),
+		})
+		.unwrap();
+
+		let records = serve_audited(&paths, AuthRequired::VSDA, &requests).await;
+		assert_eq!(
+			records[1].event,
+			AuditEvent::Rpc {
+				method: "serverlogs".to_string(),
+				target: "abc".to_string(),
+				allowed: false,
+			}
+		);
+	}
+
+	#[tokio::test]
+	async fn test_audits_local_connections_without_auth() {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let records = serve_audited(&paths, AuthRequired::None, &[]).await;
+		assert_eq!(
+			records[0].event,
+			AuditEvent::Connect {
+				auth: ConnectAuth::None
+			}
+		);
+	}
+}
@@ -2,7 +2,8 @@
  *  Copyright (c) Microsoft Corporation. All rights reserved.
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
-use super::protocol::{self, PortPrivacy, PortProtocol};
+use super::protocol::{self, PortAccess, PortPrivacy, PortProtocol};
+use super::traffic::TrafficStats;
 use crate::auth;
 use crate::constants::{IS_INTERACTIVE_CLI, PROTOCOL_VERSION_TAG, TUNNEL_SERVICE_USER_AGENT};
 use crate::state::{LauncherPaths, PersistedState};
@@ -25,8 +26,9 @@ use std::time::Duration;
 use tokio::sync::{mpsc, watch};
 use tunnels::connections::{ForwardedPortConnection, RelayTunnelHost};
 use tunnels::contracts::{
-	Tunnel, TunnelAccessControl, TunnelPort, TunnelRelayTunnelEndpoint, PORT_TOKEN,
-	TUNNEL_ACCESS_SCOPES_CONNECT, TUNNEL_PROTOCOL_AUTO,
+	Tunnel, TunnelAccessControl, TunnelAccessControlEntry, TunnelAccessControlEntryType,
+	TunnelPort, TunnelRelayTunnelEndpoint, PORT_TOKEN, TUNNEL_ACCESS_SCOPES_CONNECT,
+	TUNNEL_PROTOCOL_AUTO,
 };
 use tunnels::management::{
 	new_tunnel_management, HttpError, TunnelLocator, TunnelManagementClient, TunnelRequestOptions,
@@ -222,9 +224,11 @@ impl ActiveTunnel {
 		port_number: u16,
 		privacy: PortPrivacy,
 		protocol: PortProtocol,
+		access: &[PortAccess],
+		labels: Vec<String>,
 	) -> Result<(), AnyError> {
 		self.manager
-			.add_port_tcp(port_number, privacy, protocol)
+			.add_port_tcp(port_number, privacy, protocol, access, labels)
 			.await?;
 		Ok(())
 	}
@@ -235,6 +239,12 @@ impl ActiveTunnel {
 		Ok(())
 	}
 
+	/// Waits until the tunnel has connected to the relay, after which port
+	/// URIs are available.
+	pub async fn wait_for_endpoint(&mut self) -> Result<(), AnyError> {
+		self.manager.get_endpoint().await.map(|_| ())
+	}
+
 	/// Gets the template string for forming forwarded port web URIs..
 	pub fn get_port_format(&self) -> Result<String, AnyError> {
 		if let Some(details) = &*self.manager.endpoint_rx.borrow() {
@@ -353,7 +363,7 @@ impl DevTunnels {
 			auth,
 			log: log.clone(),
 			client: client.into(),
-			launcher_tunnel: PersistedState::new(paths.root().join("code_tunnel.json")),
+			launcher_tunnel: PersistedState::new(paths.remote_tunnel_file()),
 			tag: VSCODE_CLI_TUNNEL_TAG,
 		}
 	}
@@ -897,17 +907,23 @@ impl DevTunnels {
 }
 
 #[derive(Clone, Default)]
-pub struct StatusLock(Arc<std::sync::Mutex<protocol::singleton::Status>>);
+pub struct StatusLock {
+	status: Arc<std::sync::Mutex<protocol::singleton::Status>>,
+	traffic: TrafficStats,
+}
 
 impl StatusLock {
 	fn succeed(&self) {
-		let mut status = self.0.lock().unwrap();
+		let mut status = self.status.lock().unwrap();
 		status.tunnel = protocol::singleton::TunnelState::Connected;
+		if status.last_connected_at.is_some() {
+			self.traffic.record_reconnect();
+		}
 		status.last_connected_at = Some(chrono::Utc::now());
 	}
 
 	fn fail(&self, reason: String) {
-		let mut status = self.0.lock().unwrap();
+		let mut status = self.status.lock().unwrap();
 		if let protocol::singleton::TunnelState::Connected = status.tunnel {
 			status.last_disconnected_at = Some(chrono::Utc::now());
 			status.tunnel = protocol::singleton::TunnelState::Disconnected;
@@ -916,8 +932,19 @@ impl StatusLock {
 	}
 
 	pub fn read(&self) -> protocol::singleton::Status {
-		let status = self.0.lock().unwrap();
-		status.clone()
+		let mut status = self.status.lock().unwrap().clone();
+		status.traffic = self.traffic.snapshot();
+		status
+	}
+
+	/// Updates parts of the status that other subsystems report.
+	pub fn update(&self, f: impl FnOnce(&mut protocol::singleton::Status)) {
+		f(&mut self.status.lock().unwrap());
+	}
+
+	/// Gets the counters for traffic over the tunnel.
+	pub fn traffic(&self) -> &TrafficStats {
+		&self.traffic
 	}
 }
 
@@ -975,6 +1002,8 @@ impl ActiveTunnelManager {
 		port_number: u16,
 		privacy: PortPrivacy,
 		protocol: PortProtocol,
+		access: &[PortAccess],
+		labels: Vec<String>,
 	) -> Result<(), WrappedError> {
 		self.relay
 			.lock()
@@ -982,7 +1011,8 @@ impl ActiveTunnelManager {
 			.add_port(&TunnelPort {
 				port_number,
 				protocol: Some(protocol.to_contract_str().to_string()),
-				access_control: Some(privacy_to_tunnel_acl(privacy)),
+				access_control: Some(privacy_to_tunnel_acl(privacy, access)),
+				labels,
 				..Default::default()
 			})
 			.await
@@ -1001,7 +1031,7 @@ impl ActiveTunnelManager {
 			.add_port_raw(&TunnelPort {
 				port_number,
 				protocol: Some(TUNNEL_PROTOCOL_AUTO.to_owned()),
-				access_control: Some(privacy_to_tunnel_acl(PortPrivacy::Private)),
+				access_control: Some(privacy_to_tunnel_acl(PortPrivacy::Private, &[])),
 				..Default::default()
 			})
 			.await
@@ -1201,35 +1231,70 @@ fn vec_eq_as_set(a: &[String], b: &[String]) -> bool {
 	true
 }
 
-fn privacy_to_tunnel_acl(privacy: PortPrivacy) -> TunnelAccessControl {
-	TunnelAccessControl {
-		entries: vec![match privacy {
-			PortPrivacy::Public => tunnels::contracts::TunnelAccessControlEntry {
-				kind: tunnels::contracts::TunnelAccessControlEntryType::Anonymous,
-				provider: None,
-				is_inherited: false,
-				is_deny: false,
-				is_inverse: false,
-				organization: None,
-				expiration: None,
-				subjects: vec![],
-				scopes: vec![TUNNEL_ACCESS_SCOPES_CONNECT.to_string()],
-			},
-			// Ensure private ports are actually private and do not inherit any
-			// default visibility that may be set on the tunnel:
-			PortPrivacy::Private => tunnels::contracts::TunnelAccessControlEntry {
-				kind: tunnels::contracts::TunnelAccessControlEntryType::Anonymous,
-				provider: None,
-				is_inherited: false,
-				is_deny: true,
-				is_inverse: false,
-				organization: None,
-				expiration: None,
-				subjects: vec![],
-				scopes: vec![TUNNEL_ACCESS_SCOPES_CONNECT.to_string()],
+/// Gets the access control for a port that was forwarded with the given
+/// access, by one or more clients. Anyone that one of them let in may connect.
+fn privacy_to_tunnel_acl(privacy: PortPrivacy, access: &[PortAccess]) -> TunnelAccessControl {
+	let entry = |kind, is_deny, subjects| TunnelAccessControlEntry {
+		kind,
+		provider: None,
+		is_inherited: false,
+		is_deny,
+		is_inverse: false,
+		organization: None,
+		expiration: None,
+		subjects,
+		scopes: vec![TUNNEL_ACCESS_SCOPES_CONNECT.to_string()],
+	};
+
+	let mut entries = vec![];
+	match privacy {
+		// The port only expires once every client's access has expired, and
+		// doesn't if one of them asked for it not to.
+		PortPrivacy::Public => entries.push(TunnelAccessControlEntry {
+			expiration: match access.iter().all(|a| a.expires_at.is_some()) {
+				true => access.iter().filter_map(|a| a.expires_at).max(),
+				false => None,
 			},
-		}],
+			..entry(TunnelAccessControlEntryType::Anonymous, false, vec![])
+		}),
+		// Ensure private ports are actually private and do not inherit any
+		// default visibility that may be set on the tunnel. Organizations and
+		// users that are allowed in explicitly are still let through:
+		PortPrivacy::Private => {
+			entries.push(entry(TunnelAccessControlEntryType::Anonymous, true, vec![]));
+			let mut providers = vec![];
+			for a in access {
+				if !providers.contains(&a.provider) {
+					providers.push(a.provider);
+				}
+			}
+			for provider in providers {
+				let of_provider = access.iter().filter(|a| a.provider == provider);
+				let orgs = of_provider
+					.clone()
+					.filter_map(|a| a.organization.clone())
+					.collect::<Vec<_>>();
+				let users = of_provider
+					.flat_map(|a| a.users.iter().cloned())
+					.collect::<Vec<_>>();
+				for (kind, mut subjects) in [
+					(TunnelAccessControlEntryType::Organizations, orgs),
+					(TunnelAccessControlEntryType::Users, users),
+				] {
+					subjects.sort();
+					subjects.dedup();
+					if !subjects.is_empty() {
+						entries.push(TunnelAccessControlEntry {
+							provider: Some(provider.to_contract_str().to_string()),
+							..entry(kind, false, subjects)
+						});
+					}
+				}
+			}
+		}
 	}
+
+	TunnelAccessControl { entries }
 }
 
 fn tunnel_has_host_connection(tunnel: &Tunnel) -> bool {
@@ -1243,6 +1308,7 @@ fn tunnel_has_host_connection(tunnel: &Tunnel) -> bool {
 #[cfg(test)]
 mod test {
 	use super::*;
+	use crate::tunnels::protocol::AccessProvider;
 
 	#[test]
 	fn test_clean_hostname_for_tunnel() {
@@ -1260,4 +1326,97 @@ mod test {
 		);
 		assert_eq!(clean_hostname_for_tunnel("z"), "remote-machine".to_string());
 	}
+
+	#[test]
+	fn test_privacy_to_tunnel_acl() {
+		let acl = privacy_to_tunnel_acl(PortPrivacy::Private, &[PortAccess::default()]);
+		assert_eq!(acl.entries.len(), 1);
+		assert!(acl.entries[0].is_deny);
+
+		let acl = privacy_to_tunnel_acl(
+			PortPrivacy::Private,
+			&[PortAccess {
+				provider: AccessProvider::Microsoft,
+				organization: Some("tenant-id".to_string()),
+				users: vec!["alice".to_string()],
+				expires_at: None,
+			}],
+		);
+		assert_eq!(acl.entries.len(), 3);
+		assert!(matches!(
+			acl.entries[1].kind,
+			TunnelAccessControlEntryType::Organizations
+		));
+		assert!(matches!(
+			acl.entries[2].kind,
+			TunnelAccessControlEntryType::Users
+		));
+		assert_eq!(acl.entries[1].provider.as_deref(), Some("microsoft"));
+		assert_eq!(acl.entries[1].subjects, vec!["tenant-id".to_string()]);
+		assert_eq!(acl.entries[2].subjects, vec!["alice".to_string()]);
+
+		let expires_at = chrono::Utc::now() + chrono::Duration::hours(1);
+		let access = PortAccess {
+			expires_at: Some(expires_at),
+			..Default::default()
+		};
+		let acl = privacy_to_tunnel_acl(PortPrivacy::Public, std::slice::from_ref(&access));
+		assert_eq!(acl.entries.len(), 1);
+		assert!(!acl.entries[0].is_deny);
+		assert_eq!(acl.entries[0].expiration, Some(expires_at));
+
+		assert!(access.validate(PortPrivacy::Public).is_ok());
+		assert!(access.validate(PortPrivacy::Private).is_err());
+	}
+
+	#[test]
+	fn test_privacy_to_tunnel_acl_merges_access() {
+		let users = |users: &[&str]| PortAccess {
+			users: users.iter().map(|u| u.to_string()).collect(),
+			..Default::default()
+		};
+		let acl = privacy_to_tunnel_acl(
+			PortPrivacy::Private,
+			&[
+				users(&["bob", "alice"]),
+				PortAccess {
+					organization: Some("contoso".to_string()),
+					..users(&["alice"])
+				},
+				PortAccess {
+					provider: AccessProvider::Microsoft,
+					organization: Some("tenant-id".to_string()),
+					..Default::default()
+				},
+			],
+		);
+		let entries = acl
+			.entries
+			.iter()
+			.skip(1)
+			.map(|e| (e.provider.as_deref().unwrap(), e.subjects.clone()))
+			.collect::<Vec<_>>();
+		assert_eq!(
+			entries,
+			vec![
+				("github", vec!["contoso".to_string()]),
+				("github", vec!["alice".to_string(), "bob".to_string()]),
+				("microsoft", vec!["tenant-id".to_string()]),
+			]
+		);
+
+		let soon = chrono::Utc::now() + chrono::Duration::hours(1);
+		let later = soon + chrono::Duration::hours(1);
+		let expiring = |t| PortAccess {
+			expires_at: Some(t),
+			..Default::default()
+		};
+		let acl = privacy_to_tunnel_acl(PortPrivacy::Public, &[expiring(later), expiring(soon)]);
+		assert_eq!(acl.entries[0].expiration, Some(later));
+		let acl = privacy_to_tunnel_acl(
+			PortPrivacy::Public,
+			&[expiring(soon), PortAccess::default()],
+		);
+		assert_eq!(acl.entries[0].expiration, None);
+	}
 }
@@ -0,0 +1,297 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	fs::{self, File},
+	io::Read,
+	path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+	state::LauncherPaths,
+	util::{
+		errors::{wrap, AnyError, CodeError},
+		io::SilentCopyProgress,
+		tar::{decompress_tarball, has_gzip_header},
+		zipper::unzip_file,
+	},
+};
+
+/// Name of the manifest at the root of a bundle.
+pub const MANIFEST_FILE: &str = "manifest.json";
+/// Written to a cached bundle once its contents are verified.
+const VERIFIED_MARKER: &str = ".verified";
+/// Written to a server's directory with the ID of the bundle installed in it.
+pub const INSTALLED_MARKER: &str = "extension-bundle";
+
+/// Manifest of an extension bundle, which locks each extension to a version
+/// and the hash of its VSIX.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BundleManifest {
+	pub extensions: Vec<BundleEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BundleEntry {
+	/// Extension ID, as `publisher.name`.
+	pub id: String,
+	pub version: String,
+	/// Name of the VSIX file in the bundle.
+	pub file: String,
+	/// Hex-encoded SHA-256 of the VSIX file.
+	pub sha256: String,
+}
+
+/// The part of an extension's package.json that identifies it.
+#[derive(Deserialize)]
+struct PackageJson {
+	publisher: String,
+	name: String,
+	version: String,
+}
+
+/// A verified bundle in the launcher's cache.
+#[derive(Debug, Clone)]
+pub struct ExtensionBundle {
+	/// Identifies the bundle's contents, derived from its manifest.
+	pub id: String,
+	pub dir: PathBuf,
+	pub manifest: BundleManifest,
+}
+
+impl ExtensionBundle {
+	/// Verifies the bundle at `source`, a directory or a zip or tar.gz archive
+	/// with a manifest and VSIX files, and copies it into the launcher's cache.
+	/// Bundles that were verified before are used from the cache.
+	pub fn prepare(launcher_paths: &LauncherPaths, source: &Path) -> Result<Self, AnyError> {
+		let bundles_dir = launcher_paths.extension_bundles();
+		fs::create_dir_all(&bundles_dir).map_err(|e| {
+			wrap(
+				e,
+				format!("error creating directory {}", bundles_dir.display()),
+			)
+		})?;
+
+		let extracted;
+		let source_dir = if source.is_dir() {
+			source
+		} else {
+			extracted = tempfile::tempdir_in(&bundles_dir)
+				.map_err(|e| wrap(e, "error creating temporary directory"))?;
+			extract_archive(source, extracted.path())?;
+			extracted.path()
+		};
+
+		let manifest_bytes = fs::read(source_dir.join(MANIFEST_FILE)).map_err(|e| {
+			invalid(format!(
+				"could not read {} in {}: {}",
+				MANIFEST_FILE,
+				source.display(),
+				e
+			))
+		})?;
+		let manifest: BundleManifest = serde_json::from_slice(&manifest_bytes)
+			.map_err(|e| invalid(format!("invalid {MANIFEST_FILE}: {e}")))?;
+
+		let id = hex(&Sha256::digest(&manifest_bytes))[..16].to_string();
+		let dir = bundles_dir.join(&id);
+		if dir.join(VERIFIED_MARKER).exists() {
+			return Ok(Self { id, dir, manifest });
+		}
+
+		let staging = bundles_dir.join(format!("{}.{}.tmp", id, uuid::Uuid::new_v4()));
+		let staged = stage_bundle(source_dir, &manifest, &manifest_bytes, &staging);
+		if let Err(e) = staged {
+			let _ = fs::remove_dir_all(&staging);
+			return Err(e);
+		}
+
+		// another server may have cached the same bundle in the meantime
+		if let Err(e) = fs::rename(&staging, &dir) {
+			let _ = fs::remove_dir_all(&staging);
+			if !dir.join(VERIFIED_MARKER).exists() {
+				return Err(wrap(e, format!("error caching bundle in {}", dir.display())).into());
+			}
+		}
+
+		Ok(Self { id, dir, manifest })
+	}
+
+	/// Gets the paths of the bundle's VSIX files.
+	pub fn vsix_paths(&self) -> Vec<PathBuf> {
+		self.manifest
+			.extensions
+			.iter()
+			.map(|e| self.dir.join(&e.file))
+			.collect()
+	}
+}
+
+/// Copies the bundle's files into the `staging` directory and verifies the
+/// copies, so that the source can't be swapped after it was checked.
+fn stage_bundle(
+	source_dir: &Path,
+	manifest: &BundleManifest,
+	manifest_bytes: &[u8],
+	staging: &Path,
+) -> Result<(), AnyError> {
+	fs::create_dir(staging).map_err(|e| wrap(e, "error creating staging directory"))?;
+	for entry in &manifest.extensions {
+		verify_file_name(entry)?;
+		fs::copy(source_dir.join(&entry.file), staging.join(&entry.file))
+			.map_err(|e| invalid(format!("could not copy {}: {}", entry.file, e)))?;
+		verify_entry(staging, entry)?;
+	}
+	fs::write(staging.join(MANIFEST_FILE), manifest_bytes)
+		.map_err(|e| wrap(e, "error writing bundle manifest"))?;
+	fs::write(staging.join(VERIFIED_MARKER), "")
+		.map_err(|e| wrap(e, "error writing bundle marker"))?;
+	Ok(())
+}
+
+fn invalid(message: String) -> CodeError {
+	CodeError::InvalidExtensionBundle(message)
+}
+
+fn hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn extract_archive(archive: &Path, to: &Path) -> Result<(), AnyError> {
+	let (file, is_gzip) = has_gzip_header(archive).map_err(|e| {
+		invalid(format!(
+			"could not open bundle {}: {}",
+			archive.display(),
+			e
+		))
+	})?;
+	if is_gzip {
+		decompress_tarball(file, to, SilentCopyProgress())?;
+	} else {
+		unzip_file(file, to, SilentCopyProgress())?;
+	}
+	Ok(())
+}
+
+/// Checks that the entry's file is directly in the bundle.
+fn verify_file_name(entry: &BundleEntry) -> Result<(), CodeError> {
+	let is_plain_name = Path::new(&entry.file)
+		.file_name()
+		.map(|n| n == entry.file.as_str())
+		.unwrap_or(false);
+	if !is_plain_name {
+		return Err(invalid(format!(
+			"{} must be a file name in the bundle",
+			entry.file
+		)));
+	}
+	Ok(())
+}
+
+/// Checks that the entry's VSIX in `dir` has the expected hash, and that it
+/// contains the extension and version the manifest says it does.
+fn verify_entry(dir: &Path, entry: &BundleEntry) -> Result<(), CodeError> {
+	let path = dir.join(&entry.file);
+	let contents = fs::read(&path).map_err(|e| invalid(format!("{}: {}", entry.file, e)))?;
+	let actual = hex(&Sha256::digest(&contents));
+	if !actual.eq_ignore_ascii_case(&entry.sha256) {
+		return Err(invalid(format!(
+			"{} has SHA-256 {}, but the manifest expects {}",
+			entry.file, actual, entry.sha256
+		)));
+	}
+
+	let package = read_package_json(&path)
+		.map_err(|e| invalid(format!("could not read {}: {}", entry.file, e)))?;
+	let id = format!("{}.{}", package.publisher, package.name);
+	if !id.eq_ignore_ascii_case(&entry.id) || package.version != entry.version {
+		return Err(invalid(format!(
+			"{} contains {}@{}, but the manifest expects {}@{}",
+			entry.file, id, package.version, entry.id, entry.version
+		)));
+	}
+
+	Ok(())
+}
+
+fn read_package_json(vsix: &Path) -> Result<PackageJson, String> {
+	let file = File::open(vsix).map_err(|e| e.to_string())?;
+	let mut archive = zip::ZipArchive::new(file).map_err(|e| e.to_string())?;
+	let mut package = archive
+		.by_name("extension/package.json")
+		.map_err(|e| e.to_string())?;
+	let mut contents = String::new();
+	package
+		.read_to_string(&mut contents)
+		.map_err(|e| e.to_string())?;
+	serde_json::from_str(&contents).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Write;
+
+	use super::*;
+
+	fn write_vsix(path: &Path, publisher: &str, name: &str, version: &str) -> String {
+		let mut zip = zip::ZipWriter::new(File::create(path).unwrap());
+		zip.start_file("extension/package.json", Default::default())
+			.unwrap();
+		write!(
+			zip,
+			r#"{{"publisher":"{publisher}","name":"{name}","version":"{version}"}}"#
+		)
+		.unwrap();
+		zip.finish().unwrap();
+		hex(&Sha256::digest(fs::read(path).unwrap()))
+	}
+
+	#[test]
+	fn test_prepare_verifies_and_caches() {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = LauncherPaths::new_without_replacements(dir.path().join("launcher"));
+		let source = dir.path().join("bundle");
+		fs::create_dir(&source).unwrap();
+
+		let sha256 = write_vsix(&source.join("a.vsix"), "pub", "ext", "1.2.3");
+		let mut manifest = BundleManifest {
+			extensions: vec![BundleEntry {
+				id: "pub.ext".to_string(),
+				version: "1.2.3".to_string(),
+				file: "a.vsix".to_string(),
+				sha256: sha256.clone(),
+			}],
+		};
+		let write_manifest = |m: &BundleManifest| {
+			fs::write(
+				source.join(MANIFEST_FILE),
+				serde_json::to_string(m).unwrap(),
+			)
+			.unwrap()
+		};
+
+		write_manifest(&manifest);
+		let bundle = ExtensionBundle::prepare(&paths, &source).unwrap();
+		assert!(bundle.vsix_paths()[0].exists());
+		assert!(bundle.dir.join(VERIFIED_MARKER).exists());
+
+		manifest.extensions[0].version = "1.2.4".to_string();
+		write_manifest(&manifest);
+		assert!(ExtensionBundle::prepare(&paths, &source).is_err());
+
+		manifest.extensions[0].version = "1.2.3".to_string();
+		manifest.extensions[0].sha256 = "00".repeat(32);
+		write_manifest(&manifest);
+		assert!(ExtensionBundle::prepare(&paths, &source).is_err());
+
+		manifest.extensions[0].sha256 = sha256;
+		manifest.extensions[0].file = "../a.vsix".to_string();
+		write_manifest(&manifest);
+		assert!(ExtensionBundle::prepare(&paths, &source).is_err());
+	}
+}
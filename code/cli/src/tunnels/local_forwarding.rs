@@ -7,6 +7,7 @@ use std::{
 	collections::HashMap,
 	ops::{Index, IndexMut},
 	sync::{Arc, Mutex},
+	time::Duration,
 };
 
 use tokio::{
@@ -26,12 +27,19 @@ use super::{
 	dev_tunnels::ActiveTunnel,
 	protocol::{
 		self,
-		forward_singleton::{PortList, SetPortsResponse},
-		PortPrivacy, PortProtocol,
+		forward_singleton::{
+			ForwardedPort, ListPortsResponse, PortList, PortRec, RemovePortsParams,
+			RemovePortsResponse, SetPortsParams, SetPortsResponse,
+		},
+		PortAccess, PortPrivacy, PortProtocol,
 	},
 	shutdown_signal::ShutdownSignal,
 };
 
+/// How long a forwarding process started with `exit_when_idle` keeps
+/// running once no ports are forwarded.
+const IDLE_EXIT_AFTER: Duration = Duration::from_secs(30);
+
 #[derive(Default, Clone)]
 struct PortCount {
 	public: u32,
@@ -75,6 +83,29 @@ impl PortCount {
 struct PortMapRec {
 	count: PortCount,
 	protocol: PortProtocol,
+	/// Access that each forward of the port asked for, with the privacy it
+	/// was forwarded with. That of forwards with the port's privacy is merged.
+	access: Vec<(PortPrivacy, PortAccess)>,
+	labels: Vec<String>,
+}
+
+impl PortMapRec {
+	/// Whether the port must be re-added to the tunnel to go from `self` to `other`.
+	fn needs_update(&self, other: &PortMapRec) -> bool {
+		self.count.primary_privacy() != other.count.primary_privacy()
+			|| self.access() != other.access()
+			|| self.labels != other.labels
+	}
+
+	/// Gets the access that forwards with the port's privacy asked for.
+	fn access(&self) -> Vec<PortAccess> {
+		let privacy = self.count.primary_privacy();
+		self.access
+			.iter()
+			.filter(|(p, _)| *p == privacy)
+			.map(|(_, a)| a.clone())
+			.collect()
+	}
 }
 
 type PortMap = HashMap<u16, PortMapRec>;
@@ -105,6 +136,10 @@ impl PortForwardingSender {
 				if !ports.contains(p) {
 					let n = v.get_mut(&p.number).expect("expected port in map");
 					n.count[p.privacy] -= 1;
+					let access = (p.privacy, p.access.clone());
+					if let Some(i) = n.access.iter().position(|a| a == &access) {
+						n.access.remove(i);
+					}
 					if n.count.is_empty() {
 						v.remove(&p.number);
 					}
@@ -117,6 +152,8 @@ impl PortForwardingSender {
 						Some(n) => {
 							n.count[p.privacy] += 1;
 							n.protocol = p.protocol;
+							n.access.push((p.privacy, p.access.clone()));
+							n.labels = p.labels.clone();
 						}
 						None => {
 							let mut count = PortCount::default();
@@ -126,6 +163,8 @@ impl PortForwardingSender {
 								PortMapRec {
 									count,
 									protocol: p.protocol,
+									access: vec![(p.privacy, p.access.clone())],
+									labels: p.labels.clone(),
 								},
 							);
 						}
@@ -136,6 +175,16 @@ impl PortForwardingSender {
 			current.splice(.., ports);
 		});
 	}
+
+	/// Gets the ports set through this sender.
+	pub fn current(&self) -> PortList {
+		self.current.lock().unwrap().clone()
+	}
+
+	/// Gets the ports set through all senders.
+	fn forwarded(&self) -> PortMap {
+		self.sender.lock().unwrap().borrow().clone()
+	}
 }
 
 impl Clone for PortForwardingSender {
@@ -178,7 +227,7 @@ impl PortForwardingReceiver {
 
 			for (port, rec) in current.iter() {
 				let privacy = rec.count.primary_privacy();
-				if !matches!(next.get(port), Some(n) if n.count.primary_privacy() == privacy) {
+				if !matches!(next.get(port), Some(n) if !n.needs_update(rec)) {
 					match tunnel.remove_port(*port).await {
 						Ok(_) => info!(
 							log,
@@ -194,8 +243,17 @@ impl PortForwardingReceiver {
 
 			for (port, rec) in next.iter() {
 				let privacy = rec.count.primary_privacy();
-				if !matches!(current.get(port), Some(n) if n.count.primary_privacy() == privacy) {
-					match tunnel.add_port_tcp(*port, privacy, rec.protocol).await {
+				if !matches!(current.get(port), Some(n) if !n.needs_update(rec)) {
+					match tunnel
+						.add_port_tcp(
+							*port,
+							privacy,
+							rec.protocol,
+							&rec.access(),
+							rec.labels.clone(),
+						)
+						.await
+					{
 						Ok(_) => info!(
 							log,
 							"forwarding {} port {} at {:?}", rec.protocol, port, privacy
@@ -213,17 +271,25 @@ impl PortForwardingReceiver {
 	}
 }
 
+/// Reports the result of setting ports, e.g. by printing where they can be
+/// reached.
+pub type PortsCallback = Arc<dyn Fn(&SetPortsResponse) + Send + Sync>;
+
 pub struct SingletonClientArgs {
 	pub log: log::Logger,
 	pub stream: AsyncPipe,
 	pub shutdown: Barrier<ShutdownSignal>,
 	pub port_requests: watch::Receiver<PortList>,
+	pub on_ports: PortsCallback,
 }
 
 #[derive(Clone)]
 struct SingletonServerContext {
 	log: log::Logger,
 	handle: PortForwardingSender,
+	/// Ports handed to the process with `detach_ports`, which are forwarded
+	/// regardless of which clients are connected.
+	detached: Arc<PortForwardingSender>,
 	tunnel: Arc<ActiveTunnel>,
 }
 
@@ -236,6 +302,7 @@ pub async fn client(args: SingletonClientArgs) -> Result<(), std::io::Error> {
 		shutdown,
 		stream,
 		mut port_requests,
+		on_ports,
 	} = args;
 
 	debug!(
@@ -261,7 +328,7 @@ pub async fn client(args: SingletonClientArgs) -> Result<(), std::io::Error> {
 
 			match r {
 				Err(e) => error!(log, "failed to set ports: {:?}", e),
-				Ok(r) => print_forwarding_addr(&r),
+				Ok(r) => on_ports(&r),
 			};
 		}
 	};
@@ -272,14 +339,30 @@ pub async fn client(args: SingletonClientArgs) -> Result<(), std::io::Error> {
 	}
 }
 
+pub struct SingletonServerArgs {
+	pub log: log::Logger,
+	pub tunnel: ActiveTunnel,
+	pub server: SingletonServer,
+	pub port_requests: watch::Receiver<PortList>,
+	pub on_ports: PortsCallback,
+	/// Exits once no ports have been forwarded for a while, used for
+	/// processes that only serve detached ports.
+	pub exit_when_idle: bool,
+	pub shutdown: Barrier<ShutdownSignal>,
+}
+
 /// Serves a port-forwarding singleton.
-pub async fn server(
-	log: log::Logger,
-	tunnel: ActiveTunnel,
-	server: SingletonServer,
-	mut port_requests: watch::Receiver<PortList>,
-	shutdown_rx: Barrier<ShutdownSignal>,
-) -> Result<(), CodeError> {
+pub async fn server(args: SingletonServerArgs) -> Result<(), CodeError> {
+	let SingletonServerArgs {
+		log,
+		tunnel,
+		server,
+		mut port_requests,
+		on_ports,
+		exit_when_idle,
+		shutdown,
+	} = args;
+
 	let tunnel = Arc::new(tunnel);
 	let (forward_tx, mut forward_rx) = PortForwardingReceiver::new();
 
@@ -287,17 +370,41 @@ pub async fn server(
 	let forward_own_tx = forward_tx.clone();
 	let forward_own = async move {
 		while port_requests.changed().await.is_ok() {
-			forward_own_tx.set_ports(port_requests.borrow().clone());
-			print_forwarding_addr(&SetPortsResponse {
+			let ports = port_requests.borrow().clone();
+			forward_own_tx.set_ports(ports.clone());
+			on_ports(&SetPortsResponse {
 				port_format: forward_own_tunnel.get_port_format().ok(),
+				ports: describe_ports(&forward_own_tunnel, &ports, false),
 			});
 		}
+
+		// with nothing of its own to forward, keep serving other clients
+		if exit_when_idle {
+			std::future::pending::<()>().await;
+		}
+	};
+
+	let idle_tx = forward_tx.clone();
+	let idle_log = log.clone();
+	let exit_idle = async move {
+		if !exit_when_idle {
+			return std::future::pending().await;
+		}
+
+		loop {
+			tokio::time::sleep(IDLE_EXIT_AFTER).await;
+			if idle_tx.forwarded().is_empty() {
+				info!(idle_log, "No ports are forwarded, exiting");
+				return;
+			}
+		}
 	};
 
 	tokio::select! {
 		_ = forward_own => Ok(()),
+		_ = exit_idle => Ok(()),
 		_ = forward_rx.apply_to(log.clone(), tunnel.clone()) => Ok(()),
-		r = serve_singleton_rpc(server, log, tunnel, forward_tx, shutdown_rx) => r,
+		r = serve_singleton_rpc(server, log, tunnel, forward_tx, shutdown) => r,
 	}
 }
 
@@ -312,6 +419,8 @@ async fn serve_singleton_rpc(
 	let shutdown_fut = own_shutdown.wait();
 	pin!(shutdown_fut);
 
+	let detached = Arc::new(forward_tx.clone());
+
 	loop {
 		let cnx = tokio::select! {
 			c = server.accept() => c?,
@@ -322,6 +431,7 @@ async fn serve_singleton_rpc(
 		let shutdown_rx = shutdown_rx.clone();
 
 		let handle = forward_tx.clone();
+		let detached = detached.clone();
 		let log = log.clone();
 		let tunnel = tunnel.clone();
 		tokio::spawn(async move {
@@ -331,16 +441,71 @@ async fn serve_singleton_rpc(
 			let mut rpc = rpc.methods(SingletonServerContext {
 				log: log.clone(),
 				handle,
+				detached,
 				tunnel,
 			});
 
 			rpc.register_sync(
 				protocol::forward_singleton::METHOD_SET_PORTS,
-				|p: protocol::forward_singleton::SetPortsParams, ctx| {
+				|p: SetPortsParams, ctx| {
 					info!(ctx.log, "client setting ports to {:?}", p.ports);
-					ctx.handle.set_ports(p.ports);
+					ctx.handle.set_ports(p.ports.clone());
+					Ok(SetPortsResponse {
+						port_format: ctx.tunnel.get_port_format().ok(),
+						ports: describe_ports(&ctx.tunnel, &p.ports, false),
+					})
+				},
+			);
+
+			rpc.register_sync(
+				protocol::forward_singleton::METHOD_LIST_PORTS,
+				|_: protocol::EmptyObject, ctx| {
+					let detached = ctx.detached.current();
+					let mut ports: Vec<ForwardedPort> = ctx
+						.handle
+						.forwarded()
+						.into_iter()
+						.map(|(number, rec)| ForwardedPort {
+							number,
+							privacy: rec.count.primary_privacy(),
+							protocol: rec.protocol,
+							labels: rec.labels,
+							uri: ctx.tunnel.get_port_uri(number).ok(),
+							detached: detached.iter().any(|p| p.number == number),
+						})
+						.collect();
+					ports.sort_by_key(|p| p.number);
+					Ok(ListPortsResponse { ports })
+				},
+			);
+
+			rpc.register_sync(
+				protocol::forward_singleton::METHOD_DETACH_PORTS,
+				|p: SetPortsParams, ctx| {
+					info!(ctx.log, "client detaching ports {:?}", p.ports);
+					let mut ports = ctx.detached.current();
+					ports.retain(|c| !p.ports.iter().any(|n| n.number == c.number));
+					ports.extend(p.ports.iter().cloned());
+					ctx.detached.set_ports(ports);
 					Ok(SetPortsResponse {
 						port_format: ctx.tunnel.get_port_format().ok(),
+						ports: describe_ports(&ctx.tunnel, &p.ports, true),
+					})
+				},
+			);
+
+			rpc.register_sync(
+				protocol::forward_singleton::METHOD_REMOVE_PORTS,
+				|p: RemovePortsParams, ctx| {
+					info!(ctx.log, "client removing detached ports {:?}", p.ports);
+					let (removed, kept): (PortList, PortList) = ctx
+						.detached
+						.current()
+						.into_iter()
+						.partition(|c| p.ports.contains(&c.number));
+					ctx.detached.set_ports(kept);
+					Ok(RemovePortsResponse {
+						removed: removed.into_iter().map(|r| r.number).collect(),
 					})
 				},
 			);
@@ -350,6 +515,64 @@ async fn serve_singleton_rpc(
 	}
 }
 
-fn print_forwarding_addr(r: &SetPortsResponse) {
+fn describe_ports(tunnel: &ActiveTunnel, ports: &[PortRec], detached: bool) -> Vec<ForwardedPort> {
+	ports
+		.iter()
+		.map(|p| ForwardedPort {
+			number: p.number,
+			privacy: p.privacy,
+			protocol: p.protocol,
+			labels: p.labels.clone(),
+			uri: tunnel.get_port_uri(p.number).ok(),
+			detached,
+		})
+		.collect()
+}
+
+pub fn print_forwarding_addr(r: &SetPortsResponse) {
 	eprintln!("{}\n", serde_json::to_string(r).unwrap());
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn port(number: u16, privacy: PortPrivacy, users: &[&str]) -> PortRec {
+		PortRec {
+			number,
+			privacy,
+			protocol: PortProtocol::Auto,
+			access: PortAccess {
+				users: users.iter().map(|u| u.to_string()).collect(),
+				..Default::default()
+			},
+			labels: vec![],
+		}
+	}
+
+	fn users(rec: &PortMapRec) -> Vec<Vec<String>> {
+		rec.access().into_iter().map(|a| a.users).collect()
+	}
+
+	#[test]
+	fn test_set_ports_merges_access() {
+		let (alice, _receiver) = PortForwardingReceiver::new();
+		let bob = alice.clone();
+
+		alice.set_ports(vec![port(3000, PortPrivacy::Private, &["alice"])]);
+		let before = alice.forwarded()[&3000].clone();
+		bob.set_ports(vec![port(3000, PortPrivacy::Private, &["bob"])]);
+		let merged = alice.forwarded()[&3000].clone();
+		assert_eq!(users(&merged), vec![vec!["alice"], vec!["bob"]]);
+		assert!(merged.needs_update(&before));
+
+		// access of forwards with another privacy doesn't apply
+		let carol = alice.clone();
+		carol.set_ports(vec![port(3000, PortPrivacy::Public, &[])]);
+		assert_eq!(users(&alice.forwarded()[&3000]), vec![Vec::<String>::new()]);
+		drop(carol);
+
+		drop(alice);
+		assert_eq!(users(&bob.forwarded()[&3000]), vec![vec!["bob"]]);
+	}
+}
@@ -5,12 +5,13 @@
 
 use std::{
 	fs::{read_dir, read_to_string, remove_dir_all, write},
-	path::PathBuf,
+	path::{Path, PathBuf, MAIN_SEPARATOR},
 };
 
 use serde::{Deserialize, Serialize};
 
 use crate::{
+	download_cache::{DownloadCache, PruneCandidate, RetentionPolicy},
 	options::{self, Quality},
 	state::LauncherPaths,
 	util::{
@@ -30,6 +31,8 @@ pub struct ServerPaths {
 	pub logfile: PathBuf,
 	// File where the process ID for the server should be written.
 	pub pidfile: PathBuf,
+	// Whether the paths are for a named tunnel instance.
+	pub is_named_instance: bool,
 }
 
 impl ServerPaths {
@@ -43,7 +46,17 @@ impl ServerPaths {
 			};
 		}
 
+		// named instances always record their servers' process IDs, so don't
+		// guess, which could pick up a server another instance started
+		if self.is_named_instance {
+			return None;
+		}
+
 		if let Some(pid) = machine::find_running_process(&self.executable) {
+			if self.instance_pids().contains(&pid) {
+				return None;
+			}
+
 			// attempt to backfill process ID:
 			self.write_pid(pid).ok();
 			return Some(pid);
@@ -52,6 +65,33 @@ impl ServerPaths {
 		None
 	}
 
+	/// Gets whether the server is running for any tunnel instance.
+	pub fn is_running_in_any_instance(&self) -> bool {
+		self.instance_pids()
+			.into_iter()
+			.any(|pid| machine::process_at_path_exists(pid, &self.executable))
+			|| machine::find_running_process(&self.executable).is_some()
+	}
+
+	/// Reads process IDs recorded by every tunnel instance that ran the server.
+	fn instance_pids(&self) -> Vec<u32> {
+		let entries = match read_dir(&self.server_dir) {
+			Ok(e) => e,
+			Err(_) => return vec![],
+		};
+
+		entries
+			.flatten()
+			.filter(|e| {
+				let name = e.file_name();
+				let name = name.to_string_lossy();
+				name.starts_with("pid") && name.ends_with(".txt")
+			})
+			.filter_map(|e| read_to_string(e.path()).ok())
+			.filter_map(|s| s.parse::<u32>().ok())
+			.collect()
+	}
+
 	/// Delete the server directory
 	pub fn delete(&self) -> Result<(), WrappedError> {
 		remove_dir_all(&self.server_dir).map_err(|e| {
@@ -100,8 +140,9 @@ impl InstalledServer {
 					.join("bin")
 					.join(self.quality.server_entrypoint())
 			},
-			logfile: server_dir.join("log.txt"),
-			pidfile: server_dir.join("pid.txt"),
+			logfile: server_dir.join(format!("log{}.txt", p.tunnel_instance_suffix())),
+			pidfile: server_dir.join(format!("pid{}.txt", p.tunnel_instance_suffix())),
+			is_named_instance: p.tunnel_instance().is_some(),
 			server_dir,
 		}
 	}
@@ -120,12 +161,106 @@ pub fn prune_stopped_servers(launcher_paths: &LauncherPaths) -> Result<Vec<Serve
 	get_all_servers(launcher_paths)
 		.into_iter()
 		.map(|s| s.server_paths(launcher_paths))
-		.filter(|s| s.get_running_pid().is_none())
+		.filter(|s| !s.is_running_in_any_instance())
 		.map(|s| s.delete().map(|_| s))
 		.collect::<Result<_, _>>()
 		.map_err(AnyError::from)
 }
 
+/// Downloads removed, or that would be removed, from one of the caches.
+#[derive(Serialize)]
+pub struct PrunedCache {
+	pub cache: &'static str,
+	pub path: PathBuf,
+	pub removed: Vec<PruneCandidate>,
+}
+
+/// Applies the retention policy to the server cache, and also to the CLI and
+/// web server caches if `include_downloads` is set. Servers whose pidfile
+/// names a running process are never removed, nor are other downloads that
+/// appear on a running process's command line.
+pub fn prune_caches(
+	launcher_paths: &LauncherPaths,
+	policy: &RetentionPolicy,
+	include_downloads: bool,
+	dry_run: bool,
+) -> Result<Vec<PrunedCache>, AnyError> {
+	let server_in_use = |path: &Path| match installed_server_in(path) {
+		Some(s) => s.server_paths(launcher_paths).is_running_in_any_instance(),
+		None => true,
+	};
+
+	let mut pruned = vec![plan_cache_prune(
+		"servers",
+		&launcher_paths.server_cache,
+		policy,
+		server_in_use,
+		dry_run,
+	)?];
+
+	if include_downloads {
+		let running = machine::running_process_args();
+		let in_use = |path: &Path| {
+			let prefix = format!("{}{}", path.display(), MAIN_SEPARATOR);
+			running.iter().any(|a| a.contains(&prefix))
+		};
+
+		pruned.push(plan_cache_prune(
+			"cli",
+			&launcher_paths.cli_cache,
+			policy,
+			in_use,
+			dry_run,
+		)?);
+		pruned.push(plan_cache_prune(
+			"serve-web",
+			&DownloadCache::new(launcher_paths.web_server_storage()),
+			policy,
+			in_use,
+			dry_run,
+		)?);
+	}
+
+	Ok(pruned)
+}
+
+fn plan_cache_prune(
+	name: &'static str,
+	cache: &DownloadCache,
+	policy: &RetentionPolicy,
+	in_use: impl Fn(&Path) -> bool,
+	dry_run: bool,
+) -> Result<PrunedCache, AnyError> {
+	let removed = cache.plan_prune(policy, in_use);
+	if !dry_run {
+		cache.remove(&removed)?;
+	}
+
+	Ok(PrunedCache {
+		cache: name,
+		path: cache.path().to_path_buf(),
+		removed,
+	})
+}
+
+/// Gets the server installed in a directory of the server cache.
+fn installed_server_in(dir: &Path) -> Option<InstalledServer> {
+	let name = dir.file_name()?.to_str()?;
+	let (quality, commit) = name.split_once('-')?;
+	// folders are named with the capitalized quality, see get_server_folder_name
+	let quality = options::Quality::try_from(quality.to_lowercase().as_str()).ok()?;
+	let (commit, headless) = match commit.strip_suffix("-web") {
+		Some(c) => (c, false),
+		None => (commit, true),
+	};
+
+	Some(InstalledServer {
+		quality,
+		commit: commit.to_string(),
+		headless,
+	})
+}
+
 // Gets a list of all servers which look like they might be running.
 pub fn get_all_servers(lp: &LauncherPaths) -> Vec<InstalledServer> {
 	let mut servers: Vec<InstalledServer> = vec![];
@@ -138,7 +273,7 @@ pub fn get_all_servers(lp: &LauncherPaths) -> Vec<InstalledServer> {
 				None => continue,
 			};
 
-			let quality = match options::Quality::try_from(quality) {
+			let quality = match options::Quality::try_from(quality.to_lowercase().as_str()) {
 				Ok(q) => q,
 				Err(_) => continue,
 			};
@@ -157,3 +292,45 @@ pub fn get_all_servers(lp: &LauncherPaths) -> Vec<InstalledServer> {
 pub fn get_server_folder_name(quality: Quality, commit: &str) -> String {
 	format!("{quality}-{commit}")
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_prune_caches_only_includes_downloads_when_asked() {
+		let dir = tempfile::tempdir().unwrap();
+		let paths = LauncherPaths::new_without_replacements(dir.path().to_owned());
+		let server = paths.server_cache.path().join("stable-abc123");
+		let cli = paths.cli_cache.path().join("stable-def456");
+		let unknown = paths.server_cache.path().join("not-a-server");
+		for d in [&server, &cli, &unknown] {
+			std::fs::create_dir_all(d).unwrap();
+		}
+
+		let pruned = prune_caches(&paths, &RetentionPolicy::default(), false, false).unwrap();
+		assert_eq!(pruned.len(), 1);
+		assert_eq!(pruned[0].removed.len(), 1);
+		assert!(!server.exists());
+		assert!(cli.exists());
+		assert!(unknown.exists());
+
+		let pruned = prune_caches(&paths, &RetentionPolicy::default(), true, true).unwrap();
+		assert_eq!(pruned.len(), 3);
+		assert_eq!(pruned[1].removed.len(), 1);
+		assert!(cli.exists());
+	}
+
+	#[test]
+	fn test_installed_server_in() {
+		let s = installed_server_in(Path::new("/c/stable-abc-web")).unwrap();
+		assert_eq!(s.commit, "abc");
+		assert!(!s.headless);
+		assert!(
+			installed_server_in(Path::new("/c/stable-abc"))
+				.unwrap()
+				.headless
+		);
+		assert!(installed_server_in(Path::new("/c/lru.json")).is_none());
+	}
+}
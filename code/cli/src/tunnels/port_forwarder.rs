@@ -3,7 +3,7 @@
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
 
-use std::collections::HashSet;
+use std::collections::HashMap;
 
 use tokio::sync::{mpsc, oneshot};
 
@@ -14,11 +14,68 @@ use crate::{
 
 use super::{
 	dev_tunnels::ActiveTunnel,
-	protocol::{PortPrivacy, PortProtocol},
+	protocol::{PortAccess, PortPrivacy, PortProtocol},
 };
 
+/// A port forwarded for clients, with the access each of them asked for.
+struct ForwardedPort {
+	options: ForwardOptions,
+	access: Vec<PortAccess>,
+}
+
+impl ForwardedPort {
+	fn new(options: ForwardOptions) -> Self {
+		Self {
+			access: vec![options.access.clone()],
+			options,
+		}
+	}
+
+	/// Adds the access another client asked for, returning whether the port's
+	/// access changed as a result. Access for another privacy than the port
+	/// was forwarded with doesn't apply.
+	fn add_access(&mut self, options: &ForwardOptions) -> bool {
+		if options.privacy != self.options.privacy || self.access.contains(&options.access) {
+			return false;
+		}
+		self.access.push(options.access.clone());
+		true
+	}
+}
+
+/// How a port is forwarded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ForwardOptions {
+	pub privacy: PortPrivacy,
+	pub protocol: PortProtocol,
+	pub labels: Vec<String>,
+	pub access: PortAccess,
+}
+
+impl ForwardOptions {
+	pub fn new(privacy: PortPrivacy) -> Self {
+		Self {
+			privacy,
+			protocol: PortProtocol::Auto,
+			labels: vec![],
+			access: PortAccess::default(),
+		}
+	}
+}
+
 pub enum PortForwardingRec {
-	Forward(u16, PortPrivacy, oneshot::Sender<Result<String, AnyError>>),
+	Forward(
+		u16,
+		ForwardOptions,
+		oneshot::Sender<Result<String, AnyError>>,
+	),
+	/// Forwards the port unless it's already forwarded, in which case `None`
+	/// is sent back.
+	ForwardNew(
+		u16,
+		ForwardOptions,
+		oneshot::Sender<Result<Option<String>, AnyError>>,
+	),
 	Unforward(u16, oneshot::Sender<Result<(), AnyError>>),
 }
 
@@ -28,7 +85,7 @@ pub enum PortForwardingRec {
 pub struct PortForwardingProcessor {
 	tx: mpsc::Sender<PortForwardingRec>,
 	rx: mpsc::Receiver<PortForwardingRec>,
-	forwarded: HashSet<u16>,
+	forwarded: HashMap<u16, ForwardedPort>,
 }
 
 impl PortForwardingProcessor {
@@ -37,7 +94,7 @@ impl PortForwardingProcessor {
 		Self {
 			tx,
 			rx,
-			forwarded: HashSet::new(),
+			forwarded: HashMap::new(),
 		}
 	}
 
@@ -57,10 +114,18 @@ impl PortForwardingProcessor {
 	/// Processes the incoming forwarding request.
 	pub async fn process(&mut self, req: PortForwardingRec, tunnel: &mut ActiveTunnel) {
 		match req {
-			PortForwardingRec::Forward(port, privacy, tx) => {
-				tx.send(self.process_forward(port, privacy, tunnel).await)
+			PortForwardingRec::Forward(port, options, tx) => {
+				tx.send(self.process_forward(port, options, tunnel).await)
 					.ok();
 			}
+			PortForwardingRec::ForwardNew(port, options, tx) => {
+				let r = if self.forwarded.contains_key(&port) {
+					Ok(None)
+				} else {
+					self.process_forward(port, options, tunnel).await.map(Some)
+				};
+				tx.send(r).ok();
+			}
 			PortForwardingRec::Unforward(port, tx) => {
 				tx.send(self.process_unforward(port, tunnel).await).ok();
 			}
@@ -84,22 +149,59 @@ impl PortForwardingProcessor {
 	async fn process_forward(
 		&mut self,
 		port: u16,
-		privacy: PortPrivacy,
+		options: ForwardOptions,
 		tunnel: &mut ActiveTunnel,
 	) -> Result<String, AnyError> {
 		if port == CONTROL_PORT || port == AGENT_HOST_PORT {
 			return Err(CannotForwardControlPort().into());
 		}
 
-		if !self.forwarded.contains(&port) {
-			tunnel
-				.add_port_tcp(port, privacy, PortProtocol::Auto)
-				.await?;
-			self.forwarded.insert(port);
+		options.access.validate(options.privacy)?;
+		match self.forwarded.get_mut(&port) {
+			None => {
+				let forwarded = ForwardedPort::new(options);
+				Self::add_port(port, &forwarded, tunnel).await?;
+				self.forwarded.insert(port, forwarded);
+			}
+			// The port's access control is only set when it's added, so it's
+			// added again to let in who this client asked for as well. If that
+			// fails, it's added back as it was for the clients it already had.
+			Some(forwarded) => {
+				let previous = forwarded.access.clone();
+				if forwarded.add_access(&options) {
+					if let Err(e) = tunnel.remove_port(port).await {
+						forwarded.access = previous;
+						return Err(e);
+					}
+					if let Err(e) = Self::add_port(port, forwarded, tunnel).await {
+						forwarded.access = previous;
+						if Self::add_port(port, forwarded, tunnel).await.is_err() {
+							self.forwarded.remove(&port);
+						}
+						return Err(e);
+					}
+				}
+			}
 		}
 
 		tunnel.get_port_uri(port)
 	}
+
+	async fn add_port(
+		port: u16,
+		forwarded: &ForwardedPort,
+		tunnel: &mut ActiveTunnel,
+	) -> Result<(), AnyError> {
+		tunnel
+			.add_port_tcp(
+				port,
+				forwarded.options.privacy,
+				forwarded.options.protocol,
+				&forwarded.access,
+				forwarded.options.labels.clone(),
+			)
+			.await
+	}
 }
 
 #[derive(Clone)]
@@ -108,9 +210,29 @@ pub struct PortForwarding {
 }
 
 impl PortForwarding {
-	pub async fn forward(&self, port: u16, privacy: PortPrivacy) -> Result<String, AnyError> {
+	pub async fn forward(&self, port: u16, options: ForwardOptions) -> Result<String, AnyError> {
 		let (tx, rx) = oneshot::channel();
-		let req = PortForwardingRec::Forward(port, privacy, tx);
+		let req = PortForwardingRec::Forward(port, options, tx);
+
+		if self.tx.send(req).await.is_err() {
+			return Err(ServerHasClosed().into());
+		}
+
+		match rx.await {
+			Ok(r) => r,
+			Err(_) => Err(ServerHasClosed().into()),
+		}
+	}
+
+	/// Forwards the port if it isn't already forwarded, returning its URI, or
+	/// `None` if it was forwarded by someone else.
+	pub async fn forward_new(
+		&self,
+		port: u16,
+		options: ForwardOptions,
+	) -> Result<Option<String>, AnyError> {
+		let (tx, rx) = oneshot::channel();
+		let req = PortForwardingRec::ForwardNew(port, options, tx);
 
 		if self.tx.send(req).await.is_err() {
 			return Err(ServerHasClosed().into());
@@ -136,3 +258,31 @@ impl PortForwarding {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_add_access() {
+		let with_users = |privacy, users: &[&str]| ForwardOptions {
+			access: PortAccess {
+				users: users.iter().map(|u| u.to_string()).collect(),
+				..Default::default()
+			},
+			..ForwardOptions::new(privacy)
+		};
+
+		let mut port = ForwardedPort::new(with_users(PortPrivacy::Private, &["alice"]));
+		assert!(!port.add_access(&with_users(PortPrivacy::Private, &["alice"])));
+		assert!(port.add_access(&with_users(PortPrivacy::Private, &["bob"])));
+		assert!(!port.add_access(&with_users(PortPrivacy::Public, &[])));
+		assert_eq!(
+			port.access
+				.iter()
+				.map(|a| a.users.clone())
+				.collect::<Vec<_>>(),
+			vec![vec!["alice".to_string()], vec!["bob".to_string()]]
+		);
+	}
+}
@@ -4,10 +4,13 @@
  *--------------------------------------------------------------------------------------------*/
 use std::collections::HashMap;
 
+use chrono::{DateTime, Utc};
+
 use crate::{
 	constants::{PROTOCOL_VERSION, VSCODE_CLI_VERSION},
 	options::Quality,
 	update_service::Platform,
+	util::errors::CodeError,
 };
 use serde::{Deserialize, Serialize};
 
@@ -49,6 +52,8 @@ pub struct ForwardParams {
 	pub port: u16,
 	#[serde(default)]
 	pub public: bool,
+	#[serde(default)]
+	pub access: PortAccess,
 }
 
 #[derive(Deserialize, Debug)]
@@ -75,6 +80,10 @@ pub struct ServeParams {
 	/// If true, the client and server should gzip servermsg's sent in either direction.
 	#[serde(default)]
 	pub compress: bool,
+	/// Environment variables to set for the server, if it's started for this
+	/// session. Restricted by the tunnel's allowlist and denylist.
+	#[serde(default)]
+	pub env: HashMap<String, String>,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -259,6 +268,24 @@ pub struct SpawnParams {
 	pub env: HashMap<String, String>,
 }
 
+/// Method: `serverlogs`. Gets recent lines from the log of an installed
+/// server, the most recently used one if no commit is given.
+#[derive(Deserialize)]
+pub struct ServerLogsParams {
+	pub commit: Option<String>,
+	/// RFC 3339 time before which lines are omitted.
+	pub since: Option<String>,
+	/// Regular expression that lines must match.
+	pub grep: Option<String>,
+	pub max_lines: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct ServerLogsResponse {
+	pub commit: String,
+	pub lines: Vec<String>,
+}
+
 #[derive(Deserialize)]
 pub struct AcquireCliParams {
 	pub platform: Platform,
@@ -299,6 +326,61 @@ pub enum PortPrivacy {
 	Private,
 }
 
+/// Identity provider that access restrictions on a port refer to.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessProvider {
+	#[default]
+	Github,
+	Microsoft,
+}
+
+impl AccessProvider {
+	pub fn to_contract_str(&self) -> &'static str {
+		match self {
+			Self::Github => "github",
+			Self::Microsoft => "microsoft",
+		}
+	}
+}
+
+/// Narrows who may connect to a forwarded port. Organization and user
+/// restrictions apply to private ports, which otherwise only the tunnel's
+/// owner may connect to; an expiry applies to public ports.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PortAccess {
+	/// Provider that `organization` and `users` refer to.
+	#[serde(default)]
+	pub provider: AccessProvider,
+	/// GitHub organization, or Microsoft tenant ID, whose members may connect.
+	pub organization: Option<String>,
+	/// GitHub logins or Microsoft account IDs of users who may connect.
+	#[serde(default)]
+	pub users: Vec<String>,
+	/// When anonymous access to a public port ends.
+	pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl PortAccess {
+	/// Checks that the restrictions make sense for a port with the privacy.
+	pub fn validate(&self, privacy: PortPrivacy) -> Result<(), CodeError> {
+		let err = |m: String| Err(CodeError::InvalidPortAccess(m));
+		match privacy {
+			PortPrivacy::Public if self.organization.is_some() || !self.users.is_empty() => {
+				err("organization and user restrictions only apply to private ports".to_string())
+			}
+			PortPrivacy::Private if self.expires_at.is_some() => {
+				err("only public ports can expire".to_string())
+			}
+			_ => match self.expires_at {
+				Some(t) if t <= Utc::now() => err(format!("the expiry {t} has passed")),
+				_ => Ok(()),
+			},
+		}
+	}
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Copy, Eq, Clone, Debug)]
 #[serde(rename_all = "lowercase")]
 pub enum PortProtocol {
@@ -307,6 +389,15 @@ pub enum PortProtocol {
 	Https,
 }
 
+impl std::fmt::Display for PortPrivacy {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			PortPrivacy::Public => write!(f, "public"),
+			PortPrivacy::Private => write!(f, "private"),
+		}
+	}
+}
+
 impl std::fmt::Display for PortProtocol {
 	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
 		write!(f, "{}", self.to_contract_str())
@@ -329,18 +420,60 @@ impl PortProtocol {
 	}
 }
 
+pub mod agent_host_singleton {
+	use chrono::{DateTime, Utc};
+	use serde::{Deserialize, Serialize};
+
+	pub const METHOD_STATUS: &str = "status";
+	pub const METHOD_SHUTDOWN: &str = "shutdown";
+	pub const METHOD_RESTART: &str = "restart";
+	pub const METHOD_UPDATE: &str = "update";
+
+	#[derive(Serialize, Deserialize, Clone, Debug)]
+	pub struct Status {
+		/// Address the proxy is listening on.
+		pub address: String,
+		pub started_at: DateTime<Utc>,
+		/// Commit of the latest release, if the running servers don't use it yet.
+		pub pending_update: Option<String>,
+		pub servers: Vec<ServerStatus>,
+	}
+
+	#[derive(Serialize, Deserialize, Clone, Debug)]
+	pub struct ServerStatus {
+		pub workspace: String,
+		pub commit: String,
+		pub started_at: DateTime<Utc>,
+	}
+
+	#[derive(Serialize, Deserialize, Clone, Debug)]
+	pub struct UpdateResult {
+		/// Commit of the latest release, which has been downloaded.
+		pub commit: String,
+		/// Whether running servers need to be restarted to use it.
+		pub restart_required: bool,
+	}
+}
+
 pub mod forward_singleton {
 	use serde::{Deserialize, Serialize};
 
-	use super::{PortPrivacy, PortProtocol};
+	use super::{PortAccess, PortPrivacy, PortProtocol};
 
 	pub const METHOD_SET_PORTS: &str = "set_ports";
+	pub const METHOD_LIST_PORTS: &str = "list_ports";
+	pub const METHOD_DETACH_PORTS: &str = "detach_ports";
+	pub const METHOD_REMOVE_PORTS: &str = "remove_ports";
 
 	#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
 	pub struct PortRec {
 		pub number: u16,
 		pub privacy: PortPrivacy,
 		pub protocol: PortProtocol,
+		#[serde(default)]
+		pub access: PortAccess,
+		#[serde(default)]
+		pub labels: Vec<String>,
 	}
 
 	pub type PortList = Vec<PortRec>;
@@ -353,6 +486,38 @@ pub mod forward_singleton {
 	#[derive(Serialize, Deserialize)]
 	pub struct SetPortsResponse {
 		pub port_format: Option<String>,
+		/// The ports that were set.
+		#[serde(default)]
+		pub ports: Vec<ForwardedPort>,
+	}
+
+	#[derive(Serialize, Deserialize, Clone, Debug)]
+	pub struct ForwardedPort {
+		pub number: u16,
+		pub privacy: PortPrivacy,
+		pub protocol: PortProtocol,
+		pub labels: Vec<String>,
+		pub uri: Option<String>,
+		/// Whether the port was handed to the forwarding process with
+		/// `detach_ports`, rather than being held by a connected client.
+		pub detached: bool,
+	}
+
+	#[derive(Serialize, Deserialize)]
+	pub struct ListPortsResponse {
+		pub ports: Vec<ForwardedPort>,
+	}
+
+	#[derive(Serialize, Deserialize)]
+	pub struct RemovePortsParams {
+		pub ports: Vec<u16>,
+	}
+
+	#[derive(Serialize, Deserialize)]
+	pub struct RemovePortsResponse {
+		/// Ports that are no longer forwarded. Ports held by connected clients
+		/// are not removed.
+		pub removed: Vec<u16>,
 	}
 }
 
@@ -361,6 +526,8 @@ pub mod singleton {
 	use chrono::{DateTime, Utc};
 	use serde::{Deserialize, Serialize};
 
+	use super::{PortPrivacy, PortProtocol};
+
 	pub const METHOD_RESTART: &str = "restart";
 	pub const METHOD_SHUTDOWN: &str = "shutdown";
 	pub const METHOD_STATUS: &str = "status";
@@ -395,6 +562,18 @@ pub mod singleton {
 		pub last_connected_at: Option<DateTime<Utc>>,
 		pub last_disconnected_at: Option<DateTime<Utc>>,
 		pub last_fail_reason: Option<String>,
+		/// Ports that were detected and forwarded automatically.
+		#[serde(default)]
+		pub auto_forwarded: Vec<AutoForwardedPort>,
+		#[serde(default)]
+		pub traffic: TrafficStatus,
+		/// Data directory of the servers the tunnel starts, if not the default.
+		#[serde(default)]
+		pub server_data_dir: Option<String>,
+		/// Extensions directory of the servers the tunnel starts, if not the
+		/// default.
+		#[serde(default)]
+		pub extensions_dir: Option<String>,
 	}
 
 	impl Default for Status {
@@ -405,10 +584,59 @@ pub mod singleton {
 				last_connected_at: None,
 				last_disconnected_at: None,
 				last_fail_reason: None,
+				auto_forwarded: vec![],
+				traffic: TrafficStatus::default(),
+				server_data_dir: None,
+				extensions_dir: None,
 			}
 		}
 	}
 
+	/// Traffic over the tunnel's control connections, and the health of its
+	/// connection to the relay.
+	#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+	pub struct TrafficStatus {
+		/// Times the tunnel connected to the relay again after being connected.
+		pub reconnects: u32,
+		/// Control connections served since the tunnel started, including
+		/// active ones.
+		pub total_connections: u64,
+		/// Totals across all control connections, including active ones.
+		#[serde(flatten)]
+		pub totals: ConnectionTraffic,
+		/// Control connections that are currently open.
+		pub connections: Vec<ConnectionStatus>,
+	}
+
+	#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+	pub struct ConnectionTraffic {
+		pub bytes_rx: u64,
+		pub bytes_tx: u64,
+		pub messages_rx: u64,
+		pub messages_tx: u64,
+	}
+
+	#[derive(Serialize, Deserialize, Clone, Debug)]
+	pub struct ConnectionStatus {
+		pub id: u64,
+		pub connected_at: DateTime<Utc>,
+		#[serde(flatten)]
+		pub traffic: ConnectionTraffic,
+	}
+
+	#[derive(Serialize, Deserialize, Clone, Debug)]
+	pub struct AutoForwardedPort {
+		pub port: u16,
+		/// Name of the process listening on the port, if known.
+		pub process: Option<String>,
+		pub privacy: PortPrivacy,
+		pub protocol: PortProtocol,
+		pub labels: Vec<String>,
+		pub uri: Option<String>,
+		/// Why forwarding the port failed, if it did.
+		pub error: Option<String>,
+	}
+
 	#[derive(Deserialize, Serialize, Debug)]
 	pub struct LogReplayFinished {}
 
@@ -0,0 +1,225 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{collections::HashMap, fs, path::Path};
+
+use crate::util::errors::CodeError;
+
+/// Variables clients may never set, even if allowed, since they change how
+/// the server itself or the dynamic loader runs rather than the environment
+/// of the tools it launches.
+const ALWAYS_DENIED: &[&str] = &[
+	"VSCODE_*",
+	"ELECTRON_RUN_AS_NODE",
+	"NODE_OPTIONS",
+	"LD_*",
+	"DYLD_*",
+	"BASH_ENV",
+	"BASH_FUNC_*",
+	"ENV",
+];
+
+/// Parses a `KEY=VALUE` pair given on the command line.
+pub fn parse_env_pair(s: &str) -> Result<(String, String), String> {
+	match s.split_once('=') {
+		Some((k, v)) if is_valid_name(k) => Ok((k.to_string(), v.to_string())),
+		_ => Err(format!("expected KEY=VALUE, got '{s}'")),
+	}
+}
+
+fn is_valid_name(name: &str) -> bool {
+	!name.is_empty()
+		&& !name.starts_with(|c: char| c.is_ascii_digit())
+		&& name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Reads variables from a dotenv file. Supports comments, blank lines, an
+/// optional `export` prefix, and single- or double-quoted values, where the
+/// latter may contain `\n`, `\t`, `\"` and `\\` escapes.
+pub fn read_env_file(path: &Path) -> Result<Vec<(String, String)>, CodeError> {
+	let err = |line: usize, e: &str| {
+		CodeError::InvalidServerEnv(format!("{}:{}: {}", path.display(), line, e))
+	};
+	let contents = fs::read_to_string(path).map_err(|e| err(0, &e.to_string()))?;
+
+	let mut vars = vec![];
+	for (i, line) in contents.lines().enumerate() {
+		let line = line.trim();
+		if line.is_empty() || line.starts_with('#') {
+			continue;
+		}
+
+		let line = line.strip_prefix("export ").unwrap_or(line);
+		let (key, value) = line
+			.split_once('=')
+			.ok_or_else(|| err(i + 1, "expected KEY=VALUE"))?;
+		let key = key.trim();
+		if !is_valid_name(key) {
+			return Err(err(i + 1, &format!("invalid variable name '{key}'")));
+		}
+
+		let value = parse_value(value.trim()).ok_or_else(|| err(i + 1, "unterminated quote"))?;
+		vars.push((key.to_string(), value));
+	}
+
+	Ok(vars)
+}
+
+fn parse_value(value: &str) -> Option<String> {
+	if let Some(rest) = value.strip_prefix('\'') {
+		return rest.split_once('\'').map(|(v, _)| v.to_string());
+	}
+
+	if let Some(rest) = value.strip_prefix('"') {
+		let mut out = String::new();
+		let mut chars = rest.chars();
+		while let Some(c) = chars.next() {
+			match c {
+				'"' => return Some(out),
+				'\\' => match chars.next()? {
+					'n' => out.push('\n'),
+					't' => out.push('\t'),
+					c => out.push(c),
+				},
+				c => out.push(c),
+			}
+		}
+		return None;
+	}
+
+	// unquoted values end at an inline comment
+	let value = match value.find(" #") {
+		Some(i) => &value[..i],
+		None => value,
+	};
+	Some(value.trim_end().to_string())
+}
+
+/// Controls which variables clients may set for the servers they start.
+/// Patterns match a variable name exactly, or by prefix if they end in `*`.
+/// Names are compared case-insensitively on Windows, where variables are.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EnvPolicy {
+	/// Only matching variables may be set. Clients may set no variables if
+	/// this is empty.
+	pub allow: Vec<String>,
+	/// Matching variables may not be set, even if they're allowed.
+	pub deny: Vec<String>,
+}
+
+impl EnvPolicy {
+	pub fn is_allowed(&self, name: &str) -> bool {
+		self.is_allowed_with_case(name, cfg!(windows))
+	}
+
+	fn is_allowed_with_case(&self, name: &str, ignore_case: bool) -> bool {
+		let fold = |s: &str| {
+			if ignore_case {
+				s.to_ascii_uppercase()
+			} else {
+				s.to_string()
+			}
+		};
+		let name = fold(name);
+		let matches = |p: &str| {
+			let p = fold(p);
+			match p.strip_suffix('*') {
+				Some(prefix) => name.starts_with(prefix),
+				None => name == p,
+			}
+		};
+
+		if ALWAYS_DENIED.iter().any(|p| matches(p)) || self.deny.iter().any(|p| matches(p)) {
+			return false;
+		}
+
+		self.allow.iter().any(|p| matches(p))
+	}
+
+	/// Checks variables a client asked to set, failing on the first that
+	/// isn't allowed.
+	pub fn check(&self, env: HashMap<String, String>) -> Result<Vec<(String, String)>, CodeError> {
+		let mut vars: Vec<_> = env.into_iter().collect();
+		vars.sort();
+		for (name, _) in &vars {
+			if !is_valid_name(name) {
+				return Err(CodeError::InvalidServerEnv(format!(
+					"invalid variable name '{name}'"
+				)));
+			}
+			if !self.is_allowed(name) {
+				return Err(CodeError::ServerEnvNotAllowed(name.clone()));
+			}
+		}
+		Ok(vars)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_read_env_file() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join(".env");
+		fs::write(
+			&path,
+			"# toolchains\nJAVA_HOME=/opt/jdk # current\nexport HTTPS_PROXY=\"http://proxy:3128\"\nGREETING='a # b'\nMULTI=\"x\\ny\"\n\n",
+		)
+		.unwrap();
+
+		assert_eq!(
+			read_env_file(&path).unwrap(),
+			vec![
+				("JAVA_HOME".to_string(), "/opt/jdk".to_string()),
+				("HTTPS_PROXY".to_string(), "http://proxy:3128".to_string()),
+				("GREETING".to_string(), "a # b".to_string()),
+				("MULTI".to_string(), "x\ny".to_string()),
+			]
+		);
+
+		fs::write(&path, "BROKEN=\"value\n").unwrap();
+		assert!(read_env_file(&path).is_err());
+		fs::write(&path, "1BAD=value\n").unwrap();
+		assert!(read_env_file(&path).is_err());
+	}
+
+	#[test]
+	fn test_env_policy() {
+		let closed = EnvPolicy::default();
+		assert!(!closed.is_allowed("JAVA_HOME"));
+		assert!(!closed.is_allowed("PATH"));
+
+		let open = EnvPolicy {
+			allow: vec!["*".to_string()],
+			deny: vec![],
+		};
+		assert!(open.is_allowed("JAVA_HOME"));
+		assert!(!open.is_allowed("LD_PRELOAD"));
+		assert!(!open.is_allowed("LD_AUDIT"));
+		assert!(!open.is_allowed("BASH_ENV"));
+		assert!(!open.is_allowed("VSCODE_AGENT_FOLDER"));
+		assert!(!open.is_allowed_with_case("Node_Options", true));
+		assert!(open.is_allowed_with_case("Node_Options", false));
+
+		let policy = EnvPolicy {
+			allow: vec!["JAVA_HOME".to_string(), "HTTP*".to_string()],
+			deny: vec!["HTTP_PROXY".to_string()],
+		};
+		assert!(policy.is_allowed("JAVA_HOME"));
+		assert!(policy.is_allowed("HTTPS_PROXY"));
+		assert!(!policy.is_allowed("HTTP_PROXY"));
+		assert!(!policy.is_allowed("PATH"));
+		assert!(policy.is_allowed_with_case("java_home", true));
+		assert!(!policy.is_allowed_with_case("http_proxy", true));
+
+		let env = HashMap::from([("PATH".to_string(), "/bin".to_string())]);
+		assert!(matches!(
+			policy.check(env),
+			Err(CodeError::ServerEnvNotAllowed(n)) if n == "PATH"
+		));
+	}
+}
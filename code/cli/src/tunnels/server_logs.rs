@@ -0,0 +1,256 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	fs::{self, File},
+	io::{self, BufRead, BufReader, Write},
+	path::{Path, PathBuf},
+	time::Duration,
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use regex::Regex;
+
+use crate::{
+	state::LauncherPaths,
+	util::{
+		errors::{wrap, AnyError, CodeError, WrappedError},
+		io::{tailf, TailEvent},
+		ring_buffer::RingBuffer,
+		rotating_file::{rotated_path, RotatingFile},
+	},
+};
+
+use super::paths::{get_all_servers, ServerPaths};
+
+/// Default size a server log may grow to before it's rotated.
+pub const DEFAULT_MAX_SIZE_MB: u64 = 10;
+/// Default number of rotated server logs to keep.
+pub const DEFAULT_MAX_FILES: usize = 3;
+/// Lines to show from a log that was rotated while being followed.
+const FOLLOW_ROTATED_LINES: usize = 1000;
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Opens the log for a server that's starting, moving aside the log of any
+/// previous run.
+pub fn open_server_log(
+	path: &Path,
+	max_size_mb: Option<u64>,
+	max_files: Option<usize>,
+) -> Result<RotatingFile, WrappedError> {
+	let err = |e| wrap(e, format!("error creating log file {}", path.display()));
+	let mut file = RotatingFile::open(
+		path,
+		max_size_mb.unwrap_or(DEFAULT_MAX_SIZE_MB) * 1024 * 1024,
+		max_files.unwrap_or(DEFAULT_MAX_FILES),
+	)
+	.map_err(err)?;
+	file.start_new_file().map_err(err)?;
+	Ok(file)
+}
+
+/// Formats a line of server output for its log, with the time it was written.
+pub fn format_log_line(line: &str) -> String {
+	format!(
+		"{} {}\n",
+		Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+		line
+	)
+}
+
+fn parse_log_time(line: &str) -> Option<DateTime<Utc>> {
+	let (time, _) = line.split_once(' ')?;
+	DateTime::parse_from_rfc3339(time)
+		.ok()
+		.map(|t| t.with_timezone(&Utc))
+}
+
+/// Selects lines of a server log.
+pub struct LogFilter {
+	since: Option<DateTime<Utc>>,
+	grep: Option<Regex>,
+}
+
+impl LogFilter {
+	pub fn new(since: Option<DateTime<Utc>>, grep: Option<&str>) -> Result<Self, CodeError> {
+		let grep = grep
+			.map(|g| Regex::new(g).map_err(|e| CodeError::InvalidLogFilter(e.to_string())))
+			.transpose()?;
+		Ok(Self { since, grep })
+	}
+
+	/// Gets whether the line should be shown. Lines without a time, which were
+	/// written by older versions, never match a `since` filter.
+	pub fn matches(&self, line: &str) -> bool {
+		if let Some(since) = self.since {
+			match parse_log_time(line) {
+				Some(t) if t >= since => {}
+				_ => return false,
+			}
+		}
+
+		match &self.grep {
+			Some(re) => re.is_match(line),
+			None => true,
+		}
+	}
+}
+
+/// Finds the server whose log to read: the one with the given commit, or the
+/// one whose log was written most recently.
+pub fn find_server_log(
+	launcher_paths: &LauncherPaths,
+	commit: Option<&str>,
+) -> Result<(String, ServerPaths), CodeError> {
+	let mut candidates: Vec<_> = get_all_servers(launcher_paths)
+		.into_iter()
+		.filter(|s| commit.map(|c| s.commit.starts_with(c)).unwrap_or(true))
+		.map(|s| {
+			let paths = s.server_paths(launcher_paths);
+			let modified = fs::metadata(&paths.logfile).and_then(|m| m.modified()).ok();
+			(s.commit, paths, modified)
+		})
+		.filter(|(_, _, modified)| modified.is_some())
+		.collect();
+
+	candidates.sort_by_key(|(_, _, modified)| std::cmp::Reverse(*modified));
+	candidates
+		.into_iter()
+		.next()
+		.map(|(commit, paths, _)| (commit, paths))
+		.ok_or_else(|| CodeError::ServerLogNotFound(commit.unwrap_or("any server").to_string()))
+}
+
+/// Gets the paths of the log and its rotated files, oldest first.
+fn log_paths(path: &Path) -> Vec<PathBuf> {
+	let mut paths = vec![path.to_path_buf()];
+	for i in 1.. {
+		let p = rotated_path(path, i);
+		if !p.exists() {
+			break;
+		}
+		paths.push(p);
+	}
+	paths.reverse();
+	paths
+}
+
+/// Reads the last `max_lines` lines matching the filter from the log and
+/// its rotated files.
+pub fn read_recent_lines(path: &Path, filter: &LogFilter, max_lines: usize) -> Vec<String> {
+	let mut lines = RingBuffer::new(max_lines.max(1));
+	for p in log_paths(path) {
+		let f = match File::open(&p) {
+			Ok(f) => f,
+			Err(_) => continue,
+		};
+		for line in BufReader::new(f).lines().map_while(Result::ok) {
+			if filter.matches(&line) {
+				lines.push(line);
+			}
+		}
+	}
+
+	lines.into_iter().collect()
+}
+
+/// Calls `on_line` with lines matching the filter as they're written to the
+/// log, starting with the last `initial_lines` lines. Keeps following the log
+/// when it's rotated, and returns only if it can't be read.
+pub async fn follow_log(
+	path: &Path,
+	filter: &LogFilter,
+	initial_lines: usize,
+	mut on_line: impl FnMut(&str),
+) -> Result<(), AnyError> {
+	let open =
+		|p: &Path| File::open(p).map_err(|e| wrap(e, format!("error opening {}", p.display())));
+	let mut file = open(path)?;
+	let f = file.try_clone().map_err(|e| wrap(e, "error opening log"))?;
+	let mut rx = tailf(f, initial_lines.max(1));
+
+	loop {
+		tokio::select! {
+			ev = rx.recv() => match ev {
+				Some(TailEvent::Line(l)) => {
+					let l = l.trim_end();
+					if filter.matches(l) {
+						on_line(l);
+					}
+				}
+				Some(TailEvent::Reset) => {}
+				Some(TailEvent::Err(e)) => return Err(wrap(e, "error reading log").into()),
+				None => return Ok(()),
+			},
+			_ = tokio::time::sleep(FOLLOW_POLL_INTERVAL) => {
+				// A rotated log is moved aside and a new, smaller one is created.
+				if is_rotated(&file, path) {
+					file = open(path)?;
+					let f = file.try_clone().map_err(|e| wrap(e, "error opening log"))?;
+					rx = tailf(f, FOLLOW_ROTATED_LINES);
+				}
+			}
+		}
+	}
+}
+
+fn is_rotated(file: &File, path: &Path) -> bool {
+	let followed_len = match file.metadata() {
+		Ok(m) => m.len(),
+		Err(_) => return false,
+	};
+	match fs::metadata(path) {
+		Ok(m) => m.len() < followed_len,
+		Err(_) => false,
+	}
+}
+
+/// Writes a line of server output to its log.
+pub fn write_log_line(file: &mut RotatingFile, line: &str) -> io::Result<()> {
+	file.write_all(format_log_line(line).as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_read_recent_lines() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("log.txt");
+		fs::write(
+			rotated_path(&path, 1),
+			"2024-06-01T10:00:00.000Z old error\n2024-06-01T11:00:00.000Z old info\n",
+		)
+		.unwrap();
+		fs::write(
+			&path,
+			"2024-06-01T12:00:00.000Z new error\n2024-06-01T13:00:00.000Z new info\n",
+		)
+		.unwrap();
+
+		let all = LogFilter::new(None, None).unwrap();
+		assert_eq!(
+			read_recent_lines(&path, &all, 3),
+			vec![
+				"2024-06-01T11:00:00.000Z old info",
+				"2024-06-01T12:00:00.000Z new error",
+				"2024-06-01T13:00:00.000Z new info",
+			]
+		);
+
+		let since = DateTime::parse_from_rfc3339("2024-06-01T10:30:00Z")
+			.unwrap()
+			.with_timezone(&Utc);
+		let errors = LogFilter::new(Some(since), Some("err")).unwrap();
+		assert_eq!(
+			read_recent_lines(&path, &errors, 10),
+			vec!["2024-06-01T12:00:00.000Z new error"]
+		);
+
+		assert!(!errors.matches("untimed error"));
+		assert!(LogFilter::new(None, Some("(")).is_err());
+	}
+}
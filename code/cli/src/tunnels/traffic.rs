@@ -0,0 +1,175 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	collections::HashMap,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc, Mutex,
+	},
+};
+
+use chrono::{DateTime, Utc};
+
+use super::protocol::singleton::{ConnectionStatus, ConnectionTraffic, TrafficStatus};
+
+/// Counts traffic on the tunnel's control connections, and records the
+/// health of its relay connection, for reporting in the tunnel status.
+#[derive(Clone, Default)]
+pub struct TrafficStats(Arc<Mutex<TrafficState>>);
+
+#[derive(Default)]
+struct TrafficState {
+	next_id: u64,
+	/// Totals of connections that have closed.
+	closed: ConnectionTraffic,
+	active: HashMap<u64, (DateTime<Utc>, Arc<ConnectionCounter>)>,
+	reconnects: u32,
+}
+
+impl TrafficStats {
+	/// Starts counting a new connection, until the returned handle is dropped.
+	pub fn connect(&self) -> ConnectionHandle {
+		let mut state = self.0.lock().unwrap();
+		let id = state.next_id;
+		state.next_id += 1;
+		let counter = Arc::new(ConnectionCounter::default());
+		state.active.insert(id, (Utc::now(), counter.clone()));
+		ConnectionHandle {
+			stats: self.clone(),
+			id,
+			counter,
+		}
+	}
+
+	pub fn record_reconnect(&self) {
+		self.0.lock().unwrap().reconnects += 1;
+	}
+
+	pub fn snapshot(&self) -> TrafficStatus {
+		let state = self.0.lock().unwrap();
+		let mut totals = state.closed.clone();
+		let mut connections: Vec<ConnectionStatus> = state
+			.active
+			.iter()
+			.map(|(id, (connected_at, counter))| {
+				let traffic = counter.read();
+				totals.add(&traffic);
+				ConnectionStatus {
+					id: *id,
+					connected_at: *connected_at,
+					traffic,
+				}
+			})
+			.collect();
+		connections.sort_by_key(|c| c.id);
+
+		TrafficStatus {
+			reconnects: state.reconnects,
+			total_connections: state.next_id,
+			totals,
+			connections,
+		}
+	}
+}
+
+impl ConnectionTraffic {
+	fn add(&mut self, other: &ConnectionTraffic) {
+		self.bytes_rx += other.bytes_rx;
+		self.bytes_tx += other.bytes_tx;
+		self.messages_rx += other.messages_rx;
+		self.messages_tx += other.messages_tx;
+	}
+}
+
+/// Traffic counts of a single connection.
+#[derive(Default)]
+pub struct ConnectionCounter {
+	bytes_rx: AtomicU64,
+	bytes_tx: AtomicU64,
+	messages_rx: AtomicU64,
+	messages_tx: AtomicU64,
+}
+
+impl ConnectionCounter {
+	pub fn add_rx_bytes(&self, n: usize) {
+		self.bytes_rx.fetch_add(n as u64, Ordering::Relaxed);
+	}
+
+	pub fn add_rx_message(&self) {
+		self.messages_rx.fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Records a message of `n` bytes sent to the client.
+	pub fn add_tx_message(&self, n: usize) {
+		self.bytes_tx.fetch_add(n as u64, Ordering::Relaxed);
+		self.messages_tx.fetch_add(1, Ordering::Relaxed);
+	}
+
+	pub fn read(&self) -> ConnectionTraffic {
+		ConnectionTraffic {
+			bytes_rx: self.bytes_rx.load(Ordering::Relaxed),
+			bytes_tx: self.bytes_tx.load(Ordering::Relaxed),
+			messages_rx: self.messages_rx.load(Ordering::Relaxed),
+			messages_tx: self.messages_tx.load(Ordering::Relaxed),
+		}
+	}
+}
+
+/// A connection being counted. Its totals are kept once it's dropped.
+pub struct ConnectionHandle {
+	stats: TrafficStats,
+	id: u64,
+	counter: Arc<ConnectionCounter>,
+}
+
+impl ConnectionHandle {
+	pub fn id(&self) -> u64 {
+		self.id
+	}
+
+	pub fn counter(&self) -> Arc<ConnectionCounter> {
+		self.counter.clone()
+	}
+}
+
+impl Drop for ConnectionHandle {
+	fn drop(&mut self) {
+		let mut state = self.stats.0.lock().unwrap();
+		state.active.remove(&self.id);
+		let traffic = self.counter.read();
+		state.closed.add(&traffic);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_traffic_stats() {
+		let stats = TrafficStats::default();
+		let a = stats.connect();
+		let b = stats.connect();
+		a.counter().add_rx_bytes(100);
+		a.counter().add_rx_message();
+		b.counter().add_tx_message(40);
+
+		let s = stats.snapshot();
+		assert_eq!(s.total_connections, 2);
+		assert_eq!(s.connections.len(), 2);
+		assert_eq!(s.connections[0].traffic.bytes_rx, 100);
+		assert_eq!(s.totals.bytes_rx, 100);
+		assert_eq!(s.totals.bytes_tx, 40);
+
+		drop(a);
+		let s = stats.snapshot();
+		assert_eq!(s.connections.len(), 1);
+		assert_eq!(s.connections[0].id, 1);
+		assert_eq!(s.totals.bytes_rx, 100);
+		assert_eq!(s.totals.messages_rx, 1);
+		assert_eq!(s.totals.messages_tx, 1);
+	}
+}
@@ -0,0 +1,299 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{fmt, str::FromStr, time::Duration};
+
+use chrono::{DateTime, Local, NaiveTime, Utc};
+use serde::{Deserialize, Deserializer};
+
+use crate::{
+	options::Quality,
+	update_service::{Platform, Release, TargetKind, UpdateService},
+	util::errors::CodeError,
+};
+
+/// A daily period of local time, like `22:00-06:00`, which may wrap around
+/// midnight. A window that starts when it ends, like `00:00-00:00`, covers the
+/// whole day.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UpdateWindow {
+	start: NaiveTime,
+	end: NaiveTime,
+}
+
+impl UpdateWindow {
+	pub fn contains(&self, t: NaiveTime) -> bool {
+		if self.start == self.end {
+			true
+		} else if self.start < self.end {
+			t >= self.start && t < self.end
+		} else {
+			t >= self.start || t < self.end
+		}
+	}
+}
+
+impl FromStr for UpdateWindow {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let err = || format!("'{s}' is not a time window like 22:00-06:00");
+		let (start, end) = s.split_once('-').ok_or_else(err)?;
+		let parse = |t: &str| NaiveTime::parse_from_str(t.trim(), "%H:%M").map_err(|_| err());
+		Ok(Self {
+			start: parse(start)?,
+			end: parse(end)?,
+		})
+	}
+}
+
+impl<'de> Deserialize<'de> for UpdateWindow {
+	fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+	where
+		D: Deserializer<'de>,
+	{
+		String::deserialize(deserializer)?
+			.parse()
+			.map_err(serde::de::Error::custom)
+	}
+}
+
+impl fmt::Display for UpdateWindow {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"{}-{}",
+			self.start.format("%H:%M"),
+			self.end.format("%H:%M")
+		)
+	}
+}
+
+/// A release that servers are pinned to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UpdatePin {
+	Commit(String),
+	Version(String),
+}
+
+impl FromStr for UpdatePin {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		if s.len() == 40 && s.chars().all(|c| c.is_ascii_hexdigit()) {
+			Ok(UpdatePin::Commit(s.to_lowercase()))
+		} else if !s.is_empty() && s.split('.').all(|p| p.parse::<u32>().is_ok()) {
+			Ok(UpdatePin::Version(s.to_string()))
+		} else {
+			Err(format!("'{s}' is not a commit or a version like 1.95.0"))
+		}
+	}
+}
+
+impl<'de> Deserialize<'de> for UpdatePin {
+	fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+	where
+		D: Deserializer<'de>,
+	{
+		String::deserialize(deserializer)?
+			.parse()
+			.map_err(serde::de::Error::custom)
+	}
+}
+
+/// What to do with a newer release than the one servers are using.
+#[derive(Debug, PartialEq, Eq)]
+pub enum UpdateDecision {
+	/// Start using the release.
+	Adopt,
+	/// Check again later, for the given reason.
+	Defer(String),
+	/// Download the release, but wait for approval to use it.
+	AwaitApproval,
+}
+
+/// Controls which releases servers are updated to, and when. Pins always
+/// apply, while the other settings only hold back updates from a release
+/// that's already in use.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UpdatePolicy {
+	pub pin: Option<UpdatePin>,
+	/// Periods in which updates may be applied. Any time if empty.
+	pub windows: Vec<UpdateWindow>,
+	/// How long a release must have been out before it's used.
+	pub min_release_age: Option<Duration>,
+	/// Whether updates wait for approval before they're used.
+	pub manual_approval: bool,
+}
+
+impl UpdatePolicy {
+	/// Gets whether the policy can hold back an update.
+	pub fn gates_updates(&self) -> bool {
+		!self.windows.is_empty() || self.min_release_age.is_some() || self.manual_approval
+	}
+
+	/// Looks up the release servers should use: the pinned one, or the
+	/// latest release of the quality. Also returns when it was released,
+	/// if known.
+	pub async fn resolve(
+		&self,
+		update_service: &UpdateService,
+		platform: Platform,
+		target: TargetKind,
+		quality: Quality,
+	) -> Result<(Release, Option<DateTime<Utc>>), CodeError> {
+		let err = |e: crate::util::errors::AnyError| CodeError::UpdateCheckFailed(e.to_string());
+		match &self.pin {
+			Some(UpdatePin::Commit(commit)) => Ok((
+				Release {
+					name: commit.clone(),
+					commit: commit.clone(),
+					platform,
+					target,
+					quality,
+				},
+				None,
+			)),
+			Some(UpdatePin::Version(version)) => update_service
+				.get_release_by_semver_version(platform, target, quality, version)
+				.await
+				.map(|r| (r, None))
+				.map_err(err),
+			None => update_service
+				.get_latest_commit_with_time(platform, target, quality)
+				.await
+				.map_err(err),
+		}
+	}
+
+	/// Decides whether to move to a newer release, released at the given
+	/// time, at the current local time.
+	pub fn decide(
+		&self,
+		released_at: Option<DateTime<Utc>>,
+		now: DateTime<Local>,
+	) -> UpdateDecision {
+		if self.pin.is_some() {
+			return UpdateDecision::Adopt;
+		}
+
+		if let Some(min_age) = self.min_release_age {
+			let age =
+				released_at.map(|r| (now.with_timezone(&Utc) - r).to_std().unwrap_or_default());
+			match age {
+				Some(age) if age >= min_age => {}
+				Some(age) => {
+					return UpdateDecision::Defer(format!(
+						"it was released {}h ago, less than the minimum of {}h",
+						age.as_secs() / 3600,
+						min_age.as_secs() / 3600
+					))
+				}
+				None => {
+					return UpdateDecision::Defer(
+						"its release date is unknown and a minimum release age is set".to_string(),
+					)
+				}
+			}
+		}
+
+		if self.manual_approval {
+			return UpdateDecision::AwaitApproval;
+		}
+
+		if !self.windows.is_empty() && !self.windows.iter().any(|w| w.contains(now.time())) {
+			return UpdateDecision::Defer(format!(
+				"it's outside of the update windows ({})",
+				self.windows
+					.iter()
+					.map(|w| w.to_string())
+					.collect::<Vec<_>>()
+					.join(", ")
+			));
+		}
+
+		UpdateDecision::Adopt
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use chrono::TimeZone;
+
+	use super::*;
+
+	fn at(h: u32, m: u32) -> DateTime<Local> {
+		Local.with_ymd_and_hms(2024, 6, 1, h, m, 0).unwrap()
+	}
+
+	#[test]
+	fn test_parse() {
+		let w: UpdateWindow = "22:00-06:30".parse().unwrap();
+		assert_eq!(w.to_string(), "22:00-06:30");
+		assert!("22:00".parse::<UpdateWindow>().is_err());
+		assert!("25:00-06:00".parse::<UpdateWindow>().is_err());
+
+		assert_eq!(
+			"1.95.0".parse::<UpdatePin>().unwrap(),
+			UpdatePin::Version("1.95.0".to_string())
+		);
+		assert_eq!(
+			"A".repeat(40).parse::<UpdatePin>().unwrap(),
+			UpdatePin::Commit("a".repeat(40))
+		);
+		assert!("latest".parse::<UpdatePin>().is_err());
+	}
+
+	#[test]
+	fn test_windows() {
+		let night: UpdateWindow = "22:00-06:00".parse().unwrap();
+		assert!(night.contains(at(23, 0).time()));
+		assert!(night.contains(at(2, 0).time()));
+		assert!(!night.contains(at(6, 0).time()));
+		assert!(!night.contains(at(12, 0).time()));
+
+		let all_day: UpdateWindow = "04:00-04:00".parse().unwrap();
+		assert!(all_day.contains(at(4, 0).time()));
+		assert!(all_day.contains(at(3, 59).time()));
+
+		let policy = UpdatePolicy {
+			windows: vec![night],
+			..Default::default()
+		};
+		assert_eq!(policy.decide(None, at(3, 0)), UpdateDecision::Adopt);
+		assert!(matches!(
+			policy.decide(None, at(12, 0)),
+			UpdateDecision::Defer(_)
+		));
+	}
+
+	#[test]
+	fn test_min_age_and_approval() {
+		let policy = UpdatePolicy {
+			min_release_age: Some(Duration::from_secs(48 * 3600)),
+			manual_approval: true,
+			..Default::default()
+		};
+		let now = at(12, 0);
+		let day_ago = now.with_timezone(&Utc) - chrono::Duration::hours(24);
+		let week_ago = now.with_timezone(&Utc) - chrono::Duration::days(7);
+
+		assert!(matches!(
+			policy.decide(Some(day_ago), now),
+			UpdateDecision::Defer(_)
+		));
+		assert!(matches!(policy.decide(None, now), UpdateDecision::Defer(_)));
+		assert_eq!(
+			policy.decide(Some(week_ago), now),
+			UpdateDecision::AwaitApproval
+		);
+
+		let pinned = UpdatePolicy {
+			pin: Some(UpdatePin::Version("1.95.0".to_string())),
+			..policy
+		};
+		assert_eq!(pinned.decide(None, now), UpdateDecision::Adopt);
+	}
+}
@@ -5,6 +5,7 @@
 
 use std::{fmt, path::Path};
 
+use chrono::{DateTime, TimeZone, Utc};
 use serde::{Deserialize, Serialize};
 
 use crate::{
@@ -46,6 +47,9 @@ impl std::fmt::Display for Release {
 struct UpdateServerVersion {
 	pub version: String,
 	pub name: String,
+	/// Time the release was published, in milliseconds since the epoch.
+	#[serde(default)]
+	pub timestamp: Option<i64>,
 }
 
 fn quality_download_segment(quality: options::Quality) -> &'static str {
@@ -120,6 +124,19 @@ impl UpdateService {
 		target: TargetKind,
 		quality: options::Quality,
 	) -> Result<Release, AnyError> {
+		self.get_latest_commit_with_time(platform, target, quality)
+			.await
+			.map(|(release, _)| release)
+	}
+
+	/// Gets the latest commit for the target of the given quality, along with
+	/// when it was released, if the update service reports it.
+	pub async fn get_latest_commit_with_time(
+		&self,
+		platform: Platform,
+		target: TargetKind,
+		quality: options::Quality,
+	) -> Result<(Release, Option<DateTime<Utc>>), AnyError> {
 		let update_endpoint = get_update_endpoint()?;
 		let download_segment = target
 			.download_segment(platform)
@@ -144,13 +161,19 @@ impl UpdateService {
 		let res = response.json::<UpdateServerVersion>().await?;
 		debug!(self.log, "Resolved quality {} to {}", quality, res.version);
 
-		Ok(Release {
-			target,
-			platform,
-			quality,
-			name: res.name,
-			commit: res.version,
-		})
+		let released_at = res
+			.timestamp
+			.and_then(|t| Utc.timestamp_millis_opt(t).single());
+		Ok((
+			Release {
+				target,
+				platform,
+				quality,
+				name: res.name,
+				commit: res.version,
+			},
+			released_at,
+		))
 	}
 
 	/// Gets the download stream for the release.
@@ -15,8 +15,13 @@ pub mod prereqs;
 pub mod ring_buffer;
 pub mod sync;
 pub use is_integrated::*;
+pub mod access_log;
 pub mod app_lock;
+pub mod cidr;
 pub mod file_lock;
 pub mod os;
+pub mod readiness;
+pub mod resource_limits;
+pub mod rotating_file;
 pub mod tar;
 pub mod zipper;
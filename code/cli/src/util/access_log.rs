@@ -0,0 +1,305 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+use std::{
+	io::{self, Write},
+	net::SocketAddr,
+	path::Path,
+	sync::{Arc, Mutex},
+	time::Instant,
+};
+
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use hyper::{body::HttpBody, Body, Request, Response};
+use serde::{Deserialize, Serialize};
+
+use super::rotating_file::RotatingFile;
+
+/// Query parameters holding connection tokens, which are redacted in the log.
+const REDACTED_QUERY_PARAMS: &[&str] = &["tkn", "vscode-tkn"];
+const REDACTED: &str = "REDACTED";
+
+#[derive(clap::ValueEnum, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+	/// NCSA Common Log Format
+	Common,
+	/// NCSA Combined Log Format, followed by the duration in milliseconds,
+	/// whether the request was upgraded to a websocket, and the server commit
+	Combined,
+	/// One JSON object per line
+	Json,
+}
+
+/// Response extension a proxy sets to record the commit of the server a
+/// request was routed to.
+#[derive(Clone)]
+pub struct RoutedCommit(pub String);
+
+/// Log of requests handled by a proxy.
+#[derive(Clone, Debug)]
+pub struct AccessLog {
+	format: AccessLogFormat,
+	file: Arc<Mutex<RotatingFile>>,
+}
+
+impl AccessLog {
+	pub fn new(
+		path: &Path,
+		format: AccessLogFormat,
+		max_size: u64,
+		max_files: usize,
+	) -> io::Result<Self> {
+		Ok(Self {
+			format,
+			file: Arc::new(Mutex::new(RotatingFile::open(path, max_size, max_files)?)),
+		})
+	}
+
+	/// Starts an entry for the request. It's written once `finish` is called
+	/// and the response body has been sent.
+	pub fn begin(&self, client: Option<SocketAddr>, req: &Request<Body>) -> AccessLogEntry {
+		let header = |name: hyper::header::HeaderName| {
+			req.headers()
+				.get(name)
+				.and_then(|v| v.to_str().ok())
+				.map(|v| v.to_string())
+		};
+
+		AccessLogEntry {
+			log: self.clone(),
+			started: Instant::now(),
+			time: Utc::now(),
+			client: client.map(|a| a.ip().to_string()),
+			method: req.method().to_string(),
+			path: redact_query(
+				req.uri()
+					.path_and_query()
+					.map(|p| p.as_str())
+					.unwrap_or_else(|| req.uri().path()),
+			),
+			protocol: format!("{:?}", req.version()),
+			referer: header(hyper::header::REFERER).map(|r| redact_query(&r)),
+			user_agent: header(hyper::header::USER_AGENT),
+		}
+	}
+
+	fn write(&self, record: &AccessLogRecord) {
+		let mut line = match self.format {
+			AccessLogFormat::Common => record.to_common(),
+			AccessLogFormat::Combined => record.to_combined(),
+			AccessLogFormat::Json => serde_json::to_string(&record.to_json()).unwrap(),
+		};
+		line.push('\n');
+
+		// ignore any errors, not much we can do if logging fails...
+		self.file.lock().unwrap().write_all(line.as_bytes()).ok();
+	}
+}
+
+/// Information about a request, captured when it's received.
+pub struct AccessLogEntry {
+	log: AccessLog,
+	started: Instant,
+	time: DateTime<Utc>,
+	client: Option<String>,
+	method: String,
+	path: String,
+	protocol: String,
+	referer: Option<String>,
+	user_agent: Option<String>,
+}
+
+impl AccessLogEntry {
+	/// Overrides the client address recorded for the request.
+	pub fn set_client(&mut self, client: impl Into<String>) {
+		self.client = Some(client.into());
+	}
+
+	/// Completes the entry with the response. The entry is written once the
+	/// response body has been sent.
+	pub fn finish(self, res: Response<Body>) -> Response<Body> {
+		let (parts, body) = res.into_parts();
+		// The record is written when dropped, so it's moved into the body.
+		let mut record = AccessLogRecord {
+			status: parts.status.as_u16(),
+			upgraded: parts.status == hyper::StatusCode::SWITCHING_PROTOCOLS,
+			commit: parts.extensions.get::<RoutedCommit>().map(|c| c.0.clone()),
+			bytes: 0,
+			entry: self,
+		};
+
+		if body.is_end_stream() {
+			return Response::from_parts(parts, body);
+		}
+
+		let body = Body::wrap_stream(body.map(move |chunk| {
+			record.count_chunk(&chunk);
+			chunk
+		}));
+
+		Response::from_parts(parts, body)
+	}
+}
+
+/// Record of a completed request, written when dropped.
+struct AccessLogRecord {
+	entry: AccessLogEntry,
+	status: u16,
+	upgraded: bool,
+	commit: Option<String>,
+	bytes: u64,
+}
+
+#[derive(Serialize)]
+struct JsonAccessLogRecord<'a> {
+	time: String,
+	client: Option<&'a str>,
+	method: &'a str,
+	path: &'a str,
+	protocol: &'a str,
+	status: u16,
+	bytes: u64,
+	duration_ms: u128,
+	upgraded: bool,
+	commit: Option<&'a str>,
+	referer: Option<&'a str>,
+	user_agent: Option<&'a str>,
+}
+
+impl AccessLogRecord {
+	fn count_chunk(&mut self, chunk: &Result<hyper::body::Bytes, hyper::Error>) {
+		if let Ok(c) = chunk {
+			self.bytes += c.len() as u64;
+		}
+	}
+
+	fn to_common(&self) -> String {
+		let e = &self.entry;
+		format!(
+			"{} - - [{}] \"{} {} {}\" {} {}",
+			e.client.as_deref().unwrap_or("-"),
+			e.time.format("%d/%b/%Y:%H:%M:%S %z"),
+			e.method,
+			e.path,
+			e.protocol,
+			self.status,
+			match self.bytes {
+				0 => "-".to_string(),
+				n => n.to_string(),
+			}
+		)
+	}
+
+	fn to_combined(&self) -> String {
+		let e = &self.entry;
+		format!(
+			"{} \"{}\" \"{}\" {} {} {}",
+			self.to_common(),
+			escape_quoted(e.referer.as_deref().unwrap_or("-")),
+			escape_quoted(e.user_agent.as_deref().unwrap_or("-")),
+			e.started.elapsed().as_millis(),
+			if self.upgraded { "ws" } else { "-" },
+			self.commit.as_deref().unwrap_or("-"),
+		)
+	}
+
+	fn to_json(&self) -> JsonAccessLogRecord<'_> {
+		let e = &self.entry;
+		JsonAccessLogRecord {
+			time: e.time.to_rfc3339(),
+			client: e.client.as_deref(),
+			method: &e.method,
+			path: &e.path,
+			protocol: &e.protocol,
+			status: self.status,
+			bytes: self.bytes,
+			duration_ms: e.started.elapsed().as_millis(),
+			upgraded: self.upgraded,
+			commit: self.commit.as_deref(),
+			referer: e.referer.as_deref(),
+			user_agent: e.user_agent.as_deref(),
+		}
+	}
+}
+
+impl Drop for AccessLogRecord {
+	fn drop(&mut self) {
+		self.entry.log.write(self);
+	}
+}
+
+/// Replaces the values of connection token query parameters in the URI.
+fn redact_query(uri: &str) -> String {
+	let (path, query) = match uri.split_once('?') {
+		Some(s) => s,
+		None => return uri.to_string(),
+	};
+
+	let query = query
+		.split('&')
+		.map(|pair| {
+			let key = pair.split_once('=').map(|(k, _)| k).unwrap_or(pair);
+			if REDACTED_QUERY_PARAMS.contains(&key) {
+				format!("{key}={REDACTED}")
+			} else {
+				pair.to_string()
+			}
+		})
+		.collect::<Vec<_>>()
+		.join("&");
+
+	format!("{path}?{query}")
+}
+
+fn escape_quoted(s: &str) -> String {
+	s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_redact_query() {
+		assert_eq!(redact_query("/foo"), "/foo");
+		assert_eq!(redact_query("/foo?tkn=secret"), "/foo?tkn=REDACTED");
+		assert_eq!(
+			redact_query("/?folder=/home&tkn=secret&x"),
+			"/?folder=/home&tkn=REDACTED&x"
+		);
+		assert_eq!(
+			redact_query("http://localhost/?vscode-tkn=secret"),
+			"http://localhost/?vscode-tkn=REDACTED"
+		);
+	}
+
+	#[tokio::test]
+	async fn test_writes_record_after_body() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("access.log");
+		let log = AccessLog::new(&path, AccessLogFormat::Json, 1024 * 1024, 1).unwrap();
+
+		let req = Request::builder()
+			.uri("/stable-abc/static/file.js?tkn=secret")
+			.body(Body::empty())
+			.unwrap();
+		let mut res = Response::new(Body::from("hello"));
+		res.extensions_mut().insert(RoutedCommit("abc".to_string()));
+
+		let res = log.begin("127.0.0.1:1234".parse().ok(), &req).finish(res);
+		assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+
+		hyper::body::to_bytes(res.into_body()).await.unwrap();
+		let line = std::fs::read_to_string(&path).unwrap();
+		let record: serde_json::Value = serde_json::from_str(line.trim()).unwrap();
+		assert_eq!(record["client"], "127.0.0.1");
+		assert_eq!(record["path"], "/stable-abc/static/file.js?tkn=REDACTED");
+		assert_eq!(record["status"], 200);
+		assert_eq!(record["bytes"], 5);
+		assert_eq!(record["upgraded"], false);
+		assert_eq!(record["commit"], "abc");
+	}
+}
@@ -0,0 +1,139 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+use std::{fmt, net::IpAddr, str::FromStr};
+
+use serde::{Deserialize, Deserializer};
+
+/// An IP network in CIDR notation, like `10.0.0.0/8` or `fd00::/8`. A plain
+/// address is treated as a network containing only that address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpCidr {
+	addr: IpAddr,
+	prefix_len: u8,
+}
+
+impl IpCidr {
+	/// Gets whether the address is in the network. IPv4-mapped IPv6 addresses
+	/// are compared as their IPv4 equivalent.
+	pub fn contains(&self, addr: &IpAddr) -> bool {
+		match (self.addr, canonical(addr)) {
+			(IpAddr::V4(net), IpAddr::V4(a)) => {
+				prefix_matches(&net.octets(), &a.octets(), self.prefix_len)
+			}
+			(IpAddr::V6(net), IpAddr::V6(a)) => {
+				prefix_matches(&net.octets(), &a.octets(), self.prefix_len)
+			}
+			_ => false,
+		}
+	}
+}
+
+fn canonical(addr: &IpAddr) -> IpAddr {
+	match addr {
+		IpAddr::V6(v6) => v6
+			.to_ipv4_mapped()
+			.map(IpAddr::V4)
+			.unwrap_or(IpAddr::V6(*v6)),
+		a => *a,
+	}
+}
+
+fn prefix_matches(net: &[u8], addr: &[u8], prefix_len: u8) -> bool {
+	let full_bytes = (prefix_len / 8) as usize;
+	if net[..full_bytes] != addr[..full_bytes] {
+		return false;
+	}
+
+	let rem = prefix_len % 8;
+	if rem == 0 {
+		return true;
+	}
+
+	let mask = 0xffu8 << (8 - rem);
+	net[full_bytes] & mask == addr[full_bytes] & mask
+}
+
+impl FromStr for IpCidr {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (addr, prefix_len) = match s.split_once('/') {
+			Some((a, p)) => (a, Some(p)),
+			None => (s, None),
+		};
+
+		let addr: IpAddr = addr
+			.parse()
+			.map_err(|_| format!("'{s}' is not a valid IP address or CIDR range"))?;
+		let max_len = if addr.is_ipv4() { 32 } else { 128 };
+		let prefix_len = match prefix_len {
+			Some(p) => p
+				.parse::<u8>()
+				.ok()
+				.filter(|p| *p <= max_len)
+				.ok_or_else(|| format!("'{s}' has an invalid prefix length"))?,
+			None => max_len,
+		};
+
+		Ok(Self { addr, prefix_len })
+	}
+}
+
+impl<'de> Deserialize<'de> for IpCidr {
+	fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+	where
+		D: Deserializer<'de>,
+	{
+		String::deserialize(deserializer)?
+			.parse()
+			.map_err(serde::de::Error::custom)
+	}
+}
+
+impl fmt::Display for IpCidr {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}/{}", self.addr, self.prefix_len)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse() {
+		assert_eq!(
+			"10.0.0.0/8".parse::<IpCidr>().unwrap().to_string(),
+			"10.0.0.0/8"
+		);
+		assert_eq!(
+			"127.0.0.1".parse::<IpCidr>().unwrap().to_string(),
+			"127.0.0.1/32"
+		);
+		assert_eq!(
+			"fd00::/8".parse::<IpCidr>().unwrap().to_string(),
+			"fd00::/8"
+		);
+		assert!("10.0.0.0/33".parse::<IpCidr>().is_err());
+		assert!("example.com".parse::<IpCidr>().is_err());
+	}
+
+	#[test]
+	fn test_contains() {
+		let net: IpCidr = "172.16.0.0/12".parse().unwrap();
+		assert!(net.contains(&"172.16.0.1".parse().unwrap()));
+		assert!(net.contains(&"172.31.255.255".parse().unwrap()));
+		assert!(!net.contains(&"172.32.0.1".parse().unwrap()));
+		assert!(net.contains(&"::ffff:172.20.1.1".parse().unwrap()));
+		assert!(!net.contains(&"fd00::1".parse().unwrap()));
+
+		let net: IpCidr = "fd00::/8".parse().unwrap();
+		assert!(net.contains(&"fd12::1".parse().unwrap()));
+		assert!(!net.contains(&"fe80::1".parse().unwrap()));
+
+		let any: IpCidr = "0.0.0.0/0".parse().unwrap();
+		assert!(any.contains(&"8.8.8.8".parse().unwrap()));
+	}
+}
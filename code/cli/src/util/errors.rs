@@ -451,6 +451,14 @@ pub enum CodeError {
 	SingletonLockedProcessExited(u32),
 	#[error("no tunnel process is currently running")]
 	NoRunningTunnel,
+	#[error("several tunnels are running ({0}), choose one with --instance")]
+	AmbiguousTunnelInstance(String),
+	#[error("no agent host process is currently running")]
+	NoRunningAgentHost,
+	#[error("invalid port access: {0}")]
+	InvalidPortAccess(String),
+	#[error("the background forwarding process did not start, see its log at {0}")]
+	ForwardingProcessNotStarted(String),
 	#[error("rpc call failed: {0:?}")]
 	TunnelRpcCallFailed(ResponseError),
 	#[cfg(windows)]
@@ -519,6 +527,38 @@ pub enum CodeError {
 	ServerOriginTimeout,
 	#[error("Server exited without writing port/socket: {0}")]
 	ServerUnexpectedExit(String),
+	#[error("Server ran out of memory before it was ready: {0}")]
+	ServerOutOfMemory(String),
+	#[error("Server did not become ready within {0}s. Its last output was:\n{1}")]
+	ServerStartupTimeout(u64, String),
+	#[error("Could not load config file {0}: {1}")]
+	CouldNotLoadConfigFile(String, String),
+	#[error("Could not load OpenID Connect configuration from {0}: {1}")]
+	OidcDiscoveryFailed(String, String),
+	#[error("Agent host servers are already running for the maximum of {0} workspaces")]
+	TooManyAgentHostWorkspaces(usize),
+	#[error("The agent host server for workspace '{0}' crashed {1} times in a row and won't be restarted for another {2}s. Last failure: {3}")]
+	AgentHostCrashLoop(String, u32, u64, String),
+	#[error("The agent host server for workspace '{0}' crashed and will be restarted in {1}s")]
+	AgentHostRestartBackoff(String, u64),
+	#[error("No server log was found for {0}")]
+	ServerLogNotFound(String),
+	#[error("Invalid log filter: {0}")]
+	InvalidLogFilter(String),
+	#[error("Invalid server environment: {0}")]
+	InvalidServerEnv(String),
+	#[error("Setting the server environment variable {0} is not allowed")]
+	ServerEnvNotAllowed(String),
+	#[error("Invalid extension bundle: {0}")]
+	InvalidExtensionBundle(String),
+	#[error("Could not load extension lock file {0}: {1}")]
+	InvalidExtensionLockFile(String, String),
+	#[error("No installation of the editor was found for version {0}")]
+	NoEditorInstalled(String),
+	#[error("No server is installed for {0}")]
+	NoServerInstalled(String),
+	#[error("No server is running for {0}")]
+	NoServerRunning(String),
 }
 
 makeAnyError!(
@@ -69,6 +69,16 @@ pub fn find_running_process(name: &Path) -> Option<u32> {
 	None
 }
 
+/// Gets the command line arguments of all running processes.
+pub fn running_process_args() -> Vec<String> {
+	let mut sys = System::new();
+	sys.refresh_processes();
+	sys.processes()
+		.values()
+		.flat_map(|p| p.cmd().iter().cloned())
+		.collect()
+}
+
 pub async fn wait_until_exe_deleted(current_exe: &Path, poll_ms: u64) {
 	let duration = Duration::from_millis(poll_ms);
 	while current_exe.exists() {
@@ -0,0 +1,148 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::{
+	path::PathBuf,
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+
+use tokio::{
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+	net::TcpStream,
+	time::{sleep, timeout},
+};
+
+use crate::async_pipe::get_socket_rw_stream;
+
+use super::ring_buffer::RingBuffer;
+
+/// Time between probes of a server that isn't ready yet.
+const PROBE_INTERVAL: Duration = Duration::from_millis(250);
+/// Time a single probe may take.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+/// Number of output lines included in startup errors.
+pub const OUTPUT_TAIL_LINES: usize = 20;
+
+/// Where a server accepts HTTP requests.
+#[derive(Clone, Debug)]
+pub enum ProbeTarget {
+	Port(u16),
+	Socket(PathBuf),
+}
+
+/// Sends a minimal HTTP request to the target, returning whether it got any
+/// HTTP response back. Error statuses count, since they show the server is
+/// handling requests.
+pub async fn probe_http(target: &ProbeTarget) -> bool {
+	let probe = async {
+		match target {
+			ProbeTarget::Port(port) => match TcpStream::connect(("localhost", *port)).await {
+				Ok(s) => send_probe(s).await,
+				Err(_) => false,
+			},
+			ProbeTarget::Socket(path) => match get_socket_rw_stream(path).await {
+				Ok(s) => send_probe(s).await,
+				Err(_) => false,
+			},
+		}
+	};
+
+	timeout(PROBE_TIMEOUT, probe).await.unwrap_or(false)
+}
+
+async fn send_probe(mut stream: impl AsyncRead + AsyncWrite + Unpin) -> bool {
+	let req = b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+	if stream.write_all(req).await.is_err() {
+		return false;
+	}
+
+	let mut buf = [0u8; 7];
+	match stream.read_exact(&mut buf).await {
+		Ok(_) => &buf == b"HTTP/1.",
+		Err(_) => false,
+	}
+}
+
+/// Resolves once a probe of the target succeeds.
+pub async fn wait_for_http(target: ProbeTarget) {
+	while !probe_http(&target).await {
+		sleep(PROBE_INTERVAL).await;
+	}
+}
+
+/// The last lines a server wrote, kept to explain why it failed to start.
+#[derive(Clone)]
+pub struct OutputTail(Arc<Mutex<RingBuffer<String>>>);
+
+impl Default for OutputTail {
+	fn default() -> Self {
+		Self(Arc::new(Mutex::new(RingBuffer::new(OUTPUT_TAIL_LINES))))
+	}
+}
+
+impl OutputTail {
+	pub fn push(&self, line: &str) {
+		self.0.lock().unwrap().push(line.to_string());
+	}
+}
+
+impl std::fmt::Display for OutputTail {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let lines = self.0.lock().unwrap();
+		if lines.is_empty() {
+			return write!(f, "(no output)");
+		}
+		for (i, line) in lines.iter().enumerate() {
+			if i > 0 {
+				writeln!(f)?;
+			}
+			write!(f, "{line}")?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tokio::net::TcpListener;
+
+	use super::*;
+
+	#[tokio::test]
+	async fn test_probe_http() {
+		let listener = TcpListener::bind("localhost:0").await.unwrap();
+		let port = listener.local_addr().unwrap().port();
+		let target = ProbeTarget::Port(port);
+
+		tokio::spawn(async move {
+			let (mut s, _) = listener.accept().await.unwrap();
+			let mut buf = [0u8; 64];
+			let _ = s.read(&mut buf).await;
+			s.write_all(b"HTTP/1.1 401 Unauthorized\r\n\r\n")
+				.await
+				.unwrap();
+
+			// something that isn't http:
+			let (mut s, _) = listener.accept().await.unwrap();
+			s.write_all(b"SSH-2.0-OpenSSH\r\n").await.unwrap();
+		});
+
+		assert!(probe_http(&target).await);
+		assert!(!probe_http(&target).await);
+	}
+
+	#[test]
+	fn test_output_tail() {
+		let tail = OutputTail::default();
+		assert_eq!(tail.to_string(), "(no output)");
+		for i in 0..OUTPUT_TAIL_LINES + 2 {
+			tail.push(&format!("line {i}"));
+		}
+		let s = tail.to_string();
+		assert!(s.starts_with("line 2\n"));
+		assert!(s.ends_with(&format!("line {}", OUTPUT_TAIL_LINES + 1)));
+	}
+}
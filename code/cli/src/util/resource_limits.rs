@@ -0,0 +1,382 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+
+use std::path::PathBuf;
+
+use tokio::process::Command;
+
+use crate::log;
+
+/// Limits on the resources a server process and its children may use.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ResourceLimits {
+	/// Memory the processes may use, in bytes.
+	pub memory_max: Option<u64>,
+	/// Relative share of CPU time, from 1 to 10000, where 100 is the default.
+	pub cpu_weight: Option<u32>,
+	/// CPU time the processes may use, in percent of one CPU.
+	pub cpu_quota_percent: Option<u32>,
+	/// Number of processes and threads that may exist at once.
+	pub pids_max: Option<u32>,
+	/// Scheduling priority, from 0 (the default) to 19 (lowest).
+	pub nice: Option<i32>,
+	/// Best-effort I/O priority, from 0 (highest) to 7 (lowest).
+	pub ionice: Option<u8>,
+}
+
+impl ResourceLimits {
+	pub fn is_empty(&self) -> bool {
+		*self == ResourceLimits::default()
+	}
+}
+
+/// Handle to the limits applied to a spawned process. It should be kept until
+/// the process exits, after which its cgroup, if any, is removed.
+#[derive(Debug, Default)]
+pub struct AppliedLimits {
+	cgroup: Option<PathBuf>,
+	memory_max: Option<u64>,
+}
+
+impl AppliedLimits {
+	/// Gets the number of processes that were killed for exceeding the memory
+	/// limit. Only known when the limits were applied with a cgroup.
+	pub fn oom_kills(&self) -> u64 {
+		let events = match &self.cgroup {
+			Some(c) => std::fs::read_to_string(c.join("memory.events")).unwrap_or_default(),
+			None => return 0,
+		};
+		events
+			.lines()
+			.find_map(|l| l.strip_prefix("oom_kill "))
+			.and_then(|n| n.trim().parse().ok())
+			.unwrap_or(0)
+	}
+
+	/// Gets a description of why processes were killed, if it was for running
+	/// out of memory.
+	pub fn oom_reason(&self) -> Option<String> {
+		match self.oom_kills() {
+			0 => None,
+			n => Some(format!(
+				"{} process(es) were killed for exceeding the memory limit of {} MB",
+				n,
+				self.memory_max.unwrap_or_default() / 1024 / 1024
+			)),
+		}
+	}
+}
+
+impl Drop for AppliedLimits {
+	fn drop(&mut self) {
+		if let Some(c) = &self.cgroup {
+			let _ = std::fs::remove_dir(c);
+		}
+	}
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+	use std::{
+		ffi::CString,
+		fs, io,
+		os::unix::ffi::OsStrExt,
+		path::{Path, PathBuf},
+		sync::atomic::{AtomicU32, Ordering},
+	};
+
+	use lazy_static::lazy_static;
+
+	use super::ResourceLimits;
+
+	const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+	const CPU_PERIOD_US: u64 = 100_000;
+	const IOPRIO_CLASS_BE: i32 = 2;
+	const IOPRIO_CLASS_SHIFT: i32 = 13;
+	const IOPRIO_WHO_PROCESS: i32 = 1;
+
+	lazy_static! {
+		static ref DELEGATED_CGROUP: Result<PathBuf, String> = find_delegated_cgroup();
+	}
+
+	static NEXT_CGROUP_ID: AtomicU32 = AtomicU32::new(0);
+
+	/// Gets the cgroup v2 directory the CLI runs in.
+	fn own_cgroup() -> Result<PathBuf, String> {
+		let contents = fs::read_to_string("/proc/self/cgroup")
+			.map_err(|e| format!("could not read /proc/self/cgroup: {e}"))?;
+		let path = contents
+			.lines()
+			.find_map(|l| l.strip_prefix("0::"))
+			.ok_or("cgroup v2 is not in use")?;
+		let dir = Path::new(CGROUP_ROOT).join(path.trim_start_matches('/'));
+		if !dir.join("cgroup.controllers").exists() {
+			return Err(format!("{} is not a cgroup v2 directory", dir.display()));
+		}
+		Ok(dir)
+	}
+
+	/// Finds the cgroup that limited groups are created in. cgroup v2 only
+	/// allows controllers to be enabled for groups without processes of their
+	/// own, so this is the parent of the CLI's cgroup, which must be writable
+	/// and already enable the controllers for its children. The CLI itself is
+	/// never moved.
+	fn find_delegated_cgroup() -> Result<PathBuf, String> {
+		let own = own_cgroup()?;
+		let dir = own
+			.parent()
+			.filter(|p| p.starts_with(CGROUP_ROOT) && p != &Path::new(CGROUP_ROOT))
+			.ok_or_else(|| format!("{} has no delegated parent", own.display()))?
+			.to_path_buf();
+
+		// moving a process between groups needs write access to the procs
+		// file of their common parent
+		let procs = CString::new(dir.join("cgroup.procs").as_os_str().as_bytes()).unwrap();
+		if unsafe { libc::access(procs.as_ptr(), libc::W_OK) } != 0 {
+			return Err(format!(
+				"{} is not writable: {}",
+				dir.display(),
+				io::Error::last_os_error()
+			));
+		}
+
+		Ok(dir)
+	}
+
+	/// Gets the controllers needed for the limits that aren't enabled for
+	/// children of the cgroup.
+	pub(super) fn missing_controllers(dir: &Path, limits: &ResourceLimits) -> Vec<&'static str> {
+		let enabled = fs::read_to_string(dir.join("cgroup.subtree_control")).unwrap_or_default();
+		let wanted = [
+			("memory", limits.memory_max.is_some()),
+			(
+				"cpu",
+				limits.cpu_weight.is_some() || limits.cpu_quota_percent.is_some(),
+			),
+			("pids", limits.pids_max.is_some()),
+		];
+		wanted
+			.iter()
+			.filter(|(c, needed)| *needed && !enabled.split_whitespace().any(|e| e == *c))
+			.map(|(c, _)| *c)
+			.collect()
+	}
+
+	/// Creates a cgroup with the limits, returning its path.
+	pub fn create_cgroup(limits: &ResourceLimits) -> Result<PathBuf, String> {
+		let parent = DELEGATED_CGROUP.as_ref().map_err(|e| e.clone())?;
+		let missing = missing_controllers(parent, limits);
+		if !missing.is_empty() {
+			return Err(format!(
+				"the {} controller(s) are not enabled in {}",
+				missing.join(", "),
+				parent.display()
+			));
+		}
+
+		let dir = parent.join(format!(
+			"server-{}-{}",
+			std::process::id(),
+			NEXT_CGROUP_ID.fetch_add(1, Ordering::SeqCst)
+		));
+		fs::create_dir(&dir).map_err(|e| format!("could not create {}: {}", dir.display(), e))?;
+
+		let write = |file: &str, value: String| {
+			fs::write(dir.join(file), value)
+				.map_err(|e| format!("could not set {} in {}: {}", file, dir.display(), e))
+		};
+		let result = (|| {
+			if let Some(m) = limits.memory_max {
+				write("memory.max", m.to_string())?;
+			}
+			if let Some(w) = limits.cpu_weight {
+				write("cpu.weight", w.to_string())?;
+			}
+			if let Some(p) = limits.cpu_quota_percent {
+				let quota = CPU_PERIOD_US * p as u64 / 100;
+				write("cpu.max", format!("{quota} {CPU_PERIOD_US}"))?;
+			}
+			if let Some(p) = limits.pids_max {
+				write("pids.max", p.to_string())?;
+			}
+			Ok(())
+		})();
+
+		if let Err(e) = result {
+			let _ = fs::remove_dir(&dir);
+			return Err(e);
+		}
+
+		Ok(dir)
+	}
+
+	/// Settings applied in the child process between fork and exec.
+	pub struct PreExec {
+		pub cgroup_procs: Option<CString>,
+		pub memory_rlimit: Option<u64>,
+		pub nice: Option<i32>,
+		pub ionice: Option<u8>,
+	}
+
+	impl PreExec {
+		pub fn new(cgroup: Option<&Path>, limits: &ResourceLimits) -> Self {
+			Self {
+				cgroup_procs: cgroup
+					.map(|c| CString::new(c.join("cgroup.procs").as_os_str().as_bytes()).unwrap()),
+				memory_rlimit: if cgroup.is_none() {
+					limits.memory_max
+				} else {
+					None
+				},
+				nice: limits.nice,
+				ionice: limits.ionice,
+			}
+		}
+
+		/// Applies the settings to the current process. Only calls functions
+		/// that are safe to use after forking. Priorities are best-effort, and
+		/// the server still starts if they can't be set.
+		pub fn run(&self) -> io::Result<()> {
+			unsafe {
+				if let Some(procs) = &self.cgroup_procs {
+					let fd = libc::open(procs.as_ptr(), libc::O_WRONLY);
+					if fd < 0 {
+						return Err(io::Error::last_os_error());
+					}
+					let written = libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1);
+					libc::close(fd);
+					if written != 1 {
+						return Err(io::Error::last_os_error());
+					}
+				}
+
+				if let Some(m) = self.memory_rlimit {
+					let limit = libc::rlimit {
+						rlim_cur: m as libc::rlim_t,
+						rlim_max: m as libc::rlim_t,
+					};
+					if libc::setrlimit(libc::RLIMIT_DATA, &limit) != 0 {
+						return Err(io::Error::last_os_error());
+					}
+				}
+
+				if let Some(n) = self.nice {
+					libc::setpriority(libc::PRIO_PROCESS, 0, n);
+				}
+
+				if let Some(level) = self.ionice {
+					let prio = (IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT) | level.min(7) as i32;
+					libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, prio);
+				}
+			}
+
+			Ok(())
+		}
+	}
+}
+
+impl ResourceLimits {
+	/// Applies the limits to a command that's about to be spawned. Uses a
+	/// cgroup if the CLI runs in a writable cgroup v2 subtree, and otherwise
+	/// falls back to rlimits, which can't limit CPU use or the number of
+	/// processes.
+	#[cfg(target_os = "linux")]
+	pub fn apply(&self, log: &log::Logger, cmd: &mut Command) -> AppliedLimits {
+		if self.is_empty() {
+			return AppliedLimits::default();
+		}
+
+		let needs_cgroup = self.memory_max.is_some()
+			|| self.cpu_weight.is_some()
+			|| self.cpu_quota_percent.is_some()
+			|| self.pids_max.is_some();
+		let cgroup = if needs_cgroup {
+			match linux::create_cgroup(self) {
+				Ok(c) => {
+					debug!(log, "Limiting server resources with cgroup {}", c.display());
+					Some(c)
+				}
+				Err(e) => {
+					warning!(
+						log,
+						"Could not use a cgroup to limit server resources ({}), using rlimits instead. CPU and process limits won't apply.",
+						e
+					);
+					None
+				}
+			}
+		} else {
+			None
+		};
+
+		let pre_exec = linux::PreExec::new(cgroup.as_deref(), self);
+		unsafe {
+			cmd.pre_exec(move || pre_exec.run());
+		}
+
+		AppliedLimits {
+			cgroup,
+			memory_max: self.memory_max,
+		}
+	}
+
+	#[cfg(not(target_os = "linux"))]
+	pub fn apply(&self, log: &log::Logger, _cmd: &mut Command) -> AppliedLimits {
+		if !self.is_empty() {
+			warning!(log, "Server resource limits are only supported on Linux");
+		}
+		AppliedLimits::default()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_oom_kills() {
+		let dir = tempfile::tempdir().unwrap();
+		let cgroup = dir.path().join("server");
+		std::fs::create_dir(&cgroup).unwrap();
+		std::fs::write(
+			cgroup.join("memory.events"),
+			"low 0\nhigh 0\nmax 3\noom 1\noom_kill 2\n",
+		)
+		.unwrap();
+
+		let applied = AppliedLimits {
+			cgroup: Some(cgroup.clone()),
+			memory_max: Some(512 * 1024 * 1024),
+		};
+		assert_eq!(applied.oom_kills(), 2);
+		assert!(applied.oom_reason().unwrap().contains("512 MB"));
+
+		assert_eq!(AppliedLimits::default().oom_kills(), 0);
+	}
+
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn test_missing_controllers() {
+		let dir = tempfile::tempdir().unwrap();
+		std::fs::write(dir.path().join("cgroup.subtree_control"), "cpu pids\n").unwrap();
+
+		let limits = ResourceLimits {
+			memory_max: Some(1024),
+			cpu_weight: Some(50),
+			nice: Some(10),
+			..Default::default()
+		};
+		assert_eq!(
+			linux::missing_controllers(dir.path(), &limits),
+			vec!["memory"]
+		);
+
+		let limits = ResourceLimits {
+			pids_max: Some(10),
+			..Default::default()
+		};
+		assert!(linux::missing_controllers(dir.path(), &limits).is_empty());
+	}
+}
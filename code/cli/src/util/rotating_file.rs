@@ -0,0 +1,219 @@
+/*---------------------------------------------------------------------------------------------
+ *  Copyright (c) Microsoft Corporation. All rights reserved.
+ *  Licensed under the MIT License. See License.txt in the project root for license information.
+ *--------------------------------------------------------------------------------------------*/
+use std::{
+	fs::{self, File},
+	io::{self, Write},
+	path::{Path, PathBuf},
+};
+
+/// A file that's appended to, and rotated once it grows past a size limit.
+/// Rotated files get a numeric suffix, where `<name>.1` is the most recent.
+#[derive(Debug)]
+pub struct RotatingFile {
+	path: PathBuf,
+	max_size: u64,
+	max_files: usize,
+	file: File,
+	size: u64,
+	header: Vec<u8>,
+	private: bool,
+}
+
+impl RotatingFile {
+	/// Opens the file for appending. Once it's larger than `max_size` bytes,
+	/// it's rotated, keeping up to `max_files` previous files.
+	pub fn open(path: &Path, max_size: u64, max_files: usize) -> io::Result<Self> {
+		Self::open_inner(path, max_size, max_files, false)
+	}
+
+	/// Like [RotatingFile::open], but on Unix the files are only readable and
+	/// writable by the current user.
+	pub fn open_private(path: &Path, max_size: u64, max_files: usize) -> io::Result<Self> {
+		Self::open_inner(path, max_size, max_files, true)
+	}
+
+	fn open_inner(path: &Path, max_size: u64, max_files: usize, private: bool) -> io::Result<Self> {
+		if let Some(parent) = path.parent() {
+			fs::create_dir_all(parent)?;
+		}
+
+		let file = Self::open_file(path, private)?;
+		let size = file.metadata()?.len();
+		Ok(Self {
+			path: path.to_owned(),
+			max_size,
+			max_files,
+			file,
+			size,
+			header: vec![],
+			private,
+		})
+	}
+
+	/// Sets content written at the start of each file created by rotation,
+	/// so that details logged once at startup remain in the active file.
+	pub fn set_header(&mut self, header: &[u8]) {
+		self.header = header.to_vec();
+	}
+
+	/// Rotates the active file now, if it has any contents.
+	pub fn start_new_file(&mut self) -> io::Result<()> {
+		if self.size > 0 {
+			self.rotate()?;
+		}
+		Ok(())
+	}
+
+	/// Gets the path of the active file.
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+
+	/// Gets the paths of the active file and any rotated files that exist,
+	/// from newest to oldest.
+	pub fn all_paths(&self) -> Vec<PathBuf> {
+		let mut paths = vec![self.path.clone()];
+		for i in 1..=self.max_files {
+			let p = rotated_path(&self.path, i);
+			if !p.exists() {
+				break;
+			}
+			paths.push(p);
+		}
+
+		paths
+	}
+
+	#[allow(unused_variables)]
+	fn open_file(path: &Path, private: bool) -> io::Result<File> {
+		let mut options = fs::OpenOptions::new();
+		options.append(true).create(true);
+
+		#[cfg(unix)]
+		if private {
+			use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+			options.mode(0o600);
+			let file = options.open(path)?;
+			// the mode only applies to new files, so restrict existing ones too
+			file.set_permissions(fs::Permissions::from_mode(0o600))?;
+			return Ok(file);
+		}
+
+		options.open(path)
+	}
+
+	fn rotate(&mut self) -> io::Result<()> {
+		if self.max_files == 0 {
+			fs::remove_file(&self.path)?;
+		} else {
+			for i in (1..self.max_files).rev() {
+				let from = rotated_path(&self.path, i);
+				if from.exists() {
+					fs::rename(&from, rotated_path(&self.path, i + 1))?;
+				}
+			}
+			fs::rename(&self.path, rotated_path(&self.path, 1))?;
+		}
+
+		self.file = Self::open_file(&self.path, self.private)?;
+		self.file.write_all(&self.header)?;
+		self.size = self.header.len() as u64;
+		Ok(())
+	}
+}
+
+impl Write for RotatingFile {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		if self.size > self.header.len() as u64 && self.size + buf.len() as u64 > self.max_size {
+			self.rotate()?;
+		}
+
+		let n = self.file.write(buf)?;
+		self.size += n as u64;
+		Ok(n)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.file.flush()
+	}
+}
+
+/// Gets the path of the n-th rotated file for the given path.
+pub fn rotated_path(path: &Path, n: usize) -> PathBuf {
+	let mut name = path.file_name().unwrap_or_default().to_owned();
+	name.push(format!(".{n}"));
+	path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_rotates_at_size_limit() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("out.log");
+		let mut f = RotatingFile::open(&path, 10, 2).unwrap();
+
+		f.write_all(b"aaaaaaaa\n").unwrap();
+		f.write_all(b"bbbbbbbb\n").unwrap();
+		f.write_all(b"cccccccc\n").unwrap();
+		f.write_all(b"dddddddd\n").unwrap();
+
+		assert_eq!(fs::read_to_string(&path).unwrap(), "dddddddd\n");
+		assert_eq!(
+			fs::read_to_string(rotated_path(&path, 1)).unwrap(),
+			"cccccccc\n"
+		);
+		assert_eq!(
+			fs::read_to_string(rotated_path(&path, 2)).unwrap(),
+			"bbbbbbbb\n"
+		);
+		assert!(!rotated_path(&path, 3).exists());
+		assert_eq!(f.all_paths().len(), 3);
+	}
+
+	#[test]
+	fn test_writes_header_after_rotation() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("out.log");
+		let mut f = RotatingFile::open(&path, 20, 1).unwrap();
+
+		f.write_all(b"listening\n").unwrap();
+		f.set_header(b"listening\n");
+		f.write_all(b"aaaaaaaaa\n").unwrap();
+		f.write_all(b"bbbbbbbbb\n").unwrap();
+
+		assert_eq!(fs::read_to_string(&path).unwrap(), "listening\nbbbbbbbbb\n");
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn test_private_files() {
+		use std::os::unix::fs::PermissionsExt;
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("out.log");
+		fs::write(&path, "existing\n").unwrap();
+
+		let mut f = RotatingFile::open_private(&path, 10, 1).unwrap();
+		f.write_all(b"aaaaaaaaaaaa\n").unwrap();
+		for p in f.all_paths() {
+			let mode = fs::metadata(p).unwrap().permissions().mode();
+			assert_eq!(mode & 0o777, 0o600);
+		}
+	}
+
+	#[test]
+	fn test_continues_existing_file() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("out.log");
+		fs::write(&path, "existing\n").unwrap();
+
+		let mut f = RotatingFile::open(&path, 100, 1).unwrap();
+		f.write_all(b"appended\n").unwrap();
+
+		assert_eq!(fs::read_to_string(&path).unwrap(), "existing\nappended\n");
+	}
+}